@@ -0,0 +1,285 @@
+//! Requests and raw MBAP frames against the Modbus/TCP server
+//!
+//! `cargo fuzz run --target x86_64-unknown-linux-gnu modbus`
+//!
+//! The server must not panic, must not hold more than
+//! [`ALLOCATION_LIMIT`] for one input, and must leave the relays alone when
+//! it answers with an exception.
+
+#![no_main]
+
+use anyhow::Result;
+use arbitrary::Arbitrary;
+use digital_relay_controller::{
+    modbus::{self, gateway::Request as GatewayRequest},
+    relay::{self, Backend, COUNT},
+};
+use libfuzzer_sys::fuzz_target;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::SocketAddr,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc as std_mpsc,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime::{Builder, Runtime},
+    spawn,
+    sync::mpsc,
+    time::timeout,
+};
+use tokio_modbus::{
+    client::{Context, tcp},
+    prelude::*,
+};
+
+/// Memory one input may hold on top of what was allocated before it
+const ALLOCATION_LIMIT: usize = 1 << 20;
+const TIMEOUT: Duration = Duration::from_millis(100);
+/// MBAP header: transaction, protocol, length, unit
+const HEADER_SIZE: usize = 7;
+
+// Protocol limits, larger requests can not be encoded
+const MAX_COILS: usize = 1968;
+const MAX_REGISTERS: usize = 123;
+const MAX_READ_WRITE_REGISTERS: usize = 121;
+const MAX_DATA: usize = 252;
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+static HARNESS: LazyLock<Mutex<Harness>> = LazyLock::new(|| Mutex::new(Harness::new()));
+
+/// Allocator that keeps track of the allocated and the peak memory
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+/// Mock relay outputs
+#[derive(Clone, Default)]
+struct Relays(Arc<Mutex<[bool; COUNT as _]>>);
+
+impl Relays {
+    fn get(&self) -> [bool; COUNT as _] {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl Backend for Relays {
+    fn set(&mut self, index: usize, value: bool) -> Result<()> {
+        self.0.lock().unwrap()[index] = value;
+        Ok(())
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Input {
+    /// Request encoded by the tokio-modbus client
+    Request { unit: u8, request: FuzzRequest },
+    /// PDU in a well formed MBAP frame
+    Pdu { unit: u8, pdu: Vec<u8> },
+    /// Anything at all, possibly several or partial frames
+    Raw(Vec<u8>),
+}
+
+#[derive(Arbitrary, Debug)]
+enum FuzzRequest {
+    ReadCoils(u16, u16),
+    ReadDiscreteInputs(u16, u16),
+    WriteSingleCoil(u16, bool),
+    WriteMultipleCoils(u16, Vec<bool>),
+    ReadInputRegisters(u16, u16),
+    ReadHoldingRegisters(u16, u16),
+    WriteSingleRegister(u16, u16),
+    WriteMultipleRegisters(u16, Vec<u16>),
+    MaskWriteRegister(u16, u16, u16),
+    ReadWriteMultipleRegisters(u16, u16, u16, Vec<u16>),
+    ReportServerId,
+    Custom(u8, Vec<u8>),
+}
+
+impl From<FuzzRequest> for Request<'static> {
+    fn from(request: FuzzRequest) -> Self {
+        fn truncate<T>(mut values: Vec<T>, length: usize) -> Vec<T> {
+            values.truncate(length);
+            values
+        }
+        match request {
+            FuzzRequest::ReadCoils(address, count) => Self::ReadCoils(address, count),
+            FuzzRequest::ReadDiscreteInputs(address, count) => {
+                Self::ReadDiscreteInputs(address, count)
+            }
+            FuzzRequest::WriteSingleCoil(address, value) => Self::WriteSingleCoil(address, value),
+            FuzzRequest::WriteMultipleCoils(address, values) => {
+                Self::WriteMultipleCoils(address, truncate(values, MAX_COILS).into())
+            }
+            FuzzRequest::ReadInputRegisters(address, count) => {
+                Self::ReadInputRegisters(address, count)
+            }
+            FuzzRequest::ReadHoldingRegisters(address, count) => {
+                Self::ReadHoldingRegisters(address, count)
+            }
+            FuzzRequest::WriteSingleRegister(address, value) => {
+                Self::WriteSingleRegister(address, value)
+            }
+            FuzzRequest::WriteMultipleRegisters(address, values) => {
+                Self::WriteMultipleRegisters(address, truncate(values, MAX_REGISTERS).into())
+            }
+            FuzzRequest::MaskWriteRegister(address, and, or) => {
+                Self::MaskWriteRegister(address, and, or)
+            }
+            FuzzRequest::ReadWriteMultipleRegisters(read_address, count, write_address, values) => {
+                Self::ReadWriteMultipleRegisters(
+                    read_address,
+                    count,
+                    write_address,
+                    truncate(values, MAX_READ_WRITE_REGISTERS).into(),
+                )
+            }
+            FuzzRequest::ReportServerId => Self::ReportServerId,
+            FuzzRequest::Custom(function, data) => {
+                Self::Custom(function, truncate(data, MAX_DATA).into())
+            }
+        }
+    }
+}
+
+/// Server on a thread of its own, and the client side of it
+struct Harness {
+    runtime: Runtime,
+    socket_addr: SocketAddr,
+    relays: Relays,
+    context: Option<Context>,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let relays = Relays::default();
+        let (sender, receiver) = std_mpsc::channel();
+        let backend = relays.clone();
+        thread::spawn(move || {
+            let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async move {
+                let relay_sender = relay::start(backend).unwrap();
+                let (led_sender, mut led_receiver) = mpsc::channel(9);
+                spawn(async move { while led_receiver.recv().await.is_some() {} });
+                // No RTU slave ever answers
+                let (gateway_sender, mut gateway_receiver) = mpsc::channel::<GatewayRequest>(9);
+                spawn(async move {
+                    while let Some((_, _, sender)) = gateway_receiver.recv().await {
+                        let _ = sender.send(Err(ExceptionCode::GatewayTargetDevice));
+                    }
+                });
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                sender.send(listener.local_addr().unwrap()).unwrap();
+                modbus::serve(listener, relay_sender, led_sender, gateway_sender)
+                    .await
+                    .unwrap();
+            });
+        });
+        Self {
+            runtime: Builder::new_current_thread().enable_all().build().unwrap(),
+            socket_addr: receiver.recv().unwrap(),
+            relays,
+            context: None,
+        }
+    }
+
+    /// Send the request, `Some(true)` if it was answered with an exception
+    fn request(&mut self, unit: u8, request: Request<'static>) -> Option<bool> {
+        let socket_addr = self.socket_addr;
+        let context = &mut self.context;
+        let result = self.runtime.block_on(async {
+            if context.is_none() {
+                *context = Some(tcp::connect(socket_addr).await.ok()?);
+            }
+            let context = context.as_mut()?;
+            context.set_slave(Slave(unit));
+            timeout(TIMEOUT, context.call(request)).await.ok()?.ok()
+        });
+        if result.is_none() {
+            self.context = None;
+        }
+        result.map(|result| result.is_err())
+    }
+
+    /// Send the bytes on a new connection, `Some(true)` if the first answer
+    /// is an exception
+    fn send(&mut self, frame: &[u8]) -> Option<bool> {
+        let socket_addr = self.socket_addr;
+        self.runtime.block_on(async {
+            let mut stream = TcpStream::connect(socket_addr).await.ok()?;
+            stream.write_all(frame).await.ok()?;
+            let mut header = [0; HEADER_SIZE];
+            timeout(TIMEOUT, stream.read_exact(&mut header))
+                .await
+                .ok()?
+                .ok()?;
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let mut pdu = vec![0; length.checked_sub(1)?];
+            timeout(TIMEOUT, stream.read_exact(&mut pdu))
+                .await
+                .ok()?
+                .ok()?;
+            Some(pdu.first()? & 0x80 != 0)
+        })
+    }
+}
+
+fuzz_target!(|input: Input| {
+    let mut harness = HARNESS.lock().unwrap();
+    modbus::reset();
+    let before = harness.relays.get();
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(allocated, Ordering::Relaxed);
+    let exception = match input {
+        Input::Request { unit, request } => harness.request(unit, request.into()),
+        Input::Pdu { unit, mut pdu } => {
+            pdu.truncate(MAX_DATA + 1);
+            let mut frame = vec![0x00, 0x01, 0x00, 0x00];
+            frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+            frame.push(unit);
+            frame.extend_from_slice(&pdu);
+            harness.send(&frame)
+        }
+        Input::Raw(bytes) => {
+            // Later frames in the bytes may still be running, so the relays
+            // are not checked
+            harness.send(&bytes);
+            None
+        }
+    };
+    let peak = PEAK.load(Ordering::Relaxed);
+    assert!(
+        peak - allocated <= ALLOCATION_LIMIT,
+        "{} bytes allocated for one input",
+        peak - allocated
+    );
+    if exception == Some(true) {
+        assert_eq!(
+            harness.relays.get(),
+            before,
+            "relays changed by an exception"
+        );
+    }
+});
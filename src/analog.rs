@@ -0,0 +1,363 @@
+//! Analog inputs: ADC channels oversampled, smoothed, scaled to engineering
+//! units and checked against alarm limits
+//!
+//! A channel can feed its value into a virtual input, where bindings, timers,
+//! sequences, rules and permissives use it like any other.
+
+use self::{
+    alarm::{Alarm, Limits},
+    filter::{Filter, oversample},
+    scale::{MAX_POINTS, Scale},
+};
+use crate::{input, storage};
+use anyhow::Result;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::hal::{
+    adc::{
+        ADC1,
+        attenuation::DB_11,
+        oneshot::{
+            AdcChannelDriver, AdcDriver,
+            config::{AdcChannelConfig, Calibration},
+        },
+    },
+    gpio::{ADCPin, Gpio0, Gpio1, Gpio2, Gpio3},
+    sys::EspError,
+};
+use log::{error, info, warn};
+#[cfg(target_os = "espidf")]
+use std::sync::Arc;
+use std::sync::{Mutex, RwLock};
+use tokio::{
+    spawn,
+    time::{Duration, sleep},
+};
+use tokio_modbus::prelude::*;
+
+pub mod alarm;
+pub mod filter;
+pub mod scale;
+
+/// Analog channel count
+pub const COUNT: u16 = 4;
+
+const PERIOD: Duration = Duration::from_millis(100);
+/// A virtual input fed by a channel turns stale once the channel fails
+const STALE_AFTER: Duration = Duration::from_secs(1);
+
+const STORAGE_KEY: &str = "analog";
+
+/// Most samples per reading
+const MAX_OVERSAMPLE: u16 = 64;
+/// Holding registers per channel: virtual input, oversampling, filter, point
+/// count, the points, then the low and high limit and the hysteresis
+const ENTRY_SIZE: u16 = LIMITS as u16 + 3;
+/// Offset of the limits in the registers of a channel
+const LIMITS: usize = 4 + MAX_POINTS * 2;
+pub(crate) const REGISTER_COUNT: u16 = COUNT * ENTRY_SIZE;
+/// Input registers per channel: millivolts, value, alarm, status
+const STATUS_SIZE: u16 = 4;
+pub(crate) const STATUS_REGISTER_COUNT: u16 = COUNT * STATUS_SIZE;
+
+/// The holding registers as written, the settings are parsed from them
+static REGISTERS: RwLock<[u16; REGISTER_COUNT as _]> = RwLock::new(registers());
+static SETTINGS: RwLock<[Settings; COUNT as _]> = RwLock::new([Settings::NONE; COUNT as _]);
+static READINGS: Mutex<[Reading; COUNT as _]> = Mutex::new([Reading::new(100); COUNT as _]);
+
+/// Analog channels
+pub trait Sampler: Send + 'static {
+    /// A sample of the channel in millivolts
+    fn sample(&mut self, channel: usize) -> Result<u16>;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Settings {
+    /// The virtual input plus one the value goes to, `0` for none
+    input: u16,
+    /// Samples per reading, `0` disables the channel
+    oversample: u16,
+    /// Share of a new reading in percent, 100 does not smooth
+    filter: u16,
+    scale: Scale,
+    limits: Limits,
+}
+
+impl Settings {
+    const NONE: Self = Self {
+        input: 0,
+        oversample: 0,
+        filter: 100,
+        scale: Scale::NONE,
+        limits: Limits::NONE,
+    };
+}
+
+/// Channel state
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Status {
+    Disabled = 0,
+    Ok = 1,
+    /// The last reading failed
+    Failed = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Reading {
+    filter: Filter,
+    millivolts: u16,
+    value: i16,
+    alarm: Alarm,
+    status: Status,
+}
+
+impl Reading {
+    const fn new(filter: u16) -> Self {
+        Self {
+            filter: Filter::new(filter),
+            millivolts: 0,
+            value: 0,
+            alarm: Alarm::Normal,
+            status: Status::Disabled,
+        }
+    }
+}
+
+/// The register image of unused channels
+const fn registers() -> [u16; REGISTER_COUNT as _] {
+    let mut registers = [0; REGISTER_COUNT as _];
+    let mut channel = 0;
+    while channel < COUNT {
+        let entry = (channel * ENTRY_SIZE) as usize;
+        registers[entry + 2] = Settings::NONE.filter;
+        registers[entry + LIMITS] = Limits::NONE.low as _;
+        registers[entry + LIMITS + 1] = Limits::NONE.high as _;
+        channel += 1;
+    }
+    registers
+}
+
+/// Start sampling the channels, with the settings stored in NVS
+pub fn start(mut sampler: impl Sampler) {
+    match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => {
+            let (values, _) = bytes.as_chunks();
+            let values: Vec<_> = values
+                .iter()
+                .map(|&value| u16::from_be_bytes(value))
+                .collect();
+            match values.try_into() {
+                Ok(registers) => match parse(&registers) {
+                    Ok(settings) => {
+                        *REGISTERS.write().unwrap() = registers;
+                        apply(settings);
+                    }
+                    Err(exception) => warn!("Stored analog settings ignored: {exception:?}"),
+                },
+                Err(values) => warn!("Stored analog settings ignored: {} registers", values.len()),
+            }
+        }
+        Ok(None) => {}
+        Err(error) => error!("{error:?}"),
+    }
+    spawn(async move {
+        let mut samples = Vec::with_capacity(MAX_OVERSAMPLE as _);
+        loop {
+            sleep(PERIOD).await;
+            let settings = *SETTINGS.read().unwrap();
+            for (channel, settings) in settings.iter().enumerate() {
+                if settings.oversample == 0 {
+                    continue;
+                }
+                samples.clear();
+                let sampled = (0..settings.oversample).try_for_each(|_| {
+                    samples.push(sampler.sample(channel)?);
+                    anyhow::Ok(())
+                });
+                let mut readings = READINGS.lock().unwrap();
+                let reading = &mut readings[channel];
+                let millivolts = match sampled.map(|_| oversample(&mut samples)) {
+                    Ok(Some(millivolts)) => reading.filter.update(millivolts),
+                    Ok(None) => continue,
+                    Err(error) => {
+                        if reading.status != Status::Failed {
+                            warn!("Analog channel {channel} failed: {error}");
+                        }
+                        reading.status = Status::Failed;
+                        continue;
+                    }
+                };
+                let value = settings.scale.apply(millivolts);
+                let alarm = settings.limits.check(reading.alarm, value);
+                if alarm != reading.alarm {
+                    match alarm {
+                        Alarm::Normal => info!("Analog channel {channel} alarm cleared at {value}"),
+                        alarm => warn!("Analog channel {channel} alarm {alarm:?} at {value}"),
+                    }
+                }
+                reading.millivolts = millivolts.round() as u16;
+                reading.value = value;
+                reading.alarm = alarm;
+                reading.status = Status::Ok;
+                if settings.input != 0 {
+                    input::update(settings.input - 1, value as u16, STALE_AFTER);
+                }
+            }
+        }
+    });
+}
+
+/// Read the analog holding registers
+pub(crate) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    REGISTERS.read().unwrap()[address as usize..][..count as usize].to_vec()
+}
+
+/// Write the analog holding registers
+///
+/// The settings of all channels are validated before any of them is applied
+/// and stored in NVS, a changed channel starts its filter and alarm over.
+pub(crate) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut registers = REGISTERS.write().unwrap();
+    let mut updated = *registers;
+    updated[address as usize..][..values.len()].copy_from_slice(values);
+    let settings = parse(&updated)?;
+    if updated == *registers {
+        return Ok(());
+    }
+    let bytes: Vec<_> = updated
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect();
+    storage::save(STORAGE_KEY, &bytes).map_err(|error| {
+        error!("{error:?}");
+        ExceptionCode::ServerDeviceFailure
+    })?;
+    *registers = updated;
+    apply(settings);
+    Ok(())
+}
+
+/// The settings of all channels in the registers
+fn parse(registers: &[u16; REGISTER_COUNT as _]) -> Result<[Settings; COUNT as _], ExceptionCode> {
+    let (entries, _) = registers.as_chunks::<{ ENTRY_SIZE as _ }>();
+    let mut settings = [Settings::NONE; COUNT as _];
+    for (channel, (entry, settings)) in entries.iter().zip(&mut settings).enumerate() {
+        *settings = self::settings(entry).inspect_err(|exception| {
+            warn!("Analog channel {channel} settings rejected: {exception:?}");
+        })?;
+    }
+    Ok(settings)
+}
+
+/// Apply the settings, a changed channel starts its filter and alarm over
+fn apply(updated: [Settings; COUNT as _]) {
+    let mut settings = SETTINGS.write().unwrap();
+    let mut readings = READINGS.lock().unwrap();
+    for (channel, (entry, previous)) in updated.iter().zip(settings.iter()).enumerate() {
+        if entry != previous {
+            info!("Analog channel {channel}: {entry:?}");
+            readings[channel] = Reading::new(entry.filter);
+            if previous.input != 0 {
+                input::clear(previous.input - 1);
+            }
+        }
+    }
+    *settings = updated;
+}
+
+/// The settings in a channel's registers
+fn settings(entry: &[u16; ENTRY_SIZE as _]) -> Result<Settings, ExceptionCode> {
+    let [input, oversample, filter, count] = [entry[0], entry[1], entry[2], entry[3]];
+    let [low, high, hysteresis] = [entry[LIMITS], entry[LIMITS + 1], entry[LIMITS + 2]];
+    if input > input::COUNT
+        || oversample > MAX_OVERSAMPLE
+        || !(1..=100).contains(&filter)
+        || count as usize > MAX_POINTS
+        || low as i16 > high as i16
+    {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let (points, _) = entry[4..LIMITS].as_chunks::<2>();
+    let points: Vec<_> = points
+        .iter()
+        .take(count as _)
+        .map(|&[millivolts, value]| (millivolts, value as i16))
+        .collect();
+    let scale = match count {
+        0 => Scale::NONE,
+        _ => Scale::new(&points).map_err(|error| {
+            warn!("{error}");
+            ExceptionCode::IllegalDataValue
+        })?,
+    };
+    Ok(Settings {
+        input,
+        oversample,
+        filter,
+        scale,
+        limits: Limits {
+            low: low as _,
+            high: high as _,
+            hysteresis,
+        },
+    })
+}
+
+/// Read the analog input registers, per channel: millivolts, the value in
+/// engineering units (signed), the alarm (0 normal, 1 low, 2 high) and the
+/// status (0 disabled, 1 ok, 2 failed)
+pub(crate) fn read(address: u16, count: u16) -> Vec<u16> {
+    let readings = READINGS.lock().unwrap();
+    let values: Vec<_> = readings
+        .iter()
+        .flat_map(|reading| {
+            [
+                reading.millivolts,
+                reading.value as _,
+                reading.alarm as _,
+                reading.status as _,
+            ]
+        })
+        .collect();
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// ADC1 channels on gpio0 to gpio3, calibrated to millivolts
+#[cfg(target_os = "espidf")]
+pub struct Adc(Vec<Box<dyn FnMut() -> Result<u16, EspError> + Send>>);
+
+#[cfg(target_os = "espidf")]
+impl Adc {
+    pub fn new(adc: ADC1, pins: (Gpio0, Gpio1, Gpio2, Gpio3)) -> Result<Self> {
+        let driver = Arc::new(AdcDriver::new(adc)?);
+        Ok(Self(vec![
+            channel(&driver, pins.0)?,
+            channel(&driver, pins.1)?,
+            channel(&driver, pins.2)?,
+            channel(&driver, pins.3)?,
+        ]))
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Sampler for Adc {
+    fn sample(&mut self, channel: usize) -> Result<u16> {
+        Ok(self.0[channel]()?)
+    }
+}
+
+/// A reader of the pin's channel
+#[cfg(target_os = "espidf")]
+fn channel<T: ADCPin<Adc = ADC1> + 'static>(
+    driver: &Arc<AdcDriver<'static, ADC1>>,
+    pin: T,
+) -> Result<Box<dyn FnMut() -> Result<u16, EspError> + Send>, EspError> {
+    // 11 dB attenuation measures up to about 2.5 V on the ESP32-C3, 3.3 V
+    // sensors need a divider
+    let config = AdcChannelConfig {
+        attenuation: DB_11,
+        calibration: Calibration::Curve,
+        ..Default::default()
+    };
+    let mut channel = AdcChannelDriver::new(driver.clone(), pin, &config)?;
+    Ok(Box::new(move || channel.read()))
+}
@@ -0,0 +1,41 @@
+//! High and low alarm limits with hysteresis
+
+/// Alarm state
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Alarm {
+    Normal = 0,
+    Low = 1,
+    High = 2,
+}
+
+/// Alarm limits in engineering units
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// Below it the value is low
+    pub low: i16,
+    /// Above it the value is high
+    pub high: i16,
+    /// How far the value has to come back inside a limit to clear its alarm
+    pub hysteresis: u16,
+}
+
+impl Limits {
+    /// Never alarms
+    pub const NONE: Self = Self {
+        low: i16::MIN,
+        high: i16::MAX,
+        hysteresis: 0,
+    };
+
+    /// The alarm after the value, given the alarm before it
+    pub fn check(&self, alarm: Alarm, value: i16) -> Alarm {
+        let (value, hysteresis) = (value as i32, self.hysteresis as i32);
+        match alarm {
+            Alarm::High if value > self.high as i32 - hysteresis => Alarm::High,
+            Alarm::Low if value < self.low as i32 + hysteresis => Alarm::Low,
+            _ if value > self.high as i32 => Alarm::High,
+            _ if value < self.low as i32 => Alarm::Low,
+            _ => Alarm::Normal,
+        }
+    }
+}
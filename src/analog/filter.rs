@@ -0,0 +1,43 @@
+//! Oversampling and smoothing of ADC samples
+
+/// The mean of the samples without the lowest and the highest one, which
+/// drops single spikes, `None` if there are none
+pub fn oversample(samples: &mut [u16]) -> Option<f32> {
+    samples.sort_unstable();
+    let samples = match samples.len() {
+        0 => return None,
+        1 | 2 => &samples[..],
+        length => &samples[1..length - 1],
+    };
+    let sum: u32 = samples.iter().map(|&sample| sample as u32).sum();
+    Some(sum as f32 / samples.len() as f32)
+}
+
+/// Exponential moving average
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filter {
+    /// Share of a new sample in percent, 100 does not smooth
+    percent: u16,
+    value: Option<f32>,
+}
+
+impl Filter {
+    /// Smooth with `percent` of each new sample, from 1 to 100
+    pub const fn new(percent: u16) -> Self {
+        Self {
+            percent,
+            value: None,
+        }
+    }
+
+    /// Add a sample, returns the smoothed value; the first sample is taken as
+    /// it is
+    pub fn update(&mut self, sample: f32) -> f32 {
+        let value = match self.value {
+            Some(value) => value + (sample - value) * self.percent as f32 / 100.0,
+            None => sample,
+        };
+        self.value = Some(value);
+        value
+    }
+}
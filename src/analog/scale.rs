@@ -0,0 +1,64 @@
+//! Scaling of millivolts to engineering units
+
+use anyhow::{Result, bail};
+
+/// Most points of a scale
+pub const MAX_POINTS: usize = 8;
+
+/// Piecewise linear scale through points of millivolts and values, two points
+/// make a linear scale
+///
+/// Millivolts outside the points are clamped to the first or last one, so a
+/// sensor out of its range reads its end value.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Scale {
+    points: [(u16, i16); MAX_POINTS],
+    count: usize,
+}
+
+impl Scale {
+    /// Millivolts as they are
+    pub const NONE: Self = Self {
+        points: [(0, 0); MAX_POINTS],
+        count: 0,
+    };
+
+    /// The scale through the points, which need increasing millivolts
+    pub fn new(points: &[(u16, i16)]) -> Result<Self> {
+        if points.len() < 2 || points.len() > MAX_POINTS {
+            bail!("{} points instead of 2 to {MAX_POINTS}", points.len());
+        }
+        if let Some(pair) = points.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+            bail!("{} mV is not above {} mV", pair[1].0, pair[0].0);
+        }
+        let mut scale = Self::NONE;
+        scale.points[..points.len()].copy_from_slice(points);
+        scale.count = points.len();
+        Ok(scale)
+    }
+
+    pub fn points(&self) -> &[(u16, i16)] {
+        &self.points[..self.count]
+    }
+
+    /// The value of the millivolts, rounded and limited to 16 bits
+    pub fn apply(&self, millivolts: f32) -> i16 {
+        let points = self.points();
+        let value = match *points {
+            [] | [_] => millivolts,
+            [(first, value), ..] if millivolts <= first as f32 => value as f32,
+            [.., (last, value)] if millivolts >= last as f32 => value as f32,
+            _ => {
+                // Above the first point and below the last one
+                let index = points
+                    .iter()
+                    .position(|&(point, _)| millivolts < point as f32)
+                    .unwrap_or(points.len() - 1);
+                let ((x0, y0), (x1, y1)) = (points[index - 1], points[index]);
+                y0 as f32
+                    + (millivolts - x0 as f32) * (y1 as f32 - y0 as f32) / (x1 as f32 - x0 as f32)
+            }
+        };
+        value.round().clamp(i16::MIN as _, i16::MAX as _) as i16
+    }
+}
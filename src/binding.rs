@@ -0,0 +1,198 @@
+use crate::{
+    input,
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+};
+use log::info;
+use std::sync::RwLock;
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, sleep},
+};
+use tokio_modbus::prelude::*;
+
+const PERIOD: Duration = Duration::from_secs(1);
+
+/// Holding registers per relay: mode, input, setpoint, hysteresis, stale
+/// action
+const ENTRY_SIZE: u16 = 5;
+pub(crate) const REGISTER_COUNT: u16 = RELAY_COUNT * ENTRY_SIZE;
+
+static BINDINGS: RwLock<[Binding; RELAY_COUNT as _]> =
+    RwLock::new([Binding::NONE; RELAY_COUNT as _]);
+
+/// How a relay follows its virtual input
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    None = 0,
+    /// On while the input is not zero
+    Follow = 1,
+    /// On while the input is zero
+    Invert = 2,
+    /// Thermostat: on below the setpoint, off above it
+    Heat = 3,
+    /// Thermostat: on above the setpoint, off below it
+    Cool = 4,
+}
+
+impl TryFrom<u16> for Mode {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Follow),
+            2 => Ok(Self::Invert),
+            3 => Ok(Self::Heat),
+            4 => Ok(Self::Cool),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+/// What a relay does while its input is stale or unknown
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Stale {
+    Off = 0,
+    On = 1,
+    Hold = 2,
+}
+
+impl TryFrom<u16> for Stale {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Off),
+            1 => Ok(Self::On),
+            2 => Ok(Self::Hold),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+/// Relay binding to a virtual input
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Binding {
+    mode: Mode,
+    input: u16,
+    /// Thermostat setpoint, compared with the input as a signed value
+    setpoint: i16,
+    /// Thermostat dead band on either side of the setpoint
+    hysteresis: u16,
+    stale: Stale,
+}
+
+impl Binding {
+    const NONE: Self = Self {
+        mode: Mode::None,
+        input: 0,
+        setpoint: 0,
+        hysteresis: 0,
+        stale: Stale::Off,
+    };
+
+    /// The relay state for the input value, `None` leaves the relay as it is
+    fn evaluate(&self, value: Option<u16>) -> Option<bool> {
+        let Some(value) = value else {
+            return match self.stale {
+                Stale::Off => Some(false),
+                Stale::On => Some(true),
+                Stale::Hold => None,
+            };
+        };
+        let value = value as i16 as i32;
+        let low = self.setpoint as i32 - self.hysteresis as i32;
+        let high = self.setpoint as i32 + self.hysteresis as i32;
+        match self.mode {
+            Mode::None => None,
+            Mode::Follow => Some(value != 0),
+            Mode::Invert => Some(value == 0),
+            Mode::Heat if value < low => Some(true),
+            Mode::Heat if value > high => Some(false),
+            Mode::Cool if value > high => Some(true),
+            Mode::Cool if value < low => Some(false),
+            Mode::Heat | Mode::Cool => None,
+        }
+    }
+}
+
+/// Start evaluating the relay bindings
+///
+/// A relay is only switched when its binding changes its mind, so a relay
+/// written over Modbus in between keeps its state until then.
+pub fn start(relay_sender: Sender<RelayRequest>) {
+    spawn(async move {
+        let mut states = [None; RELAY_COUNT as _];
+        loop {
+            sleep(PERIOD).await;
+            let bindings = *BINDINGS.read().unwrap();
+            for (relay, binding) in bindings.iter().enumerate() {
+                if binding.mode == Mode::None {
+                    states[relay] = None;
+                    continue;
+                }
+                let Some(state) = binding.evaluate(input::value(binding.input)) else {
+                    continue;
+                };
+                if states[relay] == Some(state) {
+                    continue;
+                }
+                states[relay] = Some(state);
+                info!("Relay {relay} bound to input {}: {state}", binding.input);
+                let (sender, receiver) = oneshot::channel();
+                if relay_sender
+                    .send((Mask::write(relay as _, &[state]), sender))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                let _ = receiver.await;
+            }
+        }
+    });
+}
+
+/// Read the binding holding registers
+pub(crate) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    let bindings = BINDINGS.read().unwrap();
+    (address..address + count)
+        .map(|address| {
+            let binding = &bindings[(address / ENTRY_SIZE) as usize];
+            match address % ENTRY_SIZE {
+                0 => binding.mode as _,
+                1 => binding.input,
+                2 => binding.setpoint as _,
+                3 => binding.hysteresis,
+                _ => binding.stale as _,
+            }
+        })
+        .collect()
+}
+
+/// Write the binding holding registers
+///
+/// All values are validated before any of them is applied.
+pub(crate) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut bindings = BINDINGS.write().unwrap();
+    let mut updated = *bindings;
+    for (address, &value) in (address..).zip(values) {
+        let binding = &mut updated[(address / ENTRY_SIZE) as usize];
+        match address % ENTRY_SIZE {
+            0 => binding.mode = value.try_into()?,
+            1 if value < input::COUNT => binding.input = value,
+            2 => binding.setpoint = value as _,
+            3 => binding.hysteresis = value,
+            4 => binding.stale = value.try_into()?,
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+    for (relay, (binding, previous)) in updated.iter().zip(bindings.iter()).enumerate() {
+        if binding != previous {
+            info!("Relay {relay} binding: {binding:?}");
+        }
+    }
+    *bindings = updated;
+    Ok(())
+}
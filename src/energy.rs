@@ -0,0 +1,241 @@
+//! Energy and runtime accounting per relay, estimated from the rated watts of
+//! the power settings
+//!
+//! The totals of the day, week and month start over at local midnight, on
+//! Monday and on the first of the month once SNTP has set the clock. They are
+//! stored in NVS every few minutes and when a period starts over.
+
+use crate::{
+    power,
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+    schedule::{self, time::LocalTime},
+    storage,
+};
+use log::{error, info, warn};
+use std::sync::Mutex;
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, Instant, sleep},
+};
+use tokio_modbus::prelude::*;
+
+const PERIOD: Duration = Duration::from_secs(1);
+/// How often the totals are stored, which bounds what a power loss loses
+/// and the flash wear
+const SAVE_PERIOD: Duration = Duration::from_secs(15 * 60);
+
+const STORAGE_KEY: &str = "energy";
+
+/// Day, week and month
+pub(crate) const PERIOD_NAMES: [&str; 3] = ["day", "week", "month"];
+pub const PERIOD_COUNT: usize = PERIOD_NAMES.len();
+/// Input registers per relay and period: watt-hours and seconds, 32 bits
+/// each with the high word first
+pub(crate) const ENTRY_SIZE: u16 = 4;
+pub(crate) const STATUS_REGISTER_COUNT: u16 = RELAY_COUNT * PERIOD_COUNT as u16 * ENTRY_SIZE;
+/// Holding register: reset the totals of the relays in the bitmask
+pub(crate) const REGISTER_COUNT: u16 = 1;
+
+/// Bytes per stored total
+const TOTAL_SIZE: usize = 16;
+/// Bytes stored: the periods, then the totals
+const STORAGE_SIZE: usize = PERIOD_COUNT * 8 + RELAY_COUNT as usize * PERIOD_COUNT * TOTAL_SIZE;
+
+static TOTALS: Mutex<Totals> = Mutex::new(Totals::NEW);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Total {
+    /// Watt-milliseconds
+    pub energy: u64,
+    /// Milliseconds on
+    pub runtime: u64,
+}
+
+impl Total {
+    pub const ZERO: Self = Self {
+        energy: 0,
+        runtime: 0,
+    };
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Totals {
+    /// The day, week and month the totals are for, `None` until the clock
+    /// has been set once
+    pub periods: Option<[i64; PERIOD_COUNT]>,
+    /// Per relay the day, week and month
+    pub totals: [[Total; PERIOD_COUNT]; RELAY_COUNT as _],
+}
+
+impl Totals {
+    pub const NEW: Self = Self {
+        periods: None,
+        totals: [[Total::ZERO; PERIOD_COUNT]; RELAY_COUNT as _],
+    };
+
+    /// Add the time to the totals of the relays that are on
+    pub fn add(&mut self, relays: u16, watts: &[u16], elapsed: Duration) {
+        let milliseconds = elapsed.as_millis() as u64;
+        for (relay, totals) in self.totals.iter_mut().enumerate() {
+            if relays & (1 << relay) == 0 {
+                continue;
+            }
+            for total in totals {
+                total.energy += watts[relay] as u64 * milliseconds;
+                total.runtime += milliseconds;
+            }
+        }
+    }
+
+    /// Start the periods that are over, returns whether the periods changed
+    ///
+    /// A clock that is set back keeps the later periods and their totals, so
+    /// they do not start over twice.
+    pub fn roll(&mut self, local: &LocalTime) -> bool {
+        let periods = [
+            local.days,
+            local.days - local.weekday as i64,
+            local.year as i64 * 12 + local.month as i64,
+        ];
+        // The time counted before the clock was set belongs to now
+        let Some(stored) = self.periods.as_mut() else {
+            self.periods = Some(periods);
+            return true;
+        };
+        let mut rolled = false;
+        for (period, name) in PERIOD_NAMES.iter().enumerate() {
+            if periods[period] > stored[period] {
+                stored[period] = periods[period];
+                for totals in &mut self.totals {
+                    totals[period] = Total::ZERO;
+                }
+                info!("Energy totals: new {name}");
+                rolled = true;
+            }
+        }
+        rolled
+    }
+
+    /// The bytes stored in NVS: the periods, then per relay and period the
+    /// energy and the runtime, 8 bytes each
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let periods = self.periods.unwrap_or([i64::MIN; PERIOD_COUNT]);
+        periods
+            .iter()
+            .flat_map(|period| period.to_be_bytes())
+            .chain(self.totals.iter().flatten().flat_map(|total| {
+                total
+                    .energy
+                    .to_be_bytes()
+                    .into_iter()
+                    .chain(total.runtime.to_be_bytes())
+            }))
+            .collect()
+    }
+
+    /// The totals of the stored bytes, `None` if they are not of this build
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != STORAGE_SIZE {
+            return None;
+        }
+        let (words, _) = bytes.as_chunks::<8>();
+        let mut words = words.iter().map(|&word| u64::from_be_bytes(word));
+        let periods: [i64; PERIOD_COUNT] = [(); PERIOD_COUNT].map(|_| words.next().unwrap() as i64);
+        let mut totals = [[Total::ZERO; PERIOD_COUNT]; RELAY_COUNT as _];
+        for total in totals.iter_mut().flatten() {
+            total.energy = words.next()?;
+            total.runtime = words.next()?;
+        }
+        Some(Self {
+            periods: (periods[0] != i64::MIN).then_some(periods),
+            totals,
+        })
+    }
+}
+
+/// Start accounting, continuing from the totals stored in NVS
+pub fn start(relay_sender: Sender<RelayRequest>) {
+    match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => match Totals::from_bytes(&bytes) {
+            Some(totals) => *TOTALS.lock().unwrap() = totals,
+            None => warn!("Stored energy totals ignored: {} bytes", bytes.len()),
+        },
+        Ok(None) => {}
+        Err(error) => error!("{error:?}"),
+    }
+    spawn(async move {
+        let mut last = Instant::now();
+        let mut saved = last;
+        loop {
+            sleep(PERIOD).await;
+            let (sender, receiver) = oneshot::channel();
+            if relay_sender.send((Mask::READ, sender)).await.is_err() {
+                error!("Relay task stopped");
+                return;
+            }
+            let Ok((_, relays)) = receiver.await else {
+                error!("Relay task stopped");
+                return;
+            };
+            let now = Instant::now();
+            let rolled = {
+                let mut totals = TOTALS.lock().unwrap();
+                let rolled = schedule::local().is_some_and(|local| totals.roll(&local));
+                totals.add(relays, &power::watts(), now - last);
+                rolled
+            };
+            last = now;
+            if rolled || now - saved >= SAVE_PERIOD {
+                if let Err(error) = save() {
+                    error!("{error:?}");
+                }
+                saved = now;
+            }
+        }
+    });
+}
+
+fn save() -> anyhow::Result<()> {
+    let bytes = TOTALS.lock().unwrap().to_bytes();
+    storage::save(STORAGE_KEY, &bytes)
+}
+
+/// Read the totals input registers, per relay the watt-hours and seconds of
+/// the day, the week and the month
+pub(crate) fn read(address: u16, count: u16) -> Vec<u16> {
+    let totals = TOTALS.lock().unwrap();
+    let values: Vec<_> = totals
+        .totals
+        .iter()
+        .flatten()
+        .flat_map(|total| {
+            let energy = (total.energy / 3_600_000).min(u32::MAX as _) as u32;
+            let runtime = (total.runtime / 1000).min(u32::MAX as _) as u32;
+            [energy, runtime]
+        })
+        .flat_map(|value| [(value >> 16) as u16, value as u16])
+        .collect();
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// Reset the totals of the relays in the written bitmask and store them
+pub(crate) fn reset(values: &[u16]) -> Result<(), ExceptionCode> {
+    let relays = values[0];
+    if relays == 0 || relays >> RELAY_COUNT != 0 {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    {
+        let mut totals = TOTALS.lock().unwrap();
+        for (relay, totals) in totals.totals.iter_mut().enumerate() {
+            if relays & (1 << relay) != 0 {
+                *totals = [Total::ZERO; PERIOD_COUNT];
+            }
+        }
+    }
+    info!("Relays {relays:#06b} energy totals reset");
+    save().map_err(|error| {
+        error!("{error:?}");
+        ExceptionCode::ServerDeviceFailure
+    })
+}
@@ -0,0 +1,114 @@
+//! HTTP server with the MQTT topics as paths
+//!
+//! `POST /<topic>` runs the command of `<topic>/set` and answers with its
+//! reply, `GET /<topic>` gives a status topic. A rejected command is answered
+//! with an error status and the exception.
+
+use crate::{mqtt, relay::Request as RelayRequest};
+use anyhow::Result;
+use esp_idf_svc::{
+    http::{
+        Method,
+        server::{Configuration, EspHttpServer},
+    },
+    io::{Read, Write},
+};
+use log::{error, info};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::{
+    spawn,
+    sync::{
+        mpsc::{self, Sender},
+        oneshot,
+    },
+};
+use tokio_modbus::prelude::*;
+
+/// The largest accepted request body
+const BODY_SIZE: usize = 4096;
+
+/// The client address of HTTP commands, for the locks and selections
+const CLIENT: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80));
+
+/// Method, topic, body and the sender of the reply
+type Request = (
+    Method,
+    String,
+    Vec<u8>,
+    oneshot::Sender<Result<Option<String>, ExceptionCode>>,
+);
+
+/// Start the HTTP server, it runs until the returned server is dropped
+///
+/// The handlers run in the server's task and pass the requests on to the
+/// runtime.
+pub fn start(relay_sender: Sender<RelayRequest>) -> Result<EspHttpServer<'static>> {
+    info!("Initialize HTTP");
+    let (sender, mut receiver) = mpsc::channel::<Request>(4);
+    spawn(async move {
+        while let Some((method, topic, body, sender)) = receiver.recv().await {
+            let reply = match method {
+                Method::Post => mqtt::command(&relay_sender, CLIENT, &topic, &body).await,
+                _ => mqtt::status(&relay_sender).await.and_then(|topics| {
+                    topics
+                        .into_iter()
+                        .find(|(name, _)| *name == topic)
+                        .map(|(_, payload)| Some(payload))
+                        .ok_or(ExceptionCode::IllegalFunction)
+                }),
+            };
+            let _ = sender.send(reply);
+        }
+    });
+    let mut server = EspHttpServer::new(&Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    for method in [Method::Get, Method::Post] {
+        let sender = sender.clone();
+        server.fn_handler::<anyhow::Error, _>("/*", method, move |mut request| {
+            let uri = request.uri().to_owned();
+            let topic = uri[..uri.find('?').unwrap_or(uri.len())]
+                .trim_matches('/')
+                .to_owned();
+            let mut body = Vec::new();
+            let mut buffer = [0; 256];
+            loop {
+                let read = request.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                if body.len() + read > BODY_SIZE {
+                    request.into_status_response(413)?;
+                    return Ok(());
+                }
+                body.extend_from_slice(&buffer[..read]);
+            }
+            let (reply_sender, reply_receiver) = oneshot::channel();
+            sender.blocking_send((method, topic, body, reply_sender))?;
+            let (code, reply) = match reply_receiver.blocking_recv()? {
+                Ok(reply) => (200, reply.unwrap_or_default()),
+                Err(exception) => {
+                    error!("HTTP {method:?} {uri}: {exception:?}");
+                    (status(exception), format!("{exception:?}"))
+                }
+            };
+            request
+                .into_status_response(code)?
+                .write_all(reply.as_bytes())?;
+            Ok(())
+        })?;
+    }
+    Ok(server)
+}
+
+/// The HTTP status of a rejected command
+fn status(exception: ExceptionCode) -> u16 {
+    match exception {
+        ExceptionCode::IllegalFunction => 404,
+        ExceptionCode::IllegalDataAddress | ExceptionCode::IllegalDataValue => 400,
+        ExceptionCode::Acknowledge => 202,
+        ExceptionCode::ServerDeviceBusy => 409,
+        _ => 500,
+    }
+}
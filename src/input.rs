@@ -0,0 +1,77 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Virtual input count
+pub(crate) const COUNT: u16 = 8;
+
+static INPUTS: Mutex<[Input; COUNT as _]> = Mutex::new([Input::NEW; COUNT as _]);
+
+/// Virtual input status
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Status {
+    /// No value yet
+    Unknown = 0,
+    Fresh = 1,
+    /// The source stopped updating the value
+    Stale = 2,
+}
+
+/// Virtual input, a value mirrored from elsewhere
+#[derive(Clone, Copy, Debug)]
+struct Input {
+    value: u16,
+    updated: Option<Instant>,
+    stale_after: Duration,
+}
+
+impl Input {
+    const NEW: Self = Self {
+        value: 0,
+        updated: None,
+        stale_after: Duration::ZERO,
+    };
+
+    fn status(&self) -> Status {
+        match self.updated {
+            None => Status::Unknown,
+            Some(updated) if updated.elapsed() > self.stale_after => Status::Stale,
+            Some(_) => Status::Fresh,
+        }
+    }
+}
+
+/// Update a virtual input, it turns stale if it is not updated again within
+/// `stale_after`
+pub(crate) fn update(index: u16, value: u16, stale_after: Duration) {
+    if let Some(input) = INPUTS.lock().unwrap().get_mut(index as usize) {
+        *input = Input {
+            value,
+            updated: Some(Instant::now()),
+            stale_after,
+        };
+    }
+}
+
+/// Forget a virtual input's value
+pub(crate) fn clear(index: u16) {
+    if let Some(input) = INPUTS.lock().unwrap().get_mut(index as usize) {
+        *input = Input::NEW;
+    }
+}
+
+/// The value of a virtual input, `None` unless it is fresh
+pub(crate) fn value(index: u16) -> Option<u16> {
+    let inputs = INPUTS.lock().unwrap();
+    let input = inputs.get(index as usize)?;
+    (input.status() == Status::Fresh).then_some(input.value)
+}
+
+/// The last value of a virtual input and its status
+pub(crate) fn read(index: u16) -> (u16, Status) {
+    let inputs = INPUTS.lock().unwrap();
+    inputs
+        .get(index as usize)
+        .map_or((0, Status::Unknown), |input| (input.value, input.status()))
+}
@@ -2,25 +2,34 @@
 //     SmartLedsWrite, brightness, gamma,
 //     hsv::{Hsv, hsv2rgb},
 // };
+#[cfg(target_os = "espidf")]
 use anyhow::{Error, Result};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::{
     gpio::OutputPin,
     peripheral::Peripheral,
     rmt::{FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver, config::TransmitConfig},
 };
+#[cfg(target_os = "espidf")]
 use log::{info, trace};
+use tokio::time::Duration;
+#[cfg(target_os = "espidf")]
 use tokio::{
     spawn,
     sync::mpsc::{self, Sender},
-    time::{Duration, sleep},
+    time::sleep,
 };
 
+#[cfg(target_os = "espidf")]
 const DELAY: Duration = Duration::from_millis(3000);
+#[cfg(target_os = "espidf")]
 const SLEEP: Duration = Duration::from_millis(10);
 
-pub(crate) type Request = Result<Duration, Duration>;
+/// Blink green (`Ok`) or red (`Err`) for the duration
+pub type Request = Result<Duration, Duration>;
 
-pub(crate) fn start(
+#[cfg(target_os = "espidf")]
+pub fn start(
     pin: impl Peripheral<P = impl OutputPin> + 'static,
     channel: impl Peripheral<P = impl RmtChannel> + 'static,
 ) -> Result<Sender<Request>> {
@@ -49,6 +58,7 @@ pub(crate) fn start(
     Ok(sender)
 }
 
+#[cfg(target_os = "espidf")]
 fn neopixel(driver: &mut TxRmtDriver, rgb: Rgb) -> Result<()> {
     let color: u32 = rgb.into();
     let ticks_hz = driver.counter_clock()?;
@@ -69,12 +79,14 @@ fn neopixel(driver: &mut TxRmtDriver, rgb: Rgb) -> Result<()> {
     Ok(())
 }
 
+#[cfg(target_os = "espidf")]
 struct Rgb {
     r: u8,
     g: u8,
     b: u8,
 }
 
+#[cfg(target_os = "espidf")]
 impl Rgb {
     pub fn new(r: u8, g: u8, b: u8) -> Self {
         Self { r, g, b }
@@ -106,6 +118,7 @@ impl Rgb {
     // }
 }
 
+#[cfg(target_os = "espidf")]
 impl From<Rgb> for u32 {
     /// Convert RGB to u32 color value
     ///
@@ -0,0 +1,23 @@
+#![feature(impl_trait_in_assoc_type)]
+
+//! Relay controller logic, the ESP-IDF drivers are only built for the device
+
+pub mod analog;
+pub mod binding;
+pub mod energy;
+#[cfg(target_os = "espidf")]
+pub mod http;
+mod input;
+pub mod led;
+pub mod modbus;
+pub mod mqtt;
+pub mod permissive;
+pub mod power;
+pub mod relay;
+pub mod rule;
+pub mod scene;
+pub mod schedule;
+pub mod sequence;
+pub mod storage;
+pub mod timed;
+pub mod timer;
@@ -1,12 +1,18 @@
-#![feature(impl_trait_in_assoc_type)]
-
-use self::{relay::Driver as RelayDriver, wifi::connect};
+#[cfg(target_os = "espidf")]
+use self::wifi::connect;
+#[cfg(target_os = "espidf")]
 use anyhow::{Result, bail};
+#[cfg(target_os = "espidf")]
+use digital_relay_controller::{
+    analog, binding, energy, http, led, modbus, mqtt, permissive, power, relay, rule, scene,
+    schedule, sequence, storage, timed, timer,
+};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
         delay::FreeRtos,
-        gpio::{PinDriver, Pull},
+        gpio::{OutputPin, PinDriver, Pull},
         prelude::Peripherals,
         reset::restart,
         rmt::{FixedLengthSignal, PinState, Pulse, TxRmtDriver, config::TransmitConfig},
@@ -18,13 +24,16 @@ use esp_idf_svc::{
     timer::EspTaskTimerService,
     wifi::WifiEvent,
 };
+#[cfg(target_os = "espidf")]
 use log::{error, info, warn};
+#[cfg(target_os = "espidf")]
 use tokio::{
     runtime::Builder,
     spawn,
     time::{Duration, sleep},
 };
 
+#[cfg(target_os = "espidf")]
 fn main() -> Result<()> {
     link_patches();
     EspLogger::initialize_default();
@@ -42,11 +51,19 @@ fn main() -> Result<()> {
     restart();
 }
 
+/// The firmware only runs on ESP-IDF, the host build is for the tests
+#[cfg(not(target_os = "espidf"))]
+fn main() {
+    eprintln!("digital_relay_controller runs on ESP-IDF only");
+}
+
+#[cfg(target_os = "espidf")]
 async fn run() -> Result<()> {
     let event_loop = EspSystemEventLoop::take()?;
     let timer = EspTaskTimerService::new()?;
     let peripherals = Peripherals::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
+    storage::init(nvs.clone())?;
     // Initialize the network stack, this must be done before starting the server
     let mut wifi = connect(peripherals.modem, event_loop.clone(), timer, Some(nvs)).await?;
     let _subscription = event_loop.subscribe::<WifiEvent, _>(move |event| {
@@ -98,18 +115,64 @@ async fn run() -> Result<()> {
     //         }
     //     });
     // }
+    // Start relay driver
+    let relay_sender = relay::start(relay::Drivers::new([
+        peripherals.pins.gpio4.downgrade_output(),
+        peripherals.pins.gpio5.downgrade_output(),
+    ])?)?;
+    // Start checking the relay permissives, before anything switches a relay
+    permissive::start(relay_sender.clone());
+    // Start restoring relays shed to keep within the power budget
+    power::start(relay_sender.clone());
+    // Start the energy and runtime accounting, from the totals stored in NVS
+    energy::start(relay_sender.clone());
+    // Start RS-485 RTU master (UART1: TX gpio6, RX gpio7, DE gpio10)
+    let gateway_sender = modbus::gateway::start(
+        peripherals.uart1,
+        peripherals.pins.gpio6,
+        peripherals.pins.gpio7,
+        peripherals.pins.gpio10,
+    )?;
+    // Start sampling the analog inputs (ADC1: gpio0 to gpio3)
+    analog::start(analog::Adc::new(
+        peripherals.adc1,
+        (
+            peripherals.pins.gpio0,
+            peripherals.pins.gpio1,
+            peripherals.pins.gpio2,
+            peripherals.pins.gpio3,
+        ),
+    )?);
+    // Start polling remote devices into the virtual inputs
+    modbus::client::start();
+    // Start relay bindings to the virtual inputs
+    binding::start(relay_sender.clone());
+    // Start the relay schedules, the clock is set by SNTP
+    schedule::start(relay_sender.clone());
+    // Start the relay timers, triggered by coils or virtual inputs
+    timer::start(relay_sender.clone());
+    // Start the timed commands, the stored ones resume once the clock is set
+    timed::start(relay_sender.clone());
+    // Load the scenes stored in NVS
+    scene::load();
+    // Load the sequences stored in NVS, they are started over Modbus
+    sequence::load();
+    // Start the rule engine, with the rules stored in NVS
+    rule::start(relay_sender.clone());
+    // Start the MQTT client and the HTTP server, with the same topics
+    mqtt::start(relay_sender.clone())?;
+    let _http = http::start(relay_sender.clone())?;
     // Run modbus server
     modbus::run(
-        peripherals.pins.gpio4,
-        peripherals.pins.gpio5,
+        relay_sender.clone(),
         led_sender.clone(),
+        gateway_sender.clone(),
     )
     .await?;
     Ok(())
 }
 
+#[cfg(target_os = "espidf")]
 mod deadline;
-mod led;
-mod modbus;
-mod relay;
+#[cfg(target_os = "espidf")]
 mod wifi;
@@ -1,144 +1,1021 @@
 // use crate::relay::Request as LedRequest;
-use crate::{RelayDriver, led::Request as LedRequest};
+use self::{
+    audit::{AUDIT_LOG, AUDIT_LOG_REGISTER_COUNT, Entry as AuditEntry},
+    certificates::{COMMIT_REGISTER_COUNT, WRITE_FILE_RECORD},
+    connection::{
+        CONNECTIONS, Connection, SETTINGS_REGISTER_COUNT, STATISTICS_REGISTER_COUNT,
+        Stream as ConnectionStream,
+    },
+    diagnostics::{Counters, DIAGNOSTICS, GET_COMM_EVENT_COUNTER, GET_COMM_EVENT_LOG},
+    gateway::Request as GatewayRequest,
+    role::Role,
+    trace::{Entry as TraceEntry, READ_FIFO_QUEUE, TRACE},
+};
+#[cfg(not(target_os = "espidf"))]
+use crate::storage;
+use crate::{
+    analog, binding, energy,
+    led::Request as LedRequest,
+    permissive, power,
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+    rule, scene, schedule, sequence, timed, timer,
+};
 use anyhow::Result;
-use esp_idf_hal::{
-    gpio::OutputPin,
-    peripheral::{Peripheral, PeripheralRef},
+use log::{error, info, trace};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, LazyLock, OnceLock},
+    time::{Duration, Instant},
 };
-use log::{error, info, warn};
-use std::{net::SocketAddr, sync::LazyLock, time::Duration};
 use tokio::{
     net::TcpListener,
     sync::{mpsc::Sender, oneshot},
+    task::JoinSet,
 };
 use tokio_modbus::{
     prelude::*,
-    server::{
-        Service,
-        tcp::{Server, accept_tcp_connection},
-    },
+    server::{Service, tcp::Server},
 };
 
-const INPUT_REGISTER_SIZE: usize = 6;
+// Holding registers
+/// Relay bitmask, one bit per relay
+pub(crate) const RELAYS_REGISTER: u16 = 0x0000;
+/// Connection limits and allowlist
+const CONNECTION_SETTINGS_REGISTER: u16 = 0x0100;
+/// Store a TLS certificate file staged with write file record
+const TLS_COMMIT_REGISTER: u16 = 0x0180;
+/// RTU gateway unit ID, response timeout and retries
+const GATEWAY_SETTINGS_REGISTER: u16 = 0x01A0;
+/// Remote values polled into the virtual inputs
+const CLIENT_SETTINGS_REGISTER: u16 = 0x0200;
+/// Relay bindings to the virtual inputs
+const BINDINGS_REGISTER: u16 = 0x0300;
+/// Relay ownership locks
+const LOCKS_REGISTER: u16 = 0x0400;
+/// Select-before-operate mode and window per relay
+const SBO_SETTINGS_REGISTER: u16 = 0x0410;
+/// Timer function, trigger and times per relay
+const TIMERS_REGISTER: u16 = 0x0420;
+/// Timed command state and seconds per relay
+pub(crate) const TIMED_REGISTER: u16 = 0x0430;
+/// Applied scene, then the scenes
+pub(crate) const SCENES_REGISTER: u16 = 0x0440;
+/// Sequence start and abort per sequence
+pub(crate) const SEQUENCE_COMMANDS_REGISTER: u16 = 0x04A0;
+/// Permissive condition per relay
+pub(crate) const PERMISSIVES_REGISTER: u16 = 0x04B0;
+/// Power budget, policy, and watts and priority per relay
+pub(crate) const POWER_REGISTER: u16 = 0x04F0;
+/// Reset the energy totals of the relays in the bitmask
+pub(crate) const ENERGY_RESET_REGISTER: u16 = 0x04F8;
+/// Timezone, weekly relay schedules, cron and one-shot entries
+pub(crate) const SCHEDULE_REGISTER: u16 = 0x0500;
+/// Sequence steps
+const SEQUENCES_REGISTER: u16 = 0x0800;
+/// Rule activation, then the rule source text
+pub(crate) const RULES_REGISTER: u16 = 0x0A00;
+/// Analog input scaling, filter and alarm limits per channel
+const ANALOG_REGISTER: u16 = 0x0C10;
+
+const HOLDING_REGISTERS: &[(u16, u16)] = &[
+    (RELAYS_REGISTER, 1),
+    (CONNECTION_SETTINGS_REGISTER, SETTINGS_REGISTER_COUNT),
+    (TLS_COMMIT_REGISTER, COMMIT_REGISTER_COUNT),
+    (GATEWAY_SETTINGS_REGISTER, gateway::SETTINGS_REGISTER_COUNT),
+    (CLIENT_SETTINGS_REGISTER, client::SETTINGS_REGISTER_COUNT),
+    (BINDINGS_REGISTER, binding::REGISTER_COUNT),
+    (LOCKS_REGISTER, lock::REGISTER_COUNT),
+    (SBO_SETTINGS_REGISTER, sbo::REGISTER_COUNT),
+    (TIMERS_REGISTER, timer::REGISTER_COUNT),
+    (TIMED_REGISTER, timed::REGISTER_COUNT),
+    (SCENES_REGISTER, scene::REGISTER_COUNT),
+    (SEQUENCE_COMMANDS_REGISTER, sequence::COMMAND_REGISTER_COUNT),
+    (PERMISSIVES_REGISTER, permissive::REGISTER_COUNT),
+    (POWER_REGISTER, power::REGISTER_COUNT),
+    (ENERGY_RESET_REGISTER, energy::REGISTER_COUNT),
+    (SCHEDULE_REGISTER, schedule::REGISTER_COUNT),
+    (SEQUENCES_REGISTER, sequence::REGISTER_COUNT),
+    (RULES_REGISTER, rule::REGISTER_COUNT),
+    (ANALOG_REGISTER, analog::REGISTER_COUNT),
+];
+
+// Input registers
+/// Connection statistics
+const CONNECTION_STATISTICS_REGISTER: u16 = 0x0100;
+/// Denied requests
+const AUDIT_LOG_REGISTER: u16 = 0x0200;
+/// Virtual input values and status
+const VIRTUAL_INPUTS_REGISTER: u16 = 0x0300;
+/// Remaining time of the relay timers
+const TIMERS_REMAINING_REGISTER: u16 = 0x0400;
+/// Permissive reasons and blocked commands per relay
+pub(crate) const PERMISSIVES_STATUS_REGISTER: u16 = 0x0410;
+/// Power usage, budget, shed relays and refused switch-ons
+pub(crate) const POWER_STATUS_REGISTER: u16 = 0x0420;
+/// Energy and runtime per relay of the day, week and month
+pub(crate) const ENERGY_REGISTER: u16 = 0x0430;
+/// Next fire time of the schedule entries
+const SCHEDULE_NEXT_REGISTER: u16 = 0x0500;
+/// Sequence state, step and abort reason
+pub(crate) const SEQUENCE_STATUS_REGISTER: u16 = 0x0600;
+/// Rule state, error line and code size
+pub(crate) const RULES_STATUS_REGISTER: u16 = 0x0700;
+/// Analog input millivolts, value, alarm and status per channel
+pub(crate) const ANALOG_INPUTS_REGISTER: u16 = 0x0800;
+/// RTU gateway statistics per downstream unit ID
+const GATEWAY_STATISTICS_REGISTER: u16 = 0x1000;
+
+const INPUT_REGISTERS: &[(u16, u16)] = &[
+    (CONNECTION_STATISTICS_REGISTER, STATISTICS_REGISTER_COUNT),
+    (AUDIT_LOG_REGISTER, AUDIT_LOG_REGISTER_COUNT),
+    (VIRTUAL_INPUTS_REGISTER, client::INPUTS_REGISTER_COUNT),
+    (TIMERS_REMAINING_REGISTER, timer::REMAINING_REGISTER_COUNT),
+    (
+        PERMISSIVES_STATUS_REGISTER,
+        permissive::STATUS_REGISTER_COUNT,
+    ),
+    (POWER_STATUS_REGISTER, power::STATUS_REGISTER_COUNT),
+    (ENERGY_REGISTER, energy::STATUS_REGISTER_COUNT),
+    (SCHEDULE_NEXT_REGISTER, schedule::NEXT_REGISTER_COUNT),
+    (SEQUENCE_STATUS_REGISTER, sequence::STATUS_REGISTER_COUNT),
+    (RULES_STATUS_REGISTER, rule::STATUS_REGISTER_COUNT),
+    (ANALOG_INPUTS_REGISTER, analog::STATUS_REGISTER_COUNT),
+    (
+        GATEWAY_STATISTICS_REGISTER,
+        gateway::STATISTICS_REGISTER_COUNT,
+    ),
+];
 
 static SOCKET_ADDR: LazyLock<SocketAddr> = LazyLock::new(|| "0.0.0.0:5502".parse().unwrap());
 
-pub(super) async fn run(
-    pin1: impl Peripheral<P = impl OutputPin> + 'static,
-    pin2: impl Peripheral<P = impl OutputPin> + 'static,
+/// Modbus TCP transport counters
+static TCP_COUNTERS: Counters = Counters::new();
+/// Modbus/TCP Security transport counters
+static TLS_COUNTERS: Counters = Counters::new();
+
+/// Modbus transport
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Transport {
+    Tcp,
+    #[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+    Tls,
+}
+
+impl Transport {
+    fn counters(self) -> &'static Counters {
+        match self {
+            Self::Tcp => &TCP_COUNTERS,
+            Self::Tls => &TLS_COUNTERS,
+        }
+    }
+}
+
+/// Run the Modbus servers enabled by the `tcp` and `tls` features
+///
+/// Requests to other unit IDs than the device's own go to the RTU gateway.
+pub async fn run(
+    relay_sender: Sender<RelayRequest>,
     led_sender: Sender<LedRequest>,
+    gateway_sender: Sender<GatewayRequest>,
 ) -> Result<()> {
-    let driver1 = RelayDriver::new(pin1)?;
-    let driver2 = RelayDriver::new(pin2)?;
-    let server = Server::new(TcpListener::bind(*SOCKET_ADDR).await?);
-    let new_service = |_socket_addr| Ok(Some(RelayService::new(led_sender.clone())));
-    let on_connected = |stream, socket_addr| async move {
-        accept_tcp_connection(stream, socket_addr, new_service)
+    let mut servers = JoinSet::new();
+    #[cfg(feature = "tcp")]
+    servers.spawn(tcp(
+        relay_sender.clone(),
+        led_sender.clone(),
+        gateway_sender.clone(),
+    ));
+    #[cfg(all(feature = "tls", target_os = "espidf"))]
+    servers.spawn(tls::run(
+        relay_sender.clone(),
+        led_sender.clone(),
+        gateway_sender.clone(),
+    ));
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+/// Restore the default connection, select-before-operate, timer, scene,
+/// sequence, rule, permissive, power and schedule settings, drop the timed
+/// commands, release the relay locks and clear the storage, so a fuzzed or
+/// tested write can not lock the next input or test out
+///
+/// Host builds only, for the fuzz target and the tests.
+#[cfg(not(target_os = "espidf"))]
+pub fn reset() {
+    CONNECTIONS.reset();
+    lock::reset();
+    sbo::reset();
+    timer::reset();
+    timed::reset();
+    scene::reset();
+    sequence::reset();
+    rule::reset();
+    permissive::reset();
+    power::reset();
+    schedule::reset();
+    storage::clear();
+}
+
+/// Read holding registers for another protocol
+pub(crate) async fn read_holding(
+    relay_sender: &Sender<RelayRequest>,
+    address: u16,
+    count: u16,
+) -> Result<Vec<u16>, ExceptionCode> {
+    read_holding_registers(relay_sender, address, count, None).await
+}
+
+/// Read input registers for another protocol
+pub(crate) async fn read_input(
+    relay_sender: &Sender<RelayRequest>,
+    address: u16,
+    count: u16,
+) -> Result<Vec<u16>, ExceptionCode> {
+    read_input_registers(relay_sender, address, count).await
+}
+
+/// Write holding registers for another protocol
+///
+/// The client is held to the locks and select before operate like a
+/// plaintext Modbus/TCP client with the configure role.
+pub(crate) async fn write_holding(
+    relay_sender: &Sender<RelayRequest>,
+    client: SocketAddr,
+    address: u16,
+    values: &[u16],
+) -> Result<(), ExceptionCode> {
+    owned(
+        client,
+        &Request::WriteMultipleRegisters(address, values.into()),
+        true,
+    )?;
+    write_holding_registers(relay_sender, client, Transport::Tcp, address, values).await?;
+    Ok(())
+}
+
+/// The trace as text, one transaction per line, oldest first
+pub(crate) fn dump() -> String {
+    TRACE.dump()
+}
+
+/// Plaintext Modbus/TCP server
+#[cfg_attr(not(feature = "tcp"), allow(dead_code))]
+async fn tcp(
+    relay_sender: Sender<RelayRequest>,
+    led_sender: Sender<LedRequest>,
+    gateway_sender: Sender<GatewayRequest>,
+) -> Result<()> {
+    let listener = TcpListener::bind(*SOCKET_ADDR).await?;
+    serve(listener, relay_sender, led_sender, gateway_sender).await
+}
+
+/// Serve plaintext Modbus/TCP on the listener
+pub async fn serve(
+    listener: TcpListener,
+    relay_sender: Sender<RelayRequest>,
+    led_sender: Sender<LedRequest>,
+    gateway_sender: Sender<GatewayRequest>,
+) -> Result<()> {
+    let server = Server::new(listener);
+    let on_connected = |stream, socket_addr| {
+        let relay_sender = relay_sender.clone();
+        let led_sender = led_sender.clone();
+        let gateway_sender = gateway_sender.clone();
+        async move {
+            let Some(connection) = CONNECTIONS.accept(socket_addr) else {
+                return Ok(None);
+            };
+            let service = RelayService::new(
+                socket_addr,
+                connection.clone(),
+                Transport::Tcp,
+                Arc::new(OnceLock::from(Role::Configure)),
+                relay_sender,
+                led_sender,
+                gateway_sender,
+            );
+            Ok(Some((service, ConnectionStream::new(stream, connection))))
+        }
+    };
+    let on_process_error = |error| {
+        TCP_COUNTERS.communication_error();
+        error!("{error}");
     };
-    let on_process_error = |error| error!("{error}");
     server.serve(&on_connected, on_process_error).await?;
     Ok(())
 }
 
 /// Relay service
 struct RelayService {
+    socket_addr: SocketAddr,
+    connection: Arc<Connection>,
+    transport: Transport,
+    /// The highest role the transport allows, e.g. from the client certificate
+    ///
+    /// A TLS session sets it once the handshake is done, before the first
+    /// request is passed on.
+    role: Arc<OnceLock<Role>>,
+    relay_sender: Sender<RelayRequest>,
     led_sender: Sender<LedRequest>,
+    gateway_sender: Sender<GatewayRequest>,
+    counters: Arc<Counters>,
 }
 
 impl RelayService {
-    fn new(led_sender: Sender<LedRequest>) -> Self {
-        Self { led_sender }
+    fn new(
+        socket_addr: SocketAddr,
+        connection: Arc<Connection>,
+        transport: Transport,
+        role: Arc<OnceLock<Role>>,
+        relay_sender: Sender<RelayRequest>,
+        led_sender: Sender<LedRequest>,
+        gateway_sender: Sender<GatewayRequest>,
+    ) -> Self {
+        Self {
+            socket_addr,
+            connection,
+            transport,
+            role,
+            relay_sender,
+            led_sender,
+            gateway_sender,
+            counters: Arc::new(Counters::new()),
+        }
+    }
+}
+
+impl Drop for RelayService {
+    fn drop(&mut self) {
+        info!(
+            "Modbus connection {} closed: {}",
+            self.socket_addr, self.counters
+        );
     }
 }
 
 impl Service for RelayService {
-    type Request = Request<'static>;
+    type Request = SlaveRequest<'static>;
     type Response = Response;
     type Exception = ExceptionCode;
     type Future = impl Future<Output = Result<Self::Response, Self::Exception>>;
 
     fn call(&self, request: Self::Request) -> Self::Future {
-        info!("Modbus request: {request:?}");
+        trace!("Modbus request: {request:?}");
+        let SlaveRequest { slave, request } = request;
+        let role = self.role.get().copied().unwrap_or(Role::ReadOnly);
+        let role = CONNECTIONS.role(&self.connection, slave).min(role);
+        let socket_addr = self.socket_addr;
+        let client = socket_addr.ip();
+        let relay_sender = self.relay_sender.clone();
         let led_sender = self.led_sender.clone();
+        let gateway_sender = self.gateway_sender.clone();
+        let local = gateway::local(slave);
+        let transport = self.transport;
+        let counters = self.counters.clone();
         async move {
+            let start = Instant::now();
+            let transport_counters = transport.counters();
+            let function = request.function_code().value();
+            let (address, count) = trace::range(&request);
+            transport_counters.received();
+            counters.received();
             let _ = led_sender.send(Ok(Duration::from_millis(100))).await;
-            match request {
-                Request::ReadCoils(address, count) => {
-                    if address > 1 || address + count > 2 {
-                        error!("IllegalAddress {{ address: {address}, count: {count} }}");
-                        return Err(ExceptionCode::IllegalDataAddress);
-                    }
-                    if count == 0 {
-                        return Ok(Response::ReadCoils(vec![]));
-                    }
-                    Ok(Response::ReadCoils(vec![false]))
-                }
-                Request::WriteSingleCoil(address, value) => {
-                    if address > 1 {
-                        error!("IllegalAddress {{ address: {address} }}");
-                        return Err(ExceptionCode::IllegalDataAddress);
-                    }
-                    Ok(Response::WriteSingleCoil(address, value))
-                }
-                Request::WriteMultipleCoils(address, values) => {
-                    let count = values.len() as u16;
-                    if address > 1 || address + count > 2 {
-                        error!("IllegalAddress {{ address: {address} }}");
-                        return Err(ExceptionCode::IllegalDataAddress);
-                    }
-                    if count == 0 {
-                        return Ok(Response::ReadCoils(vec![]));
-                    }
-                    Ok(Response::WriteMultipleCoils(address, values.len() as _))
+            let result = match write(&request).map(|write| {
+                let authorized = authorize(role, write, local)
+                    .and_then(|()| owned(socket_addr, &request, local));
+                (write, authorized)
+            }) {
+                Some((write, Err(exception))) => {
+                    AUDIT_LOG.record(AuditEntry {
+                        client,
+                        unit: slave,
+                        function,
+                        address: write.address(),
+                        exception,
+                    });
+                    let _ = led_sender.send(Err(Duration::from_millis(100))).await;
+                    Err(exception)
                 }
-                // Request::ReadInputRegisters(address, count) => {
-                //     let address = address as usize;
-                //     let count = count as usize;
-                //     if address % INPUT_REGISTER_SIZE != 0 || count % INPUT_REGISTER_SIZE != 0 {
-                //         error!("IllegalAddress {{ address: {address}, count: {count} }}");
-                //         return Err(ExceptionCode::IllegalDataAddress);
-                //     }
-                //     let start = address / INPUT_REGISTER_SIZE;
-                //     let end = start + count / INPUT_REGISTER_SIZE;
-                //     let (sender, receiver) = oneshot::channel();
-                //     if let Err(error) = temperature_sender.send((start..end, sender)).await {
-                //         error!("{error:?}");
-                //         return Err(ExceptionCode::ServerDeviceFailure);
-                //     };
-                //     let input_registers: Vec<_> = match receiver.await {
-                //         Ok(Ok(temperatures)) => temperatures
-                //             .into_iter()
-                //             .flat_map(|(address, temperature)| {
-                //                 let address = address.to_be_bytes();
-                //                 let temperature = temperature.to_be_bytes();
-                //                 [
-                //                     u16::from_be_bytes([address[0], address[1]]),
-                //                     u16::from_be_bytes([address[2], address[3]]),
-                //                     u16::from_be_bytes([address[4], address[5]]),
-                //                     u16::from_be_bytes([address[6], address[7]]),
-                //                     u16::from_be_bytes([temperature[0], temperature[1]]),
-                //                     u16::from_be_bytes([temperature[2], temperature[3]]),
-                //                 ]
-                //             })
-                //             .collect(),
-                //         Ok(Err(error)) => {
-                //             error!("{error:?}");
-                //             return Err(error.into());
-                //         }
-                //         Err(error) => {
-                //             error!("{error:?}");
-                //             return Err(ExceptionCode::ServerDeviceFailure);
-                //         }
-                //     };
-                //     Ok(Response::ReadInputRegisters(
-                //         input_registers[address..count].to_vec(),
-                //     ))
-                // }
+                _ if !local => forward(&gateway_sender, slave, request).await,
                 _ => {
-                    let _ = led_sender.send(Err(Duration::from_millis(100))).await;
-                    Err(ExceptionCode::IllegalFunction)
+                    handle(
+                        request,
+                        socket_addr,
+                        transport,
+                        &counters,
+                        &relay_sender,
+                        &led_sender,
+                    )
+                    .await
                 }
+            };
+            transport_counters.responded(function, &result);
+            counters.responded(function, &result);
+            TRACE.record(TraceEntry {
+                client,
+                unit: slave,
+                function,
+                address,
+                count,
+                exception: result.as_ref().err().copied(),
+                latency: start.elapsed(),
+            });
+            result
+        }
+    }
+}
+
+async fn handle(
+    request: Request<'static>,
+    socket_addr: SocketAddr,
+    transport: Transport,
+    counters: &Counters,
+    relay_sender: &Sender<RelayRequest>,
+    led_sender: &Sender<LedRequest>,
+) -> Result<Response, ExceptionCode> {
+    match request {
+        Request::ReadCoils(address, count) => {
+            check(address, count, RELAY_COUNT)?;
+            let (_, state) = relays(relay_sender, Mask::READ).await?;
+            Ok(Response::ReadCoils(
+                (address..address + count)
+                    .map(|index| state & (1 << index) != 0)
+                    .collect(),
+            ))
+        }
+        Request::WriteSingleCoil(address, value) => {
+            check(address, 1, RELAY_COUNT)?;
+            let mask = Mask::write(address, &[value]);
+            operate_coils(relay_sender, socket_addr, mask).await?;
+            Ok(Response::WriteSingleCoil(address, value))
+        }
+        Request::WriteMultipleCoils(address, values) => {
+            let count = values.len() as u16;
+            check(address, count, RELAY_COUNT)?;
+            let mask = Mask::write(address, &values);
+            operate_coils(relay_sender, socket_addr, mask).await?;
+            Ok(Response::WriteMultipleCoils(address, count))
+        }
+        Request::ReadHoldingRegisters(address, count) => Ok(Response::ReadHoldingRegisters(
+            read_holding_registers(relay_sender, address, count, None).await?,
+        )),
+        Request::WriteSingleRegister(address, value) => {
+            write_holding_registers(relay_sender, socket_addr, transport, address, &[value])
+                .await?;
+            Ok(Response::WriteSingleRegister(address, value))
+        }
+        Request::WriteMultipleRegisters(address, values) => {
+            write_holding_registers(relay_sender, socket_addr, transport, address, &values).await?;
+            Ok(Response::WriteMultipleRegisters(address, values.len() as _))
+        }
+        Request::MaskWriteRegister(address, and, or) => {
+            if block(address, 1, HOLDING_REGISTERS)? != (RELAYS_REGISTER, 0) {
+                return Err(ExceptionCode::IllegalDataAddress);
             }
+            operate_coils(relay_sender, socket_addr, Mask { and, or }).await?;
+            Ok(Response::MaskWriteRegister(address, and, or))
+        }
+        Request::ReadWriteMultipleRegisters(read_address, read_count, write_address, values) => {
+            block(read_address, read_count, HOLDING_REGISTERS)?;
+            // The write is performed before the read, the relay bitmask is
+            // written and read back in one relay request
+            let state = write_holding_registers(
+                relay_sender,
+                socket_addr,
+                transport,
+                write_address,
+                &values,
+            )
+            .await?;
+            Ok(Response::ReadWriteMultipleRegisters(
+                read_holding_registers(relay_sender, read_address, read_count, state).await?,
+            ))
+        }
+        Request::ReadInputRegisters(address, count) => Ok(Response::ReadInputRegisters(
+            read_input_registers(relay_sender, address, count).await?,
+        )),
+        Request::Custom(DIAGNOSTICS, data) => {
+            diagnostics::select(transport.counters(), counters, &data)
+                .diagnostics(&data)
+                .map(|data| Response::Custom(DIAGNOSTICS, data.into()))
+        }
+        Request::Custom(GET_COMM_EVENT_COUNTER, _) => Ok(Response::Custom(
+            GET_COMM_EVENT_COUNTER,
+            transport.counters().comm_event_counter().into(),
+        )),
+        Request::Custom(GET_COMM_EVENT_LOG, _) => Ok(Response::Custom(
+            GET_COMM_EVENT_LOG,
+            transport.counters().comm_event_log().into(),
+        )),
+        Request::Custom(READ_FIFO_QUEUE, data) => TRACE
+            .read_fifo_queue(&data)
+            .map(|data| Response::Custom(READ_FIFO_QUEUE, data.into())),
+        // Once provisioned, certificates and keys are only replaced over an
+        // encrypted transport
+        Request::Custom(WRITE_FILE_RECORD, data)
+            if transport == Transport::Tls || !certificates::provisioned() =>
+        {
+            certificates::write_file_record(&data)
+                .map(|data| Response::Custom(WRITE_FILE_RECORD, data.into()))
+        }
+        // Request::ReadInputRegisters(address, count) => {
+        //     let address = address as usize;
+        //     let count = count as usize;
+        //     if address % INPUT_REGISTER_SIZE != 0 || count % INPUT_REGISTER_SIZE != 0 {
+        //         error!("IllegalAddress {{ address: {address}, count: {count} }}");
+        //         return Err(ExceptionCode::IllegalDataAddress);
+        //     }
+        //     let start = address / INPUT_REGISTER_SIZE;
+        //     let end = start + count / INPUT_REGISTER_SIZE;
+        //     let (sender, receiver) = oneshot::channel();
+        //     if let Err(error) = temperature_sender.send((start..end, sender)).await {
+        //         error!("{error:?}");
+        //         return Err(ExceptionCode::ServerDeviceFailure);
+        //     };
+        //     let input_registers: Vec<_> = match receiver.await {
+        //         Ok(Ok(temperatures)) => temperatures
+        //             .into_iter()
+        //             .flat_map(|(address, temperature)| {
+        //                 let address = address.to_be_bytes();
+        //                 let temperature = temperature.to_be_bytes();
+        //                 [
+        //                     u16::from_be_bytes([address[0], address[1]]),
+        //                     u16::from_be_bytes([address[2], address[3]]),
+        //                     u16::from_be_bytes([address[4], address[5]]),
+        //                     u16::from_be_bytes([address[6], address[7]]),
+        //                     u16::from_be_bytes([temperature[0], temperature[1]]),
+        //                     u16::from_be_bytes([temperature[2], temperature[3]]),
+        //                 ]
+        //             })
+        //             .collect(),
+        //         Ok(Err(error)) => {
+        //             error!("{error:?}");
+        //             return Err(error.into());
+        //         }
+        //         Err(error) => {
+        //             error!("{error:?}");
+        //             return Err(ExceptionCode::ServerDeviceFailure);
+        //         }
+        //     };
+        //     Ok(Response::ReadInputRegisters(
+        //         input_registers[address..count].to_vec(),
+        //     ))
+        // }
+        _ => {
+            let _ = led_sender.send(Err(Duration::from_millis(100))).await;
+            Err(ExceptionCode::IllegalFunction)
+        }
+    }
+}
+
+/// Data written by a request
+#[derive(Clone, Copy, Debug)]
+enum Write {
+    Coils(u16),
+    HoldingRegisters(u16, u16),
+    File,
+}
+
+impl Write {
+    fn address(self) -> u16 {
+        match self {
+            Self::Coils(address) | Self::HoldingRegisters(address, _) => address,
+            Self::File => 0,
         }
     }
 }
+
+/// Data written by the request, `None` for requests that do not write
+fn write(request: &Request) -> Option<Write> {
+    match *request {
+        Request::WriteSingleCoil(address, _) | Request::WriteMultipleCoils(address, _) => {
+            Some(Write::Coils(address))
+        }
+        Request::WriteSingleRegister(address, _) | Request::MaskWriteRegister(address, ..) => {
+            Some(Write::HoldingRegisters(address, 1))
+        }
+        Request::WriteMultipleRegisters(address, ref values)
+        | Request::ReadWriteMultipleRegisters(_, _, address, ref values) => {
+            Some(Write::HoldingRegisters(address, values.len() as _))
+        }
+        Request::Custom(WRITE_FILE_RECORD, _) => Some(Write::File),
+        _ => None,
+    }
+}
+
+/// Check that the role allows the write
+///
+/// Read-only clients may not write at all, operators may write coils, the
+/// relay bitmask register, the relay locks, timed commands, the applied scene
+/// and the sequence commands only. Downstream slaves guard their own
+/// registers, so forwarded writes only need more than read-only.
+fn authorize(role: Role, write: Write, local: bool) -> Result<(), ExceptionCode> {
+    match (role, write) {
+        (Role::ReadOnly, _) => Err(ExceptionCode::IllegalFunction),
+        _ if !local => Ok(()),
+        (Role::Operate, Write::File) => Err(ExceptionCode::IllegalFunction),
+        (Role::Operate, Write::HoldingRegisters(RELAYS_REGISTER | SCENES_REGISTER, 1)) => Ok(()),
+        (Role::Operate, Write::HoldingRegisters(address, count))
+            if !within(address, count, LOCKS_REGISTER, lock::REGISTER_COUNT)
+                && !within(address, count, TIMED_REGISTER, timed::REGISTER_COUNT)
+                && !within(
+                    address,
+                    count,
+                    SEQUENCE_COMMANDS_REGISTER,
+                    sequence::COMMAND_REGISTER_COUNT,
+                ) =>
+        {
+            Err(ExceptionCode::IllegalDataAddress)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The registers are all in the block
+fn within(address: u16, count: u16, start: u16, size: u16) -> bool {
+    address >= start && address as u32 + count as u32 <= start as u32 + size as u32
+}
+
+/// Check that no other client holds a lock on the relays the request writes
+fn owned(client: SocketAddr, request: &Request, local: bool) -> Result<(), ExceptionCode> {
+    if !local {
+        return Ok(());
+    }
+    let all = (1 << RELAY_COUNT) - 1;
+    let coils = |address: u16, count: usize| {
+        (address as usize..address as usize + count)
+            .filter(|&index| index < RELAY_COUNT as _)
+            .fold(0, |relays, index| relays | 1 << index)
+    };
+    let relays = match *request {
+        Request::WriteSingleCoil(address, _) => coils(address, 1),
+        Request::WriteMultipleCoils(address, ref values) => coils(address, values.len()),
+        Request::WriteSingleRegister(RELAYS_REGISTER, _)
+        | Request::WriteMultipleRegisters(RELAYS_REGISTER, _)
+        | Request::ReadWriteMultipleRegisters(_, _, RELAYS_REGISTER, _) => all,
+        // Bits kept by the AND mask and not set by the OR mask are untouched
+        Request::MaskWriteRegister(RELAYS_REGISTER, and, or) => (!and | or) & all,
+        Request::WriteSingleRegister(address, _)
+            if within(address, 1, TIMED_REGISTER, timed::REGISTER_COUNT) =>
+        {
+            timed::relays(address - TIMED_REGISTER, 1)
+        }
+        Request::WriteMultipleRegisters(address, ref values)
+        | Request::ReadWriteMultipleRegisters(_, _, address, ref values)
+            if within(
+                address,
+                values.len() as _,
+                TIMED_REGISTER,
+                timed::REGISTER_COUNT,
+            ) =>
+        {
+            timed::relays(address - TIMED_REGISTER, values.len() as _)
+        }
+        Request::WriteSingleRegister(SCENES_REGISTER, value) => scene::relays(value),
+        Request::WriteMultipleRegisters(SCENES_REGISTER, ref values)
+        | Request::ReadWriteMultipleRegisters(_, _, SCENES_REGISTER, ref values) => {
+            values.first().map_or(0, |&value| scene::relays(value))
+        }
+        Request::WriteSingleRegister(address, value)
+            if within(
+                address,
+                1,
+                SEQUENCE_COMMANDS_REGISTER,
+                sequence::COMMAND_REGISTER_COUNT,
+            ) =>
+        {
+            sequence::relays(address - SEQUENCE_COMMANDS_REGISTER, &[value])
+        }
+        Request::WriteMultipleRegisters(address, ref values)
+        | Request::ReadWriteMultipleRegisters(_, _, address, ref values)
+            if within(
+                address,
+                values.len() as _,
+                SEQUENCE_COMMANDS_REGISTER,
+                sequence::COMMAND_REGISTER_COUNT,
+            ) =>
+        {
+            sequence::relays(address - SEQUENCE_COMMANDS_REGISTER, values)
+        }
+        _ => 0,
+    };
+    lock::check(client, relays)
+}
+
+/// Read holding registers
+///
+/// `state` is the relay bitmask, if it is already known.
+async fn read_holding_registers(
+    relay_sender: &Sender<RelayRequest>,
+    address: u16,
+    count: u16,
+    state: Option<u16>,
+) -> Result<Vec<u16>, ExceptionCode> {
+    Ok(match block(address, count, HOLDING_REGISTERS)? {
+        (RELAYS_REGISTER, _) => match state {
+            Some(state) => vec![state],
+            None => vec![relays(relay_sender, Mask::READ).await?.1],
+        },
+        (CONNECTION_SETTINGS_REGISTER, offset) => CONNECTIONS.read_settings(offset, count),
+        (TLS_COMMIT_REGISTER, _) => vec![0],
+        (GATEWAY_SETTINGS_REGISTER, offset) => gateway::read_settings(offset, count),
+        (CLIENT_SETTINGS_REGISTER, offset) => client::read_settings(offset, count),
+        (BINDINGS_REGISTER, offset) => binding::read_settings(offset, count),
+        (LOCKS_REGISTER, offset) => lock::read(offset, count),
+        (SBO_SETTINGS_REGISTER, offset) => sbo::read_settings(offset, count),
+        (TIMERS_REGISTER, offset) => timer::read_settings(offset, count),
+        (TIMED_REGISTER, offset) => timed::read(offset, count),
+        (SCENES_REGISTER, offset) => {
+            let state = match state {
+                Some(state) => state,
+                None => relays(relay_sender, Mask::READ).await?.1,
+            };
+            scene::read(offset, count, state)
+        }
+        (SEQUENCE_COMMANDS_REGISTER, offset) => sequence::read_commands(offset, count),
+        (PERMISSIVES_REGISTER, offset) => permissive::read_settings(offset, count),
+        (POWER_REGISTER, offset) => power::read_settings(offset, count),
+        (ENERGY_RESET_REGISTER, _) => vec![0],
+        (SCHEDULE_REGISTER, offset) => schedule::read_settings(offset, count),
+        (SEQUENCES_REGISTER, offset) => sequence::read_settings(offset, count),
+        (RULES_REGISTER, offset) => rule::read(offset, count),
+        (ANALOG_REGISTER, offset) => analog::read_settings(offset, count),
+        _ => unreachable!(),
+    })
+}
+
+/// Read input registers
+async fn read_input_registers(
+    relay_sender: &Sender<RelayRequest>,
+    address: u16,
+    count: u16,
+) -> Result<Vec<u16>, ExceptionCode> {
+    Ok(match block(address, count, INPUT_REGISTERS)? {
+        (CONNECTION_STATISTICS_REGISTER, offset) => CONNECTIONS.read_statistics(offset, count),
+        (AUDIT_LOG_REGISTER, offset) => AUDIT_LOG.read(offset, count),
+        (VIRTUAL_INPUTS_REGISTER, offset) => client::read_inputs(offset, count),
+        (TIMERS_REMAINING_REGISTER, offset) => timer::read_remaining(offset, count),
+        (PERMISSIVES_STATUS_REGISTER, offset) => {
+            let (_, state) = relays(relay_sender, Mask::READ).await?;
+            permissive::read_status(offset, count, state)
+        }
+        (POWER_STATUS_REGISTER, offset) => {
+            let (_, state) = relays(relay_sender, Mask::READ).await?;
+            power::read_status(offset, count, state)
+        }
+        (ENERGY_REGISTER, offset) => energy::read(offset, count),
+        (SCHEDULE_NEXT_REGISTER, offset) => schedule::read_next(offset, count),
+        (SEQUENCE_STATUS_REGISTER, offset) => sequence::read_status(offset, count),
+        (RULES_STATUS_REGISTER, offset) => rule::read_status(offset, count),
+        (ANALOG_INPUTS_REGISTER, offset) => analog::read(offset, count),
+        (GATEWAY_STATISTICS_REGISTER, offset) => gateway::read_statistics(offset, count),
+        _ => unreachable!(),
+    })
+}
+
+/// Write holding registers
+///
+/// Returns the relay bitmask, if it has been written.
+async fn write_holding_registers(
+    relay_sender: &Sender<RelayRequest>,
+    socket_addr: SocketAddr,
+    transport: Transport,
+    address: u16,
+    values: &[u16],
+) -> Result<Option<u16>, ExceptionCode> {
+    match block(address, values.len() as _, HOLDING_REGISTERS)? {
+        (RELAYS_REGISTER, _) => {
+            let mask = replace(values[0])?;
+            Ok(Some(
+                operate_coils(relay_sender, socket_addr, mask).await?.1,
+            ))
+        }
+        (CONNECTION_SETTINGS_REGISTER, offset) => {
+            CONNECTIONS.write_settings(offset, values)?;
+            Ok(None)
+        }
+        // Like the files, only over an encrypted transport once provisioned
+        (TLS_COMMIT_REGISTER, _) if transport == Transport::Tls || !certificates::provisioned() => {
+            certificates::commit(values[0])?;
+            Ok(None)
+        }
+        (TLS_COMMIT_REGISTER, _) => Err(ExceptionCode::IllegalDataAddress),
+        (GATEWAY_SETTINGS_REGISTER, offset) => {
+            gateway::write_settings(offset, values)?;
+            Ok(None)
+        }
+        (CLIENT_SETTINGS_REGISTER, offset) => {
+            client::write_settings(offset, values)?;
+            Ok(None)
+        }
+        (BINDINGS_REGISTER, offset) => {
+            binding::write_settings(offset, values)?;
+            Ok(None)
+        }
+        (LOCKS_REGISTER, offset) => {
+            lock::write(socket_addr, offset, values)?;
+            Ok(None)
+        }
+        (SBO_SETTINGS_REGISTER, offset) => {
+            sbo::write_settings(offset, values)?;
+            Ok(None)
+        }
+        (TIMERS_REGISTER, offset) => {
+            timer::write_settings(offset, values)?;
+            Ok(None)
+        }
+        (TIMED_REGISTER, offset) => {
+            let (commands, mask) = timed::write(offset, values)?;
+            operate(relay_sender, socket_addr, mask).await?;
+            timed::command(&commands);
+            Ok(None)
+        }
+        (SCENES_REGISTER, offset) => {
+            if let Some(index) = scene::write(offset, values)? {
+                permit(relay_sender, scene::mask(index)).await?;
+                if !sbo::command(socket_addr, scene::mask(index))? {
+                    return Err(ExceptionCode::Acknowledge);
+                }
+                scene::apply(relay_sender, index).await?;
+            }
+            Ok(None)
+        }
+        (SEQUENCE_COMMANDS_REGISTER, offset) => {
+            let mask = sequence::mask(offset, values)?;
+            permit(relay_sender, mask).await?;
+            if !sbo::command(socket_addr, mask)? {
+                return Err(ExceptionCode::Acknowledge);
+            }
+            sequence::command(relay_sender, offset, values)?;
+            Ok(None)
+        }
+        (PERMISSIVES_REGISTER, offset) => {
+            permissive::write_settings(offset, values)?;
+            Ok(None)
+        }
+        (POWER_REGISTER, offset) => {
+            power::write_settings(offset, values)?;
+            Ok(None)
+        }
+        // Every plaintext client has the configure role while the allowlist
+        // is empty, the role has to come from a client certificate
+        (ENERGY_RESET_REGISTER, _) if transport == Transport::Tls => {
+            energy::reset(values)?;
+            Ok(None)
+        }
+        (ENERGY_RESET_REGISTER, _) => Err(ExceptionCode::IllegalDataAddress),
+        (SCHEDULE_REGISTER, offset) => {
+            schedule::write_settings(offset, values)?;
+            Ok(None)
+        }
+        (SEQUENCES_REGISTER, offset) => {
+            sequence::write_settings(offset, values)?;
+            Ok(None)
+        }
+        (RULES_REGISTER, offset) => {
+            rule::write(offset, values)?;
+            Ok(None)
+        }
+        (ANALOG_REGISTER, offset) => {
+            analog::write_settings(offset, values)?;
+            Ok(None)
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Find the register block that holds `count` registers starting at
+/// `address`, returns the block address and the offset into it
+fn block(address: u16, count: u16, blocks: &[(u16, u16)]) -> Result<(u16, u16), ExceptionCode> {
+    if count == 0 {
+        error!("IllegalValue {{ count: {count} }}");
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    for &(start, size) in blocks {
+        if address >= start && address as u32 + count as u32 <= start as u32 + size as u32 {
+            return Ok((start, address - start));
+        }
+    }
+    error!("IllegalAddress {{ address: {address}, count: {count} }}");
+    Err(ExceptionCode::IllegalDataAddress)
+}
+
+/// Check that `count` items starting at `address` fit into `size`
+fn check(address: u16, count: u16, size: u16) -> Result<(), ExceptionCode> {
+    if count == 0 {
+        error!("IllegalValue {{ count: {count} }}");
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    if address as u32 + count as u32 > size as u32 {
+        error!("IllegalAddress {{ address: {address}, count: {count} }}");
+        return Err(ExceptionCode::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+fn replace(state: u16) -> Result<Mask, ExceptionCode> {
+    if state >> RELAY_COUNT != 0 {
+        error!("IllegalValue {{ state: {state:#06b} }}");
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    Ok(Mask::replace(state))
+}
+
+/// Apply a written mask to the relays
+///
+/// A command to select-before-operate relays only selects it the first time
+/// and is answered with acknowledge, one that switches on a relay whose
+/// permissive does not hold or that does not fit in the power budget is
+/// rejected.
+async fn operate(
+    relay_sender: &Sender<RelayRequest>,
+    client: SocketAddr,
+    mask: Mask,
+) -> Result<(u16, u16), ExceptionCode> {
+    permit(relay_sender, mask).await?;
+    if !sbo::command(client, mask)? {
+        return Err(ExceptionCode::Acknowledge);
+    }
+    scene::interrupt(mask);
+    relays(relay_sender, mask).await
+}
+
+/// Apply a coil or relay bitmask write like [`operate`], the relays with a
+/// coil triggered timer take it as the timer's control signal instead, once
+/// the whole mask passed the checks
+async fn operate_coils(
+    relay_sender: &Sender<RelayRequest>,
+    client: SocketAddr,
+    mask: Mask,
+) -> Result<(u16, u16), ExceptionCode> {
+    permit(relay_sender, mask).await?;
+    if !sbo::command(client, mask)? {
+        return Err(ExceptionCode::Acknowledge);
+    }
+    let mask = timer::coils(mask);
+    scene::interrupt(mask);
+    relays(relay_sender, mask).await
+}
+
+/// Check the permissives and the power budget of the relays the mask
+/// switches on
+async fn permit(relay_sender: &Sender<RelayRequest>, mask: Mask) -> Result<(), ExceptionCode> {
+    let (_, state) = relays(relay_sender, Mask::READ).await?;
+    permissive::check(mask, state)?;
+    power::check(mask, state)
+}
+
+/// Apply the mask to the relays atomically
+async fn relays(
+    relay_sender: &Sender<RelayRequest>,
+    mask: Mask,
+) -> Result<(u16, u16), ExceptionCode> {
+    let (sender, receiver) = oneshot::channel();
+    if let Err(error) = relay_sender.send((mask, sender)).await {
+        error!("{error:?}");
+        return Err(ExceptionCode::ServerDeviceFailure);
+    }
+    receiver.await.map_err(|error| {
+        error!("{error:?}");
+        ExceptionCode::ServerDeviceFailure
+    })
+}
+
+/// Forward the request to a downstream RTU slave
+async fn forward(
+    gateway_sender: &Sender<GatewayRequest>,
+    unit: u8,
+    request: Request<'static>,
+) -> Result<Response, ExceptionCode> {
+    let pdu = pdu::encode(&request);
+    let (sender, receiver) = oneshot::channel();
+    if let Err(error) = gateway_sender.send((unit, pdu, sender)).await {
+        error!("{error:?}");
+        return Err(ExceptionCode::ServerDeviceFailure);
+    }
+    let response = receiver.await.map_err(|error| {
+        error!("{error:?}");
+        ExceptionCode::ServerDeviceFailure
+    })??;
+    pdu::decode(&request, &response).unwrap_or_else(|| {
+        error!("Invalid response {{ unit: {unit}, response: {response:02X?} }}");
+        Err(ExceptionCode::GatewayTargetDevice)
+    })
+}
+
+mod audit;
+mod certificates;
+pub mod client;
+mod connection;
+mod diagnostics;
+pub mod gateway;
+mod lock;
+mod pdu;
+mod role;
+mod sbo;
+#[cfg(target_os = "espidf")]
+mod tls;
+mod trace;
@@ -0,0 +1,84 @@
+use super::pdu;
+use log::warn;
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU16, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio_modbus::prelude::*;
+
+const AUDIT_LOG_SIZE: u16 = 16;
+/// Input registers per audit log entry: time high, time low, client address
+/// high, client address low, unit and function, address, exception
+const ENTRY_SIZE: u16 = 7;
+/// Input registers: total entry count, then the entries, most recent first
+pub(super) const AUDIT_LOG_REGISTER_COUNT: u16 = 1 + AUDIT_LOG_SIZE * ENTRY_SIZE;
+
+pub(super) static AUDIT_LOG: AuditLog = AuditLog::new();
+
+/// Audit log entry
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Entry {
+    pub(super) client: IpAddr,
+    pub(super) unit: u8,
+    pub(super) function: u8,
+    pub(super) address: u16,
+    pub(super) exception: ExceptionCode,
+}
+
+/// Audit log of denied requests
+pub(super) struct AuditLog {
+    count: AtomicU16,
+    entries: Mutex<VecDeque<(u32, Entry)>>,
+}
+
+impl AuditLog {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU16::new(0),
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub(super) fn record(&self, entry: Entry) {
+        warn!(target: "audit", "Modbus request denied: {entry:?}");
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs() as _);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == AUDIT_LOG_SIZE as _ {
+            entries.pop_back();
+        }
+        entries.push_front((time, entry));
+    }
+
+    /// Read the audit log input registers
+    pub(super) fn read(&self, address: u16, count: u16) -> Vec<u16> {
+        let entries = self.entries.lock().unwrap();
+        let mut registers = vec![self.count.load(Ordering::Relaxed)];
+        for &(time, entry) in entries.iter() {
+            let client = match entry.client {
+                IpAddr::V4(address) => address.to_bits(),
+                IpAddr::V6(address) => address
+                    .to_ipv4_mapped()
+                    .map_or(0, |address| address.to_bits()),
+            };
+            registers.extend([
+                (time >> 16) as _,
+                time as _,
+                (client >> 16) as _,
+                client as _,
+                u16::from_be_bytes([entry.unit, entry.function]),
+                entry.address,
+                pdu::code(entry.exception) as _,
+            ]);
+        }
+        registers.resize(AUDIT_LOG_REGISTER_COUNT as _, 0);
+        registers[address as usize..][..count as usize].to_vec()
+    }
+}
@@ -0,0 +1,119 @@
+use crate::storage;
+use anyhow::{Result, anyhow};
+use log::{error, info};
+use std::sync::Mutex;
+use tokio_modbus::prelude::*;
+
+/// Write file record (FC 0x15)
+pub(super) const WRITE_FILE_RECORD: u8 = 0x15;
+/// Holding register: write a file number to store the staged file
+pub(super) const COMMIT_REGISTER_COUNT: u16 = 1;
+
+/// Certificate files: CA, server certificate, server private key
+const FILES: [&str; 3] = ["tls_ca", "tls_cert", "tls_key"];
+const MAX_FILE_SIZE: usize = 4096;
+const RECORD_REFERENCE_TYPE: u8 = 6;
+
+/// Files written with write file record, not stored yet
+static STAGING: Mutex<[Vec<u8>; 3]> = Mutex::new([Vec::new(), Vec::new(), Vec::new()]);
+
+/// Write file record (FC 0x15)
+///
+/// Stages certificate files: 1 is the CA, 2 the server certificate, 3 the
+/// server private key. Record numbers are word offsets, writing record 0
+/// starts the file over.
+pub(super) fn write_file_record(data: &[u8]) -> Result<Vec<u8>, ExceptionCode> {
+    let Some((&byte_count, mut records)) = data.split_first() else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    if records.len() != byte_count as usize {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let mut writes = Vec::new();
+    while !records.is_empty() {
+        let [
+            RECORD_REFERENCE_TYPE,
+            file_high,
+            file_low,
+            record_high,
+            record_low,
+            length_high,
+            length_low,
+            ref tail @ ..,
+        ] = *records
+        else {
+            return Err(ExceptionCode::IllegalDataValue);
+        };
+        let file = u16::from_be_bytes([file_high, file_low]) as usize;
+        let offset = u16::from_be_bytes([record_high, record_low]) as usize * 2;
+        let length = u16::from_be_bytes([length_high, length_low]) as usize * 2;
+        if !(1..=FILES.len()).contains(&file) || offset + length > MAX_FILE_SIZE {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        if tail.len() < length {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        writes.push((file - 1, offset, &tail[..length]));
+        records = &tail[length..];
+    }
+    let mut staging = STAGING.lock().unwrap();
+    for (file, offset, record) in writes {
+        let staged = &mut staging[file];
+        if offset == 0 {
+            staged.clear();
+        }
+        if staged.len() < offset + record.len() {
+            staged.resize(offset + record.len(), 0);
+        }
+        staged[offset..][..record.len()].copy_from_slice(record);
+    }
+    Ok(data.to_vec())
+}
+
+/// Store a staged file, new sessions use it right away
+pub(super) fn commit(file: u16) -> Result<(), ExceptionCode> {
+    let Some(key) = FILES.get((file as usize).wrapping_sub(1)) else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    let mut staging = STAGING.lock().unwrap();
+    let staged = &mut staging[file as usize - 1];
+    // Records are whole words, so there may be padding after the PEM
+    let pem = staged.split(|&byte| byte == 0).next().unwrap_or_default();
+    if !pem.starts_with(b"-----BEGIN ") {
+        error!("IllegalValue {{ file: {file} }}");
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let mut value = pem.to_vec();
+    value.push(0);
+    storage::save(key, &value).map_err(|error| {
+        error!("{error:?}");
+        ExceptionCode::ServerDeviceFailure
+    })?;
+    staged.clear();
+    info!("Modbus/TLS {key} replaced");
+    Ok(())
+}
+
+/// Whether the certificates and the key have all been stored
+pub(super) fn provisioned() -> bool {
+    Certificates::load().is_ok()
+}
+
+/// NUL terminated PEM certificates and key
+#[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+pub(super) struct Certificates {
+    pub(super) ca: Vec<u8>,
+    pub(super) certificate: Vec<u8>,
+    pub(super) key: Vec<u8>,
+}
+
+impl Certificates {
+    pub(super) fn load() -> Result<Self> {
+        let load = |key| storage::load(key)?.ok_or_else(|| anyhow!("{key} is not provisioned"));
+        Ok(Self {
+            ca: load(FILES[0])?,
+            certificate: load(FILES[1])?,
+            key: load(FILES[2])?,
+        })
+    }
+}
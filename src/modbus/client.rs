@@ -0,0 +1,232 @@
+use crate::input;
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    net::{Ipv4Addr, SocketAddr},
+    sync::RwLock,
+};
+use tokio::{
+    spawn,
+    time::{Duration, Instant, sleep, timeout},
+};
+use tokio_modbus::{
+    client::{Context, tcp},
+    prelude::*,
+};
+
+const TICK: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Holding registers per poll: address high, address low, port, unit ID,
+/// kind, register, period in seconds, stale timeout in seconds
+const ENTRY_SIZE: u16 = 8;
+pub(super) const SETTINGS_REGISTER_COUNT: u16 = input::COUNT * ENTRY_SIZE;
+
+/// Input registers per virtual input: value, status
+const INPUT_SIZE: u16 = 2;
+pub(super) const INPUTS_REGISTER_COUNT: u16 = input::COUNT * INPUT_SIZE;
+
+static POLLS: RwLock<[Poll; input::COUNT as _]> = RwLock::new([Poll::DISABLED; input::COUNT as _]);
+
+/// What to read from the remote device
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Disabled = 0,
+    Coil = 1,
+    DiscreteInput = 2,
+    HoldingRegister = 3,
+    InputRegister = 4,
+}
+
+impl TryFrom<u16> for Kind {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Disabled),
+            1 => Ok(Self::Coil),
+            2 => Ok(Self::DiscreteInput),
+            3 => Ok(Self::HoldingRegister),
+            4 => Ok(Self::InputRegister),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+/// Remote value mirrored to a virtual input
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Poll {
+    address: Ipv4Addr,
+    port: u16,
+    unit: u8,
+    kind: Kind,
+    register: u16,
+    /// Seconds between polls
+    period: u16,
+    /// Seconds without an answer before the input is stale, `0` is three
+    /// periods
+    stale_timeout: u16,
+}
+
+impl Poll {
+    const DISABLED: Self = Self {
+        address: Ipv4Addr::UNSPECIFIED,
+        port: 502,
+        unit: 1,
+        kind: Kind::Disabled,
+        register: 0,
+        period: 1,
+        stale_timeout: 0,
+    };
+
+    fn socket_addr(&self) -> SocketAddr {
+        (self.address, self.port).into()
+    }
+
+    fn stale_after(&self) -> Duration {
+        match self.stale_timeout {
+            0 => Duration::from_secs(3 * self.period as u64),
+            timeout => Duration::from_secs(timeout as _),
+        }
+    }
+}
+
+/// Start the Modbus TCP client that polls remote values into the virtual
+/// inputs
+pub fn start() {
+    spawn(run());
+}
+
+async fn run() {
+    // One connection per remote device, shared by its polls
+    let mut contexts = HashMap::new();
+    let mut due = [Instant::now(); input::COUNT as _];
+    loop {
+        sleep(TICK).await;
+        let polls = *POLLS.read().unwrap();
+        for (index, poll) in polls.iter().enumerate() {
+            if poll.kind == Kind::Disabled || Instant::now() < due[index] {
+                continue;
+            }
+            due[index] = Instant::now() + Duration::from_secs(poll.period as _);
+            match read(&mut contexts, poll).await {
+                Ok(value) => input::update(index as _, value, poll.stale_after()),
+                Err(error) => {
+                    warn!(
+                        "Modbus poll {index} of {} failed: {error}",
+                        poll.socket_addr()
+                    );
+                    contexts.remove(&poll.socket_addr());
+                }
+            }
+        }
+    }
+}
+
+/// Read the remote value, bits read as `0` or `1`
+async fn read(contexts: &mut HashMap<SocketAddr, Context>, poll: &Poll) -> Result<u16> {
+    let socket_addr = poll.socket_addr();
+    let context = match contexts.entry(socket_addr) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let context = timeout(TIMEOUT, tcp::connect(socket_addr)).await??;
+            info!("Modbus client connected to {socket_addr}");
+            entry.insert(context)
+        }
+    };
+    context.set_slave(Slave(poll.unit));
+    timeout(TIMEOUT, async {
+        match poll.kind {
+            Kind::Coil => first(context.read_coils(poll.register, 1).await??).map(u16::from),
+            Kind::DiscreteInput => {
+                first(context.read_discrete_inputs(poll.register, 1).await??).map(u16::from)
+            }
+            Kind::HoldingRegister => {
+                first(context.read_holding_registers(poll.register, 1).await??)
+            }
+            Kind::InputRegister => first(context.read_input_registers(poll.register, 1).await??),
+            Kind::Disabled => unreachable!(),
+        }
+    })
+    .await?
+}
+
+fn first<T>(values: Vec<T>) -> Result<T> {
+    values
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("empty response"))
+}
+
+/// Read the poll settings holding registers
+pub(super) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    let polls = POLLS.read().unwrap();
+    (address..address + count)
+        .map(|address| {
+            let poll = &polls[(address / ENTRY_SIZE) as usize];
+            let octets = poll.address.octets();
+            match address % ENTRY_SIZE {
+                0 => u16::from_be_bytes([octets[0], octets[1]]),
+                1 => u16::from_be_bytes([octets[2], octets[3]]),
+                2 => poll.port,
+                3 => poll.unit as _,
+                4 => poll.kind as _,
+                5 => poll.register,
+                6 => poll.period,
+                _ => poll.stale_timeout,
+            }
+        })
+        .collect()
+}
+
+/// Write the poll settings holding registers
+///
+/// All values are validated before any of them is applied, the virtual
+/// inputs of changed polls start over as unknown.
+pub(super) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut polls = POLLS.write().unwrap();
+    let mut updated = *polls;
+    for (address, &value) in (address..).zip(values) {
+        let poll = &mut updated[(address / ENTRY_SIZE) as usize];
+        let mut octets = poll.address.octets();
+        let [high, low] = value.to_be_bytes();
+        match address % ENTRY_SIZE {
+            0 => (octets[0], octets[1]) = (high, low),
+            1 => (octets[2], octets[3]) = (high, low),
+            2 => poll.port = value,
+            3 => {
+                poll.unit = value
+                    .try_into()
+                    .map_err(|_| ExceptionCode::IllegalDataValue)?;
+            }
+            4 => poll.kind = value.try_into()?,
+            5 => poll.register = value,
+            6 if (1..=3600).contains(&value) => poll.period = value,
+            7 => poll.stale_timeout = value,
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        }
+        poll.address = octets.into();
+    }
+    for (index, (poll, previous)) in updated.iter().zip(polls.iter()).enumerate() {
+        if poll != previous {
+            info!("Modbus poll {index}: {poll:?}");
+            input::clear(index as _);
+        }
+    }
+    *polls = updated;
+    Ok(())
+}
+
+/// Read the virtual inputs input registers
+pub(super) fn read_inputs(address: u16, count: u16) -> Vec<u16> {
+    (address..address + count)
+        .map(|address| {
+            let (value, status) = input::read(address / INPUT_SIZE);
+            match address % INPUT_SIZE {
+                0 => value,
+                _ => status as _,
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,419 @@
+use super::role::Role;
+use log::{info, warn};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU16, Ordering},
+    },
+    task::{Context, Poll, Waker},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::{Duration, Instant, Sleep, sleep},
+};
+use tokio_modbus::prelude::*;
+
+const MAX_CONNECTIONS: u16 = 4;
+const IDLE_TIMEOUT: u16 = 60;
+
+/// Allowlist capacity
+pub(super) const ALLOWLIST_SIZE: u16 = 8;
+/// Holding registers per allowlist entry: address high, address low, prefix
+/// length, role
+pub(super) const ALLOWLIST_ENTRY_SIZE: u16 = 4;
+/// Unit role table capacity
+pub(super) const UNIT_ROLES_SIZE: u16 = 8;
+/// Holding registers per unit role entry: unit ID, role
+pub(super) const UNIT_ROLE_ENTRY_SIZE: u16 = 2;
+
+// Holding registers, relative to the settings block
+const MAX_CONNECTIONS_REGISTER: u16 = 0x00;
+const IDLE_TIMEOUT_REGISTER: u16 = 0x01;
+const ALLOWLIST_LENGTH_REGISTER: u16 = 0x02;
+const UNIT_ROLES_LENGTH_REGISTER: u16 = 0x03;
+const ALLOWLIST_REGISTER: u16 = 0x10;
+const UNIT_ROLES_REGISTER: u16 = ALLOWLIST_REGISTER + ALLOWLIST_SIZE * ALLOWLIST_ENTRY_SIZE;
+pub(super) const SETTINGS_REGISTER_COUNT: u16 =
+    UNIT_ROLES_REGISTER + UNIT_ROLES_SIZE * UNIT_ROLE_ENTRY_SIZE;
+
+/// Input registers: active, accepted, rejected, evicted, timed out
+pub(super) const STATISTICS_REGISTER_COUNT: u16 = 5;
+
+pub(super) static CONNECTIONS: Connections = Connections::new();
+
+/// Connection settings
+#[derive(Clone, Debug)]
+struct Settings {
+    max_connections: u16,
+    /// Idle timeout in seconds, `0` disables it
+    idle_timeout: u16,
+    /// Number of active allowlist entries, `0` allows everybody
+    allowlist_length: u16,
+    allowlist: [Cidr; ALLOWLIST_SIZE as _],
+    /// Number of active unit role entries
+    unit_roles_length: u16,
+    unit_roles: [(u8, Role); UNIT_ROLES_SIZE as _],
+}
+
+impl Settings {
+    const DEFAULT: Self = Self {
+        max_connections: MAX_CONNECTIONS,
+        idle_timeout: IDLE_TIMEOUT,
+        allowlist_length: 0,
+        allowlist: [Cidr::EMPTY; ALLOWLIST_SIZE as _],
+        unit_roles_length: 0,
+        unit_roles: [(0, Role::ReadOnly); UNIT_ROLES_SIZE as _],
+    };
+
+    /// The role of the client, `None` if it is not allowed
+    ///
+    /// The first matching allowlist entry wins, an empty allowlist allows
+    /// everybody to configure.
+    fn role(&self, address: IpAddr) -> Option<Role> {
+        let allowlist = &self.allowlist[..self.allowlist_length as _];
+        if allowlist.is_empty() {
+            return Some(Role::Configure);
+        }
+        allowlist
+            .iter()
+            .find(|cidr| cidr.contains(address))
+            .map(|cidr| cidr.role)
+    }
+
+    fn unit_role(&self, unit: u8) -> Option<Role> {
+        self.unit_roles[..self.unit_roles_length as _]
+            .iter()
+            .find(|&&(id, _)| id == unit)
+            .map(|&(_, role)| role)
+    }
+}
+
+/// IPv4 network
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Cidr {
+    address: Ipv4Addr,
+    prefix: u8,
+    role: Role,
+}
+
+impl Cidr {
+    const EMPTY: Self = Self {
+        address: Ipv4Addr::UNSPECIFIED,
+        prefix: 32,
+        role: Role::ReadOnly,
+    };
+
+    fn contains(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V4(address) => address,
+            IpAddr::V6(address) => match address.to_ipv4_mapped() {
+                Some(address) => address,
+                None => return false,
+            },
+        };
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(address) & mask == u32::from(self.address) & mask
+    }
+}
+
+/// Modbus TCP connections
+///
+/// Limits the number of concurrent connections, evicting the one that has
+/// been idle the longest, and rejects clients outside the allowlist.
+pub(super) struct Connections {
+    settings: RwLock<Settings>,
+    active: Mutex<Vec<Arc<Connection>>>,
+    accepted: AtomicU16,
+    rejected: AtomicU16,
+    evicted: AtomicU16,
+    timed_out: AtomicU16,
+}
+
+impl Connections {
+    const fn new() -> Self {
+        Self {
+            settings: RwLock::new(Settings::DEFAULT),
+            active: Mutex::new(Vec::new()),
+            accepted: AtomicU16::new(0),
+            rejected: AtomicU16::new(0),
+            evicted: AtomicU16::new(0),
+            timed_out: AtomicU16::new(0),
+        }
+    }
+
+    /// Register a new connection, `None` if the client is not allowed
+    pub(super) fn accept(&self, socket_addr: SocketAddr) -> Option<Arc<Connection>> {
+        let settings = self.settings.read().unwrap().clone();
+        let Some(role) = settings.role(socket_addr.ip()) else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!("Modbus connection {socket_addr} rejected: not in the allowlist");
+            return None;
+        };
+        if settings.max_connections == 0 {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!("Modbus connection {socket_addr} rejected: connections are disabled");
+            return None;
+        }
+        let mut active = self.active.lock().unwrap();
+        while active.len() >= settings.max_connections as _ {
+            let (index, _) = active
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, connection)| *connection.last_activity.lock().unwrap())
+                .unwrap();
+            let evicted = active.swap_remove(index);
+            evicted.close();
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Modbus connection {} evicted for {socket_addr}",
+                evicted.socket_addr
+            );
+        }
+        let connection = Arc::new(Connection {
+            socket_addr,
+            role,
+            idle_timeout: Duration::from_secs(settings.idle_timeout as _),
+            last_activity: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        });
+        active.push(connection.clone());
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        info!(
+            "Modbus connection {socket_addr} accepted as {role} ({})",
+            active.len()
+        );
+        Some(connection)
+    }
+
+    /// The role of the connection for requests to `unit`
+    ///
+    /// A unit role can only lower the role of the connection.
+    pub(super) fn role(&self, connection: &Connection, unit: u8) -> Role {
+        match self.settings.read().unwrap().unit_role(unit) {
+            Some(role) => role.min(connection.role),
+            None => connection.role,
+        }
+    }
+
+    pub(super) fn remove(&self, connection: &Arc<Connection>) {
+        self.active
+            .lock()
+            .unwrap()
+            .retain(|active| !Arc::ptr_eq(active, connection));
+    }
+
+    /// Restore the default settings
+    #[cfg(not(target_os = "espidf"))]
+    pub(super) fn reset(&self) {
+        *self.settings.write().unwrap() = Settings::DEFAULT;
+    }
+
+    /// Read the settings holding registers
+    pub(super) fn read_settings(&self, address: u16, count: u16) -> Vec<u16> {
+        let settings = self.settings.read().unwrap();
+        (address..address + count)
+            .map(|address| match address {
+                MAX_CONNECTIONS_REGISTER => settings.max_connections,
+                IDLE_TIMEOUT_REGISTER => settings.idle_timeout,
+                ALLOWLIST_LENGTH_REGISTER => settings.allowlist_length,
+                UNIT_ROLES_LENGTH_REGISTER => settings.unit_roles_length,
+                ALLOWLIST_REGISTER..UNIT_ROLES_REGISTER => {
+                    let offset = address - ALLOWLIST_REGISTER;
+                    let cidr = &settings.allowlist[(offset / ALLOWLIST_ENTRY_SIZE) as usize];
+                    let octets = cidr.address.octets();
+                    match offset % ALLOWLIST_ENTRY_SIZE {
+                        0 => u16::from_be_bytes([octets[0], octets[1]]),
+                        1 => u16::from_be_bytes([octets[2], octets[3]]),
+                        2 => cidr.prefix as _,
+                        _ => cidr.role as _,
+                    }
+                }
+                UNIT_ROLES_REGISTER.. => {
+                    let offset = address - UNIT_ROLES_REGISTER;
+                    let (unit, role) =
+                        settings.unit_roles[(offset / UNIT_ROLE_ENTRY_SIZE) as usize];
+                    match offset % UNIT_ROLE_ENTRY_SIZE {
+                        0 => unit as _,
+                        _ => role as _,
+                    }
+                }
+                _ => 0,
+            })
+            .collect()
+    }
+
+    /// Write the settings holding registers
+    ///
+    /// All values are validated before any of them is applied.
+    pub(super) fn write_settings(&self, address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+        let mut settings = self.settings.write().unwrap();
+        let mut updated = settings.clone();
+        for (address, &value) in (address..).zip(values) {
+            match address {
+                MAX_CONNECTIONS_REGISTER => updated.max_connections = value,
+                IDLE_TIMEOUT_REGISTER => updated.idle_timeout = value,
+                ALLOWLIST_LENGTH_REGISTER if value <= ALLOWLIST_SIZE => {
+                    updated.allowlist_length = value;
+                }
+                UNIT_ROLES_LENGTH_REGISTER if value <= UNIT_ROLES_SIZE => {
+                    updated.unit_roles_length = value;
+                }
+                ALLOWLIST_LENGTH_REGISTER | UNIT_ROLES_LENGTH_REGISTER => {
+                    return Err(ExceptionCode::IllegalDataValue);
+                }
+                ALLOWLIST_REGISTER..UNIT_ROLES_REGISTER => {
+                    let offset = address - ALLOWLIST_REGISTER;
+                    let cidr = &mut updated.allowlist[(offset / ALLOWLIST_ENTRY_SIZE) as usize];
+                    let mut octets = cidr.address.octets();
+                    let [high, low] = value.to_be_bytes();
+                    match offset % ALLOWLIST_ENTRY_SIZE {
+                        0 => (octets[0], octets[1]) = (high, low),
+                        1 => (octets[2], octets[3]) = (high, low),
+                        2 if value <= 32 => cidr.prefix = value as _,
+                        2 => return Err(ExceptionCode::IllegalDataValue),
+                        _ => cidr.role = value.try_into()?,
+                    }
+                    cidr.address = octets.into();
+                }
+                UNIT_ROLES_REGISTER.. => {
+                    let offset = address - UNIT_ROLES_REGISTER;
+                    let (unit, role) =
+                        &mut updated.unit_roles[(offset / UNIT_ROLE_ENTRY_SIZE) as usize];
+                    match offset % UNIT_ROLE_ENTRY_SIZE {
+                        0 => {
+                            *unit = value
+                                .try_into()
+                                .map_err(|_| ExceptionCode::IllegalDataValue)?;
+                        }
+                        _ => *role = value.try_into()?,
+                    }
+                }
+                // Unused registers between the lengths and the allowlist
+                _ => return Err(ExceptionCode::IllegalDataAddress),
+            }
+        }
+        info!("Modbus connection settings: {updated:?}");
+        *settings = updated;
+        Ok(())
+    }
+
+    /// Read the statistics input registers
+    pub(super) fn read_statistics(&self, address: u16, count: u16) -> Vec<u16> {
+        let statistics = [
+            self.active.lock().unwrap().len() as _,
+            self.accepted.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            self.evicted.load(Ordering::Relaxed),
+            self.timed_out.load(Ordering::Relaxed),
+        ];
+        statistics[address as usize..][..count as usize].to_vec()
+    }
+}
+
+/// Modbus TCP connection
+#[derive(Debug)]
+pub(super) struct Connection {
+    socket_addr: SocketAddr,
+    role: Role,
+    idle_timeout: Duration,
+    last_activity: Mutex<Instant>,
+    closed: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Connection {
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Stream that ends when its connection is idle for too long or evicted
+pub(super) struct Stream<T = TcpStream> {
+    inner: T,
+    connection: Arc<Connection>,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<T> Stream<T> {
+    pub(super) fn new(inner: T, connection: Arc<Connection>) -> Self {
+        let sleep = Box::pin(sleep(connection.idle_timeout));
+        Self {
+            inner,
+            connection,
+            sleep,
+        }
+    }
+
+    fn closed(&self) -> io::Result<()> {
+        if self.connection.closed.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "evicted"));
+        }
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Stream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        self.closed()?;
+        *self.connection.waker.lock().unwrap() = Some(cx.waker().clone());
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                let now = Instant::now();
+                *self.connection.last_activity.lock().unwrap() = now;
+                let deadline = now + self.connection.idle_timeout;
+                self.sleep.as_mut().reset(deadline);
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                if !self.connection.idle_timeout.is_zero()
+                    && self.sleep.as_mut().poll(cx).is_ready()
+                {
+                    CONNECTIONS.timed_out.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Modbus connection {} timed out",
+                        self.connection.socket_addr
+                    );
+                    return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Stream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.closed()?;
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T> Drop for Stream<T> {
+    fn drop(&mut self) {
+        CONNECTIONS.remove(&self.connection);
+    }
+}
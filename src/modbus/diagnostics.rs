@@ -0,0 +1,260 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display, Formatter},
+    sync::{
+        Mutex,
+        atomic::{AtomicU16, Ordering},
+    },
+};
+use tokio_modbus::prelude::*;
+
+/// Diagnostics (FC 0x08)
+pub(super) const DIAGNOSTICS: u8 = 0x08;
+/// Get comm event counter (FC 0x0B)
+pub(super) const GET_COMM_EVENT_COUNTER: u8 = 0x0B;
+/// Get comm event log (FC 0x0C)
+pub(super) const GET_COMM_EVENT_LOG: u8 = 0x0C;
+
+const EVENT_LOG_SIZE: usize = 64;
+/// Status word: no previous command is still being processed
+const STATUS_READY: u16 = 0x0000;
+
+// Diagnostics sub-functions
+const RETURN_QUERY_DATA: u16 = 0x00;
+const RESTART_COMMUNICATIONS_OPTION: u16 = 0x01;
+const RETURN_DIAGNOSTIC_REGISTER: u16 = 0x02;
+const CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER: u16 = 0x0A;
+const RETURN_BUS_MESSAGE_COUNT: u16 = 0x0B;
+const RETURN_BUS_COMMUNICATION_ERROR_COUNT: u16 = 0x0C;
+const RETURN_BUS_EXCEPTION_ERROR_COUNT: u16 = 0x0D;
+const RETURN_SERVER_MESSAGE_COUNT: u16 = 0x0E;
+const RETURN_SERVER_NO_RESPONSE_COUNT: u16 = 0x0F;
+const RETURN_SERVER_NAK_COUNT: u16 = 0x10;
+const RETURN_SERVER_BUSY_COUNT: u16 = 0x11;
+const RETURN_BUS_CHARACTER_OVERRUN_COUNT: u16 = 0x12;
+const CLEAR_OVERRUN_COUNTER_AND_FLAG: u16 = 0x14;
+/// Counter sub-function data that selects the requesting connection's
+/// counters instead of the transport's
+const CONNECTION: [u8; 2] = [0x00, 0x01];
+
+// Event log bytes
+const RECEIVE_EVENT: u8 = 0x80;
+const SEND_EVENT: u8 = 0x40;
+const COMMUNICATION_RESTART_EVENT: u8 = 0x00;
+const READ_EXCEPTION_SENT: u8 = 0x01;
+const SERVER_ABORT_EXCEPTION_SENT: u8 = 0x02;
+const SERVER_BUSY_EXCEPTION_SENT: u8 = 0x04;
+const SERVER_NAK_EXCEPTION_SENT: u8 = 0x08;
+
+/// Communication counters
+///
+/// Kept once per transport and once per client connection, see [`select`].
+#[derive(Debug)]
+pub(super) struct Counters {
+    bus_message: AtomicU16,
+    bus_communication_error: AtomicU16,
+    bus_exception_error: AtomicU16,
+    server_message: AtomicU16,
+    server_no_response: AtomicU16,
+    server_nak: AtomicU16,
+    server_busy: AtomicU16,
+    bus_character_overrun: AtomicU16,
+    comm_event: AtomicU16,
+    events: Mutex<VecDeque<u8>>,
+}
+
+impl Counters {
+    pub(super) const fn new() -> Self {
+        Self {
+            bus_message: AtomicU16::new(0),
+            bus_communication_error: AtomicU16::new(0),
+            bus_exception_error: AtomicU16::new(0),
+            server_message: AtomicU16::new(0),
+            server_no_response: AtomicU16::new(0),
+            server_nak: AtomicU16::new(0),
+            server_busy: AtomicU16::new(0),
+            bus_character_overrun: AtomicU16::new(0),
+            comm_event: AtomicU16::new(0),
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// A request has been received
+    pub(super) fn received(&self) {
+        increment(&self.bus_message);
+        increment(&self.server_message);
+        self.event(RECEIVE_EVENT);
+    }
+
+    /// A frame could not be decoded
+    pub(super) fn communication_error(&self) {
+        increment(&self.bus_communication_error);
+        self.event(RECEIVE_EVENT | 0x02);
+    }
+
+    /// A response has been sent
+    pub(super) fn responded(&self, function: u8, result: &Result<Response, ExceptionCode>) {
+        match result {
+            Ok(_) => {
+                // Comm event counter and log requests do not count as events
+                if function != GET_COMM_EVENT_COUNTER && function != GET_COMM_EVENT_LOG {
+                    increment(&self.comm_event);
+                }
+                self.event(SEND_EVENT);
+            }
+            Err(exception) => {
+                increment(&self.bus_exception_error);
+                let flags = match exception {
+                    ExceptionCode::IllegalFunction
+                    | ExceptionCode::IllegalDataAddress
+                    | ExceptionCode::IllegalDataValue => READ_EXCEPTION_SENT,
+                    ExceptionCode::ServerDeviceFailure => SERVER_ABORT_EXCEPTION_SENT,
+                    ExceptionCode::ServerDeviceBusy => {
+                        increment(&self.server_busy);
+                        SERVER_BUSY_EXCEPTION_SENT
+                    }
+                    ExceptionCode::MemoryParityError => {
+                        increment(&self.server_nak);
+                        SERVER_NAK_EXCEPTION_SENT
+                    }
+                    _ => 0,
+                };
+                self.event(SEND_EVENT | flags);
+            }
+        }
+    }
+
+    /// Diagnostics (FC 0x08)
+    pub(super) fn diagnostics(&self, data: &[u8]) -> Result<Vec<u8>, ExceptionCode> {
+        let [high, low, ..] = *data else {
+            return Err(ExceptionCode::IllegalDataValue);
+        };
+        let sub_function = u16::from_be_bytes([high, low]);
+        let value = match sub_function {
+            RETURN_QUERY_DATA => return Ok(data.to_vec()),
+            RESTART_COMMUNICATIONS_OPTION => {
+                let clear_log = match data[2..] {
+                    [0x00, 0x00] => false,
+                    [0xFF, 0x00] => true,
+                    _ => return Err(ExceptionCode::IllegalDataValue),
+                };
+                self.clear();
+                let mut events = self.events.lock().unwrap();
+                if clear_log {
+                    events.clear();
+                }
+                push(&mut events, COMMUNICATION_RESTART_EVENT);
+                return Ok(data.to_vec());
+            }
+            RETURN_DIAGNOSTIC_REGISTER => 0,
+            CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER => {
+                self.clear();
+                return Ok(data.to_vec());
+            }
+            RETURN_BUS_MESSAGE_COUNT => load(&self.bus_message),
+            RETURN_BUS_COMMUNICATION_ERROR_COUNT => load(&self.bus_communication_error),
+            RETURN_BUS_EXCEPTION_ERROR_COUNT => load(&self.bus_exception_error),
+            RETURN_SERVER_MESSAGE_COUNT => load(&self.server_message),
+            RETURN_SERVER_NO_RESPONSE_COUNT => load(&self.server_no_response),
+            RETURN_SERVER_NAK_COUNT => load(&self.server_nak),
+            RETURN_SERVER_BUSY_COUNT => load(&self.server_busy),
+            RETURN_BUS_CHARACTER_OVERRUN_COUNT => load(&self.bus_character_overrun),
+            CLEAR_OVERRUN_COUNTER_AND_FLAG => {
+                self.bus_character_overrun.store(0, Ordering::Relaxed);
+                return Ok(data.to_vec());
+            }
+            _ => return Err(ExceptionCode::IllegalFunction),
+        };
+        let mut response = data[..2].to_vec();
+        response.extend_from_slice(&value.to_be_bytes());
+        Ok(response)
+    }
+
+    /// Get comm event counter (FC 0x0B)
+    pub(super) fn comm_event_counter(&self) -> Vec<u8> {
+        let mut response = STATUS_READY.to_be_bytes().to_vec();
+        response.extend_from_slice(&load(&self.comm_event).to_be_bytes());
+        response
+    }
+
+    /// Get comm event log (FC 0x0C)
+    pub(super) fn comm_event_log(&self) -> Vec<u8> {
+        let events = self.events.lock().unwrap();
+        let mut response = vec![(6 + events.len()) as _];
+        response.extend_from_slice(&STATUS_READY.to_be_bytes());
+        response.extend_from_slice(&load(&self.comm_event).to_be_bytes());
+        response.extend_from_slice(&load(&self.bus_message).to_be_bytes());
+        // Most recent event first
+        response.extend(events.iter());
+        response
+    }
+
+    fn event(&self, event: u8) {
+        push(&mut self.events.lock().unwrap(), event);
+    }
+
+    fn clear(&self) {
+        for counter in [
+            &self.bus_message,
+            &self.bus_communication_error,
+            &self.bus_exception_error,
+            &self.server_message,
+            &self.server_no_response,
+            &self.server_nak,
+            &self.server_busy,
+            &self.bus_character_overrun,
+            &self.comm_event,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Display for Counters {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "messages: {}, exceptions: {}, communication errors: {}, busy: {}",
+            load(&self.bus_message),
+            load(&self.bus_exception_error),
+            load(&self.bus_communication_error),
+            load(&self.server_busy),
+        )
+    }
+}
+
+/// The counters a diagnostics request (FC 0x08) is for
+///
+/// The counter sub-functions read and clear the transport's counters with
+/// data `0x0000` and the requesting connection's with data `0x0001`.
+pub(super) fn select<'a>(
+    transport: &'a Counters,
+    connection: &'a Counters,
+    data: &[u8],
+) -> &'a Counters {
+    match *data {
+        [high, low, ref value @ ..]
+            if value == CONNECTION
+                && (CLEAR_COUNTERS_AND_DIAGNOSTIC_REGISTER..=CLEAR_OVERRUN_COUNTER_AND_FLAG)
+                    .contains(&u16::from_be_bytes([high, low])) =>
+        {
+            connection
+        }
+        _ => transport,
+    }
+}
+
+fn push(events: &mut VecDeque<u8>, event: u8) {
+    if events.len() == EVENT_LOG_SIZE {
+        events.pop_back();
+    }
+    events.push_front(event);
+}
+
+fn increment(counter: &AtomicU16) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+fn load(counter: &AtomicU16) -> u16 {
+    counter.load(Ordering::Relaxed)
+}
@@ -0,0 +1,282 @@
+#[cfg(target_os = "espidf")]
+use anyhow::Result;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::{
+    hal::{
+        delay::TickType,
+        gpio::{AnyIOPin, InputPin, OutputPin},
+        peripheral::Peripheral,
+        uart::{Uart, UartDriver, config::Config},
+        units::Hertz,
+    },
+    sys::{EspError, esp, uart_mode_t_UART_MODE_RS485_HALF_DUPLEX, uart_set_mode},
+};
+use log::info;
+#[cfg(target_os = "espidf")]
+use log::warn;
+use std::sync::{
+    RwLock,
+    atomic::{AtomicU16, Ordering},
+};
+#[cfg(target_os = "espidf")]
+use std::thread;
+#[cfg(target_os = "espidf")]
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::oneshot;
+use tokio_modbus::prelude::*;
+
+#[cfg(target_os = "espidf")]
+const BAUD_RATE: u32 = 9600;
+#[cfg(target_os = "espidf")]
+const STACK_SIZE: usize = 8 * 1024;
+/// RTU frame: unit, PDU of up to 253 bytes, CRC
+#[cfg(target_os = "espidf")]
+const MAX_FRAME_SIZE: usize = 256;
+/// Silence that ends a frame, well above 3.5 characters at 9600 baud
+#[cfg(target_os = "espidf")]
+const FRAME_GAP: u64 = 20;
+
+const UNIT: u16 = 1;
+const RESPONSE_TIMEOUT: u16 = 500;
+const RETRIES: u16 = 2;
+
+// Holding registers, relative to the settings block
+const UNIT_REGISTER: u16 = 0x00;
+const RESPONSE_TIMEOUT_REGISTER: u16 = 0x01;
+const RETRIES_REGISTER: u16 = 0x02;
+pub(super) const SETTINGS_REGISTER_COUNT: u16 = 3;
+
+/// Input registers per slave: requests, responses, exception responses,
+/// timeouts, invalid frames
+const STATISTICS_SIZE: u16 = 5;
+/// Input registers: statistics of every unit ID, `unit * STATISTICS_SIZE`
+pub(super) const STATISTICS_REGISTER_COUNT: u16 = 256 * STATISTICS_SIZE;
+
+static SETTINGS: RwLock<Settings> = RwLock::new(Settings {
+    unit: UNIT,
+    response_timeout: RESPONSE_TIMEOUT,
+    retries: RETRIES,
+});
+
+static STATISTICS: [Statistics; 256] = [const { Statistics::new() }; 256];
+
+/// Gateway request
+///
+/// A request PDU for a downstream unit, the answer is its response PDU.
+pub type Request = (u8, Vec<u8>, oneshot::Sender<Result<Vec<u8>, ExceptionCode>>);
+
+/// Gateway settings
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    /// Unit ID of the device itself, other unit IDs are forwarded
+    unit: u16,
+    /// Response timeout in milliseconds
+    response_timeout: u16,
+    /// Attempts after the first one
+    retries: u16,
+}
+
+/// Downstream slave statistics
+struct Statistics {
+    requests: AtomicU16,
+    responses: AtomicU16,
+    exceptions: AtomicU16,
+    timeouts: AtomicU16,
+    errors: AtomicU16,
+}
+
+impl Statistics {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU16::new(0),
+            responses: AtomicU16::new(0),
+            exceptions: AtomicU16::new(0),
+            timeouts: AtomicU16::new(0),
+            errors: AtomicU16::new(0),
+        }
+    }
+
+    #[cfg(target_os = "espidf")]
+    fn count(counter: &AtomicU16) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Start the RS-485 RTU master
+///
+/// The driver enable pin is driven by the UART in RS-485 half duplex mode.
+#[cfg(target_os = "espidf")]
+pub fn start(
+    uart: impl Peripheral<P = impl Uart> + 'static,
+    tx: impl Peripheral<P = impl OutputPin> + 'static,
+    rx: impl Peripheral<P = impl InputPin> + 'static,
+    de: impl Peripheral<P = impl OutputPin> + 'static,
+) -> Result<Sender<Request>> {
+    let config = Config::new().baudrate(Hertz(BAUD_RATE));
+    let driver = UartDriver::new(uart, tx, rx, Option::<AnyIOPin>::None, Some(de), &config)?;
+    esp!(unsafe { uart_set_mode(driver.port(), uart_mode_t_UART_MODE_RS485_HALF_DUPLEX) })?;
+    info!("RS-485 driver initialized");
+    let (sender, mut receiver) = mpsc::channel::<Request>(9);
+    info!("Spawn RTU master");
+    // UART reads block, so the master gets its own thread
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            while let Some((unit, pdu, sender)) = receiver.blocking_recv() {
+                let _ = sender.send(transact(&driver, unit, &pdu));
+            }
+        })?;
+    Ok(sender)
+}
+
+/// Whether requests to the unit are handled by the device itself
+///
+/// `0` and `255` address the device directly in Modbus TCP.
+pub(super) fn local(unit: u8) -> bool {
+    unit == 0 || unit == 0xFF || unit as u16 == SETTINGS.read().unwrap().unit
+}
+
+/// Send a request to a slave, retrying until it answers
+#[cfg(target_os = "espidf")]
+fn transact(driver: &UartDriver, unit: u8, pdu: &[u8]) -> Result<Vec<u8>, ExceptionCode> {
+    let settings = *SETTINGS.read().unwrap();
+    let statistics = &STATISTICS[unit as usize];
+    let mut frame = vec![unit];
+    frame.extend_from_slice(pdu);
+    frame.extend(crc(&frame).to_le_bytes());
+    Statistics::count(&statistics.requests);
+    for attempt in 0..=settings.retries {
+        let response = exchange(driver, &frame, settings.response_timeout).map_err(|error| {
+            warn!("RS-485 exchange failed: {error}");
+            ExceptionCode::GatewayPathUnavailable
+        })?;
+        match response {
+            None => {
+                Statistics::count(&statistics.timeouts);
+                warn!("RTU slave {unit} timed out (attempt {attempt})");
+            }
+            Some(response) => match validate(unit, &response) {
+                Some(pdu) => {
+                    Statistics::count(&statistics.responses);
+                    if pdu[0] & 0x80 != 0 {
+                        Statistics::count(&statistics.exceptions);
+                    }
+                    return Ok(pdu.to_vec());
+                }
+                None => {
+                    Statistics::count(&statistics.errors);
+                    warn!("RTU slave {unit} invalid frame {response:02X?} (attempt {attempt})");
+                }
+            },
+        }
+    }
+    Err(ExceptionCode::GatewayTargetDevice)
+}
+
+/// Write the frame and read the response, `None` if there is none
+#[cfg(target_os = "espidf")]
+fn exchange(
+    driver: &UartDriver,
+    frame: &[u8],
+    response_timeout: u16,
+) -> Result<Option<Vec<u8>>, EspError> {
+    driver.clear_rx()?;
+    driver.write(frame)?;
+    let mut buffer = [0; MAX_FRAME_SIZE];
+    let mut length = driver.read(
+        &mut buffer[..1],
+        TickType::new_millis(response_timeout as _).ticks(),
+    )?;
+    if length == 0 {
+        return Ok(None);
+    }
+    // The frame is over once the line stays silent
+    while length < MAX_FRAME_SIZE {
+        let read = driver.read(
+            &mut buffer[length..],
+            TickType::new_millis(FRAME_GAP).ticks(),
+        )?;
+        if read == 0 {
+            break;
+        }
+        length += read;
+    }
+    Ok(Some(buffer[..length].to_vec()))
+}
+
+/// The PDU of a response frame from the unit, `None` if it is invalid
+#[cfg(target_os = "espidf")]
+fn validate(unit: u8, frame: &[u8]) -> Option<&[u8]> {
+    let [address, ref pdu @ .., low, high] = *frame else {
+        return None;
+    };
+    (address == unit
+        && !pdu.is_empty()
+        && crc(&frame[..frame.len() - 2]) == u16::from_le_bytes([low, high]))
+    .then_some(pdu)
+}
+
+/// Modbus RTU CRC-16
+#[cfg(target_os = "espidf")]
+fn crc(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u16, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// Read the settings holding registers
+pub(super) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    let settings = SETTINGS.read().unwrap();
+    (address..address + count)
+        .map(|address| match address {
+            UNIT_REGISTER => settings.unit,
+            RESPONSE_TIMEOUT_REGISTER => settings.response_timeout,
+            RETRIES_REGISTER => settings.retries,
+            _ => 0,
+        })
+        .collect()
+}
+
+/// Write the settings holding registers
+///
+/// All values are validated before any of them is applied.
+pub(super) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut settings = SETTINGS.write().unwrap();
+    let mut updated = *settings;
+    for (address, &value) in (address..).zip(values) {
+        match address {
+            UNIT_REGISTER if (1..=247).contains(&value) => updated.unit = value,
+            RESPONSE_TIMEOUT_REGISTER if (10..=10000).contains(&value) => {
+                updated.response_timeout = value;
+            }
+            RETRIES_REGISTER if value <= 5 => updated.retries = value,
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+    info!("Modbus gateway settings: {updated:?}");
+    *settings = updated;
+    Ok(())
+}
+
+/// Read the per slave statistics input registers
+pub(super) fn read_statistics(address: u16, count: u16) -> Vec<u16> {
+    (address..address + count)
+        .map(|address| {
+            let statistics = &STATISTICS[(address / STATISTICS_SIZE) as usize];
+            match address % STATISTICS_SIZE {
+                0 => &statistics.requests,
+                1 => &statistics.responses,
+                2 => &statistics.exceptions,
+                3 => &statistics.timeouts,
+                _ => &statistics.errors,
+            }
+            .load(Ordering::Relaxed)
+        })
+        .collect()
+}
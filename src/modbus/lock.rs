@@ -0,0 +1,152 @@
+use crate::relay::COUNT as RELAY_COUNT;
+use log::info;
+use std::{net::SocketAddr, sync::Mutex};
+use tokio::time::{Duration, Instant};
+use tokio_modbus::prelude::*;
+
+/// Lease when the token is written without one
+const DEFAULT_LEASE: u16 = 30;
+const MAX_LEASE: u16 = 3600;
+
+/// Holding registers per relay: token, lease seconds left, owner address
+/// high, owner address low
+const ENTRY_SIZE: u16 = 4;
+pub(super) const REGISTER_COUNT: u16 = RELAY_COUNT * ENTRY_SIZE;
+
+static LOCKS: Mutex<[Option<Lock>; RELAY_COUNT as _]> = Mutex::new([None; RELAY_COUNT as _]);
+
+/// Relay claimed by one client connection
+#[derive(Clone, Copy, Debug)]
+struct Lock {
+    token: u16,
+    owner: SocketAddr,
+    lease: u16,
+    expires: Instant,
+}
+
+/// Drop the locks whose lease has expired
+fn expire(locks: &mut [Option<Lock>]) {
+    for (relay, lock) in locks.iter_mut().enumerate() {
+        if let Some(expired) = lock.take_if(|lock| lock.expires <= Instant::now()) {
+            info!(
+                "Relay {relay} lock {:#06X} of {} expired",
+                expired.token, expired.owner
+            );
+        }
+    }
+}
+
+/// Release all locks
+#[cfg(not(target_os = "espidf"))]
+pub(super) fn reset() {
+    *LOCKS.lock().unwrap() = [None; RELAY_COUNT as _];
+}
+
+/// Check that no other client holds a lock on the relays in the bitmask
+pub(super) fn check(client: SocketAddr, relays: u16) -> Result<(), ExceptionCode> {
+    let mut locks = LOCKS.lock().unwrap();
+    expire(&mut *locks);
+    for (relay, lock) in locks.iter().enumerate() {
+        if let Some(lock) = lock
+            && relays & (1 << relay) != 0
+            && lock.owner != client
+        {
+            return Err(ExceptionCode::ServerDeviceBusy);
+        }
+    }
+    Ok(())
+}
+
+/// Read the lock holding registers
+pub(super) fn read(address: u16, count: u16) -> Vec<u16> {
+    let mut locks = LOCKS.lock().unwrap();
+    expire(&mut *locks);
+    (address..address + count)
+        .map(|address| {
+            let Some(lock) = locks[(address / ENTRY_SIZE) as usize] else {
+                return 0;
+            };
+            let owner = match lock.owner {
+                SocketAddr::V4(owner) => owner.ip().to_bits(),
+                SocketAddr::V6(owner) => owner
+                    .ip()
+                    .to_ipv4_mapped()
+                    .map_or(0, |owner| owner.to_bits()),
+            };
+            match address % ENTRY_SIZE {
+                0 => lock.token,
+                1 => {
+                    let left = lock.expires.saturating_duration_since(Instant::now());
+                    left.as_millis().div_ceil(1000) as _
+                }
+                2 => (owner >> 16) as _,
+                _ => owner as _,
+            }
+        })
+        .collect()
+}
+
+/// Write the lock holding registers
+///
+/// A non-zero token claims or renews the relay, `0` releases it. The lease
+/// may be written along with the token or on its own to renew it. A client
+/// reconnecting with the token of a lock takes it over, anybody else gets
+/// busy until it is released or expires. All entries are checked before any
+/// of them is applied, so writing several relays at once claims them as a
+/// group.
+pub(super) fn write(client: SocketAddr, address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut locks = LOCKS.lock().unwrap();
+    expire(&mut *locks);
+    let mut written = [(None, None); RELAY_COUNT as _];
+    for (address, &value) in (address..).zip(values) {
+        let (token, lease) = &mut written[(address / ENTRY_SIZE) as usize];
+        match address % ENTRY_SIZE {
+            0 => *token = Some(value),
+            1 if (1..=MAX_LEASE).contains(&value) => *lease = Some(value),
+            1 => return Err(ExceptionCode::IllegalDataValue),
+            _ => return Err(ExceptionCode::IllegalDataAddress),
+        }
+    }
+    let mut updated = *locks;
+    for (lock, &(token, lease)) in updated.iter_mut().zip(&written) {
+        if token.is_none() && lease.is_none() {
+            continue;
+        }
+        if let Some(current) = lock
+            && current.owner != client
+            && token != Some(current.token)
+        {
+            return Err(ExceptionCode::ServerDeviceBusy);
+        }
+        let token = match (token, *lock) {
+            (Some(token), _) => token,
+            (None, Some(current)) => current.token,
+            (None, None) => return Err(ExceptionCode::IllegalDataValue),
+        };
+        let lease = lease
+            .or(lock.map(|lock| lock.lease))
+            .unwrap_or(DEFAULT_LEASE);
+        *lock = (token != 0).then(|| Lock {
+            token,
+            owner: client,
+            lease,
+            expires: Instant::now() + Duration::from_secs(lease as _),
+        });
+    }
+    for (relay, (lock, previous)) in updated.iter().zip(locks.iter()).enumerate() {
+        match (lock, previous) {
+            (Some(lock), Some(previous))
+                if lock.token == previous.token && lock.owner == previous.owner => {}
+            (Some(lock), _) => info!(
+                "Relay {relay} locked by {} with token {:#06X} for {} s",
+                lock.owner, lock.token, lock.lease
+            ),
+            (None, Some(previous)) => {
+                info!("Relay {relay} lock {:#06X} released", previous.token)
+            }
+            (None, None) => {}
+        }
+    }
+    *locks = updated;
+    Ok(())
+}
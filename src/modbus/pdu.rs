@@ -0,0 +1,182 @@
+use tokio_modbus::prelude::*;
+
+/// Encode a request PDU
+pub(super) fn encode(request: &Request) -> Vec<u8> {
+    let mut pdu = vec![request.function_code().value()];
+    match request {
+        &Request::ReadCoils(address, count)
+        | &Request::ReadDiscreteInputs(address, count)
+        | &Request::ReadHoldingRegisters(address, count)
+        | &Request::ReadInputRegisters(address, count) => words(&mut pdu, &[address, count]),
+        &Request::WriteSingleCoil(address, value) => {
+            words(&mut pdu, &[address, if value { 0xFF00 } else { 0x0000 }]);
+        }
+        Request::WriteMultipleCoils(address, values) => {
+            words(&mut pdu, &[*address, values.len() as _]);
+            let bytes = pack(values);
+            pdu.push(bytes.len() as _);
+            pdu.extend(bytes);
+        }
+        &Request::WriteSingleRegister(address, value) => words(&mut pdu, &[address, value]),
+        Request::WriteMultipleRegisters(address, values) => {
+            words(&mut pdu, &[*address, values.len() as _]);
+            pdu.push((values.len() * 2) as _);
+            words(&mut pdu, values);
+        }
+        &Request::MaskWriteRegister(address, and, or) => words(&mut pdu, &[address, and, or]),
+        Request::ReadWriteMultipleRegisters(read_address, read_count, write_address, values) => {
+            words(
+                &mut pdu,
+                &[
+                    *read_address,
+                    *read_count,
+                    *write_address,
+                    values.len() as _,
+                ],
+            );
+            pdu.push((values.len() * 2) as _);
+            words(&mut pdu, values);
+        }
+        Request::ReportServerId => {}
+        Request::Custom(_, data) => pdu.extend_from_slice(data),
+    }
+    pdu
+}
+
+/// Decode the response PDU to a request
+pub(super) fn decode(request: &Request, pdu: &[u8]) -> Option<Result<Response, ExceptionCode>> {
+    let (&function, data) = pdu.split_first()?;
+    if function == request.function_code().value() | 0x80 {
+        let &[code] = data else {
+            return None;
+        };
+        return Some(Err(exception(code)));
+    }
+    if function != request.function_code().value() {
+        return None;
+    }
+    let response = match *request {
+        Request::ReadCoils(_, count) => Response::ReadCoils(unpack(counted(data)?, count)?),
+        Request::ReadDiscreteInputs(_, count) => {
+            Response::ReadDiscreteInputs(unpack(counted(data)?, count)?)
+        }
+        Request::ReadHoldingRegisters(..) => {
+            Response::ReadHoldingRegisters(unwords(counted(data)?)?)
+        }
+        Request::ReadInputRegisters(..) => Response::ReadInputRegisters(unwords(counted(data)?)?),
+        Request::ReadWriteMultipleRegisters(..) => {
+            Response::ReadWriteMultipleRegisters(unwords(counted(data)?)?)
+        }
+        Request::WriteSingleCoil(..) => {
+            let &[address, value] = &*unwords(data)? else {
+                return None;
+            };
+            Response::WriteSingleCoil(address, value == 0xFF00)
+        }
+        Request::WriteMultipleCoils(..) => {
+            let &[address, count] = &*unwords(data)? else {
+                return None;
+            };
+            Response::WriteMultipleCoils(address, count)
+        }
+        Request::WriteSingleRegister(..) => {
+            let &[address, value] = &*unwords(data)? else {
+                return None;
+            };
+            Response::WriteSingleRegister(address, value)
+        }
+        Request::WriteMultipleRegisters(..) => {
+            let &[address, count] = &*unwords(data)? else {
+                return None;
+            };
+            Response::WriteMultipleRegisters(address, count)
+        }
+        Request::MaskWriteRegister(..) => {
+            let &[address, and, or] = &*unwords(data)? else {
+                return None;
+            };
+            Response::MaskWriteRegister(address, and, or)
+        }
+        Request::ReportServerId => {
+            let [id, run, ref additional @ ..] = *counted(data)? else {
+                return None;
+            };
+            Response::ReportServerId(id, run == 0xFF, additional.to_vec())
+        }
+        Request::Custom(function, _) => Response::Custom(function, data.to_vec().into()),
+    };
+    Some(Ok(response))
+}
+
+/// Exception code from its value
+pub(super) fn exception(code: u8) -> ExceptionCode {
+    match code {
+        0x01 => ExceptionCode::IllegalFunction,
+        0x02 => ExceptionCode::IllegalDataAddress,
+        0x03 => ExceptionCode::IllegalDataValue,
+        0x04 => ExceptionCode::ServerDeviceFailure,
+        0x05 => ExceptionCode::Acknowledge,
+        0x06 => ExceptionCode::ServerDeviceBusy,
+        0x08 => ExceptionCode::MemoryParityError,
+        0x0A => ExceptionCode::GatewayPathUnavailable,
+        0x0B => ExceptionCode::GatewayTargetDevice,
+        code => ExceptionCode::Custom(code),
+    }
+}
+
+/// Value of the exception code
+pub(super) fn code(exception: ExceptionCode) -> u8 {
+    match exception {
+        ExceptionCode::IllegalFunction => 0x01,
+        ExceptionCode::IllegalDataAddress => 0x02,
+        ExceptionCode::IllegalDataValue => 0x03,
+        ExceptionCode::ServerDeviceFailure => 0x04,
+        ExceptionCode::Acknowledge => 0x05,
+        ExceptionCode::ServerDeviceBusy => 0x06,
+        ExceptionCode::MemoryParityError => 0x08,
+        ExceptionCode::GatewayPathUnavailable => 0x0A,
+        ExceptionCode::GatewayTargetDevice => 0x0B,
+        ExceptionCode::Custom(code) => code,
+    }
+}
+
+fn words(pdu: &mut Vec<u8>, words: &[u16]) {
+    for word in words {
+        pdu.extend_from_slice(&word.to_be_bytes());
+    }
+}
+
+fn unwords(data: &[u8]) -> Option<Vec<u16>> {
+    let (words, []) = data.as_chunks() else {
+        return None;
+    };
+    Some(words.iter().map(|&word| u16::from_be_bytes(word)).collect())
+}
+
+/// Data after its byte count
+fn counted(data: &[u8]) -> Option<&[u8]> {
+    let (&count, data) = data.split_first()?;
+    (data.len() == count as usize).then_some(data)
+}
+
+fn pack(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |byte, (index, &bit)| byte | (bit as u8) << index)
+        })
+        .collect()
+}
+
+fn unpack(bytes: &[u8], count: u16) -> Option<Vec<bool>> {
+    if bytes.len() != (count as usize).div_ceil(8) {
+        return None;
+    }
+    Some(
+        (0..count as usize)
+            .map(|index| bytes[index / 8] & (1 << (index % 8)) != 0)
+            .collect(),
+    )
+}
@@ -0,0 +1,55 @@
+use anyhow::{Error, bail};
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+use tokio_modbus::prelude::*;
+
+/// Client role
+///
+/// Roles are ordered, every role is allowed everything the lower ones are.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub(crate) enum Role {
+    /// Read anything, write nothing
+    ReadOnly = 0,
+    /// Also write coils and the relay bitmask register
+    Operate = 1,
+    /// Also write configuration holding registers
+    Configure = 2,
+}
+
+impl TryFrom<u16> for Role {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::ReadOnly),
+            1 => Ok(Self::Operate),
+            2 => Ok(Self::Configure),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "read-only" | "readonly" => Ok(Self::ReadOnly),
+            "operate" => Ok(Self::Operate),
+            "configure" => Ok(Self::Configure),
+            _ => bail!("unknown role {s:?}"),
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::ReadOnly => f.write_str("read-only"),
+            Self::Operate => f.write_str("operate"),
+            Self::Configure => f.write_str("configure"),
+        }
+    }
+}
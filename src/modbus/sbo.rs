@@ -0,0 +1,167 @@
+use crate::relay::{COUNT as RELAY_COUNT, Mask};
+use log::{info, warn};
+use std::{
+    net::SocketAddr,
+    sync::{Mutex, RwLock},
+};
+use tokio::time::{Duration, Instant};
+use tokio_modbus::prelude::*;
+
+const DEFAULT_WINDOW: u16 = 10;
+const MAX_WINDOW: u16 = 60;
+
+/// Holding registers per relay: mode, select window in seconds
+const ENTRY_SIZE: u16 = 2;
+pub(super) const REGISTER_COUNT: u16 = RELAY_COUNT * ENTRY_SIZE;
+
+static SETTINGS: RwLock<[Settings; RELAY_COUNT as _]> =
+    RwLock::new([Settings::DEFAULT; RELAY_COUNT as _]);
+static SELECTIONS: Mutex<[Option<Selection>; RELAY_COUNT as _]> =
+    Mutex::new([None; RELAY_COUNT as _]);
+
+/// How a relay is commanded
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    /// Every write operates the relay
+    Direct = 0,
+    /// A write selects, the same write again within the window operates
+    SelectBeforeOperate = 1,
+}
+
+impl TryFrom<u16> for Mode {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Direct),
+            1 => Ok(Self::SelectBeforeOperate),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Settings {
+    mode: Mode,
+    /// Seconds a selection waits for its operate
+    window: u16,
+}
+
+impl Settings {
+    const DEFAULT: Self = Self {
+        mode: Mode::Direct,
+        window: DEFAULT_WINDOW,
+    };
+}
+
+/// Command selected by a client, waiting for its operate
+#[derive(Clone, Copy, Debug)]
+struct Selection {
+    client: SocketAddr,
+    mask: Mask,
+    expires: Instant,
+}
+
+/// Restore the default settings and drop the pending selections
+#[cfg(not(target_os = "espidf"))]
+pub(super) fn reset() {
+    *SETTINGS.write().unwrap() = [Settings::DEFAULT; RELAY_COUNT as _];
+    *SELECTIONS.lock().unwrap() = [None; RELAY_COUNT as _];
+}
+
+/// Select or operate the relay command
+///
+/// `Ok(true)` operates the command, `Ok(false)` has selected it and is
+/// answered with acknowledge. A command that touches no select-before-operate
+/// relay is operated right away. A command that differs from the pending
+/// selection, or comes from another client, cancels it.
+pub(super) fn command(client: SocketAddr, mask: Mask) -> Result<bool, ExceptionCode> {
+    let settings = *SETTINGS.read().unwrap();
+    let relays: Vec<_> = (0..RELAY_COUNT as usize)
+        .filter(|&relay| {
+            mask.and & (1 << relay) == 0 && settings[relay].mode == Mode::SelectBeforeOperate
+        })
+        .collect();
+    if relays.is_empty() {
+        return Ok(true);
+    }
+    let mut selections = SELECTIONS.lock().unwrap();
+    for (relay, selection) in selections.iter_mut().enumerate() {
+        if let Some(expired) = selection.take_if(|selection| selection.expires <= Instant::now()) {
+            warn!(
+                "Relay {relay} selection by {} timed out: {:?}",
+                expired.client, expired.mask
+            );
+        }
+    }
+    let matches = |selection: &Option<Selection>| {
+        selection.is_some_and(|selection| selection.client == client && selection.mask == mask)
+    };
+    if relays.iter().all(|&relay| matches(&selections[relay])) {
+        for &relay in &relays {
+            selections[relay] = None;
+        }
+        info!("Relays {relays:?} operated by {client}: {mask:?}");
+        return Ok(true);
+    }
+    if relays.iter().any(|&relay| selections[relay].is_some()) {
+        for &relay in &relays {
+            if let Some(cancelled) = selections[relay].take() {
+                warn!(
+                    "Relay {relay} selection by {} cancelled by {client}: {:?} does not match {:?}",
+                    cancelled.client, mask, cancelled.mask
+                );
+            }
+        }
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    for &relay in &relays {
+        selections[relay] = Some(Selection {
+            client,
+            mask,
+            expires: Instant::now() + Duration::from_secs(settings[relay].window as _),
+        });
+    }
+    info!("Relays {relays:?} selected by {client}: {mask:?}");
+    Ok(false)
+}
+
+/// Read the select-before-operate holding registers
+pub(super) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    let settings = SETTINGS.read().unwrap();
+    (address..address + count)
+        .map(|address| {
+            let settings = &settings[(address / ENTRY_SIZE) as usize];
+            match address % ENTRY_SIZE {
+                0 => settings.mode as _,
+                _ => settings.window,
+            }
+        })
+        .collect()
+}
+
+/// Write the select-before-operate holding registers
+///
+/// All values are validated before any of them is applied, pending
+/// selections of changed relays are cancelled.
+pub(super) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut settings = SETTINGS.write().unwrap();
+    let mut updated = *settings;
+    for (address, &value) in (address..).zip(values) {
+        let entry = &mut updated[(address / ENTRY_SIZE) as usize];
+        match address % ENTRY_SIZE {
+            0 => entry.mode = value.try_into()?,
+            _ if (1..=MAX_WINDOW).contains(&value) => entry.window = value,
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+    let mut selections = SELECTIONS.lock().unwrap();
+    for (relay, (entry, previous)) in updated.iter().zip(settings.iter()).enumerate() {
+        if entry != previous {
+            info!("Relay {relay} command: {entry:?}");
+            selections[relay] = None;
+        }
+    }
+    *settings = updated;
+    Ok(())
+}
@@ -0,0 +1,252 @@
+use super::{
+    RelayService, TLS_COUNTERS, Transport,
+    certificates::Certificates,
+    connection::{CONNECTIONS, Stream as ConnectionStream},
+    gateway::Request as GatewayRequest,
+    role::Role,
+};
+use crate::{led::Request as LedRequest, relay::Request as RelayRequest};
+use anyhow::{Result, anyhow, bail};
+use esp_idf_svc::{
+    io::{Read, Write},
+    sys::{
+        esp_tls_get_ssl_context, mbedtls_ssl_context, mbedtls_ssl_get_peer_cert,
+        mbedtls_ssl_get_version,
+    },
+    tls::{EspTls, ServerConfig, X509},
+};
+use log::{error, info, warn};
+use std::{
+    ffi::CStr,
+    net::{Shutdown, SocketAddr, TcpStream as StdTcpStream},
+    slice, str,
+    sync::{Arc, LazyLock, OnceLock},
+    thread,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex},
+    net::{TcpListener, TcpStream},
+    runtime::Handle,
+    sync::{mpsc::Sender, oneshot},
+    time::timeout,
+};
+use tokio_modbus::server::tcp::Server;
+
+const STACK_SIZE: usize = 16 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time a client gets to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Protocol versions accepted, TLS 1.2 is the minimum Modbus/TCP Security
+/// allows
+const VERSIONS: [&str; 2] = ["TLSv1.2", "TLSv1.3"];
+/// MBAP header: transaction, protocol, length, unit
+const HEADER_SIZE: usize = 7;
+const MAX_FRAME_SIZE: usize = 260;
+
+/// Role extension (1.3.6.1.4.1.50316.802.1) of the client certificate
+const ROLE_OID: &[u8] = &[
+    0x06, 0x0B, 0x2B, 0x06, 0x01, 0x04, 0x01, 0x83, 0x89, 0x0C, 0x86, 0x22, 0x01,
+];
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const UTF8_STRING: u8 = 0x0C;
+
+static SOCKET_ADDR: LazyLock<SocketAddr> = LazyLock::new(|| "0.0.0.0:802".parse().unwrap());
+
+/// Modbus/TCP Security server
+///
+/// Each session runs the TLS side on its own thread and hands plaintext
+/// frames to the regular Modbus/TCP server through a duplex stream. The
+/// handshake runs on that thread too, so a client that never completes it
+/// does not hold up the accept loop.
+pub(super) async fn run(
+    relay_sender: Sender<RelayRequest>,
+    led_sender: Sender<LedRequest>,
+    gateway_sender: Sender<GatewayRequest>,
+) -> Result<()> {
+    let server = Server::new(TcpListener::bind(*SOCKET_ADDR).await?);
+    let on_connected = |stream, socket_addr| {
+        let relay_sender = relay_sender.clone();
+        let led_sender = led_sender.clone();
+        let gateway_sender = gateway_sender.clone();
+        async move {
+            let Some(connection) = CONNECTIONS.accept(socket_addr) else {
+                return Ok(None);
+            };
+            let role = Arc::new(OnceLock::new());
+            let stream = match accept(stream, socket_addr, role.clone()) {
+                Ok(stream) => stream,
+                Err(error) => {
+                    warn!("Modbus/TLS connection {socket_addr} rejected: {error}");
+                    CONNECTIONS.remove(&connection);
+                    return Ok(None);
+                }
+            };
+            let service = RelayService::new(
+                socket_addr,
+                connection.clone(),
+                Transport::Tls,
+                role,
+                relay_sender,
+                led_sender,
+                gateway_sender,
+            );
+            Ok(Some((service, ConnectionStream::new(stream, connection))))
+        }
+    };
+    let on_process_error = |error| {
+        TLS_COUNTERS.communication_error();
+        error!("{error}");
+    };
+    server.serve(&on_connected, on_process_error).await?;
+    Ok(())
+}
+
+/// Start a TLS session, returns the plaintext stream
+///
+/// The session sets the client role once the handshake is done and only
+/// then passes frames on; a failed handshake closes the stream.
+fn accept(
+    stream: TcpStream,
+    socket_addr: SocketAddr,
+    role: Arc<OnceLock<Role>>,
+) -> Result<DuplexStream> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    let certificates = Certificates::load()?;
+    let (local, remote) = duplex(MAX_FRAME_SIZE);
+    let handle = Handle::current();
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            if let Err(error) = session(stream, socket_addr, &certificates, remote, &role, handle) {
+                info!("Modbus/TLS session {socket_addr} ended: {error}");
+            }
+        })?;
+    Ok(local)
+}
+
+fn session(
+    stream: StdTcpStream,
+    socket_addr: SocketAddr,
+    certificates: &Certificates,
+    mut remote: DuplexStream,
+    role: &OnceLock<Role>,
+    handle: Handle,
+) -> Result<()> {
+    // A client that trickles the handshake is cut off as a whole
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let watchdog = stream.try_clone()?;
+    let (sender, receiver) = oneshot::channel::<()>();
+    handle.spawn(async move {
+        if timeout(HANDSHAKE_TIMEOUT, receiver).await.is_err() {
+            let _ = watchdog.shutdown(Shutdown::Both);
+        }
+    });
+    let mut tls = EspTls::adopt(stream.try_clone()?)?;
+    tls.negotiate_server(&ServerConfig {
+        ca_cert: Some(X509::pem_until_nul(&certificates.ca)),
+        server_cert: Some(X509::pem_until_nul(&certificates.certificate)),
+        server_key: Some(X509::pem_until_nul(&certificates.key)),
+        ..Default::default()
+    })?;
+    let _ = sender.send(());
+    let version = version(&tls)?;
+    if !VERSIONS.contains(&version) {
+        bail!("{version} is below TLS 1.2");
+    }
+    let client_role = self::role(peer_certificate(&tls)?)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    info!("Modbus/TLS connection {socket_addr} authenticated as {client_role}");
+    let _ = role.set(client_role);
+    // Modbus is request/response, so the frames are passed one at a time
+    let mut frame = [0; MAX_FRAME_SIZE];
+    loop {
+        tls.read_exact(&mut frame[..HEADER_SIZE])
+            .map_err(|error| anyhow!("{error:?}"))?;
+        let length = frame_length(&frame)?;
+        tls.read_exact(&mut frame[HEADER_SIZE..length])
+            .map_err(|error| anyhow!("{error:?}"))?;
+        handle.block_on(remote.write_all(&frame[..length]))?;
+        handle.block_on(remote.read_exact(&mut frame[..HEADER_SIZE]))?;
+        let length = frame_length(&frame)?;
+        handle.block_on(remote.read_exact(&mut frame[HEADER_SIZE..length]))?;
+        tls.write_all(&frame[..length])?;
+    }
+}
+
+/// Frame length from the MBAP header
+fn frame_length(header: &[u8]) -> Result<usize> {
+    // The length field counts the unit identifier, which is part of the header
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if !(2..=MAX_FRAME_SIZE - HEADER_SIZE + 1).contains(&length) {
+        bail!("invalid MBAP length {length}");
+    }
+    Ok(HEADER_SIZE + length - 1)
+}
+
+/// Protocol version of the session, like `TLSv1.2`
+fn version<S>(tls: &EspTls<S>) -> Result<&'static str> {
+    unsafe {
+        let ssl = esp_tls_get_ssl_context(tls.context_handle()) as *const mbedtls_ssl_context;
+        Ok(CStr::from_ptr(mbedtls_ssl_get_version(ssl)).to_str()?)
+    }
+}
+
+/// DER of the client certificate
+fn peer_certificate<S>(tls: &EspTls<S>) -> Result<&[u8]> {
+    unsafe {
+        let ssl = esp_tls_get_ssl_context(tls.context_handle()) as *const mbedtls_ssl_context;
+        let certificate = mbedtls_ssl_get_peer_cert(ssl);
+        if certificate.is_null() {
+            bail!("no client certificate");
+        }
+        let raw = &(*certificate).raw;
+        Ok(slice::from_raw_parts(raw.p, raw.len))
+    }
+}
+
+/// The role from the Modbus role extension of a DER certificate
+fn role(certificate: &[u8]) -> Result<Role> {
+    let index = certificate
+        .windows(ROLE_OID.len())
+        .position(|window| window == ROLE_OID)
+        .ok_or_else(|| anyhow!("no role extension"))?;
+    let mut extension = &certificate[index + ROLE_OID.len()..];
+    // Skip the critical flag
+    if let Some((BOOLEAN, _, tail)) = tlv(extension) {
+        extension = tail;
+    }
+    let Some((OCTET_STRING, value, _)) = tlv(extension) else {
+        bail!("invalid role extension");
+    };
+    let Some((UTF8_STRING, role, _)) = tlv(value) else {
+        bail!("invalid role extension");
+    };
+    str::from_utf8(role)?.parse()
+}
+
+/// Split a DER tag-length-value off the data: tag, value, rest
+fn tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&length, mut data) = data.split_first()?;
+    let length = if length & 0x80 == 0 {
+        length as usize
+    } else {
+        let count = (length & 0x7F) as usize;
+        if count > 2 || data.len() < count {
+            return None;
+        }
+        let (bytes, tail) = data.split_at(count);
+        data = tail;
+        bytes
+            .iter()
+            .fold(0, |length, &byte| length << 8 | byte as usize)
+    };
+    if data.len() < length {
+        return None;
+    }
+    let (value, tail) = data.split_at(length);
+    Some((tag, value, tail))
+}
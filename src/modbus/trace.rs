@@ -0,0 +1,183 @@
+use super::pdu;
+use log::trace;
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio_modbus::prelude::*;
+
+/// Read FIFO queue (FC 0x18)
+pub(super) const READ_FIFO_QUEUE: u8 = 0x18;
+
+const TRACE_SIZE: usize = 64;
+/// FIFO registers per entry: sequence number, time high, time low, client
+/// address high, client address low, unit and function, address, count,
+/// exception, latency in milliseconds
+const ENTRY_SIZE: usize = 10;
+/// Entries per FIFO read, a FIFO holds at most 31 registers
+const ENTRIES_PER_READ: usize = 31 / ENTRY_SIZE;
+
+pub(super) static TRACE: Trace = Trace::new();
+
+/// Trace entry
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Entry {
+    pub(super) client: IpAddr,
+    pub(super) unit: u8,
+    pub(super) function: u8,
+    pub(super) address: u16,
+    pub(super) count: u16,
+    pub(super) exception: Option<ExceptionCode>,
+    pub(super) latency: Duration,
+}
+
+/// Entry with its sequence number and time in seconds since the epoch
+#[derive(Clone, Copy)]
+struct Record {
+    sequence: u16,
+    time: u32,
+    entry: Entry,
+}
+
+/// Ring of the most recent records and the sequence number of the next one
+struct Records {
+    sequence: u16,
+    ring: VecDeque<Record>,
+}
+
+/// Trace of the most recent transactions
+pub(super) struct Trace {
+    entries: Mutex<Records>,
+}
+
+impl Trace {
+    const fn new() -> Self {
+        Self {
+            entries: Mutex::new(Records {
+                sequence: 0,
+                ring: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub(super) fn record(&self, entry: Entry) {
+        trace!("Modbus transaction: {entry:?}");
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs() as _);
+        let records = &mut *self.entries.lock().unwrap();
+        if records.ring.len() == TRACE_SIZE {
+            records.ring.pop_front();
+        }
+        records.ring.push_back(Record {
+            sequence: records.sequence,
+            time,
+            entry,
+        });
+        records.sequence = records.sequence.wrapping_add(1);
+    }
+
+    /// Read FIFO queue
+    ///
+    /// The FIFO pointer address is a sequence number, the queue holds the
+    /// entries from there on, oldest first. A client downloads the whole
+    /// trace by reading again from the sequence number after the last entry
+    /// it got.
+    pub(super) fn read_fifo_queue(&self, data: &[u8]) -> Result<Vec<u8>, ExceptionCode> {
+        let &[high, low] = data else {
+            return Err(ExceptionCode::IllegalDataValue);
+        };
+        let pointer = u16::from_be_bytes([high, low]);
+        let records = &*self.entries.lock().unwrap();
+        let mut registers = Vec::new();
+        for &Record {
+            sequence,
+            time,
+            entry,
+        } in records
+            .ring
+            .iter()
+            .filter(|record| record.sequence.wrapping_sub(pointer) < 0x8000)
+            .take(ENTRIES_PER_READ)
+        {
+            let client = match entry.client {
+                IpAddr::V4(address) => address.to_bits(),
+                IpAddr::V6(address) => address
+                    .to_ipv4_mapped()
+                    .map_or(0, |address| address.to_bits()),
+            };
+            registers.extend([
+                sequence,
+                (time >> 16) as _,
+                time as _,
+                (client >> 16) as _,
+                client as _,
+                u16::from_be_bytes([entry.unit, entry.function]),
+                entry.address,
+                entry.count,
+                entry
+                    .exception
+                    .map_or(0, |exception| pdu::code(exception) as _),
+                entry.latency.as_millis().min(u16::MAX as _) as _,
+            ]);
+        }
+        // Byte count, FIFO count, FIFO values
+        let mut data = Vec::with_capacity(4 + registers.len() * 2);
+        data.extend_from_slice(&(2 + registers.len() as u16 * 2).to_be_bytes());
+        data.extend_from_slice(&(registers.len() as u16).to_be_bytes());
+        for register in registers {
+            data.extend_from_slice(&register.to_be_bytes());
+        }
+        Ok(data)
+    }
+
+    /// The entries as text, one per line: sequence number, time, client
+    /// address, unit ID, function code, address, count, exception code (0
+    /// for none) and latency in milliseconds
+    pub(super) fn dump(&self) -> String {
+        let records = &*self.entries.lock().unwrap();
+        let mut text = String::new();
+        for Record {
+            sequence,
+            time,
+            entry,
+        } in &records.ring
+        {
+            let _ = writeln!(
+                text,
+                "{sequence} {time} {} {} {} {} {} {} {}",
+                entry.client,
+                entry.unit,
+                entry.function,
+                entry.address,
+                entry.count,
+                entry.exception.map_or(0, pdu::code),
+                entry.latency.as_millis(),
+            );
+        }
+        text
+    }
+}
+
+/// Address range of the request, the written one for read/write multiple
+/// registers
+pub(super) fn range(request: &Request) -> (u16, u16) {
+    match *request {
+        Request::ReadCoils(address, count)
+        | Request::ReadDiscreteInputs(address, count)
+        | Request::ReadHoldingRegisters(address, count)
+        | Request::ReadInputRegisters(address, count) => (address, count),
+        Request::WriteSingleCoil(address, _)
+        | Request::WriteSingleRegister(address, _)
+        | Request::MaskWriteRegister(address, ..) => (address, 1),
+        Request::WriteMultipleCoils(address, ref values) => (address, values.len() as _),
+        Request::WriteMultipleRegisters(address, ref values)
+        | Request::ReadWriteMultipleRegisters(_, _, address, ref values) => {
+            (address, values.len() as _)
+        }
+        _ => (0, 0),
+    }
+}
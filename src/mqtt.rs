@@ -1,94 +1,672 @@
-use crate::{MAC_ADDRESS, temperature::Request as TemperatureRequest};
-use anyhow::Result;
-use esp_idf_svc::{
-    mqtt::client::{EspAsyncMqttClient, EspAsyncMqttConnection, MqttClientConfiguration, QoS},
-    sys::EspError,
-};
-use log::{error, info, trace, warn};
-use tokio::{
-    spawn,
-    sync::{mpsc::Sender, oneshot},
-    time::{Duration, sleep},
+//! MQTT topics, also served over HTTP
+//!
+//! A command is published to `<PREFIX>/<topic>/set` with a text payload and
+//! goes through the same registers, locks and checks as a Modbus write; its
+//! reply, if any, is published to `<PREFIX>/<topic>` and a rejected command
+//! to `<PREFIX>/error`. The status topics are retained and published when
+//! they change.
+
+use crate::{
+    analog,
+    energy::{self, PERIOD_COUNT, PERIOD_NAMES},
+    modbus::{
+        self, ANALOG_INPUTS_REGISTER, ENERGY_REGISTER, PERMISSIVES_REGISTER,
+        PERMISSIVES_STATUS_REGISTER, POWER_REGISTER, POWER_STATUS_REGISTER, RELAYS_REGISTER,
+        RULES_REGISTER, RULES_STATUS_REGISTER, SCENES_REGISTER, SCHEDULE_REGISTER,
+        SEQUENCE_COMMANDS_REGISTER, SEQUENCE_STATUS_REGISTER, TIMED_REGISTER,
+    },
+    permissive::TEXT_SIZE as PERMISSIVE_SIZE,
+    relay::{COUNT as RELAY_COUNT, Request as RelayRequest},
+    rule::SOURCE_SIZE,
+    scene::{self, SCENE_COUNT},
+    schedule::{
+        CALENDAR_COUNT, CALENDAR_SIZE, CALENDARS_REGISTER, ENTRIES_REGISTER, ENTRY_COUNT,
+        ENTRY_SIZE, LOCATION_REGISTER, LOCATION_REGISTER_COUNT, RANGE_COUNT, RANGE_SIZE,
+        RANGES_REGISTER, TIMEZONE_REGISTER, TIMEZONE_REGISTER_COUNT, WINDOW_COUNT, WINDOW_SIZE,
+        WINDOWS_REGISTER,
+    },
+    sequence::SEQUENCE_COUNT,
 };
+use log::error;
+use std::{fmt::Display, net::SocketAddr, str::FromStr};
+use tokio::sync::mpsc::Sender;
+use tokio_modbus::prelude::*;
+
+pub const PREFIX: &str = "ippras.ru/blca/relay";
 
-const MQTT_URL: &str = "mqtt://192.168.0.87:1883";
-const MQTT_CLIENT_ID: &str = MAC_ADDRESS;
-const MQTT_USERNAME: Option<&str> = option_env!("MQTT_USERNAME");
-const MQTT_PASSWORD: Option<&str> = option_env!("MQTT_PASSWORD");
-
-const MQTT_TOPIC_BLC: &str = "ippras.ru/blca/#";
-const MQTT_TOPIC_TEMPERATURE: &str = "ippras.ru/blca/temperature";
-
-const RETRY: Duration = Duration::from_millis(500);
-
-pub(crate) async fn run(
-    mut temperature_sender: Sender<TemperatureRequest>,
-) -> Result<(), EspError> {
-    info!("Initialize MQTT");
-    let (mut client, connection) = EspAsyncMqttClient::new(
-        MQTT_URL,
-        &MqttClientConfiguration {
-            client_id: Some(MQTT_CLIENT_ID),
-            username: MQTT_USERNAME,
-            password: MQTT_PASSWORD,
-            ..Default::default()
+/// Run the command of `<topic>/set`, returns the reply to publish
+///
+/// `client` holds locks and selections like a Modbus/TCP client address.
+pub async fn command(
+    relay_sender: &Sender<RelayRequest>,
+    client: SocketAddr,
+    topic: &str,
+    payload: &[u8],
+) -> Result<Option<String>, ExceptionCode> {
+    let payload = text(payload)?;
+    let (address, values) = match *topic.split('/').collect::<Vec<_>>() {
+        ["relays"] => (RELAYS_REGISTER, vec![number(payload)?]),
+        ["trace"] => return Ok(Some(modbus::dump())),
+        ["scene"] => {
+            let names = scene_names(relay_sender).await?;
+            // By its number or its name
+            let scene = match payload.parse::<u16>() {
+                Ok(scene) => Some(scene).filter(|&scene| scene < SCENE_COUNT),
+                Err(_) => names
+                    .iter()
+                    .position(|name| !name.is_empty() && name == payload)
+                    .map(|scene| scene as _),
+            };
+            (
+                SCENES_REGISTER,
+                vec![scene.ok_or_else(|| invalid(payload))? + 1],
+            )
+        }
+        ["permissive", relay] => {
+            let relay = index(relay, RELAY_COUNT)?;
+            if payload.len() > PERMISSIVE_SIZE || !payload.is_ascii() {
+                return Err(invalid(payload));
+            }
+            (
+                PERMISSIVES_REGISTER + relay * PERMISSIVE_SIZE as u16 / 2,
+                registers(payload.as_bytes(), PERMISSIVE_SIZE / 2),
+            )
+        }
+        ["power"] => match *payload.split_whitespace().collect::<Vec<_>>() {
+            [budget, policy] => (
+                POWER_REGISTER,
+                vec![number(budget)?, name(&POLICIES, policy)?],
+            ),
+            _ => return Err(invalid(payload)),
         },
-    )?;
-    spawn(subscriber(connection));
-    loop {
-        if let Err(error) = client.subscribe(MQTT_TOPIC_BLC, QoS::ExactlyOnce).await {
-            warn!(r#"Retry to subscribe to topic "{MQTT_TOPIC_BLC}": {error}"#);
-            sleep(RETRY).await;
-            continue;
-        }
-        info!(r#"Subscribed to topic "{MQTT_TOPIC_BLC}""#);
-        // Just to give a chance of our connection to get even the first published message.
-        sleep(Duration::from_secs(1)).await;
-        loop {
-            if let Err(error) = publisher(&mut client, &mut temperature_sender).await {
-                error!("{error}");
+        ["power", relay] => {
+            let relay = index(relay, RELAY_COUNT)?;
+            let values = numbers(payload)?;
+            if values.len() != POWER_SIZE as usize {
+                return Err(invalid(payload));
+            }
+            (POWER_REGISTER + 2 + relay * POWER_SIZE, values)
+        }
+        ["rules"] => {
+            // The text, then 1 in front of it activates it
+            if payload.len() > SOURCE_SIZE || !payload.is_ascii() {
+                return Err(invalid(payload));
             }
-            sleep(Duration::from_secs(1)).await;
+            let mut values = vec![1];
+            values.extend(registers(payload.as_bytes(), SOURCE_SIZE / 2));
+            (RULES_REGISTER, values)
+        }
+        ["sequence", sequence] => {
+            let sequence = index(sequence, SEQUENCE_COUNT)?;
+            let value = match payload {
+                "start" => 1,
+                "abort" => 0,
+                _ => return Err(invalid(payload)),
+            };
+            (SEQUENCE_COMMANDS_REGISTER + sequence, vec![value])
+        }
+        ["timed", relay] => {
+            let relay = index(relay, RELAY_COUNT)?;
+            let address = TIMED_REGISTER + relay * TIMED_SIZE;
+            // Without a state the one of the last command is kept
+            match *payload.split_whitespace().collect::<Vec<_>>() {
+                [seconds] => (address + 1, vec![number(seconds)?]),
+                [state, seconds] => (address, vec![name(&SWITCH, state)?, number(seconds)?]),
+                _ => return Err(invalid(payload)),
+            }
+        }
+        ["schedule", "timezone"] => {
+            let values = numbers::<i16>(payload)?;
+            if values.len() != TIMEZONE_REGISTER_COUNT as usize {
+                return Err(invalid(payload));
+            }
+            let values = values.into_iter().map(|value| value as _).collect();
+            (SCHEDULE_REGISTER + TIMEZONE_REGISTER, values)
+        }
+        ["schedule", "location"] => {
+            let values = numbers::<f32>(payload)?;
+            if values.len() != LOCATION_REGISTER_COUNT as usize {
+                return Err(invalid(payload));
+            }
+            let values = values
+                .into_iter()
+                .map(|degrees| (degrees * 100.0).round() as i16 as _)
+                .collect();
+            (SCHEDULE_REGISTER + LOCATION_REGISTER, values)
+        }
+        ["schedule", "entry", entry] => {
+            let entry = index(entry, ENTRY_COUNT as _)?;
+            (
+                SCHEDULE_REGISTER + ENTRIES_REGISTER + entry * ENTRY_SIZE,
+                schedule::entry(payload)?,
+            )
+        }
+        ["calendar", calendar] => {
+            let calendar = index(calendar, CALENDAR_COUNT as _)?;
+            (
+                SCHEDULE_REGISTER + CALENDARS_REGISTER + calendar * CALENDAR_SIZE,
+                schedule::calendar(payload)?,
+            )
+        }
+        ["calendar", calendar, "dates"] => {
+            let calendar = index(calendar, CALENDAR_COUNT as _)?;
+            let ranges = modbus::read_holding(
+                relay_sender,
+                SCHEDULE_REGISTER + RANGES_REGISTER,
+                RANGES_SIZE,
+            )
+            .await?;
+            (
+                SCHEDULE_REGISTER + RANGES_REGISTER,
+                schedule::dates(&ranges, calendar, payload)?,
+            )
+        }
+        ["schedule", relay] => {
+            let relay = index(relay, RELAY_COUNT)?;
+            (
+                SCHEDULE_REGISTER + WINDOWS_REGISTER + relay * WINDOWS_SIZE,
+                schedule::windows(payload)?,
+            )
         }
+        _ => {
+            error!("IllegalFunction {{ topic: {topic} }}");
+            return Err(ExceptionCode::IllegalFunction);
+        }
+    };
+    modbus::write_holding(relay_sender, client, address, &values).await?;
+    Ok(None)
+}
+
+/// The status topics and their payloads
+pub async fn status(
+    relay_sender: &Sender<RelayRequest>,
+) -> Result<Vec<(String, String)>, ExceptionCode> {
+    let read = |address, count| modbus::read_holding(relay_sender, address, count);
+    let relays = read(RELAYS_REGISTER, 1).await?;
+    let mut topics = vec![("relays".to_owned(), relays[0].to_string())];
+    let active = read(SCENES_REGISTER, 1).await?[0];
+    let names = scene_names(relay_sender).await?;
+    topics.push((
+        "scene".to_owned(),
+        match active.checked_sub(1) {
+            None => String::new(),
+            Some(scene) if names[scene as usize].is_empty() => scene.to_string(),
+            Some(scene) => names[scene as usize].clone(),
+        },
+    ));
+    let analog = modbus::read_input(
+        relay_sender,
+        ANALOG_INPUTS_REGISTER,
+        analog::COUNT * ANALOG_STATUS_SIZE,
+    )
+    .await?;
+    for (channel, status) in analog.chunks(ANALOG_STATUS_SIZE as _).enumerate() {
+        topics.push((
+            format!("analog/{channel}"),
+            format!(
+                "{} {} {} {}",
+                status[0],
+                status[1] as i16,
+                ALARMS.get(status[2] as usize).unwrap_or(&""),
+                ANALOG_STATES.get(status[3] as usize).unwrap_or(&"")
+            ),
+        ));
+    }
+    let energy = modbus::read_input(
+        relay_sender,
+        ENERGY_REGISTER,
+        RELAY_COUNT * PERIOD_COUNT as u16 * energy::ENTRY_SIZE,
+    )
+    .await?;
+    let (totals, _) = energy.as_chunks::<{ energy::ENTRY_SIZE as _ }>();
+    for (index, &[energy_high, energy_low, runtime_high, runtime_low]) in totals.iter().enumerate()
+    {
+        topics.push((
+            format!(
+                "energy/{}/{}",
+                index / PERIOD_COUNT,
+                PERIOD_NAMES[index % PERIOD_COUNT]
+            ),
+            format!(
+                "{} {}",
+                (energy_high as u32) << 16 | energy_low as u32,
+                (runtime_high as u32) << 16 | runtime_low as u32
+            ),
+        ));
     }
+    let statuses = modbus::read_input(
+        relay_sender,
+        PERMISSIVES_STATUS_REGISTER,
+        RELAY_COUNT * PERMISSIVE_STATUS_SIZE,
+    )
+    .await?;
+    for (relay, status) in (0..RELAY_COUNT).zip(statuses.chunks(PERMISSIVE_STATUS_SIZE as _)) {
+        let text = read(
+            PERMISSIVES_REGISTER + relay * PERMISSIVE_SIZE as u16 / 2,
+            PERMISSIVE_SIZE as u16 / 2,
+        )
+        .await?;
+        topics.push((format!("permissive/{relay}"), text_of(&text)));
+        topics.push((
+            format!("permissive/{relay}/status"),
+            format!(
+                "{} {} {}",
+                PERMISSIVE_REASONS.get(status[0] as usize).unwrap_or(&""),
+                PERMISSIVE_REASONS.get(status[1] as usize).unwrap_or(&""),
+                status[2]
+            ),
+        ));
+    }
+    let power = read(POWER_REGISTER, 2 + RELAY_COUNT * POWER_SIZE).await?;
+    topics.push((
+        "power".to_owned(),
+        format!(
+            "{} {}",
+            power[0],
+            POLICIES.get(power[1] as usize).unwrap_or(&"")
+        ),
+    ));
+    for (relay, load) in power[2..].chunks(POWER_SIZE as _).enumerate() {
+        topics.push((format!("power/{relay}"), join(load.iter())));
+    }
+    let status = modbus::read_input(relay_sender, POWER_STATUS_REGISTER, POWER_STATUS_SIZE).await?;
+    topics.push(("power/status".to_owned(), join(status.iter())));
+    let rules = read(RULES_REGISTER + 1, SOURCE_SIZE as u16 / 2).await?;
+    topics.push(("rules".to_owned(), text_of(&rules)));
+    let status = modbus::read_input(relay_sender, RULES_STATUS_REGISTER, RULES_STATUS_SIZE).await?;
+    topics.push((
+        "rules/status".to_owned(),
+        format!(
+            "{} {} {}",
+            RULE_STATES.get(status[0] as usize).unwrap_or(&""),
+            status[1],
+            status[2]
+        ),
+    ));
+    let sequences = modbus::read_input(
+        relay_sender,
+        SEQUENCE_STATUS_REGISTER,
+        SEQUENCE_COUNT * SEQUENCE_STATUS_SIZE,
+    )
+    .await?;
+    for (sequence, status) in sequences.chunks(SEQUENCE_STATUS_SIZE as _).enumerate() {
+        topics.push((
+            format!("sequence/{sequence}"),
+            format!(
+                "{} {} {}",
+                SEQUENCE_STATES.get(status[0] as usize).unwrap_or(&""),
+                status[1],
+                SEQUENCE_REASONS.get(status[2] as usize).unwrap_or(&"")
+            ),
+        ));
+    }
+    for relay in 0..RELAY_COUNT {
+        let timed = read(TIMED_REGISTER + relay * TIMED_SIZE, TIMED_SIZE).await?;
+        topics.push((
+            format!("timed/{relay}"),
+            format!("{} {}", SWITCH[timed[0] as usize], timed[1]),
+        ));
+    }
+    let timezone = read(
+        SCHEDULE_REGISTER + TIMEZONE_REGISTER,
+        TIMEZONE_REGISTER_COUNT,
+    )
+    .await?;
+    topics.push((
+        "schedule/timezone".to_owned(),
+        join(timezone.iter().map(|&value| value as i16)),
+    ));
+    let location = read(
+        SCHEDULE_REGISTER + LOCATION_REGISTER,
+        LOCATION_REGISTER_COUNT,
+    )
+    .await?;
+    topics.push((
+        "schedule/location".to_owned(),
+        join(
+            location
+                .iter()
+                .map(|&value| format!("{:.2}", value as i16 as f32 / 100.0)),
+        ),
+    ));
+    for relay in 0..RELAY_COUNT {
+        let windows = read(
+            SCHEDULE_REGISTER + WINDOWS_REGISTER + relay * WINDOWS_SIZE,
+            WINDOWS_SIZE,
+        )
+        .await?;
+        topics.push((
+            format!("schedule/{relay}"),
+            schedule::format_windows(&windows),
+        ));
+    }
+    for entry in 0..ENTRY_COUNT as u16 {
+        let values = read(
+            SCHEDULE_REGISTER + ENTRIES_REGISTER + entry * ENTRY_SIZE,
+            ENTRY_SIZE,
+        )
+        .await?;
+        topics.push((
+            format!("schedule/entry/{entry}"),
+            schedule::format_entry(&values),
+        ));
+    }
+    let ranges = read(SCHEDULE_REGISTER + RANGES_REGISTER, RANGES_SIZE).await?;
+    for calendar in 0..CALENDAR_COUNT as u16 {
+        let values = read(
+            SCHEDULE_REGISTER + CALENDARS_REGISTER + calendar * CALENDAR_SIZE,
+            CALENDAR_SIZE,
+        )
+        .await?;
+        topics.push((
+            format!("calendar/{calendar}"),
+            schedule::format_calendar(&values),
+        ));
+        topics.push((
+            format!("calendar/{calendar}/dates"),
+            schedule::format_dates(&ranges, calendar),
+        ));
+    }
+    Ok(topics)
 }
 
-// Subscriber
-pub(crate) async fn subscriber(mut connection: EspAsyncMqttConnection) {
-    info!("MQTT subscriber");
-    loop {
-        match connection.next().await {
-            Ok(event) => trace!("Subscribed: {}", event.payload()),
-            Err(error) => {
-                error!("{error}");
-                warn!("MQTT connection closed");
+/// The names of the scenes, empty for an unnamed one
+async fn scene_names(relay_sender: &Sender<RelayRequest>) -> Result<Vec<String>, ExceptionCode> {
+    let values = modbus::read_holding(
+        relay_sender,
+        SCENES_REGISTER + 1,
+        SCENE_COUNT * scene::ENTRY_SIZE,
+    )
+    .await?;
+    Ok(values
+        .chunks(scene::ENTRY_SIZE as _)
+        .map(|scene| text_of(&scene[3..]))
+        .collect())
+}
+
+/// Relay states by their register value, `off` or `on`
+const SWITCH: [&str; 2] = ["off", "on"];
+/// Analog alarms by their register value
+const ALARMS: [&str; 3] = ["normal", "low", "high"];
+/// Analog channel states by their register value
+const ANALOG_STATES: [&str; 3] = ["disabled", "ok", "failed"];
+/// Input registers of an analog channel: millivolts, value, alarm, status
+const ANALOG_STATUS_SIZE: u16 = 4;
+/// Why a relay can not be switched on by the register value
+const PERMISSIVE_REASONS: [&str; 3] = ["permitted", "false", "stale"];
+/// Input registers of the permissive status of a relay
+const PERMISSIVE_STATUS_SIZE: u16 = 3;
+/// Power budget policies by their register value
+const POLICIES: [&str; 2] = ["refuse", "shed"];
+/// Holding registers of the load of a relay: watts, priority
+const POWER_SIZE: u16 = 2;
+/// Input registers of the power status
+const POWER_STATUS_SIZE: u16 = 4;
+/// Rule states by their register value
+const RULE_STATES: [&str; 3] = ["none", "active", "error"];
+/// Input registers of the rule status
+const RULES_STATUS_SIZE: u16 = 3;
+/// Sequence states by their register value
+const SEQUENCE_STATES: [&str; 4] = ["idle", "running", "finished", "aborted"];
+/// Sequence abort reasons by their register value
+const SEQUENCE_REASONS: [&str; 4] = ["none", "command", "timeout", "relay"];
+/// Input registers of the status of a sequence
+const SEQUENCE_STATUS_SIZE: u16 = 3;
+/// Registers of the timed command of a relay
+const TIMED_SIZE: u16 = 2;
+/// Registers of the windows of a relay
+const WINDOWS_SIZE: u16 = WINDOW_COUNT as u16 * WINDOW_SIZE;
+/// Registers of the date ranges of all calendars
+const RANGES_SIZE: u16 = RANGE_COUNT as u16 * RANGE_SIZE;
+
+/// The payload as trimmed UTF-8 text
+fn text(payload: &[u8]) -> Result<&str, ExceptionCode> {
+    str::from_utf8(payload).map(str::trim).map_err(|error| {
+        error!("IllegalValue {{ payload: {error} }}");
+        ExceptionCode::IllegalDataValue
+    })
+}
+
+/// ASCII text in registers, two characters each and padded with NUL
+fn registers(text: &[u8], count: usize) -> Vec<u16> {
+    let mut values: Vec<_> = text
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]))
+        .collect();
+    values.resize(count, 0);
+    values
+}
+
+/// The text in registers up to the NUL padding
+fn text_of(values: &[u16]) -> String {
+    let bytes: Vec<_> = values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect();
+    let length = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..length]).into_owned()
+}
+
+/// A decimal number
+fn number<T: FromStr>(text: &str) -> Result<T, ExceptionCode> {
+    text.parse().map_err(|_| invalid(text))
+}
+
+/// The register value of a name in the list
+fn name(names: &[&str], text: &str) -> Result<u16, ExceptionCode> {
+    names
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(text))
+        .map(|value| value as _)
+        .ok_or_else(|| invalid(text))
+}
+
+/// Decimal numbers separated by commas or whitespace
+fn numbers<T: FromStr>(text: &str) -> Result<Vec<T>, ExceptionCode> {
+    text.split([',', ' ', '\t', '\n'])
+        .filter(|number| !number.is_empty())
+        .map(number)
+        .collect()
+}
+
+/// Values separated by a space
+fn join<T: Display>(values: impl Iterator<Item = T>) -> String {
+    values
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The index in a topic, below `count`
+fn index(text: &str, count: u16) -> Result<u16, ExceptionCode> {
+    text.parse()
+        .ok()
+        .filter(|&index| index < count)
+        .ok_or_else(|| {
+            error!("IllegalFunction {{ index: {text} }}");
+            ExceptionCode::IllegalFunction
+        })
+}
+
+fn invalid(text: &str) -> ExceptionCode {
+    error!("IllegalValue {{ text: {text:?} }}");
+    ExceptionCode::IllegalDataValue
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::client::start;
+
+/// The MQTT client
+#[cfg(target_os = "espidf")]
+mod client {
+    use super::{PREFIX, command, status};
+    use crate::relay::Request as RelayRequest;
+    use esp_idf_svc::{
+        mqtt::client::{
+            Details, EspAsyncMqttClient, EspAsyncMqttConnection, EventPayload,
+            MqttClientConfiguration, QoS,
+        },
+        sys::EspError,
+    };
+    use log::{error, info, trace, warn};
+    use std::{
+        collections::BTreeMap,
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    };
+    use tokio::{
+        select, spawn,
+        sync::mpsc::{self, Sender},
+        time::{Duration, interval},
+    };
+
+    const MQTT_URL: &str = "mqtt://192.168.0.87:1883";
+    const MQTT_CLIENT_ID: &str = "digital_relay_controller";
+    const MQTT_USERNAME: Option<&str> = option_env!("MQTT_USERNAME");
+    const MQTT_PASSWORD: Option<&str> = option_env!("MQTT_PASSWORD");
+    /// Large enough for the rule text and a calendar in one message
+    const MQTT_BUFFER_SIZE: usize = 2048;
+
+    /// Command topics, one to three levels under the prefix
+    const MQTT_TOPICS: [&str; 3] = ["+/set", "+/+/set", "+/+/+/set"];
+
+    /// The client address of MQTT commands, for the locks and selections
+    const CLIENT: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1883));
+
+    /// How often the status topics are checked for changes
+    const PERIOD: Duration = Duration::from_secs(1);
+
+    enum Event {
+        /// A new session, with nothing subscribed or retained from before
+        Connected,
+        Received(String, Vec<u8>),
+    }
+
+    /// Start the MQTT client
+    pub fn start(relay_sender: Sender<RelayRequest>) -> Result<(), EspError> {
+        info!("Initialize MQTT");
+        let (client, connection) = EspAsyncMqttClient::new(
+            MQTT_URL,
+            &MqttClientConfiguration {
+                client_id: Some(MQTT_CLIENT_ID),
+                username: MQTT_USERNAME,
+                password: MQTT_PASSWORD,
+                buffer_size: MQTT_BUFFER_SIZE,
+                ..Default::default()
+            },
+        )?;
+        let (sender, receiver) = mpsc::channel(8);
+        spawn(subscriber(connection, sender));
+        spawn(publisher(client, receiver, relay_sender));
+        Ok(())
+    }
+
+    // Subscriber
+    async fn subscriber(mut connection: EspAsyncMqttConnection, sender: Sender<Event>) {
+        info!("MQTT subscriber");
+        loop {
+            let event = match connection.next().await {
+                Ok(event) => match event.payload() {
+                    EventPayload::Connected(_) => Event::Connected,
+                    EventPayload::Received {
+                        topic: Some(topic),
+                        data,
+                        details: Details::Complete,
+                        ..
+                    } => Event::Received(topic.to_owned(), data.to_vec()),
+                    payload => {
+                        trace!("MQTT event: {payload:?}");
+                        continue;
+                    }
+                },
+                Err(error) => {
+                    error!("{error}");
+                    warn!("MQTT connection closed");
+                    return;
+                }
+            };
+            if sender.send(event).await.is_err() {
+                return;
             }
         }
     }
-}
 
-// Publisher
-pub(crate) async fn publisher(
-    client: &mut EspAsyncMqttClient,
-    temperature_sender: &mut Sender<TemperatureRequest>,
-) -> Result<()> {
-    info!("MQTT publisher");
-    loop {
-        let (sender, receiver) = oneshot::channel();
-        temperature_sender.send((0..2, sender)).await?;
-        let temperatures = receiver.await??;
-        let serialized = ron::to_string(&temperatures)?;
-        if let Err(error) = client
-            .publish(
-                MQTT_TOPIC_TEMPERATURE,
-                QoS::ExactlyOnce,
-                false,
-                serialized.as_bytes(),
-            )
+    // Publisher
+    async fn publisher(
+        mut client: EspAsyncMqttClient,
+        mut receiver: mpsc::Receiver<Event>,
+        relay_sender: Sender<RelayRequest>,
+    ) {
+        info!("MQTT publisher");
+        let mut published = BTreeMap::new();
+        let mut interval = interval(PERIOD);
+        loop {
+            select! {
+                event = receiver.recv() => match event {
+                    Some(Event::Connected) => {
+                        published.clear();
+                        for topic in MQTT_TOPICS {
+                            let topic = format!("{PREFIX}/{topic}");
+                            match client.subscribe(&topic, QoS::AtLeastOnce).await {
+                                Ok(_) => info!(r#"Subscribed to topic "{topic}""#),
+                                Err(error) => warn!(r#"Subscribe to topic "{topic}": {error}"#),
+                            }
+                        }
+                    }
+                    Some(Event::Received(topic, payload)) => {
+                        let Some(topic) = topic
+                            .strip_prefix(PREFIX)
+                            .and_then(|topic| topic.strip_prefix('/'))
+                            .and_then(|topic| topic.strip_suffix("/set"))
+                        else {
+                            continue;
+                        };
+                        let (topic, reply) =
+                            match command(&relay_sender, CLIENT, topic, &payload).await {
+                                Ok(Some(reply)) => (topic, reply),
+                                Ok(None) => continue,
+                                Err(exception) => ("error", format!("{topic}: {exception:?}")),
+                            };
+                        publish(&mut client, topic, false, &reply).await;
+                    }
+                    None => return,
+                },
+                _ = interval.tick() => match status(&relay_sender).await {
+                    Ok(topics) => {
+                        for (topic, payload) in topics {
+                            if published.get(&topic) != Some(&payload)
+                                && publish(&mut client, &topic, true, &payload).await
+                            {
+                                published.insert(topic, payload);
+                            }
+                        }
+                    }
+                    Err(exception) => error!("MQTT status: {exception:?}"),
+                },
+            }
+        }
+    }
+
+    async fn publish(
+        client: &mut EspAsyncMqttClient,
+        topic: &str,
+        retain: bool,
+        payload: &str,
+    ) -> bool {
+        let topic = format!("{PREFIX}/{topic}");
+        match client
+            .publish(&topic, QoS::AtLeastOnce, retain, payload.as_bytes())
             .await
         {
-            error!("MQTT publish {error:?}");
+            Ok(_) => true,
+            Err(error) => {
+                error!(r#"MQTT publish "{topic}": {error:?}"#);
+                false
+            }
         }
-        sleep(Duration::from_secs(1)).await;
     }
 }
+
+mod schedule;
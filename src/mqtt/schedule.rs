@@ -0,0 +1,331 @@
+//! Schedule topics: the register image of the windows and entries as text
+
+use super::{invalid, number};
+use crate::schedule::{ENTRY_SIZE, RANGE_SIZE, WINDOW_COUNT, WINDOW_SIZE};
+use std::str;
+use tokio_modbus::prelude::*;
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+/// By the trigger register value
+const TRIGGERS: [&str; 4] = ["none", "cron", "once", "solar"];
+/// By the action register value
+const ACTIONS: [&str; 4] = ["off", "on", "toggle", "pulse"];
+/// By the calendar mode register value
+const MODES: [&str; 3] = ["none", "suppress", "weekday"];
+/// The registers of an unused date range
+const UNUSED: [u16; RANGE_SIZE as _] = [0, 0, 0x0101, 0x0101];
+
+/// The window registers of a relay from lines like `mon-fri 07:30-18:00`,
+/// the windows not given are disabled
+pub(super) fn windows(text: &str) -> Result<Vec<u16>, ExceptionCode> {
+    let mut values = Vec::new();
+    for line in text.split(['\n', ';']).map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        let Some((days, times)) = line.split_once(char::is_whitespace) else {
+            return Err(invalid(line));
+        };
+        let Some((on, off)) = times.trim().split_once('-') else {
+            return Err(invalid(line));
+        };
+        values.extend([self::days(days)? as _, minute(on)?, minute(off)?]);
+    }
+    if values.len() > WINDOW_COUNT * WINDOW_SIZE as usize {
+        return Err(invalid(text));
+    }
+    values.resize(WINDOW_COUNT * WINDOW_SIZE as usize, 0);
+    Ok(values)
+}
+
+/// The enabled windows of a relay, one per line
+pub(super) fn format_windows(values: &[u16]) -> String {
+    let (windows, _) = values.as_chunks::<{ WINDOW_SIZE as _ }>();
+    windows
+        .iter()
+        .filter(|[days, ..]| *days != 0)
+        .map(|&[days, on, off]| {
+            format!(
+                "{} {}-{}",
+                format_days(days as _),
+                format_minute(on),
+                format_minute(off)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The registers of an entry from text like `cron 0 pulse 30000 */15 6-17 * *
+/// 1-5`, `solar 1 on sunset+15` or `once 0 off 1767225600`: trigger, relay,
+/// action with the pulse milliseconds, then the expression or the UTC time;
+/// empty text disables the entry
+pub(super) fn entry(text: &str) -> Result<Vec<u16>, ExceptionCode> {
+    let mut values = vec![0; ENTRY_SIZE as _];
+    let mut words = text.split_whitespace();
+    let Some(trigger) = words.next() else {
+        return Ok(values);
+    };
+    let trigger = index(&TRIGGERS, trigger)?;
+    let relay = number(words.next().unwrap_or_default())?;
+    let action = index(&ACTIONS, words.next().unwrap_or_default())?;
+    let pulse: u32 = match action {
+        3 => number(words.next().unwrap_or_default())?,
+        _ => 0,
+    };
+    let rest = words.collect::<Vec<_>>().join(" ");
+    let time: u32 = match trigger {
+        2 => number(&rest)?,
+        _ => 0,
+    };
+    values[..7].copy_from_slice(&[
+        trigger,
+        relay,
+        action,
+        (pulse >> 16) as _,
+        pulse as _,
+        (time >> 16) as _,
+        time as _,
+    ]);
+    if trigger != 2 {
+        let expression = values[8..].as_mut();
+        if rest.len() > expression.len() * 2 || !rest.is_ascii() {
+            return Err(invalid(&rest));
+        }
+        for (value, chunk) in expression.iter_mut().zip(rest.as_bytes().chunks(2)) {
+            *value = u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]);
+        }
+    }
+    Ok(values)
+}
+
+/// An entry as text, empty for a disabled one
+pub(super) fn format_entry(values: &[u16]) -> String {
+    let &[
+        trigger,
+        relay,
+        action,
+        pulse_high,
+        pulse_low,
+        time_high,
+        time_low,
+        _,
+        ..,
+    ] = values
+    else {
+        return String::new();
+    };
+    let (Some(name), Some(action_name)) = (
+        TRIGGERS.get(trigger as usize).filter(|_| trigger != 0),
+        ACTIONS.get(action as usize),
+    ) else {
+        return String::new();
+    };
+    let mut text = format!("{name} {relay} {action_name}");
+    if action == 3 {
+        text += &format!(" {}", (pulse_high as u32) << 16 | pulse_low as u32);
+    }
+    if trigger == 2 {
+        text += &format!(" {}", (time_high as u32) << 16 | time_low as u32);
+    } else {
+        let bytes: Vec<_> = values[8..]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        text += " ";
+        text += str::from_utf8(&bytes)
+            .unwrap_or_default()
+            .trim_end_matches('\0');
+    }
+    text
+}
+
+/// The registers of a calendar from text like `suppress 3` or `weekday sun
+/// 1`: mode, the weekday for the weekday mode and the relay bitmask
+pub(super) fn calendar(text: &str) -> Result<Vec<u16>, ExceptionCode> {
+    let mut words = text.split_whitespace();
+    let mode = index(&MODES, words.next().unwrap_or("none"))?;
+    let weekday = match mode {
+        2 => index(&WEEKDAYS, words.next().unwrap_or_default())?,
+        _ => 0,
+    };
+    let relays = match mode {
+        0 => 0,
+        _ => number(words.next().unwrap_or_default())?,
+    };
+    if words.next().is_some() {
+        return Err(invalid(text));
+    }
+    Ok(vec![mode, weekday, relays])
+}
+
+/// A calendar as text
+pub(super) fn format_calendar(values: &[u16]) -> String {
+    let &[mode, weekday, relays] = values else {
+        return String::new();
+    };
+    match mode {
+        1 => format!("suppress {relays}"),
+        2 => format!(
+            "weekday {} {relays}",
+            WEEKDAYS.get(weekday as usize).unwrap_or(&"")
+        ),
+        _ => "none".to_owned(),
+    }
+}
+
+/// The date range registers with the ones of the calendar replaced by a list
+/// of dates and ranges like `2026-05-01 2026-12-24..2027-01-03 12-25`, a date
+/// without a year is one of every year
+///
+/// The other calendars keep their ranges, the new ones go into the unused
+/// ones.
+pub(super) fn dates(ranges: &[u16], calendar: u16, text: &str) -> Result<Vec<u16>, ExceptionCode> {
+    let mut values = ranges.to_vec();
+    let (ranges, _) = values.as_chunks_mut::<{ RANGE_SIZE as _ }>();
+    for range in ranges.iter_mut().filter(|range| range[0] == calendar + 1) {
+        *range = UNUSED;
+    }
+    let mut unused = ranges.iter_mut().filter(|range| range[0] == 0);
+    for item in text
+        .split([',', ' ', '\t', '\n'])
+        .filter(|item| !item.is_empty())
+    {
+        let (start, end) = item.split_once("..").unwrap_or((item, item));
+        let ((year, start), (end_year, end)) = (date(start)?, date(end)?);
+        // A range runs into the next year at most
+        let valid = match (year, end_year) {
+            (0, 0) => true,
+            (year, end_year) if year == end_year => start <= end,
+            (year, end_year) => year != 0 && end_year == year + 1 && end < start,
+        };
+        if !valid {
+            return Err(invalid(item));
+        }
+        let Some(range) = unused.next() else {
+            return Err(invalid(text));
+        };
+        *range = [
+            calendar + 1,
+            year,
+            u16::from_be_bytes([start.0, start.1]),
+            u16::from_be_bytes([end.0, end.1]),
+        ];
+    }
+    Ok(values)
+}
+
+/// The dates and ranges of a calendar
+pub(super) fn format_dates(ranges: &[u16], calendar: u16) -> String {
+    let (ranges, _) = ranges.as_chunks::<{ RANGE_SIZE as _ }>();
+    ranges
+        .iter()
+        .filter(|range| range[0] == calendar + 1)
+        .map(|&[_, year, start, end]| {
+            let [start_month, start_day] = start.to_be_bytes();
+            let [end_month, end_day] = end.to_be_bytes();
+            let end_year = match year {
+                0 => 0,
+                year if end < start => year + 1,
+                year => year,
+            };
+            let date = format_date(year, start_month, start_day);
+            match start == end {
+                true => date,
+                false => format!("{date}..{}", format_date(end_year, end_month, end_day)),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Year (0 for none), month and day of `YYYY-MM-DD` or `MM-DD`
+fn date(text: &str) -> Result<(u16, (u8, u8)), ExceptionCode> {
+    let fields: Vec<_> = text.split('-').collect();
+    let (year, month, day) = match fields[..] {
+        [year, month, day] => (number(year)?, month, day),
+        [month, day] => (0, month, day),
+        _ => return Err(invalid(text)),
+    };
+    let (month, day): (u8, u8) = (number(month)?, number(day)?);
+    if !(year == 0 || (1970..10000).contains(&year))
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+    {
+        return Err(invalid(text));
+    }
+    Ok((year, (month, day)))
+}
+
+fn format_date(year: u16, month: u8, day: u8) -> String {
+    match year {
+        0 => format!("{month:02}-{day:02}"),
+        year => format!("{year}-{month:02}-{day:02}"),
+    }
+}
+
+/// Weekdays like `mon-fri,sun` as a bitmask, bit 0 is Monday
+fn days(text: &str) -> Result<u8, ExceptionCode> {
+    let mut days = 0;
+    for item in text.split(',') {
+        let (first, last) = item.split_once('-').unwrap_or((item, item));
+        let (first, last) = (index(&WEEKDAYS, first)?, index(&WEEKDAYS, last)?);
+        // A range may wrap around the end of the week, like `sat-mon`
+        let mut day = first;
+        loop {
+            days |= 1 << day;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+/// The weekdays of a bitmask, runs of days as ranges
+fn format_days(days: u8) -> String {
+    let mut items = Vec::new();
+    let mut day = 0;
+    while day < 7 {
+        if days & (1 << day) == 0 {
+            day += 1;
+            continue;
+        }
+        let first = day;
+        while day + 1 < 7 && days & (1 << (day + 1)) != 0 {
+            day += 1;
+        }
+        items.push(match day - first {
+            0 => WEEKDAYS[first].to_owned(),
+            _ => format!("{}-{}", WEEKDAYS[first], WEEKDAYS[day]),
+        });
+        day += 1;
+    }
+    items.join(",")
+}
+
+/// Minutes since midnight of `HH:MM`
+fn minute(text: &str) -> Result<u16, ExceptionCode> {
+    let Some((hour, minute)) = text.trim().split_once(':') else {
+        return Err(invalid(text));
+    };
+    let (hour, minute): (u16, u16) = (number(hour)?, number(minute)?);
+    if hour > 23 || minute > 59 {
+        return Err(invalid(text));
+    }
+    Ok(hour * 60 + minute)
+}
+
+fn format_minute(minute: u16) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+/// The position of the name in the list
+fn index(names: &[&str], name: &str) -> Result<u16, ExceptionCode> {
+    names
+        .iter()
+        .position(|candidate| candidate.eq_ignore_ascii_case(name))
+        .map(|index| index as _)
+        .ok_or_else(|| invalid(name))
+}
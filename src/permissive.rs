@@ -0,0 +1,294 @@
+//! Permissives: conditions that have to hold before a relay can be switched on
+//!
+//! A permissive is a condition in the rule language over the other relays,
+//! the inputs, the timers and the time, like `relay[0] AND input[1]`. A relay
+//! whose permissive does not hold is kept off and switched off once it
+//! drops, which takes the relays that depend on it along. A permissive that
+//! depends on an unknown value, like a stale input, does not hold.
+
+use crate::{
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+    rule::{Values, compile::condition, program::Program},
+    storage,
+};
+use log::{error, info, warn};
+use std::sync::{Mutex, RwLock};
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, sleep},
+};
+use tokio_modbus::prelude::*;
+
+/// How often the permissives are checked against the inputs
+const PERIOD: Duration = Duration::from_millis(100);
+
+const STORAGE_KEY: &str = "permissives";
+
+/// Condition length, ASCII padded with NUL
+pub(crate) const TEXT_SIZE: usize = 64;
+/// Holding registers per relay: the condition
+const ENTRY_SIZE: u16 = TEXT_SIZE as u16 / 2;
+pub(crate) const REGISTER_COUNT: u16 = RELAY_COUNT * ENTRY_SIZE;
+/// Input registers per relay: the reason now, the reason of the last blocked
+/// command and the count of blocked commands
+const STATUS_SIZE: u16 = 3;
+pub(crate) const STATUS_REGISTER_COUNT: u16 = RELAY_COUNT * STATUS_SIZE;
+
+static PERMISSIVES: RwLock<[Permissive; RELAY_COUNT as _]> =
+    RwLock::new([Permissive::NONE; RELAY_COUNT as _]);
+/// The reason of the last blocked command and the count per relay
+static BLOCKED: Mutex<[(Reason, u16); RELAY_COUNT as _]> =
+    Mutex::new([(Reason::Permitted, 0); RELAY_COUNT as _]);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Permissive {
+    text: [u8; TEXT_SIZE],
+    /// The compiled text, `None` if it is empty
+    condition: Option<Program>,
+}
+
+impl Permissive {
+    const NONE: Self = Self {
+        text: [0; TEXT_SIZE],
+        condition: None,
+    };
+
+    fn reason(&self, values: &Values) -> Reason {
+        match self
+            .condition
+            .as_ref()
+            .map(|condition| condition.test(values))
+        {
+            None | Some(Some(true)) => Reason::Permitted,
+            Some(Some(false)) => Reason::False,
+            Some(None) => Reason::Unknown,
+        }
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.text)
+            .trim_end_matches('\0')
+            .to_owned()
+    }
+}
+
+/// Why a relay can not be switched on
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Reason {
+    /// It can, or it has no permissive
+    Permitted = 0,
+    /// The condition is false
+    False = 1,
+    /// The condition depends on an unknown value
+    Unknown = 2,
+}
+
+/// Start checking the permissives, the ones stored in NVS first
+pub fn start(relay_sender: Sender<RelayRequest>) {
+    match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => {
+            let (values, _) = bytes.as_chunks();
+            let values: Vec<_> = values
+                .iter()
+                .map(|&value| u16::from_be_bytes(value))
+                .collect();
+            let mut permissives = PERMISSIVES.write().unwrap();
+            match self::permissives(&permissives, 0, &values) {
+                Ok(updated) => *permissives = updated,
+                Err(exception) => warn!("Stored permissives ignored: {exception:?}"),
+            }
+        }
+        Ok(None) => {}
+        Err(error) => error!("{error:?}"),
+    }
+    spawn(async move {
+        loop {
+            sleep(PERIOD).await;
+            let active = PERMISSIVES
+                .read()
+                .unwrap()
+                .iter()
+                .any(|permissive| permissive.condition.is_some());
+            if !active {
+                continue;
+            }
+            // The relay task switches off the relays whose permissive dropped
+            let (sender, receiver) = oneshot::channel();
+            if relay_sender.send((Mask::READ, sender)).await.is_err() || receiver.await.is_err() {
+                error!("Relay task stopped");
+                return;
+            }
+        }
+    });
+}
+
+/// Remove the permissives and forget the blocked commands
+///
+/// Host builds only, for the fuzz target and the tests.
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn reset() {
+    *PERMISSIVES.write().unwrap() = [Permissive::NONE; RELAY_COUNT as _];
+    *BLOCKED.lock().unwrap() = [(Reason::Permitted, 0); RELAY_COUNT as _];
+}
+
+/// The relay bitmask with the relays whose permissive does not hold switched
+/// off, until the permissives of the remaining ones hold
+pub(crate) fn permit(state: u16) -> u16 {
+    hold(&*PERMISSIVES.read().unwrap(), state).0
+}
+
+/// The permitted relay bitmask and the reasons of the relays held off
+fn hold(permissives: &[Permissive], state: u16) -> (u16, [Reason; RELAY_COUNT as _]) {
+    let mut state = state;
+    let mut reasons = [Reason::Permitted; RELAY_COUNT as _];
+    // Each round switches off at least one relay or ends
+    loop {
+        let values = Values::new(state);
+        let mut held = 0;
+        for (relay, permissive) in permissives.iter().enumerate() {
+            if state & (1 << relay) == 0 {
+                continue;
+            }
+            let reason = permissive.reason(&values);
+            if reason != Reason::Permitted {
+                reasons[relay] = reason;
+                held |= 1 << relay;
+            }
+        }
+        if held == 0 {
+            return (state, reasons);
+        }
+        state &= !held;
+    }
+}
+
+/// Check that the relays the mask switches on can be switched on, `state` is
+/// the relay bitmask
+///
+/// A blocked command is rejected with `IllegalDataValue` and its reason is
+/// recorded in the status registers of the relays.
+pub(crate) fn check(mask: Mask, state: u16) -> Result<(), ExceptionCode> {
+    let requested = mask.apply(state);
+    let permissives = PERMISSIVES.read().unwrap();
+    let (permitted, reasons) = hold(&*permissives, requested);
+    let blocked = requested & mask.or & !mask.and & !permitted;
+    if blocked == 0 {
+        return Ok(());
+    }
+    let mut statuses = BLOCKED.lock().unwrap();
+    for relay in (0..RELAY_COUNT as usize).filter(|relay| blocked & (1 << relay) != 0) {
+        let (reason, count) = &mut statuses[relay];
+        *reason = reasons[relay];
+        *count = count.saturating_add(1);
+        warn!(
+            "Relay {relay} blocked by its permissive {:?}: {:?}",
+            permissives[relay].text(),
+            reasons[relay]
+        );
+    }
+    Err(ExceptionCode::IllegalDataValue)
+}
+
+/// Read the permissive holding registers, the condition text per relay
+pub(crate) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    registers(&*PERMISSIVES.read().unwrap())[address as usize..][..count as usize].to_vec()
+}
+
+/// Write the permissive holding registers
+///
+/// A condition is compiled as written, so it has to be written in one
+/// request; an empty one removes the permissive. The conditions are validated
+/// before any of them is applied and stored in NVS.
+pub(crate) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut permissives = PERMISSIVES.write().unwrap();
+    let updated = self::permissives(&permissives, address, values)?;
+    if updated != *permissives {
+        let bytes: Vec<_> = registers(&updated)
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        storage::save(STORAGE_KEY, &bytes).map_err(|error| {
+            error!("{error:?}");
+            ExceptionCode::ServerDeviceFailure
+        })?;
+        for (relay, (permissive, previous)) in updated.iter().zip(permissives.iter()).enumerate() {
+            if permissive != previous {
+                match permissive.condition {
+                    Some(_) => info!("Relay {relay} permissive {:?}", permissive.text()),
+                    None => info!("Relay {relay} permissive removed"),
+                }
+            }
+        }
+    }
+    *permissives = updated;
+    Ok(())
+}
+
+/// Read the status input registers, per relay: the reason it can not be
+/// switched on now, the reason of the last blocked command and the count of
+/// blocked commands
+///
+/// Reasons are `0` permitted, `1` the condition is false and `2` it depends on
+/// an unknown value. `state` is the relay bitmask.
+pub(crate) fn read_status(address: u16, count: u16, state: u16) -> Vec<u16> {
+    let permissives = PERMISSIVES.read().unwrap();
+    let values = Values::new(state);
+    let statuses = BLOCKED.lock().unwrap();
+    let values: Vec<_> = permissives
+        .iter()
+        .zip(statuses.iter())
+        .flat_map(|(permissive, &(reason, count))| {
+            [permissive.reason(&values) as u16, reason as u16, count]
+        })
+        .collect();
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// The register image of the permissives
+fn registers(permissives: &[Permissive]) -> Vec<u16> {
+    permissives
+        .iter()
+        .flat_map(|permissive| {
+            let (text, _) = permissive.text.as_chunks();
+            text.iter().map(|&chunk| u16::from_be_bytes(chunk))
+        })
+        .collect()
+}
+
+/// The permissives with the registers written, the conditions of the relays
+/// written to are compiled again
+fn permissives(
+    permissives: &[Permissive; RELAY_COUNT as _],
+    address: u16,
+    values: &[u16],
+) -> Result<[Permissive; RELAY_COUNT as _], ExceptionCode> {
+    let mut updated = permissives.clone();
+    for (address, &value) in (address..).zip(values) {
+        let index = (address % ENTRY_SIZE) as usize * 2;
+        updated[(address / ENTRY_SIZE) as usize].text[index..index + 2]
+            .copy_from_slice(&value.to_be_bytes());
+    }
+    let first = address / ENTRY_SIZE;
+    let last = (address + values.len() as u16 - 1) / ENTRY_SIZE;
+    for relay in first..=last {
+        let permissive = &mut updated[relay as usize];
+        let text = permissive
+            .text
+            .split(|&byte| byte == 0)
+            .next()
+            .unwrap_or_default();
+        let Ok(text) = str::from_utf8(text) else {
+            return Err(ExceptionCode::IllegalDataValue);
+        };
+        permissive.condition = if text.trim().is_empty() {
+            None
+        } else {
+            Some(condition(text).map_err(|error| {
+                warn!("Relay {relay} permissive {text:?} rejected: {error:#}");
+                ExceptionCode::IllegalDataValue
+            })?)
+        };
+    }
+    Ok(updated)
+}
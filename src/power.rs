@@ -0,0 +1,332 @@
+//! Power budget: relay loads with a wattage and priority under a total budget
+//!
+//! A relay that would take the relays over the budget is refused, or sheds
+//! running relays with a lower priority to make room, depending on the
+//! policy. Shed relays are switched back on, the most important first, once
+//! they fit again.
+
+use crate::{
+    permissive,
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+    storage,
+};
+use log::{error, info, warn};
+use std::{
+    cmp::Reverse,
+    sync::{Mutex, RwLock},
+};
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, sleep},
+};
+use tokio_modbus::prelude::*;
+
+/// How often shed relays are checked for room
+const PERIOD: Duration = Duration::from_secs(1);
+
+const STORAGE_KEY: &str = "power";
+
+/// Holding registers per relay: watts, priority
+const ENTRY_SIZE: u16 = 2;
+/// Holding registers: budget, policy, then the relays
+pub(crate) const REGISTER_COUNT: u16 = 2 + RELAY_COUNT * ENTRY_SIZE;
+/// Input registers: usage, budget, shed relays, refused switch-ons
+pub(crate) const STATUS_REGISTER_COUNT: u16 = 4;
+
+static SETTINGS: RwLock<Settings> = RwLock::new(Settings::NONE);
+static STATUS: Mutex<Status> = Mutex::new(Status {
+    shed: 0,
+    refused: 0,
+});
+
+/// What happens to a relay that does not fit in the budget
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Policy {
+    /// It is not switched on
+    Refuse = 0,
+    /// Running relays with a lower priority are switched off to make room
+    Shed = 1,
+}
+
+impl TryFrom<u16> for Policy {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Refuse),
+            1 => Ok(Self::Shed),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Load {
+    pub watts: u16,
+    /// A relay sheds the ones with a lower priority
+    pub priority: u16,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Settings {
+    /// Watts, `0` for no budget
+    pub budget: u16,
+    pub policy: Policy,
+    pub relays: [Load; RELAY_COUNT as _],
+}
+
+impl Settings {
+    pub const NONE: Self = Self {
+        budget: 0,
+        policy: Policy::Refuse,
+        relays: [Load {
+            watts: 0,
+            priority: 0,
+        }; RELAY_COUNT as _],
+    };
+
+    /// Watts of the relays that are on
+    fn usage(&self, state: u16) -> u32 {
+        self.relays
+            .iter()
+            .enumerate()
+            .filter(|(relay, _)| state & (1 << relay) != 0)
+            .map(|(_, load)| load.watts as u32)
+            .sum()
+    }
+
+    fn fits(&self, state: u16) -> bool {
+        self.budget == 0 || self.usage(state) <= self.budget as u32
+    }
+
+    /// The relays in the bitmask, the most important first
+    fn by_priority(&self, relays: u16) -> Vec<usize> {
+        let mut relays: Vec<_> = (0..RELAY_COUNT as usize)
+            .filter(|relay| relays & (1 << relay) != 0)
+            .collect();
+        relays.sort_by_key(|&relay| Reverse(self.relays[relay].priority));
+        relays
+    }
+
+    /// The relay bitmask within the budget for the relays switched on since
+    /// `previous`, the shed relays after that and the refused ones
+    pub fn plan(&self, shed: u16, previous: u16, requested: u16) -> (u16, u16, u16) {
+        let mut state = requested & previous;
+        let mut shed = shed;
+        let mut refused = 0;
+        for relay in self.by_priority(requested & !previous) {
+            let mut candidate = state | 1 << relay;
+            if self.policy == Policy::Shed {
+                // The least important first
+                for victim in self.by_priority(state).into_iter().rev() {
+                    if self.fits(candidate)
+                        || self.relays[victim].priority >= self.relays[relay].priority
+                    {
+                        break;
+                    }
+                    candidate &= !(1 << victim);
+                }
+            }
+            if self.fits(candidate) {
+                shed |= state & !candidate;
+                state = candidate;
+            } else {
+                refused |= 1 << relay;
+            }
+        }
+        (state, shed, refused)
+    }
+
+    /// The shed relays that fit in the budget on top of `state`, the most
+    /// important first, if `permit` keeps them on
+    ///
+    /// `permit` gives the relay bitmask the permissives allow.
+    pub fn restore(&self, shed: u16, state: u16, permit: impl Fn(u16) -> u16) -> u16 {
+        let mut restored = 0;
+        for relay in self.by_priority(shed) {
+            let candidate = state | restored | 1 << relay;
+            if self.fits(candidate) && permit(candidate) & (1 << relay) != 0 {
+                restored |= 1 << relay;
+            }
+        }
+        restored
+    }
+}
+
+/// Bookkeeping of the shed relays and refused switch-ons
+struct Status {
+    shed: u16,
+    refused: u16,
+}
+
+/// Start restoring shed relays, with the settings stored in NVS
+pub fn start(relay_sender: Sender<RelayRequest>) {
+    match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => {
+            let (values, _) = bytes.as_chunks();
+            let values: Vec<_> = values
+                .iter()
+                .map(|&value| u16::from_be_bytes(value))
+                .collect();
+            let mut settings = SETTINGS.write().unwrap();
+            match self::settings(&settings, 0, &values) {
+                Ok(updated) => *settings = updated,
+                Err(exception) => warn!("Stored power settings ignored: {exception:?}"),
+            }
+        }
+        Ok(None) => {}
+        Err(error) => error!("{error:?}"),
+    }
+    spawn(async move {
+        loop {
+            sleep(PERIOD).await;
+            if STATUS.lock().unwrap().shed == 0 {
+                continue;
+            }
+            // The relay task restores the shed relays that fit
+            let (sender, receiver) = oneshot::channel();
+            if relay_sender.send((Mask::READ, sender)).await.is_err() || receiver.await.is_err() {
+                error!("Relay task stopped");
+                return;
+            }
+        }
+    });
+}
+
+/// The rated watts of the relays
+pub(crate) fn watts() -> [u16; RELAY_COUNT as _] {
+    SETTINGS.read().unwrap().relays.map(|load| load.watts)
+}
+
+/// Keep the relays within the budget, returns the relay bitmask to apply
+///
+/// The relays the mask switches on since `previous` are refused or shed
+/// others, relays the mask writes are no longer restored and shed relays
+/// that fit again are restored if their permissive holds.
+pub(crate) fn limit(mask: Mask, previous: u16, requested: u16) -> u16 {
+    let settings = SETTINGS.read().unwrap();
+    let mut status = STATUS.lock().unwrap();
+    let (state, shed, refused) = settings.plan(status.shed & mask.and, previous, requested);
+    if refused != 0 {
+        warn!(
+            "Relays {refused:#06b} refused: {} W would exceed the {} W budget",
+            settings.usage(state | refused),
+            settings.budget
+        );
+        status.refused = status.refused.saturating_add(1);
+    }
+    if shed & !status.shed != 0 {
+        info!("Relays {:#06b} shed", shed & !status.shed);
+    }
+    status.shed = shed;
+    let restored = settings.restore(status.shed, state, permissive::permit);
+    if restored != 0 {
+        info!("Relays {restored:#06b} restored");
+        status.shed &= !restored;
+    }
+    state | restored
+}
+
+/// Restore the default settings and forget the shed relays and refused
+/// switch-ons
+///
+/// Host builds only, for the fuzz target and the tests.
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn reset() {
+    *SETTINGS.write().unwrap() = Settings::NONE;
+    *STATUS.lock().unwrap() = Status {
+        shed: 0,
+        refused: 0,
+    };
+}
+
+/// Check that the relays the mask switches on fit in the budget, `state` is
+/// the relay bitmask
+///
+/// A refused command is answered with `ServerDeviceBusy` and counted, the
+/// diagnostics count it as a busy answer too.
+pub(crate) fn check(mask: Mask, state: u16) -> Result<(), ExceptionCode> {
+    let settings = SETTINGS.read().unwrap();
+    let mut status = STATUS.lock().unwrap();
+    let (_, _, refused) = settings.plan(status.shed & mask.and, state, mask.apply(state));
+    if refused == 0 {
+        return Ok(());
+    }
+    status.refused = status.refused.saturating_add(1);
+    warn!(
+        "Relays {refused:#06b} command refused: over the {} W budget",
+        settings.budget
+    );
+    Err(ExceptionCode::ServerDeviceBusy)
+}
+
+/// Read the power holding registers
+pub(crate) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    registers(&SETTINGS.read().unwrap())[address as usize..][..count as usize].to_vec()
+}
+
+/// Write the power holding registers
+///
+/// All values are validated before any of them is applied and stored in NVS.
+pub(crate) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut settings = SETTINGS.write().unwrap();
+    let updated = self::settings(&settings, address, values)?;
+    if updated != *settings {
+        let bytes: Vec<_> = registers(&updated)
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        storage::save(STORAGE_KEY, &bytes).map_err(|error| {
+            error!("{error:?}");
+            ExceptionCode::ServerDeviceFailure
+        })?;
+        info!("Power settings: {updated:?}");
+    }
+    *settings = updated;
+    Ok(())
+}
+
+/// Read the status input registers: the watts of the relays that are on, the
+/// budget, the shed relays and the count of refused switch-ons
+///
+/// `state` is the relay bitmask.
+pub(crate) fn read_status(address: u16, count: u16, state: u16) -> Vec<u16> {
+    let settings = SETTINGS.read().unwrap();
+    let status = STATUS.lock().unwrap();
+    let usage = settings.usage(state).min(u16::MAX as _) as u16;
+    let values = [usage, settings.budget, status.shed, status.refused];
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// The register image of the settings
+fn registers(settings: &Settings) -> Vec<u16> {
+    [settings.budget, settings.policy as _]
+        .into_iter()
+        .chain(
+            settings
+                .relays
+                .iter()
+                .flat_map(|load| [load.watts, load.priority]),
+        )
+        .collect()
+}
+
+/// The settings with the registers written
+fn settings(settings: &Settings, address: u16, values: &[u16]) -> Result<Settings, ExceptionCode> {
+    let mut updated = *settings;
+    for (address, &value) in (address..).zip(values) {
+        match address {
+            0 => updated.budget = value,
+            1 => updated.policy = value.try_into()?,
+            _ => {
+                let load = &mut updated.relays[((address - 2) / ENTRY_SIZE) as usize];
+                match (address - 2) % ENTRY_SIZE {
+                    0 => load.watts = value,
+                    _ => load.priority = value,
+                }
+            }
+        }
+    }
+    Ok(updated)
+}
@@ -1,14 +1,144 @@
+use crate::{permissive, power};
+use anyhow::Result;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::hal::{
-    gpio::{Output, OutputPin, Pin, PinDriver},
+    gpio::{AnyOutputPin, Output, OutputPin, Pin, PinDriver},
     peripheral::Peripheral,
     sys::EspError,
 };
+use log::{info, warn};
+use tokio::{
+    spawn,
+    sync::{
+        mpsc::{self, Sender},
+        oneshot,
+    },
+};
+
+/// Relay count
+pub const COUNT: u16 = 2;
+
+const ALL: u16 = (1 << COUNT) - 1;
+
+/// Relay request
+///
+/// The mask is applied to the relay bitmask (bit `n` is relay `n`) in one
+/// step, the answer is the bitmask before and after the update.
+pub type Request = (Mask, oneshot::Sender<(u16, u16)>);
+
+/// Relay outputs
+pub trait Backend: Send + 'static {
+    fn set(&mut self, index: usize, value: bool) -> Result<()>;
+}
+
+pub fn start(mut backend: impl Backend) -> Result<Sender<Request>> {
+    for index in 0..COUNT as _ {
+        backend.set(index, false)?;
+    }
+    info!("Relay drivers initialized");
+    let (sender, mut receiver) = mpsc::channel::<Request>(9);
+    info!("Spawn relay receiver");
+    spawn(async move {
+        let mut state = 0;
+        while let Some((mask, sender)) = receiver.recv().await {
+            let previous = state;
+            let requested = mask.apply(state) & ALL;
+            let permitted = permissive::permit(requested);
+            if permitted != requested {
+                warn!(
+                    "Relays {:#06b} held off by their permissives",
+                    requested & !permitted
+                );
+            }
+            state = power::limit(mask, previous, permitted);
+            for index in 0..COUNT as _ {
+                if let Err(error) = backend.set(index, state & (1 << index) != 0) {
+                    warn!("Relay {index} set failed: {error}");
+                }
+            }
+            if state != previous {
+                info!("Relays {previous:#06b} -> {state:#06b}");
+            }
+            let _ = sender.send((previous, state));
+        }
+    });
+    Ok(sender)
+}
+
+/// Relay bitmask update
+///
+/// `state = (state & and) | (or & !and)`, the same as Modbus mask write
+/// register.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mask {
+    pub and: u16,
+    pub or: u16,
+}
+
+impl Mask {
+    /// Leave the bitmask as it is
+    pub const READ: Self = Self {
+        and: u16::MAX,
+        or: 0,
+    };
+
+    /// Set consecutive relays starting at `address`
+    pub fn write(address: u16, values: &[bool]) -> Self {
+        let mut mask = Self::READ;
+        for (index, &value) in values.iter().enumerate() {
+            let bit = 1 << (address as usize + index);
+            mask.and &= !bit;
+            if value {
+                mask.or |= bit;
+            }
+        }
+        mask
+    }
+
+    /// Replace the whole bitmask
+    pub fn replace(state: u16) -> Self {
+        Self { and: 0, or: state }
+    }
+
+    pub fn apply(self, state: u16) -> u16 {
+        (state & self.and) | (self.or & !self.and)
+    }
+}
+
+/// Relay drivers, one per pin
+#[cfg(target_os = "espidf")]
+pub struct Drivers(Vec<Driver<'static, AnyOutputPin>>);
+
+#[cfg(target_os = "espidf")]
+impl Drivers {
+    pub fn new(pins: [AnyOutputPin; COUNT as _]) -> Result<Self> {
+        Ok(Self(
+            pins.map(Driver::new)
+                .into_iter()
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Backend for Drivers {
+    fn set(&mut self, index: usize, value: bool) -> Result<()> {
+        self.0[index].set(value)?;
+        Ok(())
+    }
+}
 
 /// Relay driver
+#[cfg(target_os = "espidf")]
 pub struct Driver<'a, T: Pin>(PinDriver<'a, T, Output>);
 
+#[cfg(target_os = "espidf")]
 impl<'a, T: OutputPin> Driver<'a, T> {
     pub fn new(pin: impl Peripheral<P = T> + 'a) -> Result<Self, EspError> {
         Ok(Self(PinDriver::output(pin)?))
     }
+
+    pub fn set(&mut self, value: bool) -> Result<(), EspError> {
+        self.0.set_level(value.into())
+    }
 }
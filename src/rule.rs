@@ -0,0 +1,233 @@
+//! Rule engine: text rules compiled to bytecode and run periodically
+
+use self::{
+    compile::{Line, compile},
+    program::{Context, Program, Source},
+};
+use crate::{
+    input,
+    relay::{Mask, Request as RelayRequest},
+    schedule, storage, timer,
+};
+use anyhow::Result;
+use log::{error, info, warn};
+use std::sync::{Mutex, RwLock};
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, sleep},
+};
+use tokio_modbus::prelude::*;
+
+pub mod compile;
+pub mod program;
+
+const PERIOD: Duration = Duration::from_secs(1);
+
+const STORAGE_KEY: &str = "rules";
+
+/// Source text size, ASCII padded with NUL
+pub(crate) const SOURCE_SIZE: usize = 1024;
+/// Holding registers: activate, then the source text
+pub(crate) const REGISTER_COUNT: u16 = 1 + SOURCE_SIZE as u16 / 2;
+/// Input registers: state, error line, code size
+pub(crate) const STATUS_REGISTER_COUNT: u16 = 3;
+
+/// Source text written to the registers, compiled when it is activated
+static SOURCE: Mutex<[u8; SOURCE_SIZE]> = Mutex::new([0; SOURCE_SIZE]);
+static PROGRAM: RwLock<Option<Program>> = RwLock::new(None);
+static STATUS: Mutex<Status> = Mutex::new(Status::None);
+
+/// Outcome of the last activation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Status {
+    None,
+    Active,
+    /// The source did not compile, at the line or `0` if it is not ASCII or
+    /// too large
+    Error(u16),
+}
+
+/// The values of the device
+pub(crate) struct Values {
+    relays: u16,
+    minute: Option<u16>,
+}
+
+impl Values {
+    /// The values with the relay bitmask
+    pub(crate) fn new(relays: u16) -> Self {
+        Self {
+            relays,
+            minute: schedule::minute(),
+        }
+    }
+}
+
+impl Context for Values {
+    fn value(&self, source: Source, index: u8) -> Option<i32> {
+        match source {
+            Source::Input => input::value(index as _).map(|value| value as _),
+            Source::Temp => input::value(index as _).map(|value| value as i16 as _),
+            Source::Relay => Some((self.relays >> index & 1) as _),
+            Source::Timer => Some(timer::read_remaining(index as _, 1)[0] as _),
+            Source::Time => self.minute.map(|minute| minute as _),
+        }
+    }
+}
+
+/// Start running the rules, the ones stored in NVS first
+pub fn start(relay_sender: Sender<RelayRequest>) {
+    match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => {
+            let mut source = SOURCE.lock().unwrap();
+            let length = bytes.len().min(SOURCE_SIZE);
+            source[..length].copy_from_slice(&bytes[..length]);
+            if let Err(exception) = activate(&*source) {
+                warn!("Stored rules ignored: {exception:?}");
+            }
+        }
+        Ok(None) => {}
+        Err(error) => error!("{error:?}"),
+    }
+    spawn(async move {
+        loop {
+            sleep(PERIOD).await;
+            if let Err(error) = run(&relay_sender).await {
+                error!("{error:?}");
+                return;
+            }
+        }
+    });
+}
+
+/// Run the rules once, the relays are only switched when the rules change
+/// them
+async fn run(relay_sender: &Sender<RelayRequest>) -> Result<()> {
+    if PROGRAM.read().unwrap().is_none() {
+        return Ok(());
+    }
+    let (_, relays) = switch(relay_sender, Mask::READ).await?;
+    let values = Values::new(relays);
+    let Some(mask) = PROGRAM
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|program| program.run(&values))
+    else {
+        return Ok(());
+    };
+    if mask.apply(relays) != relays {
+        let (previous, state) = switch(relay_sender, mask).await?;
+        info!("Rules: relays {previous:#06b} -> {state:#06b}");
+    }
+    Ok(())
+}
+
+async fn switch(relay_sender: &Sender<RelayRequest>, mask: Mask) -> Result<(u16, u16)> {
+    let (sender, receiver) = oneshot::channel();
+    relay_sender.send((mask, sender)).await?;
+    Ok(receiver.await?)
+}
+
+/// Compile the source and run it from now on, an empty source stops the
+/// rules
+///
+/// The rules running so far keep running if it does not compile.
+fn activate(source: &[u8]) -> Result<(), ExceptionCode> {
+    let source = source.split(|&byte| byte == 0).next().unwrap_or_default();
+    let Ok(text) = str::from_utf8(source) else {
+        *STATUS.lock().unwrap() = Status::Error(0);
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    if text.trim().is_empty() {
+        *PROGRAM.write().unwrap() = None;
+        *STATUS.lock().unwrap() = Status::None;
+        info!("Rules stopped");
+        return Ok(());
+    }
+    match compile(text) {
+        Ok(program) => {
+            info!("Rules activated: {} bytes of code", program.code().len());
+            *PROGRAM.write().unwrap() = Some(program);
+            *STATUS.lock().unwrap() = Status::Active;
+            Ok(())
+        }
+        Err(error) => {
+            warn!("Rules rejected: {error:#}");
+            let line = error.downcast_ref::<Line>().map_or(0, |line| line.0);
+            *STATUS.lock().unwrap() = Status::Error(line.min(u16::MAX as _) as _);
+            Err(ExceptionCode::IllegalDataValue)
+        }
+    }
+}
+
+/// Stop the rules and clear the source text
+///
+/// Host builds only, for the fuzz target and the tests.
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn reset() {
+    *SOURCE.lock().unwrap() = [0; SOURCE_SIZE];
+    *PROGRAM.write().unwrap() = None;
+    *STATUS.lock().unwrap() = Status::None;
+}
+
+/// Read the rule holding registers, the first one is `1` while rules run
+pub(crate) fn read(address: u16, count: u16) -> Vec<u16> {
+    let source = SOURCE.lock().unwrap();
+    let (text, _) = source.as_chunks();
+    let mut values = vec![PROGRAM.read().unwrap().is_some() as u16];
+    values.extend(text.iter().map(|&chunk| u16::from_be_bytes(chunk)));
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// Write the rule holding registers
+///
+/// The source text is only compiled when `1` is written to the first
+/// register, which stores it in NVS once it has been verified; `0` stops the
+/// rules.
+pub(crate) fn write(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut source = SOURCE.lock().unwrap();
+    for (address, &value) in (address..).zip(values) {
+        if address == 0 {
+            continue;
+        }
+        let index = (address as usize - 1) * 2;
+        source[index..index + 2].copy_from_slice(&value.to_be_bytes());
+    }
+    let Some(&command) = values.first().filter(|_| address == 0) else {
+        return Ok(());
+    };
+    let stored = match command {
+        0 => {
+            activate(&[])?;
+            Vec::new()
+        }
+        1 => {
+            activate(&*source)?;
+            let length = source.iter().position(|&byte| byte == 0);
+            source[..length.unwrap_or(SOURCE_SIZE)].to_vec()
+        }
+        _ => return Err(ExceptionCode::IllegalDataValue),
+    };
+    storage::save(STORAGE_KEY, &stored).map_err(|error| {
+        error!("{error:?}");
+        ExceptionCode::ServerDeviceFailure
+    })
+}
+
+/// Read the status input registers: state (0 none, 1 active, 2 error), the
+/// line of the error and the code size in bytes
+pub(crate) fn read_status(address: u16, count: u16) -> Vec<u16> {
+    let size = PROGRAM
+        .read()
+        .unwrap()
+        .as_ref()
+        .map_or(0, |program| program.code().len() as u16);
+    let values = match *STATUS.lock().unwrap() {
+        Status::None => [0, 0, size],
+        Status::Active => [1, 0, size],
+        Status::Error(line) => [2, line, size],
+    };
+    values[address as usize..][..count as usize].to_vec()
+}
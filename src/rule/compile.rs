@@ -0,0 +1,326 @@
+//! Rule compiler, one rule per line like
+//! `IF temp[0] > 60 AND input[1] THEN relay[1] := OFF`
+//!
+//! Conditions combine `input[n]`, `temp[n]`, `relay[n]`, `timer[n]` and
+//! `time` with comparisons, `+`, `-`, `NOT`, `AND` and `OR`; actions are
+//! `relay[n] := value` separated by commas. Keywords are case insensitive,
+//! `ON` and `TRUE` are 1, `OFF` and `FALSE` are 0, `07:30` is a time of day
+//! in minutes, `#` starts a comment.
+//!
+//! A condition on its own, without `IF` and actions, is compiled to a program
+//! that evaluates it, see [`condition`].
+
+use super::program::{Op, Program, Source};
+use anyhow::{Context as _, Result, bail};
+use std::fmt;
+
+/// Deepest nesting of parentheses and unary operators, so a rule can not
+/// exhaust the stack of the compiler
+const MAX_NESTING: usize = 16;
+
+/// The line of a compile error, attached to it as context
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Line(pub usize);
+
+impl fmt::Display for Line {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "line {}", self.0)
+    }
+}
+
+/// Compile and verify the rules
+pub fn compile(source: &str) -> Result<Program> {
+    let mut code = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parser = Parser {
+            tokens: tokens(line).context(Line(index + 1))?,
+            at: 0,
+            nesting: 0,
+            code: &mut code,
+        };
+        parser.rule().context(Line(index + 1))?;
+    }
+    Program::new(code)
+}
+
+/// Compile and verify a single condition like `relay[0] AND input[2]`
+pub fn condition(source: &str) -> Result<Program> {
+    let mut code = Vec::new();
+    let mut parser = Parser {
+        tokens: tokens(source)?,
+        at: 0,
+        nesting: 0,
+        code: &mut code,
+    };
+    parser.expression()?;
+    if let Some(token) = parser.peek() {
+        bail!("unexpected {token} after the condition");
+    }
+    Program::condition(code)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    /// Upper case
+    Word(String),
+    Number(i32),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Word(word) => write!(formatter, "{word}"),
+            Self::Number(number) => write!(formatter, "{number}"),
+            Self::Symbol(symbol) => write!(formatter, "{symbol:?}"),
+        }
+    }
+}
+
+/// Longer symbols first, so `>=` is not taken for `>`
+const SYMBOLS: [&str; 15] = [
+    ":=", ">=", "<=", "==", "!=", ">", "<", "=", "[", "]", "(", ")", ",", "+", "-",
+];
+
+fn tokens(line: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while let Some(character) = rest.chars().next() {
+        let end = if character.is_ascii_alphabetic() || character == '_' {
+            let end = rest
+                .find(|character: char| !character.is_ascii_alphanumeric() && character != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..end].to_ascii_uppercase()));
+            end
+        } else if character.is_ascii_digit() {
+            let digits = |text: &str| {
+                text.find(|character: char| !character.is_ascii_digit())
+                    .unwrap_or(text.len())
+            };
+            let end = digits(rest);
+            let number: i32 = rest[..end].parse()?;
+            // A time of day, but not an assignment
+            if let Some(minutes) = rest[end..].strip_prefix(':')
+                && digits(minutes) == 2
+            {
+                let minute: i32 = minutes[..2].parse()?;
+                if number > 23 || minute > 59 {
+                    bail!("{} is not a time of day", &rest[..end + 3]);
+                }
+                tokens.push(Token::Number(number * 60 + minute));
+                end + 3
+            } else {
+                tokens.push(Token::Number(number));
+                end
+            }
+        } else if let Some(symbol) = SYMBOLS.iter().find(|&&symbol| rest.starts_with(symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        } else {
+            bail!("{character:?} is not allowed");
+        };
+        rest = rest[end..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser that emits the code as it goes
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    at: usize,
+    nesting: usize,
+    code: &'a mut Vec<u8>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let Some(token) = self.tokens.get(self.at).cloned() else {
+            bail!("unexpected end of the rule");
+        };
+        self.at += 1;
+        Ok(token)
+    }
+
+    /// Take the token if it is the word or symbol
+    fn take(&mut self, expected: &str) -> bool {
+        let matches = match self.peek() {
+            Some(Token::Word(word)) => word == expected,
+            Some(Token::Symbol(symbol)) => *symbol == expected,
+            _ => false,
+        };
+        if matches {
+            self.at += 1;
+        }
+        matches
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<()> {
+        if !self.take(expected) {
+            match self.peek() {
+                Some(token) => bail!("expected {expected} instead of {token}"),
+                None => bail!("expected {expected} at the end of the rule"),
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, op: Op) {
+        op.encode(self.code);
+    }
+
+    /// Parse a nested part of the rule
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        if self.nesting == MAX_NESTING {
+            bail!("nested more than {MAX_NESTING} deep");
+        }
+        self.nesting += 1;
+        parse(self)?;
+        self.nesting -= 1;
+        Ok(())
+    }
+
+    /// `IF condition THEN action, ...`
+    fn rule(&mut self) -> Result<()> {
+        self.expect("IF")?;
+        self.expression()?;
+        self.expect("THEN")?;
+        self.emit(Op::Then(0));
+        let start = self.code.len();
+        loop {
+            self.action()?;
+            if !self.take(",") {
+                break;
+            }
+        }
+        if let Some(token) = self.peek() {
+            bail!("unexpected {token} after the rule");
+        }
+        let skip = u16::try_from(self.code.len() - start)?;
+        self.code[start - 2..start].copy_from_slice(&skip.to_be_bytes());
+        Ok(())
+    }
+
+    /// `relay[n] := value`
+    fn action(&mut self) -> Result<()> {
+        self.expect("RELAY")?;
+        let relay = self.index(Source::Relay)?;
+        self.expect(":=")?;
+        self.expression()?;
+        self.emit(Op::Store(relay));
+        Ok(())
+    }
+
+    /// `[n]` of the source
+    fn index(&mut self, source: Source) -> Result<u8> {
+        self.expect("[")?;
+        let index = match self.next()? {
+            Token::Number(index) if (0..source.count() as i32).contains(&index) => index as u8,
+            Token::Number(index) => bail!("{source:?} {index} does not exist"),
+            token => bail!("expected an index instead of {token}"),
+        };
+        self.expect("]")?;
+        Ok(index)
+    }
+
+    fn expression(&mut self) -> Result<()> {
+        self.and()?;
+        while self.take("OR") {
+            self.and()?;
+            self.emit(Op::Or);
+        }
+        Ok(())
+    }
+
+    fn and(&mut self) -> Result<()> {
+        self.not()?;
+        while self.take("AND") {
+            self.not()?;
+            self.emit(Op::And);
+        }
+        Ok(())
+    }
+
+    fn not(&mut self) -> Result<()> {
+        if self.take("NOT") {
+            self.nested(Self::not)?;
+            self.emit(Op::Not);
+            return Ok(());
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<()> {
+        self.sum()?;
+        let op = match self.peek() {
+            Some(Token::Symbol("=" | "==")) => Op::Equal,
+            Some(Token::Symbol("!=")) => Op::NotEqual,
+            Some(Token::Symbol("<")) => Op::Less,
+            Some(Token::Symbol("<=")) => Op::LessEqual,
+            Some(Token::Symbol(">")) => Op::Greater,
+            Some(Token::Symbol(">=")) => Op::GreaterEqual,
+            _ => return Ok(()),
+        };
+        self.at += 1;
+        self.sum()?;
+        self.emit(op);
+        Ok(())
+    }
+
+    fn sum(&mut self) -> Result<()> {
+        self.unary()?;
+        loop {
+            let op = if self.take("+") {
+                Op::Add
+            } else if self.take("-") {
+                Op::Subtract
+            } else {
+                return Ok(());
+            };
+            self.unary()?;
+            self.emit(op);
+        }
+    }
+
+    fn unary(&mut self) -> Result<()> {
+        if self.take("-") {
+            self.nested(Self::unary)?;
+            self.emit(Op::Negate);
+            return Ok(());
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<()> {
+        let op = match self.next()? {
+            // Register values, signed or not
+            Token::Number(number @ -32768..=65535) => Op::Push(number),
+            Token::Number(number) => bail!("{number} is out of range"),
+            Token::Symbol("(") => {
+                self.nested(Self::expression)?;
+                self.expect(")")?;
+                return Ok(());
+            }
+            Token::Word(word) => match &word[..] {
+                "ON" | "TRUE" => Op::Push(1),
+                "OFF" | "FALSE" => Op::Push(0),
+                "TIME" => Op::Load(Source::Time, 0),
+                "INPUT" => Op::Load(Source::Input, self.index(Source::Input)?),
+                "TEMP" => Op::Load(Source::Temp, self.index(Source::Temp)?),
+                "RELAY" => Op::Load(Source::Relay, self.index(Source::Relay)?),
+                "TIMER" => Op::Load(Source::Timer, self.index(Source::Timer)?),
+                _ => bail!("unexpected {word}"),
+            },
+            token => bail!("unexpected {token}"),
+        };
+        self.emit(op);
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+//! Rule bytecode and the stack machine that runs it
+//!
+//! Jumps only go forward, so a run takes at most one pass over the code and
+//! the size of a program bounds its time.
+
+use crate::{
+    input,
+    relay::{COUNT as RELAY_COUNT, Mask},
+};
+use anyhow::{Result, anyhow, bail};
+use std::collections::BTreeMap;
+
+/// Largest program in bytes
+pub const MAX_SIZE: usize = 1024;
+/// Deepest stack
+const MAX_DEPTH: usize = 16;
+
+/// Where a loaded value comes from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    /// Virtual input, unsigned
+    Input = 0,
+    /// Virtual input, signed like a temperature
+    Temp = 1,
+    /// Relay state, `0` or `1`
+    Relay = 2,
+    /// Remaining time of the relay's timer in tenths of a second
+    Timer = 3,
+    /// Minutes since local midnight, the index is `0`
+    Time = 4,
+}
+
+impl Source {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Input),
+            1 => Some(Self::Temp),
+            2 => Some(Self::Relay),
+            3 => Some(Self::Timer),
+            4 => Some(Self::Time),
+            _ => None,
+        }
+    }
+
+    /// How many values of the source there are
+    pub fn count(self) -> u16 {
+        match self {
+            Self::Input | Self::Temp => input::COUNT,
+            Self::Relay | Self::Timer => RELAY_COUNT,
+            Self::Time => 1,
+        }
+    }
+}
+
+/// The values the rules read
+pub trait Context {
+    /// The value, `None` if it is not known, like a stale input or the time
+    /// before the clock is set
+    fn value(&self, source: Source, index: u8) -> Option<i32>;
+}
+
+/// Instruction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Op {
+    /// Push a constant
+    Push(i32),
+    /// Push a value from the context
+    Load(Source, u8),
+    /// Pop the condition of a rule and skip the bytes of its actions if it is
+    /// false or depends on an unknown value
+    Then(u16),
+    /// Pop a value and switch the relay on if it is not zero, unless it
+    /// depends on an unknown value
+    Store(u8),
+    Not,
+    And,
+    Or,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Negate,
+}
+
+impl Op {
+    /// Append the instruction to the code
+    pub fn encode(self, code: &mut Vec<u8>) {
+        match self {
+            Self::Push(value) => {
+                code.push(0x01);
+                code.extend(value.to_be_bytes());
+            }
+            Self::Load(source, index) => code.extend([0x02, source as u8, index]),
+            Self::Then(skip) => {
+                code.push(0x03);
+                code.extend(skip.to_be_bytes());
+            }
+            Self::Store(relay) => code.extend([0x04, relay]),
+            Self::Not => code.push(0x10),
+            Self::And => code.push(0x11),
+            Self::Or => code.push(0x12),
+            Self::Equal => code.push(0x13),
+            Self::NotEqual => code.push(0x14),
+            Self::Less => code.push(0x15),
+            Self::LessEqual => code.push(0x16),
+            Self::Greater => code.push(0x17),
+            Self::GreaterEqual => code.push(0x18),
+            Self::Add => code.push(0x19),
+            Self::Subtract => code.push(0x1A),
+            Self::Negate => code.push(0x1B),
+        }
+    }
+
+    /// The instruction at the start of the code and its size, operands are
+    /// checked against the sources and relays
+    pub fn decode(code: &[u8]) -> Result<(Self, usize)> {
+        let operand = |size: usize| {
+            code.get(1..1 + size)
+                .ok_or_else(|| anyhow!("{:#04X} is cut off", code[0]))
+        };
+        Ok(match code.first() {
+            Some(0x01) => {
+                let value = operand(4)?;
+                (Self::Push(i32::from_be_bytes(value.try_into()?)), 5)
+            }
+            Some(0x02) => {
+                let operand = operand(2)?;
+                let (source, index) = (operand[0], operand[1]);
+                let Some(source) = Source::from_u8(source) else {
+                    bail!("{source} is not a source");
+                };
+                if index as u16 >= source.count() {
+                    bail!("{source:?} {index} does not exist");
+                }
+                (Self::Load(source, index), 3)
+            }
+            Some(0x03) => {
+                let skip = operand(2)?;
+                (Self::Then(u16::from_be_bytes([skip[0], skip[1]])), 3)
+            }
+            Some(0x04) => {
+                let relay = operand(1)?[0];
+                if relay as u16 >= RELAY_COUNT {
+                    bail!("relay {relay} does not exist");
+                }
+                (Self::Store(relay), 2)
+            }
+            Some(0x10) => (Self::Not, 1),
+            Some(0x11) => (Self::And, 1),
+            Some(0x12) => (Self::Or, 1),
+            Some(0x13) => (Self::Equal, 1),
+            Some(0x14) => (Self::NotEqual, 1),
+            Some(0x15) => (Self::Less, 1),
+            Some(0x16) => (Self::LessEqual, 1),
+            Some(0x17) => (Self::Greater, 1),
+            Some(0x18) => (Self::GreaterEqual, 1),
+            Some(0x19) => (Self::Add, 1),
+            Some(0x1A) => (Self::Subtract, 1),
+            Some(0x1B) => (Self::Negate, 1),
+            Some(opcode) => bail!("{opcode:#04X} is not an instruction"),
+            None => bail!("the code is empty"),
+        })
+    }
+
+    /// How many values the instruction pops and pushes
+    fn stack(self) -> (usize, usize) {
+        match self {
+            Self::Push(_) | Self::Load(..) => (0, 1),
+            Self::Then(_) | Self::Store(_) => (1, 0),
+            Self::Not | Self::Negate => (1, 1),
+            _ => (2, 1),
+        }
+    }
+}
+
+/// Verified rule bytecode
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Program {
+    code: Vec<u8>,
+}
+
+impl Program {
+    /// Verify the code: instructions and operands are valid, jumps go forward
+    /// to an instruction or the end, the stack neither underflows nor grows
+    /// beyond its depth and is empty at the end and wherever paths join
+    pub fn new(code: Vec<u8>) -> Result<Self> {
+        verify(&code, false)?;
+        Ok(Self { code })
+    }
+
+    /// Verify the code of a condition, which leaves its value on the stack
+    /// and has no actions
+    pub fn condition(code: Vec<u8>) -> Result<Self> {
+        verify(&code, true)?;
+        Ok(Self { code })
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Run the rules, returns the mask of the relays their actions switch,
+    /// the last action on a relay wins
+    pub fn run(&self, context: &impl Context) -> Mask {
+        let mut mask = Mask::READ;
+        self.execute(context, &mut mask);
+        mask
+    }
+
+    /// Evaluate a condition, `None` if it depends on an unknown value
+    pub fn test(&self, context: &impl Context) -> Option<bool> {
+        let mut mask = Mask::READ;
+        let (value, unknown) = self.execute(context, &mut mask);
+        (!unknown).then_some(value != 0)
+    }
+
+    /// Run the code, returns the value left on the stack and whether it
+    /// depends on an unknown value
+    fn execute(&self, context: &impl Context, mask: &mut Mask) -> (i32, bool) {
+        let mut stack: Vec<i32> = Vec::with_capacity(MAX_DEPTH);
+        let mut unknown = false;
+        let mut at = 0;
+        while let Ok((op, size)) = Op::decode(&self.code[at..]) {
+            at += size;
+            let mut pop = || stack.pop().unwrap_or(0);
+            let value = match op {
+                Op::Push(value) => value,
+                Op::Load(source, index) => context.value(source, index).unwrap_or_else(|| {
+                    unknown = true;
+                    0
+                }),
+                Op::Then(skip) => {
+                    if pop() == 0 || unknown {
+                        at += skip as usize;
+                    }
+                    unknown = false;
+                    continue;
+                }
+                Op::Store(relay) => {
+                    let value = pop();
+                    if !unknown {
+                        let bit = 1 << relay;
+                        mask.and &= !bit;
+                        mask.or = mask.or & !bit | if value != 0 { bit } else { 0 };
+                    }
+                    unknown = false;
+                    continue;
+                }
+                Op::Not => (pop() == 0) as _,
+                Op::Negate => pop().saturating_neg(),
+                _ => {
+                    let right = pop();
+                    let left = pop();
+                    match op {
+                        Op::And => (left != 0 && right != 0) as _,
+                        Op::Or => (left != 0 || right != 0) as _,
+                        Op::Equal => (left == right) as _,
+                        Op::NotEqual => (left != right) as _,
+                        Op::Less => (left < right) as _,
+                        Op::LessEqual => (left <= right) as _,
+                        Op::Greater => (left > right) as _,
+                        Op::GreaterEqual => (left >= right) as _,
+                        Op::Add => left.saturating_add(right),
+                        _ => left.saturating_sub(right),
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        (stack.pop().unwrap_or(0), unknown)
+    }
+}
+
+/// Verify the code, a condition leaves one value on the stack instead of none
+fn verify(code: &[u8], condition: bool) -> Result<()> {
+    if code.len() > MAX_SIZE {
+        bail!("{} bytes is more than {MAX_SIZE}", code.len());
+    }
+    // The stack depth the jumps expect at their targets
+    let mut targets = BTreeMap::new();
+    let mut depth = 0;
+    let mut at = 0;
+    while at < code.len() {
+        if let Some(expected) = targets.remove(&at)
+            && expected != depth
+        {
+            bail!("stack depth {depth} at {at} does not match the jump's {expected}");
+        }
+        let (op, size) = Op::decode(&code[at..])?;
+        if condition && matches!(op, Op::Then(_) | Op::Store(_)) {
+            bail!("{op:?} at {at} in a condition");
+        }
+        let (pops, pushes) = op.stack();
+        if depth < pops {
+            bail!("stack underflow at {at}");
+        }
+        depth = depth - pops + pushes;
+        if depth > MAX_DEPTH {
+            bail!("stack overflow at {at}");
+        }
+        at += size;
+        if let Op::Then(skip) = op {
+            let target = at + skip as usize;
+            if target > code.len() {
+                bail!("jump from {at} beyond the end");
+            }
+            if targets
+                .insert(target, depth)
+                .is_some_and(|other| other != depth)
+            {
+                bail!("jumps to {target} disagree on the stack depth");
+            }
+        }
+    }
+    for (target, expected) in targets {
+        if target != code.len() {
+            bail!("jump into the instruction at {target}");
+        }
+        if expected != depth {
+            bail!("stack depth {depth} at the end does not match the jump's {expected}");
+        }
+    }
+    let result = condition as usize;
+    if depth != result {
+        bail!("{depth} values left on the stack instead of {result}");
+    }
+    Ok(())
+}
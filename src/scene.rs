@@ -0,0 +1,280 @@
+//! Scenes: named relay presets applied in one go
+
+use crate::{
+    permissive, power,
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+    storage,
+};
+use log::{error, info, warn};
+use std::sync::{Mutex, RwLock};
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, sleep},
+};
+use tokio_modbus::prelude::*;
+
+const STORAGE_KEY: &str = "scenes";
+
+pub(crate) const SCENE_COUNT: u16 = 8;
+/// Name length, ASCII padded with NUL
+const NAME_SIZE: usize = 16;
+/// Holding registers per scene: relays, states, stagger, then the name
+pub(crate) const ENTRY_SIZE: u16 = 3 + NAME_SIZE as u16 / 2;
+/// The register that applies a scene, then the scenes
+pub(crate) const REGISTER_COUNT: u16 = 1 + SCENE_COUNT * ENTRY_SIZE;
+
+static SCENES: RwLock<[Scene; SCENE_COUNT as _]> = RwLock::new([Scene::NONE; SCENE_COUNT as _]);
+/// The scene applied last and how many have been applied or interrupted, a
+/// staggered scene stops once the count changes
+static ACTIVE: Mutex<(Option<u16>, u32)> = Mutex::new((None, 0));
+
+/// Relay preset
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Scene {
+    /// Bitmask of the relays it sets, `0` for an unused scene
+    relays: u16,
+    /// Bitmask of their states
+    states: u16,
+    /// Milliseconds between the relays, which are then switched one after
+    /// the other in ascending order, `0` switches them at once
+    stagger: u16,
+    name: [u8; NAME_SIZE],
+}
+
+impl Scene {
+    const NONE: Self = Self {
+        relays: 0,
+        states: 0,
+        stagger: 0,
+        name: [0; NAME_SIZE],
+    };
+
+    fn mask(&self) -> Mask {
+        Mask {
+            and: !self.relays,
+            or: self.states & self.relays,
+        }
+    }
+
+    /// The relays are in the scene
+    fn matches(&self, state: u16) -> bool {
+        state & self.relays == self.states & self.relays
+    }
+
+    fn name(&self) -> String {
+        String::from_utf8_lossy(&self.name)
+            .trim_end_matches('\0')
+            .to_owned()
+    }
+}
+
+/// Load the scenes stored in NVS
+pub fn load() {
+    match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => {
+            let (values, _) = bytes.as_chunks();
+            let values: Vec<_> = values
+                .iter()
+                .map(|&value| u16::from_be_bytes(value))
+                .collect();
+            match scenes(&[Scene::NONE; SCENE_COUNT as _], 0, &values) {
+                Ok(scenes) => *SCENES.write().unwrap() = scenes,
+                Err(exception) => warn!("Stored scenes ignored: {exception:?}"),
+            }
+        }
+        Ok(None) => {}
+        Err(error) => error!("{error:?}"),
+    }
+}
+
+/// Restore the unused scenes and forget the active one
+///
+/// Host builds only, for the fuzz target and the tests.
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn reset() {
+    *SCENES.write().unwrap() = [Scene::NONE; SCENE_COUNT as _];
+    *ACTIVE.lock().unwrap() = (None, 0);
+}
+
+/// The relays a write of the first register switches, `0` for none
+pub(crate) fn relays(value: u16) -> u16 {
+    match value {
+        1..=SCENE_COUNT => SCENES.read().unwrap()[value as usize - 1].relays,
+        _ => 0,
+    }
+}
+
+/// The mask that applies the scene
+pub(crate) fn mask(scene: u16) -> Mask {
+    SCENES.read().unwrap()[scene as usize].mask()
+}
+
+/// Read the scene holding registers
+///
+/// The first register is the active scene plus one, `0` once a relay of it
+/// has been switched to another state or if none has been applied. `state`
+/// is the relay bitmask.
+pub(crate) fn read(address: u16, count: u16, state: u16) -> Vec<u16> {
+    let scenes = SCENES.read().unwrap();
+    let (active, _) = *ACTIVE.lock().unwrap();
+    let active = active.filter(|&scene| scenes[scene as usize].matches(state));
+    let mut values = vec![active.map_or(0, |scene| scene + 1)];
+    values.extend(registers(&*scenes));
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// Write the scene holding registers
+///
+/// Writing scene `n` plus one to the first register, on its own, returns
+/// scene `n` to apply. The scenes are validated before any of them is
+/// applied and stored in NVS.
+pub(crate) fn write(address: u16, values: &[u16]) -> Result<Option<u16>, ExceptionCode> {
+    if address == 0 {
+        return match *values {
+            [value @ 1..=SCENE_COUNT] if relays(value) != 0 => Ok(Some(value - 1)),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        };
+    }
+    let mut scenes = SCENES.write().unwrap();
+    let updated = self::scenes(&scenes, address - 1, values)?;
+    if updated != *scenes {
+        let bytes: Vec<_> = registers(&updated)
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        storage::save(STORAGE_KEY, &bytes).map_err(|error| {
+            error!("{error:?}");
+            ExceptionCode::ServerDeviceFailure
+        })?;
+        for (index, (scene, previous)) in updated.iter().zip(scenes.iter()).enumerate() {
+            if scene != previous {
+                info!(
+                    "Scene {index} {:?}: relays {:#06b}, states {:#06b}, stagger {} ms",
+                    scene.name(),
+                    scene.relays,
+                    scene.states,
+                    scene.stagger
+                );
+            }
+        }
+    }
+    *scenes = updated;
+    Ok(None)
+}
+
+/// The register image of the scenes, without the first register
+fn registers(scenes: &[Scene]) -> Vec<u16> {
+    scenes
+        .iter()
+        .flat_map(|scene| {
+            let (name, _) = scene.name.as_chunks();
+            [scene.relays, scene.states, scene.stagger]
+                .into_iter()
+                .chain(name.iter().map(|&chunk| u16::from_be_bytes(chunk)))
+        })
+        .collect()
+}
+
+/// The scenes with the registers after the first one written
+fn scenes(
+    scenes: &[Scene; SCENE_COUNT as _],
+    address: u16,
+    values: &[u16],
+) -> Result<[Scene; SCENE_COUNT as _], ExceptionCode> {
+    let mut updated = *scenes;
+    for (address, &value) in (address..).zip(values) {
+        let scene = &mut updated[(address / ENTRY_SIZE) as usize];
+        match address % ENTRY_SIZE {
+            0 if value >> RELAY_COUNT == 0 => scene.relays = value,
+            1 if value >> RELAY_COUNT == 0 => scene.states = value,
+            2 => scene.stagger = value,
+            offset @ 3.. => {
+                let index = (offset - 3) as usize * 2;
+                scene.name[index..index + 2].copy_from_slice(&value.to_be_bytes());
+            }
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+    Ok(updated)
+}
+
+/// Apply the scene, a staggered one switches its first relay right away and
+/// the others in the background
+///
+/// Each background step is checked against the permissives and the power
+/// budget again, the scene stops at the first one that fails.
+pub(crate) async fn apply(
+    relay_sender: &Sender<RelayRequest>,
+    index: u16,
+) -> Result<(), ExceptionCode> {
+    let scene = SCENES.read().unwrap()[index as usize];
+    let applied = {
+        let mut active = ACTIVE.lock().unwrap();
+        *active = (Some(index), active.1.wrapping_add(1));
+        active.1
+    };
+    info!("Scene {index} {:?} applied", scene.name());
+    if scene.stagger == 0 {
+        switch(relay_sender, scene.mask()).await?;
+        return Ok(());
+    }
+    let mut steps = (0..RELAY_COUNT)
+        .filter(|relay| scene.relays & (1 << relay) != 0)
+        .map(|relay| Mask::write(relay, &[scene.states & (1 << relay) != 0]));
+    if let Some(mask) = steps.next() {
+        switch(relay_sender, mask).await?;
+    }
+    let steps: Vec<_> = steps.collect();
+    let relay_sender = relay_sender.clone();
+    spawn(async move {
+        for mask in steps {
+            sleep(Duration::from_millis(scene.stagger as _)).await;
+            if ACTIVE.lock().unwrap().1 != applied {
+                warn!("Scene {index} {:?} interrupted", scene.name());
+                return;
+            }
+            let Ok((_, state)) = switch(&relay_sender, Mask::READ).await else {
+                return;
+            };
+            if let Err(exception) =
+                permissive::check(mask, state).and_then(|()| power::check(mask, state))
+            {
+                warn!("Scene {index} {:?} stopped: {exception:?}", scene.name());
+                return;
+            }
+            if switch(&relay_sender, mask).await.is_err() {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Stop the staggered active scene if the mask of a client write switches
+/// one of its relays
+pub(crate) fn interrupt(mask: Mask) {
+    let scenes = SCENES.read().unwrap();
+    let mut active = ACTIVE.lock().unwrap();
+    if let (Some(scene), applied) = *active
+        && scenes[scene as usize].relays & (!mask.and | mask.or) != 0
+    {
+        *active = (Some(scene), applied.wrapping_add(1));
+    }
+}
+
+/// Apply the mask to the relays, returns the relay bitmask before and after
+async fn switch(
+    relay_sender: &Sender<RelayRequest>,
+    mask: Mask,
+) -> Result<(u16, u16), ExceptionCode> {
+    let (sender, receiver) = oneshot::channel();
+    if let Err(error) = relay_sender.send((mask, sender)).await {
+        error!("{error:?}");
+        return Err(ExceptionCode::ServerDeviceFailure);
+    }
+    receiver.await.map_err(|error| {
+        error!("{error:?}");
+        ExceptionCode::ServerDeviceFailure
+    })
+}
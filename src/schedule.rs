@@ -0,0 +1,730 @@
+//! Weekly relay schedules, cron, solar and one-shot entries in local time,
+//! with holiday and exception calendars
+
+use self::{
+    calendar::{Calendar, Mode, Range},
+    cron::Cron,
+    sun::{Location, Solar},
+    time::{LocalTime, Rule, Timezone},
+};
+use crate::{
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+    storage,
+};
+use anyhow::{Result, anyhow, bail};
+use log::{error, info, warn};
+use std::{
+    str,
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Instant, sleep},
+};
+use tokio_modbus::prelude::*;
+
+const PERIOD: Duration = Duration::from_secs(1);
+/// Wall clock and uptime may drift apart this far before it is a clock jump
+const MAX_DRIFT: i64 = 5;
+/// Anything earlier means the clock has not been set yet (2025-01-01)
+const MIN_TIME: i64 = 1735689600;
+const MINUTES_PER_DAY: u16 = 1440;
+/// Suppressed days skipped while looking for the next solar event
+const MAX_SUPPRESSED: usize = 400;
+
+/// Weekly windows per relay
+pub const WINDOW_COUNT: usize = 8;
+/// Cron, solar and one-shot entries
+pub const ENTRY_COUNT: usize = 16;
+/// Bytes of a cron expression or solar trigger
+const EXPRESSION_SIZE: usize = 32;
+/// Exception calendars
+pub const CALENDAR_COUNT: usize = 4;
+/// Date ranges shared by the calendars
+pub const RANGE_COUNT: usize = 64;
+
+const STORAGE_KEY: &str = "schedule";
+
+// Holding registers, relative to the schedule block
+/// Timezone: offset, DST shift, DST start month, week, weekday, minute, DST
+/// end month, week, weekday, minute
+pub(crate) const TIMEZONE_REGISTER: u16 = 0x00;
+pub(crate) const TIMEZONE_REGISTER_COUNT: u16 = 10;
+/// Location: latitude, longitude
+pub(crate) const LOCATION_REGISTER: u16 = 0x0A;
+pub(crate) const LOCATION_REGISTER_COUNT: u16 = 2;
+/// Windows: weekdays, on minute, off minute
+pub(crate) const WINDOWS_REGISTER: u16 = 0x10;
+pub(crate) const WINDOW_SIZE: u16 = 3;
+/// Entries: trigger, relay, action, pulse milliseconds (2 registers),
+/// one-shot UTC time (2 registers), reserved, cron expression or solar trigger
+pub(crate) const ENTRIES_REGISTER: u16 =
+    WINDOWS_REGISTER + RELAY_COUNT * WINDOW_COUNT as u16 * WINDOW_SIZE;
+pub(crate) const ENTRY_SIZE: u16 = 8 + EXPRESSION_SIZE as u16 / 2;
+/// Calendars: mode, weekday, relay bitmask
+pub(crate) const CALENDARS_REGISTER: u16 = ENTRIES_REGISTER + ENTRY_COUNT as u16 * ENTRY_SIZE;
+pub(crate) const CALENDAR_SIZE: u16 = 3;
+/// Date ranges: calendar, year, start month and day, end month and day
+pub(crate) const RANGES_REGISTER: u16 = CALENDARS_REGISTER + CALENDAR_COUNT as u16 * CALENDAR_SIZE;
+pub(crate) const RANGE_SIZE: u16 = 4;
+pub(crate) const REGISTER_COUNT: u16 = RANGES_REGISTER + RANGE_COUNT as u16 * RANGE_SIZE;
+
+// Input registers
+/// Next fire time of each entry in UTC (2 registers), 0 for none
+pub(crate) const NEXT_REGISTER_COUNT: u16 = ENTRY_COUNT as u16 * 2;
+
+static SETTINGS: RwLock<Settings> = RwLock::new(Settings::DEFAULT);
+
+/// Schedule settings
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Settings {
+    pub timezone: Timezone,
+    pub location: Location,
+    pub windows: [[Window; WINDOW_COUNT]; RELAY_COUNT as _],
+    pub entries: [Entry; ENTRY_COUNT],
+    pub calendars: [Calendar; CALENDAR_COUNT],
+    pub ranges: [Range; RANGE_COUNT],
+}
+
+impl Settings {
+    pub const DEFAULT: Self = Self {
+        timezone: Timezone::UTC,
+        location: Location::NONE,
+        windows: [[Window::NONE; WINDOW_COUNT]; RELAY_COUNT as _],
+        entries: [Entry::NONE; ENTRY_COUNT],
+        calendars: [Calendar::NONE; CALENDAR_COUNT],
+        ranges: [Range::NONE; RANGE_COUNT],
+    };
+
+    /// The state each relay should have at the local time, `None` for
+    /// relays without a schedule
+    ///
+    /// A relay is off on the days a calendar suppresses.
+    pub fn states(&self, local: &LocalTime) -> [Option<bool>; RELAY_COUNT as _] {
+        let mut relay = 0;
+        self.windows.map(|windows| {
+            let local = self.calendar(relay, *local);
+            relay += 1;
+            let enabled = windows.iter().any(|window| window.days != 0);
+            enabled.then(|| {
+                local.is_some_and(|local| windows.iter().any(|window| window.contains(&local)))
+            })
+        })
+    }
+
+    /// The local time as the relay's calendars see it, with the weekday of
+    /// an override, `None` on a suppressed day
+    ///
+    /// The first calendar with the date wins.
+    pub fn calendar(&self, relay: u16, local: LocalTime) -> Option<LocalTime> {
+        for (index, calendar) in self.calendars.iter().enumerate() {
+            let contains = || {
+                self.ranges
+                    .iter()
+                    .any(|range| range.calendar as usize == index + 1 && range.contains(&local))
+            };
+            if calendar.mode == Mode::None || calendar.relays & (1 << relay) == 0 || !contains() {
+                continue;
+            }
+            return match calendar.mode {
+                Mode::Weekday => Some(LocalTime {
+                    weekday: calendar.weekday,
+                    ..local
+                }),
+                _ => None,
+            };
+        }
+        Some(local)
+    }
+
+    /// The UTC time the entry fires next after `now`, `None` for none
+    ///
+    /// Calendars apply to cron and solar entries, not to one-shot entries.
+    pub fn next(&self, entry: &Entry, now: i64) -> Option<i64> {
+        let calendar = |local| self.calendar(entry.relay, local);
+        match entry.trigger {
+            Trigger::None => None,
+            Trigger::Cron => entry.cron?.next_with(&self.timezone, now, calendar),
+            Trigger::Once => Some(entry.time as i64).filter(|&time| time > now),
+            Trigger::Sun => {
+                let solar = entry.solar?;
+                let mut time = now;
+                // A solar event a day, skip the suppressed ones
+                for _ in 0..MAX_SUPPRESSED {
+                    time = solar.next(&self.timezone, &self.location, time)?;
+                    if calendar(self.timezone.local(time)).is_some() {
+                        return Some(time);
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Weekly on/off window
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Window {
+    /// Weekdays the window starts on, bit 0 is Monday, `0` disables it
+    pub days: u8,
+    /// Minutes since midnight
+    pub on: u16,
+    /// Minutes since midnight, before `on` the window ends the next day, equal
+    /// to `on` it lasts the whole day
+    pub off: u16,
+}
+
+impl Window {
+    pub const NONE: Self = Self {
+        days: 0,
+        on: 0,
+        off: 0,
+    };
+
+    fn contains(&self, local: &LocalTime) -> bool {
+        let today = self.days & (1 << local.weekday) != 0;
+        let yesterday = self.days & (1 << ((local.weekday + 6) % 7)) != 0;
+        let minute = local.minute;
+        if self.on < self.off {
+            today && (self.on..self.off).contains(&minute)
+        } else if self.on > self.off {
+            (today && minute >= self.on) || (yesterday && minute < self.off)
+        } else {
+            today
+        }
+    }
+}
+
+/// What an entry does to its relay
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Off,
+    On,
+    Toggle,
+    /// On, and off again after the duration
+    Pulse(Duration),
+}
+
+/// What fires an entry
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Trigger {
+    None = 0,
+    Cron = 1,
+    Once = 2,
+    Sun = 3,
+}
+
+impl TryFrom<u16> for Trigger {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Cron),
+            2 => Ok(Self::Once),
+            3 => Ok(Self::Sun),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+/// Cron, solar or one-shot entry
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Entry {
+    trigger: Trigger,
+    relay: u16,
+    /// 0 off, 1 on, 2 toggle, 3 pulse
+    action: u16,
+    /// Pulse duration in milliseconds
+    pulse: u32,
+    /// One-shot UTC time in seconds since the epoch
+    time: u32,
+    /// ASCII, padded with NUL
+    expression: [u8; EXPRESSION_SIZE],
+    /// The parsed expression of a cron entry
+    cron: Option<Cron>,
+    /// The parsed expression of a solar entry
+    solar: Option<Solar>,
+}
+
+impl Entry {
+    pub const NONE: Self = Self {
+        trigger: Trigger::None,
+        relay: 0,
+        action: 0,
+        pulse: 0,
+        time: 0,
+        expression: [0; EXPRESSION_SIZE],
+        cron: None,
+        solar: None,
+    };
+
+    /// Recurring entry, fired in each minute the cron expression matches
+    pub fn cron(relay: u16, action: Action, expression: &str) -> Result<Self> {
+        Self::NONE
+            .with(relay, action)
+            .parse(Trigger::Cron, expression)
+    }
+
+    /// Entry fired at a solar event with an offset, like `sunset+15`
+    pub fn solar(relay: u16, action: Action, expression: &str) -> Result<Self> {
+        Self::NONE
+            .with(relay, action)
+            .parse(Trigger::Sun, expression)
+    }
+
+    fn parse(mut self, trigger: Trigger, expression: &str) -> Result<Self> {
+        if expression.len() > EXPRESSION_SIZE || !expression.is_ascii() {
+            bail!("{expression:?} is not up to {EXPRESSION_SIZE} ASCII characters");
+        }
+        self.trigger = trigger;
+        self.expression[..expression.len()].copy_from_slice(expression.as_bytes());
+        match trigger {
+            Trigger::Cron => self.cron = Some(expression.parse()?),
+            _ => self.solar = Some(expression.parse()?),
+        }
+        Ok(self)
+    }
+
+    /// One-shot entry, fired at the UTC time in seconds since the epoch
+    pub fn once(relay: u16, action: Action, time: u32) -> Self {
+        let mut entry = Self::NONE.with(relay, action);
+        entry.trigger = Trigger::Once;
+        entry.time = time;
+        entry
+    }
+
+    fn with(mut self, relay: u16, action: Action) -> Self {
+        self.relay = relay;
+        (self.action, self.pulse) = match action {
+            Action::Off => (0, 0),
+            Action::On => (1, 0),
+            Action::Toggle => (2, 0),
+            Action::Pulse(duration) => (3, duration.as_millis() as _),
+        };
+        self
+    }
+
+    pub fn action(&self) -> Action {
+        match self.action {
+            0 => Action::Off,
+            1 => Action::On,
+            2 => Action::Toggle,
+            _ => Action::Pulse(Duration::from_millis(self.pulse as _)),
+        }
+    }
+
+    /// Parse the expression, an enabled entry has to be complete
+    fn validate(&mut self) -> Result<(), ExceptionCode> {
+        self.cron = None;
+        self.solar = None;
+        let complete = match self.trigger {
+            Trigger::None => return Ok(()),
+            Trigger::Cron | Trigger::Sun => {
+                let expression = str::from_utf8(&self.expression)
+                    .map_err(|_| ExceptionCode::IllegalDataValue)?
+                    .trim_end_matches('\0');
+                let parsed = match self.trigger {
+                    Trigger::Cron => expression.parse().map(|cron| self.cron = Some(cron)),
+                    _ => expression.parse().map(|solar| self.solar = Some(solar)),
+                };
+                if let Err(error) = &parsed {
+                    warn!("Expression {expression:?} ignored: {error}");
+                }
+                parsed.is_ok()
+            }
+            Trigger::Once => self.time != 0,
+        };
+        if complete && (self.action != 3 || self.pulse != 0) {
+            Ok(())
+        } else {
+            Err(ExceptionCode::IllegalDataValue)
+        }
+    }
+}
+
+/// Schedule evaluation
+///
+/// A relay is only switched when its schedule changes its mind, so a relay
+/// written over Modbus in between keeps its state until the next transition.
+/// After a reboot or a clock jump every scheduled relay is set to the state
+/// it should have right now. Entries only fire when their time comes while
+/// the clock runs, the ones missed by a reboot or a clock jump are skipped.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    states: [Option<bool>; RELAY_COUNT as _],
+    /// Wall clock and uptime of the previous tick
+    previous: Option<(i64, Duration)>,
+    /// Local minutes since 1970-01-01 of the previous tick
+    minute: Option<i64>,
+    /// The settings the next fire times were found with
+    settings: Option<Settings>,
+    /// Next fire time of the one-shot and solar entries, found again only
+    /// once they fire, the settings change or the clock jumps
+    next: [Option<Option<i64>>; ENTRY_COUNT],
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Evaluate the schedule at the UTC time in seconds since the epoch,
+    /// returns the actions to carry out
+    pub fn tick(&mut self, settings: &Settings, now: i64, uptime: Duration) -> Vec<(u16, Action)> {
+        let mut since = None;
+        if let Some((time, previous)) = self.previous {
+            let drift = (now - time) - uptime.saturating_sub(previous).as_secs() as i64;
+            if drift.abs() > MAX_DRIFT {
+                warn!("Clock jumped by {drift} s, catching up with the schedule");
+                self.states = [None; RELAY_COUNT as _];
+                self.next = [None; ENTRY_COUNT];
+            } else {
+                since = Some(time);
+            }
+        }
+        self.previous = Some((now, uptime));
+        if self.settings.as_ref() != Some(settings) {
+            self.settings = Some(*settings);
+            self.next = [None; ENTRY_COUNT];
+        }
+        let local = settings.timezone.local(now);
+        let mut actions = Vec::new();
+        for (relay, state) in settings.states(&local).into_iter().enumerate() {
+            if let Some(state) = state
+                && self.states[relay] != Some(state)
+            {
+                actions.push((relay as _, if state { Action::On } else { Action::Off }));
+            }
+            self.states[relay] = state;
+        }
+        let minute = local.days * MINUTES_PER_DAY as i64 + local.minute as i64;
+        if let Some(since) = since {
+            for (entry, next) in settings.entries.iter().zip(&mut self.next) {
+                let fire = match entry.trigger {
+                    Trigger::None => false,
+                    Trigger::Cron => {
+                        self.minute != Some(minute)
+                            && entry.cron.is_some_and(|cron| {
+                                settings
+                                    .calendar(entry.relay, local)
+                                    .is_some_and(|local| cron.matches(&local))
+                            })
+                    }
+                    Trigger::Once | Trigger::Sun => {
+                        let fire = next
+                            .get_or_insert_with(|| settings.next(entry, since))
+                            .is_some_and(|time| time <= now);
+                        if fire {
+                            *next = Some(settings.next(entry, now));
+                        }
+                        fire
+                    }
+                };
+                if fire {
+                    actions.push((entry.relay, entry.action()));
+                }
+            }
+        }
+        self.minute = Some(minute);
+        actions
+    }
+}
+
+/// The UTC time in seconds since the epoch, `None` until SNTP has set the
+/// clock
+pub(crate) fn now() -> Option<i64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64);
+    (now >= MIN_TIME).then_some(now)
+}
+
+/// The local date and time, `None` until SNTP has set the clock
+pub(crate) fn local() -> Option<LocalTime> {
+    let now = now()?;
+    Some(SETTINGS.read().unwrap().timezone.local(now))
+}
+
+/// Minutes since local midnight, `None` until SNTP has set the clock
+pub(crate) fn minute() -> Option<u16> {
+    local().map(|local| local.minute)
+}
+
+/// Start the relay scheduler, with the settings stored in NVS
+pub fn start(relay_sender: Sender<RelayRequest>) {
+    match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => {
+            let (values, _) = bytes.as_chunks();
+            let values: Vec<_> = values
+                .iter()
+                .map(|&value| u16::from_be_bytes(value))
+                .collect();
+            match settings(&Settings::DEFAULT, 0, &values) {
+                Ok(settings) => *SETTINGS.write().unwrap() = settings,
+                Err(exception) => warn!("Stored schedule ignored: {exception:?}"),
+            }
+        }
+        Ok(None) => {}
+        Err(error) => error!("{error:?}"),
+    }
+    spawn(async move {
+        let boot = Instant::now();
+        let mut scheduler = Scheduler::new();
+        loop {
+            sleep(PERIOD).await;
+            let Some(now) = now() else {
+                continue;
+            };
+            let settings = *SETTINGS.read().unwrap();
+            for (relay, action) in scheduler.tick(&settings, now, boot.elapsed()) {
+                info!("Relay {relay} scheduled: {action:?}");
+                if let Err(error) = execute(&relay_sender, relay, action).await {
+                    error!("{error:?}");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Carry out a scheduled action, a pulse switches the relay off again in the
+/// background
+async fn execute(relay_sender: &Sender<RelayRequest>, relay: u16, action: Action) -> Result<()> {
+    let state = match action {
+        Action::Off => false,
+        Action::On | Action::Pulse(_) => true,
+        Action::Toggle => relays(relay_sender, Mask::READ).await? & (1 << relay) == 0,
+    };
+    relays(relay_sender, Mask::write(relay, &[state])).await?;
+    if let Action::Pulse(duration) = action {
+        let relay_sender = relay_sender.clone();
+        spawn(async move {
+            sleep(duration).await;
+            info!("Relay {relay} pulse ended");
+            if let Err(error) = relays(&relay_sender, Mask::write(relay, &[false])).await {
+                error!("{error:?}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Apply the mask to the relays, returns the relay bitmask after it
+async fn relays(relay_sender: &Sender<RelayRequest>, mask: Mask) -> Result<u16> {
+    let (sender, receiver) = oneshot::channel();
+    relay_sender
+        .send((mask, sender))
+        .await
+        .map_err(|_| anyhow!("Relay task stopped"))?;
+    Ok(receiver.await?.1)
+}
+
+/// Restore the default settings
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn reset() {
+    *SETTINGS.write().unwrap() = Settings::DEFAULT;
+}
+
+/// Read the next fire time input registers
+pub(crate) fn read_next(address: u16, count: u16) -> Vec<u16> {
+    let settings = *SETTINGS.read().unwrap();
+    let now = now();
+    let values: Vec<_> = settings
+        .entries
+        .iter()
+        .flat_map(|entry| {
+            let next = now.and_then(|now| settings.next(entry, now)).unwrap_or(0) as u32;
+            [(next >> 16) as u16, next as u16]
+        })
+        .collect();
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// Read the schedule holding registers
+pub(crate) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    let values = registers(&SETTINGS.read().unwrap());
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// Write the schedule holding registers
+///
+/// All values are validated before any of them is applied, the settings are
+/// stored in NVS.
+pub(crate) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut settings = SETTINGS.write().unwrap();
+    let updated = self::settings(&settings, address, values)?;
+    if updated != *settings {
+        let bytes: Vec<_> = registers(&updated)
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        storage::save(STORAGE_KEY, &bytes).map_err(|error| {
+            error!("{error:?}");
+            ExceptionCode::ServerDeviceFailure
+        })?;
+        info!("Schedule: {updated:?}");
+    }
+    *settings = updated;
+    Ok(())
+}
+
+/// The register image of the settings
+fn registers(settings: &Settings) -> Vec<u16> {
+    let timezone = &settings.timezone;
+    let rule = |rule: &Rule| {
+        [
+            rule.month as _,
+            rule.week as _,
+            rule.weekday as _,
+            rule.minute,
+        ]
+    };
+    let mut values = vec![timezone.offset as _, timezone.dst as _];
+    values.extend(rule(&timezone.start));
+    values.extend(rule(&timezone.end));
+    values.extend([
+        settings.location.latitude as u16,
+        settings.location.longitude as u16,
+    ]);
+    values.resize(WINDOWS_REGISTER as _, 0);
+    for window in settings.windows.as_flattened() {
+        values.extend([window.days as _, window.on, window.off]);
+    }
+    for entry in &settings.entries {
+        values.extend([
+            entry.trigger as _,
+            entry.relay,
+            entry.action,
+            (entry.pulse >> 16) as _,
+            entry.pulse as _,
+            (entry.time >> 16) as _,
+            entry.time as _,
+            0,
+        ]);
+        let (expression, _) = entry.expression.as_chunks();
+        values.extend(expression.iter().map(|&chunk| u16::from_be_bytes(chunk)));
+    }
+    for calendar in &settings.calendars {
+        values.extend([calendar.mode as _, calendar.weekday as _, calendar.relays]);
+    }
+    for range in &settings.ranges {
+        values.extend([
+            range.calendar as _,
+            range.year,
+            u16::from_be_bytes([range.start.0, range.start.1]),
+            u16::from_be_bytes([range.end.0, range.end.1]),
+        ]);
+    }
+    values
+}
+
+/// Month and day of a date range
+fn valid((month, day): (u8, u8)) -> bool {
+    (1..=12).contains(&month) && (1..=31).contains(&day)
+}
+
+/// The settings with the registers written
+fn settings(settings: &Settings, address: u16, values: &[u16]) -> Result<Settings, ExceptionCode> {
+    let mut updated = *settings;
+    for (address, &value) in (address..).zip(values) {
+        match address {
+            TIMEZONE_REGISTER..LOCATION_REGISTER => {
+                let timezone = &mut updated.timezone;
+                let signed = value as i16;
+                match address - TIMEZONE_REGISTER {
+                    0 if (-720..=840).contains(&signed) => timezone.offset = signed,
+                    1 if (0..=120).contains(&signed) => timezone.dst = signed,
+                    offset @ 2..TIMEZONE_REGISTER_COUNT => {
+                        let rule = match offset {
+                            2..6 => &mut timezone.start,
+                            _ => &mut timezone.end,
+                        };
+                        match (offset - 2) % 4 {
+                            0 if (1..=12).contains(&value) => rule.month = value as _,
+                            1 if (1..=5).contains(&value) => rule.week = value as _,
+                            2 if value < 7 => rule.weekday = value as _,
+                            3 if value < MINUTES_PER_DAY => rule.minute = value,
+                            _ => return Err(ExceptionCode::IllegalDataValue),
+                        }
+                    }
+                    _ => return Err(ExceptionCode::IllegalDataValue),
+                }
+            }
+            LOCATION_REGISTER..WINDOWS_REGISTER => {
+                let location = &mut updated.location;
+                let signed = value as i16;
+                match address - LOCATION_REGISTER {
+                    0 if (-9000..=9000).contains(&signed) => location.latitude = signed,
+                    1 if (-18000..=18000).contains(&signed) => location.longitude = signed,
+                    // Reserved
+                    LOCATION_REGISTER_COUNT.. if value == 0 => {}
+                    _ => return Err(ExceptionCode::IllegalDataValue),
+                }
+            }
+            WINDOWS_REGISTER..ENTRIES_REGISTER => {
+                let offset = address - WINDOWS_REGISTER;
+                let window =
+                    &mut updated.windows.as_flattened_mut()[(offset / WINDOW_SIZE) as usize];
+                match offset % WINDOW_SIZE {
+                    0 if value < 0x80 => window.days = value as _,
+                    1 if value < MINUTES_PER_DAY => window.on = value,
+                    2 if value < MINUTES_PER_DAY => window.off = value,
+                    _ => return Err(ExceptionCode::IllegalDataValue),
+                }
+            }
+            ENTRIES_REGISTER..CALENDARS_REGISTER => {
+                let offset = address - ENTRIES_REGISTER;
+                let entry = &mut updated.entries[(offset / ENTRY_SIZE) as usize];
+                match offset % ENTRY_SIZE {
+                    0 => entry.trigger = value.try_into()?,
+                    1 if value < RELAY_COUNT => entry.relay = value,
+                    2 if value < 4 => entry.action = value,
+                    3 => entry.pulse = entry.pulse & 0xFFFF | (value as u32) << 16,
+                    4 => entry.pulse = entry.pulse & !0xFFFF | value as u32,
+                    5 => entry.time = entry.time & 0xFFFF | (value as u32) << 16,
+                    6 => entry.time = entry.time & !0xFFFF | value as u32,
+                    7 if value == 0 => {}
+                    offset @ 8.. => {
+                        let index = (offset - 8) as usize * 2;
+                        entry.expression[index..][..2].copy_from_slice(&value.to_be_bytes());
+                    }
+                    _ => return Err(ExceptionCode::IllegalDataValue),
+                }
+            }
+            CALENDARS_REGISTER..RANGES_REGISTER => {
+                let offset = address - CALENDARS_REGISTER;
+                let calendar = &mut updated.calendars[(offset / CALENDAR_SIZE) as usize];
+                match offset % CALENDAR_SIZE {
+                    0 => calendar.mode = value.try_into()?,
+                    1 if value < 7 => calendar.weekday = value as _,
+                    2 if value < 1 << RELAY_COUNT => calendar.relays = value,
+                    _ => return Err(ExceptionCode::IllegalDataValue),
+                }
+            }
+            _ => {
+                let offset = address - RANGES_REGISTER;
+                let range = &mut updated.ranges[(offset / RANGE_SIZE) as usize];
+                let date = ((value >> 8) as u8, value as u8);
+                match offset % RANGE_SIZE {
+                    0 if value <= CALENDAR_COUNT as u16 => range.calendar = value as _,
+                    1 if value == 0 || (1970..10000).contains(&value) => range.year = value,
+                    2 if valid(date) => range.start = date,
+                    3 if valid(date) => range.end = date,
+                    _ => return Err(ExceptionCode::IllegalDataValue),
+                }
+            }
+        }
+    }
+    for entry in &mut updated.entries {
+        entry.validate()?;
+    }
+    Ok(updated)
+}
+
+pub mod calendar;
+pub mod cron;
+pub mod sun;
+pub mod time;
@@ -0,0 +1,89 @@
+//! Holiday and exception calendars
+
+use super::time::LocalTime;
+use tokio_modbus::prelude::*;
+
+/// How a calendar treats its days
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    None = 0,
+    /// Nothing is scheduled
+    Suppress = 1,
+    /// Scheduled like another weekday
+    Weekday = 2,
+}
+
+impl TryFrom<u16> for Mode {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Suppress),
+            2 => Ok(Self::Weekday),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+/// Exception calendar, its days are the date ranges that refer to it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Calendar {
+    pub mode: Mode,
+    /// The weekday of [`Mode::Weekday`], 0 is Monday
+    pub weekday: u8,
+    /// Bitmask of the relays it applies to
+    pub relays: u16,
+}
+
+impl Calendar {
+    pub const NONE: Self = Self {
+        mode: Mode::None,
+        weekday: 0,
+        relays: 0,
+    };
+}
+
+/// Dates from `start` to `end`, both included, a range that ends before it
+/// starts runs into the next year
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Range {
+    /// Index of the calendar plus one, `0` for an unused range
+    pub calendar: u8,
+    /// `0` for every year
+    pub year: u16,
+    /// Month and day
+    pub start: (u8, u8),
+    /// Month and day
+    pub end: (u8, u8),
+}
+
+impl Range {
+    pub const NONE: Self = Self {
+        calendar: 0,
+        year: 0,
+        start: (1, 1),
+        end: (1, 1),
+    };
+
+    /// A single date
+    pub fn date(calendar: u8, year: u16, month: u8, day: u8) -> Self {
+        Self {
+            calendar,
+            year,
+            start: (month, day),
+            end: (month, day),
+        }
+    }
+
+    pub fn contains(&self, local: &LocalTime) -> bool {
+        let date = (local.month, local.day);
+        let year = self.year as i32;
+        if self.start <= self.end {
+            (self.year == 0 || local.year == year) && (self.start..=self.end).contains(&date)
+        } else {
+            (date >= self.start && (self.year == 0 || local.year == year))
+                || (date <= self.end && (self.year == 0 || local.year == year + 1))
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Cron expressions: minute, hour, day of month, month and day of week
+
+use super::time::{LocalTime, Timezone};
+use anyhow::{Error, Result, bail};
+use std::str::FromStr;
+
+/// Hours and minutes stepped through while looking for the next match, a bit
+/// more than a year
+const MAX_STEPS: usize = 400 * 24 + 60;
+
+/// Cron expression like `*/15 6-17 * * 1-5`
+///
+/// Fields are lists of values, ranges (`a-b`) and steps (`*/n`, `a-b/n`,
+/// `a/n`). Day of week 0 and 7 are Sunday. When both day of month and day of
+/// week are restricted, a day matching either of them matches, like Vixie
+/// cron.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Bit 0 is Monday, like [`LocalTime::weekday`]
+    weekdays: u64,
+    either: bool,
+}
+
+impl Cron {
+    /// The local time is in a matching minute
+    pub fn matches(&self, local: &LocalTime) -> bool {
+        self.hour(local) && self.minutes & (1 << (local.minute % 60)) != 0
+    }
+
+    fn hour(&self, local: &LocalTime) -> bool {
+        if self.months & (1 << local.month) == 0 || self.hours & (1 << (local.minute / 60)) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << local.day) != 0;
+        let weekday = self.weekdays & (1 << local.weekday) != 0;
+        if self.either {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// The UTC time of the first matching minute after `after`, `None` if
+    /// there is none within a year
+    pub fn next(&self, timezone: &Timezone, after: i64) -> Option<i64> {
+        self.next_with(timezone, after, Some)
+    }
+
+    /// [`Self::next`] with the local time adjusted by `calendar`, `None`
+    /// suppresses the time
+    pub fn next_with(
+        &self,
+        timezone: &Timezone,
+        after: i64,
+        calendar: impl Fn(LocalTime) -> Option<LocalTime>,
+    ) -> Option<i64> {
+        let mut time = after - after.rem_euclid(60) + 60;
+        for _ in 0..MAX_STEPS {
+            let local = timezone.local(time);
+            let Some(local) = calendar(local) else {
+                time += (60 - (local.minute % 60) as i64) * 60;
+                continue;
+            };
+            if !self.hour(&local) {
+                // Hours rather than days, so a daylight saving time shift
+                // does not skip one
+                time += (60 - (local.minute % 60) as i64) * 60;
+            } else if !self.matches(&local) {
+                time += 60;
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let fields: Vec<_> = expression.split_whitespace().collect();
+        let &[minutes, hours, days, months, weekdays] = &fields[..] else {
+            bail!("{expression:?} does not have 5 fields");
+        };
+        // Sunday is 0 or 7 in cron and bit 6 here
+        let sundays = field(weekdays, 0, 7)?;
+        Ok(Self {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: (sundays >> 1) & 0x3F | ((sundays | sundays >> 7) & 1) << 6,
+            either: !days.starts_with('*') && !weekdays.starts_with('*'),
+        })
+    }
+}
+
+/// Bitmask of the values of a field
+fn field(field: &str, min: u8, max: u8) -> Result<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse()?)),
+            None => (part, None),
+        };
+        let (start, end) = match (range.split_once('-'), step) {
+            _ if range == "*" => (min, max),
+            (Some((start, end)), _) => (start.parse()?, end.parse()?),
+            // `a/n` runs to the end of the range
+            (None, Some(_)) => (range.parse()?, max),
+            (None, None) => {
+                let value = range.parse()?;
+                (value, value)
+            }
+        };
+        let step = step.unwrap_or(1);
+        if step == 0 || start < min || end > max || start > end {
+            bail!("{part:?} is not within {min}-{max}");
+        }
+        for value in (start..=end).step_by(step) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
@@ -0,0 +1,170 @@
+//! Sunrise, sunset and twilight, after the NOAA solar calculator
+
+use super::time::Timezone;
+use anyhow::{Error, Result, bail};
+use std::str::FromStr;
+
+const SECONDS_PER_DAY: i64 = 86400;
+/// Largest offset from the event in minutes
+const MAX_OFFSET: i16 = 720;
+/// Days searched for the next event, polar nights and days last months
+const MAX_DAYS: i64 = 200;
+
+/// Position on earth
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Location {
+    /// Hundredths of a degree north
+    pub latitude: i16,
+    /// Hundredths of a degree east
+    pub longitude: i16,
+}
+
+impl Location {
+    pub const NONE: Self = Self {
+        latitude: 0,
+        longitude: 0,
+    };
+}
+
+/// Solar event
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    Sunrise,
+    Sunset,
+    CivilDawn,
+    CivilDusk,
+    NauticalDawn,
+    NauticalDusk,
+}
+
+impl Event {
+    /// Altitude of the center of the sun in degrees, sunrise and sunset
+    /// allow for refraction and the radius of the sun
+    fn altitude(self) -> f64 {
+        match self {
+            Self::Sunrise | Self::Sunset => -0.833,
+            Self::CivilDawn | Self::CivilDusk => -6.0,
+            Self::NauticalDawn | Self::NauticalDusk => -12.0,
+        }
+    }
+
+    fn rising(self) -> bool {
+        matches!(self, Self::Sunrise | Self::CivilDawn | Self::NauticalDawn)
+    }
+
+    /// UTC time in seconds since the epoch of the event on the day at the
+    /// location, `None` if the sun does not cross the altitude that day
+    ///
+    /// The day is days since 1970-01-01 in local mean solar time.
+    pub fn time(self, location: &Location, day: i64) -> Option<i64> {
+        let latitude = location.latitude as f64 / 100.0;
+        let longitude = location.longitude as f64 / 100.0;
+        // Mean solar noon, then twice at the time of the event
+        let mut time = (day * SECONDS_PER_DAY) as f64 + 43200.0 - longitude * 240.0;
+        for _ in 0..2 {
+            let (declination, equation) = sun(time);
+            let cos = (self.altitude().to_radians().sin()
+                - latitude.to_radians().sin() * declination.sin())
+                / (latitude.to_radians().cos() * declination.cos());
+            if !(-1.0..=1.0).contains(&cos) {
+                return None;
+            }
+            let hour_angle = cos.acos().to_degrees() * if self.rising() { -1.0 } else { 1.0 };
+            let noon = 720.0 - 4.0 * longitude - equation;
+            time = (day * SECONDS_PER_DAY) as f64 + (noon + 4.0 * hour_angle) * 60.0;
+        }
+        Some(time.round() as _)
+    }
+}
+
+impl FromStr for Event {
+    type Err = Error;
+
+    fn from_str(event: &str) -> Result<Self> {
+        Ok(match event {
+            "sunrise" => Self::Sunrise,
+            "sunset" => Self::Sunset,
+            "civil-dawn" => Self::CivilDawn,
+            "civil-dusk" => Self::CivilDusk,
+            "nautical-dawn" => Self::NauticalDawn,
+            "nautical-dusk" => Self::NauticalDusk,
+            _ => bail!("{event:?} is not a solar event"),
+        })
+    }
+}
+
+/// Declination of the sun in radians and the equation of time in minutes at
+/// the UTC time in seconds since the epoch
+fn sun(time: f64) -> (f64, f64) {
+    // Julian centuries since J2000.0
+    let t = (time / SECONDS_PER_DAY as f64 + 2440587.5 - 2451545.0) / 36525.0;
+    let mean_longitude = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0);
+    let mean_anomaly = (357.52911 + t * (35999.05029 - 0.0001537 * t)).to_radians();
+    let eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t);
+    let center = mean_anomaly.sin() * (1.914602 - t * (0.004817 + 0.000014 * t))
+        + (2.0 * mean_anomaly).sin() * (0.019993 - 0.000101 * t)
+        + (3.0 * mean_anomaly).sin() * 0.000289;
+    let omega = (125.04 - 1934.136 * t).to_radians();
+    let apparent_longitude =
+        (mean_longitude + center - 0.00569 - 0.00478 * omega.sin()).to_radians();
+    let mean_obliquity =
+        23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+    let declination = (obliquity.sin() * apparent_longitude.sin()).asin();
+    let y = (obliquity / 2.0).tan().powi(2);
+    let l0 = mean_longitude.to_radians();
+    let equation = y * (2.0 * l0).sin() - 2.0 * eccentricity * mean_anomaly.sin()
+        + 4.0 * eccentricity * y * mean_anomaly.sin() * (2.0 * l0).cos()
+        - 0.5 * y * y * (4.0 * l0).sin()
+        - 1.25 * eccentricity * eccentricity * (2.0 * mean_anomaly).sin();
+    (declination, 4.0 * equation.to_degrees())
+}
+
+/// Solar trigger like `sunset+15` or `sunrise -10`: the event and an offset
+/// in minutes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Solar {
+    pub event: Event,
+    pub offset: i16,
+}
+
+impl Solar {
+    /// The UTC time of the first trigger after `after`, `None` if there is
+    /// none within the search range
+    pub fn next(&self, timezone: &Timezone, location: &Location, after: i64) -> Option<i64> {
+        // The previous day's event may still be ahead with a large offset
+        let today = timezone.local(after).days;
+        (today - 1..today + MAX_DAYS)
+            .filter_map(|day| self.event.time(location, day))
+            .map(|time| time + self.offset as i64 * 60)
+            .find(|&time| time > after)
+    }
+}
+
+impl FromStr for Solar {
+    type Err = Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let expression: String = expression.split_whitespace().collect();
+        let (event, offset) = match expression.rfind(['+', '-']) {
+            // Events have dashes too, offsets start with a digit
+            Some(index)
+                if expression[index + 1..]
+                    .starts_with(|character: char| character.is_ascii_digit()) =>
+            {
+                (
+                    &expression[..index],
+                    expression[index..].trim_start_matches('+').parse()?,
+                )
+            }
+            _ => (&expression[..], 0),
+        };
+        if !(-MAX_OFFSET..=MAX_OFFSET).contains(&offset) {
+            bail!("{offset} is not within ±{MAX_OFFSET} minutes");
+        }
+        Ok(Self {
+            event: event.parse()?,
+            offset,
+        })
+    }
+}
@@ -0,0 +1,152 @@
+//! Civil calendar and POSIX style timezone rules, without the C library
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// Local date and time
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LocalTime {
+    pub year: i32,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    /// 0 is Monday, 6 is Sunday
+    pub weekday: u8,
+    /// Minutes since midnight
+    pub minute: u16,
+    pub second: u8,
+    /// Days since 1970-01-01
+    pub days: i64,
+}
+
+/// Daylight saving time transition: the `week`th `weekday` of `month` at
+/// `minute` local time, like `Mm.w.d/time` in a POSIX `TZ`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rule {
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 5, 5 is the last one of the month
+    pub week: u8,
+    /// 0 is Monday, 6 is Sunday
+    pub weekday: u8,
+    /// Minutes since midnight, in the local time before the transition
+    pub minute: u16,
+}
+
+impl Rule {
+    /// Days since 1970-01-01 of the transition in `year`
+    fn days(&self, year: i32) -> i64 {
+        let first = days_from_civil(year, self.month, 1);
+        let mut day = first + (self.weekday as i64 - weekday(first) as i64).rem_euclid(7);
+        day += 7 * (self.week as i64 - 1);
+        let (next_year, next_month) = match self.month {
+            12 => (year + 1, 1),
+            month => (year, month + 1),
+        };
+        while day >= days_from_civil(next_year, next_month, 1) {
+            day -= 7;
+        }
+        day
+    }
+}
+
+/// Timezone with optional daylight saving time
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Timezone {
+    /// Standard time offset east of UTC in minutes
+    pub offset: i16,
+    /// Daylight saving time shift in minutes, `0` for none
+    pub dst: i16,
+    pub start: Rule,
+    pub end: Rule,
+}
+
+impl Timezone {
+    pub const UTC: Self = Self {
+        offset: 0,
+        dst: 0,
+        start: Rule {
+            month: 3,
+            week: 5,
+            weekday: 6,
+            minute: 60,
+        },
+        end: Rule {
+            month: 10,
+            week: 5,
+            weekday: 6,
+            minute: 60,
+        },
+    };
+
+    /// Offset east of UTC in seconds at the UTC time
+    pub fn offset(&self, utc: i64) -> i64 {
+        let standard = self.offset as i64 * 60;
+        if self.dst == 0 {
+            return standard;
+        }
+        let year = civil_from_days((utc + standard).div_euclid(SECONDS_PER_DAY)).0;
+        // Transitions are given in the local time in effect before them
+        let start =
+            self.start.days(year) * SECONDS_PER_DAY + self.start.minute as i64 * 60 - standard;
+        let end = self.end.days(year) * SECONDS_PER_DAY + self.end.minute as i64 * 60
+            - standard
+            - self.dst as i64 * 60;
+        let summer = if start < end {
+            (start..end).contains(&utc)
+        } else {
+            // Southern hemisphere
+            utc >= start || utc < end
+        };
+        standard + if summer { self.dst as i64 * 60 } else { 0 }
+    }
+
+    /// Local time at the UTC time in seconds since the epoch
+    pub fn local(&self, utc: i64) -> LocalTime {
+        let local = utc + self.offset(utc);
+        let days = local.div_euclid(SECONDS_PER_DAY);
+        let seconds = local.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        LocalTime {
+            year,
+            month,
+            day,
+            weekday: weekday(days),
+            minute: (seconds / 60) as _,
+            second: (seconds % 60) as _,
+            days,
+        }
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+pub fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Proleptic Gregorian date of the days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i32, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year as _, month as _, day as _)
+}
+
+/// Weekday of the days since 1970-01-01, 0 is Monday
+pub fn weekday(days: i64) -> u8 {
+    // 1970-01-01 was a Thursday
+    (days + 3).rem_euclid(7) as _
+}
@@ -0,0 +1,529 @@
+//! Relay sequences: steps that switch relays, wait and wait for inputs
+
+use crate::{
+    input,
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+    storage,
+};
+use log::{error, info, warn};
+use std::sync::{Mutex, RwLock};
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, Instant, sleep},
+};
+use tokio_modbus::prelude::*;
+
+/// Input polling and abort check period, times are set in tenths of a second
+const PERIOD: Duration = Duration::from_millis(100);
+
+const STORAGE_KEY: &str = "sequences";
+
+pub(crate) const SEQUENCE_COUNT: u16 = 4;
+const STEP_COUNT: u16 = 16;
+/// Holding registers per step: kind, relay or input, state or comparison,
+/// value, time, step to jump to on timeout
+const STEP_SIZE: u16 = 6;
+/// Holding registers per sequence
+const SEQUENCE_SIZE: u16 = STEP_COUNT * STEP_SIZE;
+pub(crate) const REGISTER_COUNT: u16 = SEQUENCE_COUNT * SEQUENCE_SIZE;
+/// Holding registers that start and abort the sequences, one per sequence
+pub(crate) const COMMAND_REGISTER_COUNT: u16 = SEQUENCE_COUNT;
+/// Input registers per sequence: state, step, abort reason
+const STATUS_SIZE: u16 = 3;
+pub(crate) const STATUS_REGISTER_COUNT: u16 = SEQUENCE_COUNT * STATUS_SIZE;
+
+static SEQUENCES: RwLock<[[Step; STEP_COUNT as _]; SEQUENCE_COUNT as _]> =
+    RwLock::new([[Step::END; STEP_COUNT as _]; SEQUENCE_COUNT as _]);
+static STATUS: Mutex<[Status; SEQUENCE_COUNT as _]> =
+    Mutex::new([Status::IDLE; SEQUENCE_COUNT as _]);
+
+/// What a step does
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    /// The sequence has finished
+    End = 0,
+    /// Switch the relay to the state
+    Set = 1,
+    /// Wait for the time
+    Wait = 2,
+    /// Wait until the input compares to the value, at most for the time
+    Until = 3,
+}
+
+impl TryFrom<u16> for Kind {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::End),
+            1 => Ok(Self::Set),
+            2 => Ok(Self::Wait),
+            3 => Ok(Self::Until),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+/// How an input compares to the value of an until step
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Compare {
+    Equal = 0,
+    NotEqual = 1,
+    /// Signed, like a temperature
+    Less = 2,
+    /// Signed, like a temperature
+    Greater = 3,
+}
+
+impl TryFrom<u16> for Compare {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Equal),
+            1 => Ok(Self::NotEqual),
+            2 => Ok(Self::Less),
+            3 => Ok(Self::Greater),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+/// Sequence step
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Step {
+    kind: Kind,
+    /// Relay of a set step, virtual input of an until step
+    index: u16,
+    /// State of a set step, [`Compare`] of an until step
+    mode: u16,
+    value: i16,
+    /// Tenths of a second
+    time: u16,
+    /// Step plus one an until step jumps to when its time is up, `0` aborts
+    /// the sequence
+    fail: u16,
+}
+
+impl Step {
+    const END: Self = Self {
+        kind: Kind::End,
+        index: 0,
+        mode: 0,
+        value: 0,
+        time: 0,
+        fail: 0,
+    };
+
+    fn valid(&self) -> bool {
+        match self.kind {
+            Kind::End | Kind::Wait => true,
+            Kind::Set => self.index < RELAY_COUNT && self.mode <= 1,
+            // A time keeps a step that jumps to itself from spinning
+            Kind::Until => {
+                self.index < input::COUNT
+                    && Compare::try_from(self.mode).is_ok()
+                    && self.time != 0
+                    && self.fail <= STEP_COUNT
+            }
+        }
+    }
+
+    /// The condition of an until step is met, an input without a fresh value
+    /// never meets it
+    fn condition(&self) -> bool {
+        let Some(value) = input::value(self.index) else {
+            return false;
+        };
+        let value = value as i16;
+        match Compare::try_from(self.mode) {
+            Ok(Compare::Equal) => value == self.value,
+            Ok(Compare::NotEqual) => value != self.value,
+            Ok(Compare::Less) => value < self.value,
+            Ok(Compare::Greater) => value > self.value,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Sequence state
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Idle = 0,
+    Running = 1,
+    Finished = 2,
+    Aborted = 3,
+}
+
+/// Why a sequence was aborted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Reason {
+    None = 0,
+    /// Aborted over Modbus
+    Command = 1,
+    /// An until step's time was up and it does not jump
+    Timeout = 2,
+    /// The relay task has stopped
+    Relay = 3,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Status {
+    state: State,
+    /// The running step, or the one it finished or was aborted at
+    step: u16,
+    reason: Reason,
+    /// How many times the sequence has been started, a task whose run is
+    /// over stops
+    run: u32,
+}
+
+impl Status {
+    const IDLE: Self = Self {
+        state: State::Idle,
+        step: 0,
+        reason: Reason::None,
+        run: 0,
+    };
+}
+
+/// Load the sequences stored in NVS
+pub fn load() {
+    match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => {
+            let (values, _) = bytes.as_chunks();
+            let values: Vec<_> = values
+                .iter()
+                .map(|&value| u16::from_be_bytes(value))
+                .collect();
+            let empty = [[Step::END; STEP_COUNT as _]; SEQUENCE_COUNT as _];
+            match sequences(&empty, 0, &values) {
+                Ok(sequences) => *SEQUENCES.write().unwrap() = sequences,
+                Err(exception) => warn!("Stored sequences ignored: {exception:?}"),
+            }
+        }
+        Ok(None) => {}
+        Err(error) => error!("{error:?}"),
+    }
+}
+
+/// Read the sequence holding registers
+pub(crate) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    let values = registers(&SEQUENCES.read().unwrap());
+    values[address as usize..][..count as usize].to_vec()
+}
+
+/// Write the sequence holding registers
+///
+/// All values are validated before any of them is applied, the sequences are
+/// stored in NVS. A running sequence keeps the steps it was started with.
+pub(crate) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut sequences = SEQUENCES.write().unwrap();
+    let updated = self::sequences(&sequences, address, values)?;
+    if updated != *sequences {
+        let bytes: Vec<_> = registers(&updated)
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        storage::save(STORAGE_KEY, &bytes).map_err(|error| {
+            error!("{error:?}");
+            ExceptionCode::ServerDeviceFailure
+        })?;
+        for (index, (steps, previous)) in updated.iter().zip(sequences.iter()).enumerate() {
+            if steps != previous {
+                info!("Sequence {index}: {steps:?}");
+            }
+        }
+    }
+    *sequences = updated;
+    Ok(())
+}
+
+/// The register image of the sequences
+fn registers(sequences: &[[Step; STEP_COUNT as _]; SEQUENCE_COUNT as _]) -> Vec<u16> {
+    sequences
+        .as_flattened()
+        .iter()
+        .flat_map(|step| {
+            [
+                step.kind as _,
+                step.index,
+                step.mode,
+                step.value as _,
+                step.time,
+                step.fail,
+            ]
+        })
+        .collect()
+}
+
+/// The sequences with the registers written
+fn sequences(
+    sequences: &[[Step; STEP_COUNT as _]; SEQUENCE_COUNT as _],
+    address: u16,
+    values: &[u16],
+) -> Result<[[Step; STEP_COUNT as _]; SEQUENCE_COUNT as _], ExceptionCode> {
+    let mut updated = *sequences;
+    let steps = updated.as_flattened_mut();
+    for (address, &value) in (address..).zip(values) {
+        let step = &mut steps[(address / STEP_SIZE) as usize];
+        match address % STEP_SIZE {
+            0 => step.kind = value.try_into()?,
+            1 => step.index = value,
+            2 => step.mode = value,
+            3 => step.value = value as _,
+            4 => step.time = value,
+            _ => step.fail = value,
+        }
+    }
+    if !steps.iter().all(Step::valid) {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    Ok(updated)
+}
+
+/// Abort the running sequences and restore the empty ones
+///
+/// Host builds only, for the fuzz target and the tests.
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn reset() {
+    *SEQUENCES.write().unwrap() = [[Step::END; STEP_COUNT as _]; SEQUENCE_COUNT as _];
+    for status in STATUS.lock().unwrap().iter_mut() {
+        *status = Status {
+            run: status.run.wrapping_add(1),
+            ..Status::IDLE
+        };
+    }
+}
+
+/// The relays the sequences started by a write of the command registers
+/// switch
+pub(crate) fn relays(address: u16, values: &[u16]) -> u16 {
+    !steps(address, values).and
+}
+
+/// Validate a write of the command registers, returns the mask of the set
+/// steps of the sequences it starts
+///
+/// A relay is on in the mask if any of the steps switches it on, so the
+/// permissives and the power budget are checked for it.
+pub(crate) fn mask(address: u16, values: &[u16]) -> Result<Mask, ExceptionCode> {
+    validate(&*STATUS.lock().unwrap(), address, values)?;
+    Ok(steps(address, values))
+}
+
+/// The set steps of the sequences started by a write of the command
+/// registers
+fn steps(address: u16, values: &[u16]) -> Mask {
+    let sequences = SEQUENCES.read().unwrap();
+    (address..)
+        .zip(values)
+        .filter(|&(_, &value)| value == 1)
+        .flat_map(|(sequence, _)| &sequences[sequence as usize])
+        .filter(|step| step.kind == Kind::Set)
+        .fold(Mask::READ, |mask, step| Mask {
+            and: mask.and & !(1 << step.index),
+            or: mask.or | step.mode << step.index,
+        })
+}
+
+/// A write of the command registers is valid, it does not start a running
+/// sequence again
+fn validate(status: &[Status], address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    for (sequence, &value) in (address..).zip(values) {
+        match value {
+            0 => {}
+            1 if status[sequence as usize].state == State::Running => {
+                return Err(ExceptionCode::ServerDeviceBusy);
+            }
+            1 => {}
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+    Ok(())
+}
+
+/// Read the command holding registers, `1` while the sequence runs
+pub(crate) fn read_commands(address: u16, count: u16) -> Vec<u16> {
+    let status = STATUS.lock().unwrap();
+    status[address as usize..][..count as usize]
+        .iter()
+        .map(|status| (status.state == State::Running) as _)
+        .collect()
+}
+
+/// Write the command holding registers: `1` starts the sequence, `0` aborts
+/// it
+///
+/// All values are validated before any sequence is started or aborted, a
+/// running sequence is not started again.
+pub(crate) fn command(
+    relay_sender: &Sender<RelayRequest>,
+    address: u16,
+    values: &[u16],
+) -> Result<(), ExceptionCode> {
+    let mut status = STATUS.lock().unwrap();
+    validate(&*status, address, values)?;
+    let sequences = SEQUENCES.read().unwrap();
+    for (sequence, &value) in (address..).zip(values) {
+        let status = &mut status[sequence as usize];
+        match value {
+            0 if status.state == State::Running => {
+                warn!("Sequence {sequence} aborted at step {}", status.step);
+                *status = Status {
+                    state: State::Aborted,
+                    reason: Reason::Command,
+                    run: status.run.wrapping_add(1),
+                    ..*status
+                };
+            }
+            0 => {}
+            _ => {
+                info!("Sequence {sequence} started");
+                *status = Status {
+                    state: State::Running,
+                    step: 0,
+                    reason: Reason::None,
+                    run: status.run.wrapping_add(1),
+                };
+                spawn(run(
+                    relay_sender.clone(),
+                    sequence,
+                    status.run,
+                    sequences[sequence as usize],
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the status input registers
+pub(crate) fn read_status(address: u16, count: u16) -> Vec<u16> {
+    let status = STATUS.lock().unwrap();
+    (address..address + count)
+        .map(|address| {
+            let status = &status[(address / STATUS_SIZE) as usize];
+            match address % STATUS_SIZE {
+                0 => status.state as _,
+                1 => status.step,
+                _ => status.reason as _,
+            }
+        })
+        .collect()
+}
+
+/// Run the steps of a sequence until it ends, is aborted or started again
+async fn run(
+    relay_sender: Sender<RelayRequest>,
+    sequence: u16,
+    run: u32,
+    steps: [Step; STEP_COUNT as _],
+) {
+    let mut index = 0;
+    let result = loop {
+        if !progress(sequence, run, index) {
+            return;
+        }
+        let Some(step) = steps.get(index as usize) else {
+            break Ok(());
+        };
+        let time = Duration::from_millis(step.time as u64 * 100);
+        match step.kind {
+            Kind::End => break Ok(()),
+            Kind::Set => {
+                let state = step.mode != 0;
+                info!(
+                    "Sequence {sequence} step {index}: relay {} {state}",
+                    step.index
+                );
+                let (sender, receiver) = oneshot::channel();
+                if relay_sender
+                    .send((Mask::write(step.index, &[state]), sender))
+                    .await
+                    .is_err()
+                    || receiver.await.is_err()
+                {
+                    break Err(Reason::Relay);
+                }
+                index += 1;
+            }
+            Kind::Wait => {
+                if wait(sequence, run, Instant::now() + time, || false)
+                    .await
+                    .is_none()
+                {
+                    return;
+                }
+                index += 1;
+            }
+            Kind::Until => {
+                let deadline = Instant::now() + time;
+                let Some(met) = wait(sequence, run, deadline, || step.condition()).await else {
+                    return;
+                };
+                if met {
+                    index += 1;
+                } else if step.fail != 0 {
+                    warn!(
+                        "Sequence {sequence} step {index} timed out, jump to step {}",
+                        step.fail - 1
+                    );
+                    index = step.fail - 1;
+                } else {
+                    break Err(Reason::Timeout);
+                }
+            }
+        }
+    };
+    let mut status = STATUS.lock().unwrap();
+    let status = &mut status[sequence as usize];
+    if status.run != run {
+        return;
+    }
+    match result {
+        Ok(()) => {
+            info!("Sequence {sequence} finished");
+            status.state = State::Finished;
+        }
+        Err(reason) => {
+            warn!("Sequence {sequence} aborted at step {index}: {reason:?}");
+            status.state = State::Aborted;
+            status.reason = reason;
+        }
+    }
+}
+
+/// Record the step the sequence is at, `false` if the run is over
+fn progress(sequence: u16, run: u32, step: u16) -> bool {
+    let mut status = STATUS.lock().unwrap();
+    let status = &mut status[sequence as usize];
+    if status.run != run {
+        return false;
+    }
+    status.step = step;
+    true
+}
+
+/// Wait until the deadline or the condition is met, returns whether it is
+/// met, `None` if the run is over
+async fn wait(
+    sequence: u16,
+    run: u32,
+    deadline: Instant,
+    condition: impl Fn() -> bool,
+) -> Option<bool> {
+    loop {
+        if condition() {
+            return Some(true);
+        }
+        if Instant::now() >= deadline {
+            return Some(false);
+        }
+        sleep(PERIOD.min(deadline - Instant::now())).await;
+        if STATUS.lock().unwrap()[sequence as usize].run != run {
+            return None;
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::Result;
+#[cfg(target_os = "espidf")]
+use anyhow::anyhow;
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+#[cfg(target_os = "espidf")]
+use log::info;
+#[cfg(not(target_os = "espidf"))]
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+#[cfg(target_os = "espidf")]
+use std::sync::OnceLock;
+
+#[cfg(target_os = "espidf")]
+const NAMESPACE: &str = "relay";
+
+#[cfg(target_os = "espidf")]
+static NVS: OnceLock<Mutex<EspNvs<NvsDefault>>> = OnceLock::new();
+
+/// In memory storage for host builds
+#[cfg(not(target_os = "espidf"))]
+static MEMORY: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+#[cfg(target_os = "espidf")]
+pub fn init(partition: EspDefaultNvsPartition) -> Result<()> {
+    let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    NVS.set(Mutex::new(nvs))
+        .map_err(|_| anyhow!("storage is already initialized"))?;
+    info!("Storage initialized");
+    Ok(())
+}
+
+/// Load a blob, `None` if it has never been saved
+#[cfg(target_os = "espidf")]
+pub(crate) fn load(key: &str) -> Result<Option<Vec<u8>>> {
+    let nvs = nvs()?.lock().unwrap();
+    let Some(length) = nvs.blob_len(key)? else {
+        return Ok(None);
+    };
+    let mut buffer = vec![0; length];
+    Ok(nvs.get_blob(key, &mut buffer)?.map(<[u8]>::to_vec))
+}
+
+/// Load a blob, `None` if it has never been saved
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn load(key: &str) -> Result<Option<Vec<u8>>> {
+    Ok(MEMORY.lock().unwrap().get(key).cloned())
+}
+
+/// Save a blob
+#[cfg(target_os = "espidf")]
+pub(crate) fn save(key: &str, value: &[u8]) -> Result<()> {
+    nvs()?.lock().unwrap().set_blob(key, value)?;
+    Ok(())
+}
+
+/// Save a blob
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn save(key: &str, value: &[u8]) -> Result<()> {
+    MEMORY
+        .lock()
+        .unwrap()
+        .insert(key.to_owned(), value.to_vec());
+    Ok(())
+}
+
+/// Erase all blobs
+///
+/// Host builds only, for the fuzz target and the tests.
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn clear() {
+    MEMORY.lock().unwrap().clear();
+}
+
+#[cfg(target_os = "espidf")]
+fn nvs() -> Result<&'static Mutex<EspNvs<NvsDefault>>> {
+    NVS.get()
+        .ok_or_else(|| anyhow!("storage is not initialized"))
+}
@@ -0,0 +1,256 @@
+//! Timed relay commands: on or off for a while, then back
+
+use crate::{
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+    schedule, storage,
+};
+use log::{error, info, warn};
+use std::sync::Mutex;
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, Instant, sleep},
+};
+use tokio_modbus::prelude::*;
+
+const PERIOD: Duration = Duration::from_millis(100);
+
+const STORAGE_KEY: &str = "timed";
+
+/// Holding registers per relay: state, seconds
+const ENTRY_SIZE: u16 = 2;
+pub(crate) const REGISTER_COUNT: u16 = RELAY_COUNT * ENTRY_SIZE;
+
+static TIMED: Mutex<[Timed; RELAY_COUNT as _]> = Mutex::new([Timed::NONE; RELAY_COUNT as _]);
+
+/// Timed command of a relay
+#[derive(Clone, Copy, Debug)]
+struct Timed {
+    /// State of the next command
+    state: bool,
+    command: Option<Command>,
+}
+
+impl Timed {
+    const NONE: Self = Self {
+        state: true,
+        command: None,
+    };
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Command {
+    /// State while the command runs, the relay gets the opposite one at the
+    /// end
+    state: bool,
+    until: Instant,
+    /// UTC time in seconds since the epoch the command ends, `None` if the
+    /// clock was not set when it started, it does not survive a reboot then
+    deadline: Option<i64>,
+}
+
+/// Write of the registers
+pub(crate) struct Commands {
+    /// Relay and state of the next command
+    states: Vec<(u16, bool)>,
+    /// Relay, state and duration, a zero duration ends the command
+    commands: Vec<(u16, bool, u16)>,
+}
+
+/// Start ending the timed commands, the ones stored in NVS resume once the
+/// clock is set
+pub fn start(relay_sender: Sender<RelayRequest>) {
+    spawn(async move {
+        let mut restored = false;
+        loop {
+            sleep(PERIOD).await;
+            let mut switches = Vec::new();
+            if !restored && let Some(now) = schedule::now() {
+                switches = restore(now);
+                restored = true;
+            }
+            switches.extend(expire());
+            for (relay, state) in switches {
+                let (sender, receiver) = oneshot::channel();
+                if relay_sender
+                    .send((Mask::write(relay, &[state]), sender))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                let _ = receiver.await;
+            }
+        }
+    });
+}
+
+/// Resume the commands stored in NVS, the ones that ended while the device
+/// was down end now
+fn restore(now: i64) -> Vec<(u16, bool)> {
+    let bytes = match storage::load(STORAGE_KEY) {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return Vec::new(),
+        Err(error) => {
+            error!("{error:?}");
+            return Vec::new();
+        }
+    };
+    let mut timed = TIMED.lock().unwrap();
+    let mut switches = Vec::new();
+    let (entries, _) = bytes.as_chunks::<9>();
+    for (relay, (timed, entry)) in timed.iter_mut().zip(entries).enumerate() {
+        let state = entry[0] != 0;
+        let deadline = i64::from_be_bytes(entry[1..].try_into().unwrap());
+        if timed.command.is_some() || deadline == 0 {
+            continue;
+        }
+        if deadline > now {
+            info!(
+                "Relay {relay} timed command resumed: {state} for {} s",
+                deadline - now
+            );
+            timed.command = Some(Command {
+                state,
+                until: Instant::now() + Duration::from_secs((deadline - now) as _),
+                deadline: Some(deadline),
+            });
+            switches.push((relay as _, state));
+        } else {
+            warn!("Relay {relay} timed command ended while the device was down");
+            switches.push((relay as _, !state));
+        }
+    }
+    save(&*timed);
+    switches
+}
+
+/// End the commands whose time is up
+fn expire() -> Vec<(u16, bool)> {
+    let mut timed = TIMED.lock().unwrap();
+    let mut switches = Vec::new();
+    for (relay, timed) in timed.iter_mut().enumerate() {
+        if let Some(command) = timed
+            .command
+            .take_if(|command| command.until <= Instant::now())
+        {
+            info!("Relay {relay} timed command ended");
+            switches.push((relay as _, !command.state));
+        }
+    }
+    if !switches.is_empty() {
+        save(&*timed);
+    }
+    switches
+}
+
+/// Store the running commands in NVS, 9 bytes per relay: state and
+/// deadline, `0` for none
+fn save(timed: &[Timed]) {
+    let bytes: Vec<_> = timed
+        .iter()
+        .flat_map(|timed| {
+            let (state, deadline) = timed
+                .command
+                .map_or((false, None), |command| (command.state, command.deadline));
+            let mut entry = vec![state as u8];
+            entry.extend(deadline.unwrap_or(0).to_be_bytes());
+            entry
+        })
+        .collect();
+    if let Err(error) = storage::save(STORAGE_KEY, &bytes) {
+        error!("{error:?}");
+    }
+}
+
+/// Forget the running commands like a reboot does, the stored ones resume
+/// with the next start
+///
+/// Host builds only, for the fuzz target and the tests.
+#[cfg(not(target_os = "espidf"))]
+pub fn reset() {
+    *TIMED.lock().unwrap() = [Timed::NONE; RELAY_COUNT as _];
+}
+
+/// The relays the registers refer to
+pub(crate) fn relays(address: u16, count: u16) -> u16 {
+    (address / ENTRY_SIZE..(address + count).div_ceil(ENTRY_SIZE))
+        .fold(0, |relays, relay| relays | 1 << relay)
+}
+
+/// Read the timed command holding registers, the seconds are the remaining
+/// time
+pub(crate) fn read(address: u16, count: u16) -> Vec<u16> {
+    let timed = TIMED.lock().unwrap();
+    (address..address + count)
+        .map(|address| {
+            let timed = &timed[(address / ENTRY_SIZE) as usize];
+            match address % ENTRY_SIZE {
+                0 => timed.state as _,
+                _ => timed.command.map_or(0, |command| {
+                    let remaining = command.until.saturating_duration_since(Instant::now());
+                    remaining.as_millis().div_ceil(1000).min(u16::MAX as _) as _
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Validate a write of the timed command holding registers, returns the
+/// commands and the mask that starts or ends them
+///
+/// A state written on its own is kept for the next command.
+pub(crate) fn write(address: u16, values: &[u16]) -> Result<(Commands, Mask), ExceptionCode> {
+    let timed = TIMED.lock().unwrap();
+    let mut states = timed.map(|timed| timed.state);
+    let mut commands = Commands {
+        states: Vec::new(),
+        commands: Vec::new(),
+    };
+    for (address, &value) in (address..).zip(values) {
+        let relay = address / ENTRY_SIZE;
+        match address % ENTRY_SIZE {
+            0 if value <= 1 => {
+                states[relay as usize] = value != 0;
+                commands.states.push((relay, value != 0));
+            }
+            1 => commands
+                .commands
+                .push((relay, states[relay as usize], value)),
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+    let mut mask = Mask::READ;
+    for &(relay, state, seconds) in &commands.commands {
+        // A command that is ended switches to its end state, if there is one
+        let state = match (seconds, timed[relay as usize].command) {
+            (0, None) => continue,
+            (0, Some(command)) => !command.state,
+            _ => state,
+        };
+        let write = Mask::write(relay, &[state]);
+        mask.and &= write.and;
+        mask.or |= write.or;
+    }
+    Ok((commands, mask))
+}
+
+/// Keep the states and run the commands once their mask has been applied
+pub(crate) fn command(commands: &Commands) {
+    let mut timed = TIMED.lock().unwrap();
+    for &(relay, state) in &commands.states {
+        timed[relay as usize].state = state;
+    }
+    for &(relay, state, seconds) in &commands.commands {
+        timed[relay as usize].command = (seconds != 0).then(|| Command {
+            state,
+            until: Instant::now() + Duration::from_secs(seconds as _),
+            deadline: schedule::now().map(|now| now + seconds as i64),
+        });
+        match seconds {
+            0 => info!("Relay {relay} timed command ended"),
+            _ => info!("Relay {relay} timed command: {state} for {seconds} s"),
+        }
+    }
+    save(&*timed);
+}
@@ -0,0 +1,312 @@
+//! Timer relay functions: on-delay, off-delay, interval and flasher
+
+use crate::{
+    input,
+    relay::{COUNT as RELAY_COUNT, Mask, Request as RelayRequest},
+};
+use log::info;
+use std::sync::{LazyLock, Mutex, RwLock};
+use tokio::{
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+    time::{Duration, Instant, sleep},
+};
+use tokio_modbus::prelude::*;
+
+/// Timer resolution, times are set in tenths of a second
+const PERIOD: Duration = Duration::from_millis(100);
+
+/// Holding registers per relay: mode, trigger, time 1, time 2
+const ENTRY_SIZE: u16 = 4;
+pub(crate) const REGISTER_COUNT: u16 = RELAY_COUNT * ENTRY_SIZE;
+/// Input registers per relay: remaining time
+pub(crate) const REMAINING_REGISTER_COUNT: u16 = RELAY_COUNT;
+
+static SETTINGS: RwLock<[Settings; RELAY_COUNT as _]> =
+    RwLock::new([Settings::NONE; RELAY_COUNT as _]);
+static TIMERS: Mutex<[Timer; RELAY_COUNT as _]> = Mutex::new([Timer::NEW; RELAY_COUNT as _]);
+/// Control signals written to the coils of coil triggered timers, bit `n` is
+/// relay `n`
+static COILS: Mutex<u16> = Mutex::new(0);
+static BOOT: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Timer function
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    None = 0,
+    /// On once the signal has been on for time 1, off with the signal
+    OnDelay = 1,
+    /// On with the signal, off once it has been off for time 1
+    OffDelay = 2,
+    /// On for time 1 when the signal turns on
+    Interval = 3,
+    /// On for time 1 and off for time 2 while the signal is on
+    Flasher = 4,
+}
+
+impl TryFrom<u16> for Mode {
+    type Error = ExceptionCode;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::OnDelay),
+            2 => Ok(Self::OffDelay),
+            3 => Ok(Self::Interval),
+            4 => Ok(Self::Flasher),
+            _ => Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+}
+
+/// Timer settings of a relay
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Settings {
+    pub mode: Mode,
+    /// `0` for the relay's coil, `n` for virtual input `n - 1`
+    pub trigger: u16,
+    /// Tenths of a second
+    pub time1: u16,
+    /// Tenths of a second, the flasher's off time
+    pub time2: u16,
+}
+
+impl Settings {
+    pub const NONE: Self = Self {
+        mode: Mode::None,
+        trigger: 0,
+        time1: 0,
+        time2: 0,
+    };
+}
+
+/// Timer state
+///
+/// Times are the uptime, so a clock change does not disturb a running timer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Timer {
+    signal: bool,
+    output: bool,
+    /// End of the running time
+    until: Option<Duration>,
+}
+
+impl Timer {
+    pub const NEW: Self = Self {
+        signal: false,
+        output: false,
+        until: None,
+    };
+
+    /// Advance the timer with the control signal, returns the relay state if
+    /// it changes
+    ///
+    /// A time ends on the first tick at or after it, a zero time on the next
+    /// tick.
+    pub fn tick(&mut self, settings: &Settings, signal: bool, now: Duration) -> Option<bool> {
+        let time = |tenths: u16| Duration::from_millis(tenths as u64 * 100);
+        let rising = signal && !self.signal;
+        let falling = !signal && self.signal;
+        self.signal = signal;
+        let output = self.output;
+        let elapsed = self.until.is_some_and(|until| now >= until);
+        match settings.mode {
+            Mode::None => {
+                *self = Self {
+                    signal,
+                    ..Self::NEW
+                };
+                return None;
+            }
+            Mode::OnDelay => {
+                if rising {
+                    self.until = Some(now + time(settings.time1));
+                } else if !signal {
+                    self.until = None;
+                    self.output = false;
+                } else if elapsed {
+                    self.until = None;
+                    self.output = true;
+                }
+            }
+            Mode::OffDelay => {
+                if signal {
+                    self.until = None;
+                    self.output = true;
+                } else if falling {
+                    self.until = Some(now + time(settings.time1));
+                } else if elapsed {
+                    self.until = None;
+                    self.output = false;
+                }
+            }
+            Mode::Interval => {
+                // Not retriggered while it runs
+                if rising && self.until.is_none() {
+                    self.until = Some(now + time(settings.time1));
+                    self.output = true;
+                } else if elapsed {
+                    self.until = None;
+                    self.output = false;
+                }
+            }
+            Mode::Flasher => {
+                if rising {
+                    self.until = Some(now + time(settings.time1));
+                    self.output = true;
+                } else if !signal {
+                    self.until = None;
+                    self.output = false;
+                } else if let Some(until) = self.until
+                    && elapsed
+                {
+                    self.output = !self.output;
+                    let phase = if self.output {
+                        settings.time1
+                    } else {
+                        settings.time2
+                    };
+                    self.until = Some(until + time(phase));
+                }
+            }
+        }
+        (self.output != output).then_some(self.output)
+    }
+
+    /// Time left until the timer changes the relay
+    pub fn remaining(&self, now: Duration) -> Duration {
+        self.until
+            .map_or(Duration::ZERO, |until| until.saturating_sub(now))
+    }
+}
+
+/// Start the relay timers
+pub fn start(relay_sender: Sender<RelayRequest>) {
+    spawn(async move {
+        loop {
+            sleep(PERIOD).await;
+            let settings = *SETTINGS.read().unwrap();
+            let coils = *COILS.lock().unwrap();
+            let now = BOOT.elapsed();
+            let mut switches = Vec::new();
+            {
+                let mut timers = TIMERS.lock().unwrap();
+                for (relay, (timer, settings)) in timers.iter_mut().zip(&settings).enumerate() {
+                    let signal = match settings.trigger {
+                        0 => coils & (1 << relay) != 0,
+                        input => input::value(input - 1).is_some_and(|value| value != 0),
+                    };
+                    if let Some(state) = timer.tick(settings, signal, now) {
+                        switches.push((relay as u16, state));
+                    }
+                }
+            }
+            for (relay, state) in switches {
+                info!("Relay {relay} timer: {state}");
+                let (sender, receiver) = oneshot::channel();
+                if relay_sender
+                    .send((Mask::write(relay, &[state]), sender))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+                let _ = receiver.await;
+            }
+        }
+    });
+}
+
+/// Take the coil and relay bitmask writes to relays with a coil triggered
+/// timer, they set the timer's control signal instead of the relay, returns
+/// the mask for the other relays
+pub(crate) fn coils(mask: Mask) -> Mask {
+    let settings = SETTINGS.read().unwrap();
+    let mut coils = COILS.lock().unwrap();
+    let mut rest = mask;
+    for (relay, settings) in settings.iter().enumerate() {
+        let bit = 1 << relay;
+        if mask.and & bit == 0 && settings.mode != Mode::None && settings.trigger == 0 {
+            *coils = *coils & !bit | mask.or & bit;
+            rest.and |= bit;
+            rest.or &= !bit;
+        }
+    }
+    rest
+}
+
+/// Restore the default settings and drop the running timers and control
+/// signals
+#[cfg(not(target_os = "espidf"))]
+pub(crate) fn reset() {
+    *SETTINGS.write().unwrap() = [Settings::NONE; RELAY_COUNT as _];
+    *TIMERS.lock().unwrap() = [Timer::NEW; RELAY_COUNT as _];
+    *COILS.lock().unwrap() = 0;
+}
+
+/// Read the timer holding registers
+pub(crate) fn read_settings(address: u16, count: u16) -> Vec<u16> {
+    let settings = SETTINGS.read().unwrap();
+    (address..address + count)
+        .map(|address| {
+            let settings = &settings[(address / ENTRY_SIZE) as usize];
+            match address % ENTRY_SIZE {
+                0 => settings.mode as _,
+                1 => settings.trigger,
+                2 => settings.time1,
+                _ => settings.time2,
+            }
+        })
+        .collect()
+}
+
+/// Write the timer holding registers
+///
+/// All values are validated before any of them is applied, the timers of
+/// changed relays start over.
+pub(crate) fn write_settings(address: u16, values: &[u16]) -> Result<(), ExceptionCode> {
+    let mut settings = SETTINGS.write().unwrap();
+    let mut updated = *settings;
+    for (address, &value) in (address..).zip(values) {
+        let entry = &mut updated[(address / ENTRY_SIZE) as usize];
+        match address % ENTRY_SIZE {
+            0 => entry.mode = value.try_into()?,
+            1 if value <= input::COUNT => entry.trigger = value,
+            2 => entry.time1 = value,
+            3 => entry.time2 = value,
+            _ => return Err(ExceptionCode::IllegalDataValue),
+        }
+    }
+    // A flasher needs both times, or it would never leave a phase
+    if updated
+        .iter()
+        .any(|entry| entry.mode == Mode::Flasher && (entry.time1 == 0 || entry.time2 == 0))
+    {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let mut timers = TIMERS.lock().unwrap();
+    for (relay, (entry, previous)) in updated.iter().zip(settings.iter()).enumerate() {
+        if entry != previous {
+            info!("Relay {relay} timer: {entry:?}");
+            timers[relay] = Timer::NEW;
+        }
+    }
+    *settings = updated;
+    Ok(())
+}
+
+/// Read the remaining time input registers, in tenths of a second
+pub(crate) fn read_remaining(address: u16, count: u16) -> Vec<u16> {
+    let timers = TIMERS.lock().unwrap();
+    let now = BOOT.elapsed();
+    timers[address as usize..][..count as usize]
+        .iter()
+        .map(|timer| {
+            timer
+                .remaining(now)
+                .as_millis()
+                .div_ceil(100)
+                .min(u16::MAX as _) as _
+        })
+        .collect()
+}
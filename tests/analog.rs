@@ -0,0 +1,97 @@
+//! Analog input filter, scaling and alarm maths
+//!
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use digital_relay_controller::analog::{
+    alarm::{Alarm, Limits},
+    filter::{Filter, oversample},
+    scale::Scale,
+};
+
+#[test]
+fn oversampling() {
+    assert_eq!(oversample(&mut []), None);
+    assert_eq!(oversample(&mut [1000]), Some(1000.0));
+    assert_eq!(oversample(&mut [1000, 1001]), Some(1000.5));
+    // The lowest and highest samples are dropped, so a spike does not count
+    assert_eq!(oversample(&mut [1000, 3300, 1002, 0, 1004]), Some(1002.0));
+}
+
+#[test]
+fn filter() {
+    let mut filter = Filter::new(25);
+    assert_eq!(filter.update(1000.0), 1000.0);
+    assert_eq!(filter.update(2000.0), 1250.0);
+    assert_eq!(filter.update(2000.0), 1437.5);
+    for _ in 0..100 {
+        filter.update(2000.0);
+    }
+    assert!((filter.update(2000.0) - 2000.0).abs() < 0.01);
+    let mut filter = Filter::new(100);
+    filter.update(1000.0);
+    assert_eq!(filter.update(2000.0), 2000.0);
+}
+
+#[test]
+fn linear_scale() {
+    // 0.5 to 4.5 V pressure sensor for 0 to 10 bar, in hundredths
+    let scale = Scale::new(&[(500, 0), (2500, 1000)]).unwrap();
+    assert_eq!(scale.apply(500.0), 0);
+    assert_eq!(scale.apply(1500.0), 500);
+    assert_eq!(scale.apply(1501.0), 501);
+    assert_eq!(scale.apply(2500.0), 1000);
+    // Clamped outside the points
+    assert_eq!(scale.apply(100.0), 0);
+    assert_eq!(scale.apply(3300.0), 1000);
+    // Falling and negative values
+    let scale = Scale::new(&[(0, 500), (3000, -1000)]).unwrap();
+    assert_eq!(scale.apply(1000.0), 0);
+    assert_eq!(scale.apply(3000.0), -1000);
+    // Millivolts as they are
+    assert_eq!(Scale::NONE.apply(1234.4), 1234);
+}
+
+#[test]
+fn multipoint_scale() {
+    // Level of a tank that widens towards the top
+    let scale = Scale::new(&[(0, 0), (1000, 100), (2000, 150), (3000, 175)]).unwrap();
+    assert_eq!(scale.apply(500.0), 50);
+    assert_eq!(scale.apply(1000.0), 100);
+    assert_eq!(scale.apply(1500.0), 125);
+    assert_eq!(scale.apply(2999.0), 175);
+    assert_eq!(scale.apply(2600.0), 165);
+    assert_eq!(scale.points().len(), 4);
+    // Millivolts have to increase, there are 2 to 8 points
+    assert!(Scale::new(&[(1000, 0), (1000, 10)]).is_err());
+    assert!(Scale::new(&[(0, 0), (2000, 10), (1000, 20)]).is_err());
+    assert!(Scale::new(&[(0, 0)]).is_err());
+    let points: Vec<_> = (0..9).map(|point| (point * 100, 0)).collect();
+    assert!(Scale::new(&points).is_err());
+    assert!(Scale::new(&points[..8]).is_ok());
+}
+
+#[test]
+fn alarm_limits() {
+    let limits = Limits {
+        low: 100,
+        high: 900,
+        hysteresis: 50,
+    };
+    let mut alarm = Alarm::Normal;
+    let mut check = |value| {
+        alarm = limits.check(alarm, value);
+        alarm
+    };
+    assert_eq!(check(900), Alarm::Normal);
+    assert_eq!(check(901), Alarm::High);
+    // Cleared only once back below the limit by the hysteresis
+    assert_eq!(check(860), Alarm::High);
+    assert_eq!(check(850), Alarm::Normal);
+    assert_eq!(check(99), Alarm::Low);
+    assert_eq!(check(149), Alarm::Low);
+    // Straight from low to high
+    assert_eq!(check(1000), Alarm::High);
+    assert_eq!(check(500), Alarm::Normal);
+    assert_eq!(Limits::NONE.check(Alarm::Normal, i16::MIN), Alarm::Normal);
+    assert_eq!(Limits::NONE.check(Alarm::Normal, i16::MAX), Alarm::Normal);
+}
@@ -0,0 +1,77 @@
+//! Energy totals over the day, week and month
+//!
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use digital_relay_controller::{
+    energy::{Total, Totals},
+    schedule::time::{LocalTime, Timezone, days_from_civil},
+};
+use std::time::Duration;
+
+fn local(year: i32, month: u8, day: u8, hour: i64) -> LocalTime {
+    Timezone::UTC.local(days_from_civil(year, month, day) * 86400 + hour * 3600)
+}
+
+/// The watt-hours and seconds of the day, week and month of the relay
+fn relay(totals: &Totals, relay: usize) -> [(u64, u64); 3] {
+    totals.totals[relay].map(|total| (total.energy / 3_600_000, total.runtime / 1000))
+}
+
+#[test]
+fn add() {
+    let mut totals = Totals::NEW;
+    totals.add(0b01, &[1000, 500], Duration::from_secs(1800));
+    totals.add(0b11, &[1000, 500], Duration::from_secs(3600));
+    assert_eq!(relay(&totals, 0), [(1500, 5400); 3]);
+    assert_eq!(relay(&totals, 1), [(500, 3600); 3]);
+}
+
+#[test]
+fn roll() {
+    let mut totals = Totals::NEW;
+    // Counted before the clock was set, it belongs to the first periods
+    totals.add(0b01, &[1000, 0], Duration::from_secs(3600));
+    // Wednesday, 2026-10-14
+    assert!(totals.roll(&local(2026, 10, 14, 12)));
+    assert_eq!(relay(&totals, 0), [(1000, 3600); 3]);
+    assert!(!totals.roll(&local(2026, 10, 14, 23)));
+    // A new day, then a new week
+    assert!(totals.roll(&local(2026, 10, 15, 0)));
+    assert_eq!(relay(&totals, 0), [(0, 0), (1000, 3600), (1000, 3600)]);
+    totals.add(0b01, &[1000, 0], Duration::from_secs(3600));
+    assert!(totals.roll(&local(2026, 10, 19, 0)));
+    assert_eq!(relay(&totals, 0), [(0, 0), (0, 0), (2000, 7200)]);
+    totals.add(0b01, &[1000, 0], Duration::from_secs(3600));
+    // A clock set back keeps the later periods
+    assert!(!totals.roll(&local(2026, 10, 12, 0)));
+    assert_eq!(
+        relay(&totals, 0),
+        [(1000, 3600), (1000, 3600), (3000, 10800)]
+    );
+    assert!(!totals.roll(&local(2026, 10, 19, 6)));
+    assert_eq!(relay(&totals, 0)[0], (1000, 3600));
+    // A new month in the middle of a week, Sunday 2026-11-01
+    assert!(totals.roll(&local(2026, 10, 28, 0)));
+    totals.add(0b01, &[1000, 0], Duration::from_secs(3600));
+    assert!(totals.roll(&local(2026, 11, 1, 0)));
+    assert_eq!(relay(&totals, 0), [(0, 0), (1000, 3600), (0, 0)]);
+}
+
+#[test]
+fn bytes() {
+    assert_eq!(
+        Totals::from_bytes(&Totals::NEW.to_bytes()),
+        Some(Totals::NEW)
+    );
+    let mut totals = Totals::NEW;
+    totals.roll(&local(2026, 10, 14, 12));
+    totals.add(0b10, &[0, 2500], Duration::from_millis(1500));
+    totals.totals[0][2] = Total {
+        energy: u64::MAX,
+        runtime: 1,
+    };
+    let bytes = totals.to_bytes();
+    assert_eq!(Totals::from_bytes(&bytes), Some(totals));
+    assert_eq!(Totals::from_bytes(&bytes[1..]), None);
+    assert_eq!(Totals::from_bytes(&[]), None);
+}
@@ -0,0 +1,1388 @@
+//! Modbus/TCP server on localhost against a mock relay backend
+//!
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use anyhow::Result;
+use digital_relay_controller::{
+    modbus::{self, gateway::Request as GatewayRequest},
+    relay::{self, Backend, COUNT},
+    timer,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::{net::TcpListener, runtime::Builder, spawn, sync::mpsc, time::sleep};
+use tokio_modbus::{
+    client::{Context, tcp},
+    prelude::*,
+};
+
+// Holding registers
+const RELAYS_REGISTER: u16 = 0x0000;
+const CONNECTION_SETTINGS_REGISTER: u16 = 0x0100;
+const TLS_COMMIT_REGISTER: u16 = 0x0180;
+const GATEWAY_SETTINGS_REGISTER: u16 = 0x01A0;
+const CLIENT_SETTINGS_REGISTER: u16 = 0x0200;
+const BINDINGS_REGISTER: u16 = 0x0300;
+const LOCKS_REGISTER: u16 = 0x0400;
+const SBO_SETTINGS_REGISTER: u16 = 0x0410;
+const TIMERS_REGISTER: u16 = 0x0420;
+const SCENES_REGISTER: u16 = 0x0440;
+const SEQUENCE_COMMANDS_REGISTER: u16 = 0x04A0;
+const PERMISSIVES_REGISTER: u16 = 0x04B0;
+const ENERGY_RESET_REGISTER: u16 = 0x04F8;
+const SEQUENCES_REGISTER: u16 = 0x0800;
+const ALLOWLIST_LENGTH_REGISTER: u16 = CONNECTION_SETTINGS_REGISTER + 0x02;
+const UNIT_ROLES_LENGTH_REGISTER: u16 = CONNECTION_SETTINGS_REGISTER + 0x03;
+const ALLOWLIST_REGISTER: u16 = CONNECTION_SETTINGS_REGISTER + 0x10;
+const UNIT_ROLES_REGISTER: u16 = CONNECTION_SETTINGS_REGISTER + 0x30;
+
+// Input registers
+const CONNECTION_STATISTICS_REGISTER: u16 = 0x0100;
+const AUDIT_LOG_REGISTER: u16 = 0x0200;
+const VIRTUAL_INPUTS_REGISTER: u16 = 0x0300;
+const PERMISSIVES_STATUS_REGISTER: u16 = 0x0410;
+const SEQUENCE_STATUS_REGISTER: u16 = 0x0600;
+const GATEWAY_STATISTICS_REGISTER: u16 = 0x1000;
+
+// Function codes
+const DIAGNOSTICS: u8 = 0x08;
+const GET_COMM_EVENT_COUNTER: u8 = 0x0B;
+const GET_COMM_EVENT_LOG: u8 = 0x0C;
+const WRITE_FILE_RECORD: u8 = 0x15;
+const READ_FIFO_QUEUE: u8 = 0x18;
+
+/// Mock RTU slave behind the gateway
+const RTU_UNIT: u8 = 2;
+/// RTU slave that never answers
+const SILENT_UNIT: u8 = 3;
+
+/// The register map is global, so the tests take turns
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Mock relay outputs
+#[derive(Clone, Default)]
+struct Relays(Arc<Mutex<[bool; COUNT as _]>>);
+
+impl Relays {
+    fn get(&self) -> [bool; COUNT as _] {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl Backend for Relays {
+    fn set(&mut self, index: usize, value: bool) -> Result<()> {
+        self.0.lock().unwrap()[index] = value;
+        Ok(())
+    }
+}
+
+/// Restores the register map when a test ends, passed or failed
+struct Reset;
+
+impl Drop for Reset {
+    fn drop(&mut self) {
+        modbus::reset();
+    }
+}
+
+/// Run a test against a fresh server
+///
+/// The runtime, and with it every connection, is gone and the register map
+/// is restored before the next test starts.
+fn test<F: Future<Output = ()>>(test: impl FnOnce(SocketAddr, Relays) -> F) {
+    let _guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let _reset = Reset;
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+        let relays = Relays::default();
+        let relay_sender = relay::start(relays.clone()).unwrap();
+        timer::start(relay_sender.clone());
+        let (led_sender, mut led_receiver) = mpsc::channel(9);
+        spawn(async move { while led_receiver.recv().await.is_some() {} });
+        let (gateway_sender, mut gateway_receiver) = mpsc::channel::<GatewayRequest>(9);
+        spawn(async move {
+            while let Some((unit, pdu, sender)) = gateway_receiver.recv().await {
+                let _ = sender.send(rtu(unit, &pdu));
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        spawn(modbus::serve(
+            listener,
+            relay_sender,
+            led_sender,
+            gateway_sender,
+        ));
+        test(socket_addr, relays).await;
+    });
+}
+
+/// Mock RTU bus: holding registers of [`RTU_UNIT`] are all `0x1234`, it
+/// answers anything else with illegal function
+fn rtu(unit: u8, pdu: &[u8]) -> Result<Vec<u8>, ExceptionCode> {
+    match (unit, pdu) {
+        (RTU_UNIT, &[0x03, _, _, 0, count @ 1..=125]) => {
+            let mut response = vec![0x03, count * 2];
+            for _ in 0..count {
+                response.extend([0x12, 0x34]);
+            }
+            Ok(response)
+        }
+        (RTU_UNIT, &[function, ..]) => Ok(vec![function | 0x80, 0x01]),
+        _ => Err(ExceptionCode::GatewayTargetDevice),
+    }
+}
+
+async fn connect(socket_addr: SocketAddr) -> Context {
+    connect_unit(socket_addr, 1).await
+}
+
+async fn connect_unit(socket_addr: SocketAddr, unit: u8) -> Context {
+    tcp::connect_slave(socket_addr, Slave(unit)).await.unwrap()
+}
+
+/// The exception to a custom function, tokio-modbus reports it as a function
+/// code mismatch
+async fn exception(context: &mut Context, function: u8, data: Vec<u8>) -> ExceptionCode {
+    match context.call(Request::Custom(function, data.into())).await {
+        Err(tokio_modbus::Error::Protocol(ProtocolError::FunctionCodeMismatch {
+            result: Err(response),
+            ..
+        })) => response.exception,
+        result => panic!("no exception: {result:?}"),
+    }
+}
+
+#[test]
+fn coils() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        assert_eq!(
+            context.read_coils(0, 2).await.unwrap(),
+            Ok(vec![false, false])
+        );
+        assert_eq!(context.write_single_coil(1, true).await.unwrap(), Ok(()));
+        assert_eq!(relays.get(), [false, true]);
+        assert_eq!(
+            context
+                .write_multiple_coils(0, &[true, false])
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(
+            context.read_coils(0, 2).await.unwrap(),
+            Ok(vec![true, false])
+        );
+        assert_eq!(context.read_coils(1, 1).await.unwrap(), Ok(vec![false]));
+    });
+}
+
+#[test]
+fn coil_bounds() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        for (address, count, exception) in [
+            (0, 0, ExceptionCode::IllegalDataValue),
+            (2, 1, ExceptionCode::IllegalDataAddress),
+            (1, 2, ExceptionCode::IllegalDataAddress),
+            (0xFFFF, 2, ExceptionCode::IllegalDataAddress),
+        ] {
+            assert_eq!(
+                context.read_coils(address, count).await.unwrap(),
+                Err(exception),
+                "read_coils({address}, {count})"
+            );
+        }
+        assert_eq!(
+            context.write_single_coil(2, true).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            context
+                .write_multiple_coils(1, &[true, true])
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            context
+                .write_multiple_coils(0xFFFF, &[true, true])
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(relays.get(), [false, false]);
+    });
+}
+
+/// Writes to a relay with a coil triggered timer set the trigger, whichever
+/// function writes them
+#[test]
+fn timer_coils() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        // On-delay of 10 s on relay 0
+        assert_eq!(
+            context
+                .write_multiple_registers(TIMERS_REGISTER, &[1, 0, 100, 0])
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        context.write_single_coil(0, true).await.unwrap().unwrap();
+        assert_eq!(relays.get(), [false, false]);
+        context
+            .write_single_register(RELAYS_REGISTER, 0b11)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(relays.get(), [false, true]);
+        context
+            .masked_write_register(RELAYS_REGISTER, 0b00, 0b01)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(relays.get(), [false, false]);
+        assert_eq!(
+            context
+                .read_write_multiple_registers(RELAYS_REGISTER, 1, RELAYS_REGISTER, &[0b11])
+                .await
+                .unwrap(),
+            Ok(vec![0b10])
+        );
+    });
+}
+
+/// The control signal of a coil triggered timer only changes once the write
+/// is selected
+#[test]
+fn timer_coil_select() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        // Interval of 10 s on relay 0, select-before-operate
+        context
+            .write_multiple_registers(TIMERS_REGISTER, &[3, 0, 100, 0])
+            .await
+            .unwrap()
+            .unwrap();
+        context
+            .write_multiple_registers(SBO_SETTINGS_REGISTER, &[1, 10])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            context.write_single_coil(0, true).await.unwrap(),
+            Err(ExceptionCode::Acknowledge)
+        );
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [false, false]);
+        assert_eq!(context.write_single_coil(0, true).await.unwrap(), Ok(()));
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [true, false]);
+    });
+}
+
+#[test]
+fn scenes() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        // "night": relay 1 on and relay 0 off at once
+        let night = [0b11, 0b10, 0, 0x6E69, 0x6768, 0x7400, 0, 0, 0, 0, 0];
+        assert_eq!(
+            context
+                .write_multiple_registers(SCENES_REGISTER + 1, &night)
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            context
+                .read_holding_registers(SCENES_REGISTER, 12)
+                .await
+                .unwrap(),
+            Ok([&[0][..], &night].concat())
+        );
+        // Relays that do not exist, an unused scene and one out of range
+        assert_eq!(
+            context
+                .write_single_register(SCENES_REGISTER + 1 + 11, 1 << COUNT)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        for value in [2, 9] {
+            assert_eq!(
+                context
+                    .write_single_register(SCENES_REGISTER, value)
+                    .await
+                    .unwrap(),
+                Err(ExceptionCode::IllegalDataValue)
+            );
+        }
+        context.write_single_coil(0, true).await.unwrap().unwrap();
+        context
+            .write_single_register(SCENES_REGISTER, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(relays.get(), [false, true]);
+        assert_eq!(
+            context
+                .read_holding_registers(SCENES_REGISTER, 1)
+                .await
+                .unwrap(),
+            Ok(vec![1])
+        );
+        // Switched otherwise, the scene is no longer active
+        context.write_single_coil(1, false).await.unwrap().unwrap();
+        assert_eq!(
+            context
+                .read_holding_registers(SCENES_REGISTER, 1)
+                .await
+                .unwrap(),
+            Ok(vec![0])
+        );
+    });
+}
+
+/// A staggered scene switches its relays one after the other until another
+/// scene or a client write to one of its relays stops it
+#[test]
+fn staggered_scene() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        assert_eq!(
+            context
+                .write_multiple_registers(SCENES_REGISTER + 1, &[0b11, 0b11, 200])
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        context
+            .write_single_register(SCENES_REGISTER, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(relays.get(), [true, false]);
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [true, true]);
+
+        context
+            .write_single_register(RELAYS_REGISTER, 0)
+            .await
+            .unwrap()
+            .unwrap();
+        context
+            .write_single_register(SCENES_REGISTER, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(relays.get(), [true, false]);
+        context.write_single_coil(0, false).await.unwrap().unwrap();
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [false, false]);
+
+        // A step is checked again before it switches and blocked, relay 1
+        // now needs a virtual input that is not there
+        context
+            .write_single_register(SCENES_REGISTER, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(relays.get(), [true, false]);
+        let input = b"input[0]".map(u16::from);
+        let condition: Vec<_> = input.chunks(2).map(|pair| pair[0] << 8 | pair[1]).collect();
+        context
+            .write_multiple_registers(PERMISSIVES_REGISTER + 32, &condition)
+            .await
+            .unwrap()
+            .unwrap();
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [true, false]);
+        let status = context
+            .read_input_registers(PERMISSIVES_STATUS_REGISTER + 3, 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(status[2], 1);
+    });
+}
+
+/// Sequences switch, wait, jump when an input does not come and abort
+#[test]
+fn sequences() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        #[rustfmt::skip]
+        let sequences: [&[u16]; 4] = [
+            // Relay 0 on, 0.2 s later relay 1 on
+            &[1, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 1, 1, 1, 0, 0, 0],
+            // Input 0 never comes, so it jumps over relay 0 off to relay 1
+            // off
+            &[3, 0, 0, 1, 2, 3, 1, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0],
+            // Aborted when input 0 does not come
+            &[3, 0, 0, 1, 2, 0],
+            // Waits for 10 s
+            &[2, 0, 0, 0, 100, 0],
+        ];
+        for (sequence, steps) in sequences.into_iter().enumerate() {
+            assert_eq!(
+                context
+                    .write_multiple_registers(SEQUENCES_REGISTER + 96 * sequence as u16, steps)
+                    .await
+                    .unwrap(),
+                Ok(())
+            );
+        }
+        // Unknown kinds and until steps without a time
+        for step in [[4, 0, 0, 0, 0, 0], [3, 0, 0, 1, 0, 0]] {
+            assert_eq!(
+                context
+                    .write_multiple_registers(SEQUENCES_REGISTER + 96 * 3 + 6, &step)
+                    .await
+                    .unwrap(),
+                Err(ExceptionCode::IllegalDataValue)
+            );
+        }
+
+        assert_eq!(
+            context
+                .write_multiple_registers(SEQUENCE_COMMANDS_REGISTER, &[1, 0, 1, 1])
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(
+            context
+                .read_input_registers(SEQUENCE_STATUS_REGISTER, 12)
+                .await
+                .unwrap(),
+            Ok(vec![1, 1, 0, 0, 0, 0, 1, 0, 0, 1, 0, 0])
+        );
+        // A running sequence is not started again
+        assert_eq!(
+            context
+                .write_single_register(SEQUENCE_COMMANDS_REGISTER + 3, 1)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::ServerDeviceBusy)
+        );
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [true, true]);
+        assert_eq!(
+            context
+                .write_multiple_registers(SEQUENCE_COMMANDS_REGISTER + 1, &[1, 0, 0])
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(
+            context
+                .read_input_registers(SEQUENCE_STATUS_REGISTER, 12)
+                .await
+                .unwrap(),
+            Ok(vec![2, 3, 0, 2, 3, 0, 3, 0, 2, 3, 0, 1])
+        );
+        assert_eq!(
+            context
+                .read_holding_registers(SEQUENCE_COMMANDS_REGISTER, 4)
+                .await
+                .unwrap(),
+            Ok(vec![0; 4])
+        );
+
+        // Started as one command of all its set steps
+        context
+            .write_single_register(SBO_SETTINGS_REGISTER + 2, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        context.write_single_coil(0, false).await.unwrap().unwrap();
+        assert_eq!(
+            context
+                .write_single_register(SEQUENCE_COMMANDS_REGISTER, 1)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::Acknowledge)
+        );
+        assert_eq!(
+            context
+                .read_holding_registers(SEQUENCE_COMMANDS_REGISTER, 1)
+                .await
+                .unwrap(),
+            Ok(vec![0])
+        );
+        assert_eq!(
+            context
+                .write_single_register(SEQUENCE_COMMANDS_REGISTER, 1)
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [true, true]);
+    });
+}
+
+#[test]
+fn relay_register() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        assert_eq!(
+            context
+                .read_holding_registers(RELAYS_REGISTER, 1)
+                .await
+                .unwrap(),
+            Ok(vec![0])
+        );
+        assert_eq!(
+            context
+                .write_single_register(RELAYS_REGISTER, 0b11)
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(relays.get(), [true, true]);
+        assert_eq!(
+            context
+                .write_single_register(RELAYS_REGISTER, 0b100)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(relays.get(), [true, true]);
+        assert_eq!(
+            context
+                .masked_write_register(RELAYS_REGISTER, 0b01, 0b00)
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(
+            context
+                .read_write_multiple_registers(RELAYS_REGISTER, 1, RELAYS_REGISTER, &[0b10])
+                .await
+                .unwrap(),
+            Ok(vec![0b10])
+        );
+        assert_eq!(relays.get(), [false, true]);
+        assert_eq!(
+            context
+                .write_multiple_registers(RELAYS_REGISTER, &[0b01])
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(relays.get(), [true, false]);
+    });
+}
+
+#[test]
+fn holding_register_bounds() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        for (address, count, exception) in [
+            (RELAYS_REGISTER, 0, ExceptionCode::IllegalDataValue),
+            (RELAYS_REGISTER, 2, ExceptionCode::IllegalDataAddress),
+            (0x0001, 1, ExceptionCode::IllegalDataAddress),
+            (0xFFFF, 2, ExceptionCode::IllegalDataAddress),
+        ] {
+            assert_eq!(
+                context
+                    .read_holding_registers(address, count)
+                    .await
+                    .unwrap(),
+                Err(exception),
+                "read_holding_registers({address}, {count})"
+            );
+        }
+        assert_eq!(
+            context
+                .write_multiple_registers(RELAYS_REGISTER, &[0b01, 0])
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            context
+                .write_multiple_registers(0xFFFF, &[0b01, 0])
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            context.write_single_register(0x0001, 0).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // Mask write only applies to the relay bitmask
+        assert_eq!(
+            context
+                .masked_write_register(CONNECTION_SETTINGS_REGISTER, 0, 0)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // The read is checked before anything is written
+        assert_eq!(
+            context
+                .read_write_multiple_registers(0xFFFF, 2, RELAYS_REGISTER, &[0b11])
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(relays.get(), [false, false]);
+    });
+}
+
+#[test]
+fn settings() {
+    test(|socket_addr, _| async move {
+        let mut context = connect(socket_addr).await;
+        assert_eq!(
+            context
+                .read_holding_registers(CONNECTION_SETTINGS_REGISTER, 4)
+                .await
+                .unwrap(),
+            Ok(vec![4, 60, 0, 0])
+        );
+        assert_eq!(
+            context
+                .read_holding_registers(GATEWAY_SETTINGS_REGISTER, 3)
+                .await
+                .unwrap(),
+            Ok(vec![1, 500, 2])
+        );
+        assert_eq!(
+            context
+                .read_holding_registers(TLS_COMMIT_REGISTER, 1)
+                .await
+                .unwrap(),
+            Ok(vec![0])
+        );
+        for (address, value) in [
+            (ALLOWLIST_LENGTH_REGISTER, 9),
+            (ALLOWLIST_REGISTER + 2, 33),
+            (ALLOWLIST_REGISTER + 3, 3),
+            (GATEWAY_SETTINGS_REGISTER, 0),
+            (GATEWAY_SETTINGS_REGISTER + 2, 6),
+            (CLIENT_SETTINGS_REGISTER + 4, 5),
+            (CLIENT_SETTINGS_REGISTER + 6, 0),
+            (BINDINGS_REGISTER, 5),
+            (BINDINGS_REGISTER + 1, 8),
+        ] {
+            assert_eq!(
+                context.write_single_register(address, value).await.unwrap(),
+                Err(ExceptionCode::IllegalDataValue),
+                "write_single_register({address:#06X}, {value})"
+            );
+        }
+        // Registers between the connection settings are not in use
+        assert_eq!(
+            context
+                .write_single_register(CONNECTION_SETTINGS_REGISTER + 0x04, 0)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // Nothing is applied when one of the values is invalid
+        assert_eq!(
+            context
+                .write_multiple_registers(GATEWAY_SETTINGS_REGISTER, &[5, 1000, 9])
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            context
+                .read_holding_registers(GATEWAY_SETTINGS_REGISTER, 3)
+                .await
+                .unwrap(),
+            Ok(vec![1, 500, 2])
+        );
+    });
+}
+
+#[test]
+fn input_registers() {
+    test(|socket_addr, _| async move {
+        let mut context = connect(socket_addr).await;
+        let statistics = context
+            .read_input_registers(CONNECTION_STATISTICS_REGISTER, 5)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(statistics[0], 1);
+        assert_eq!(
+            context
+                .read_input_registers(VIRTUAL_INPUTS_REGISTER, 16)
+                .await
+                .unwrap(),
+            Ok(vec![0; 16])
+        );
+        assert!(
+            context
+                .read_input_registers(AUDIT_LOG_REGISTER, 1 + 16 * 7)
+                .await
+                .unwrap()
+                .is_ok()
+        );
+        assert!(
+            context
+                .read_input_registers(GATEWAY_STATISTICS_REGISTER + 255 * 5, 5)
+                .await
+                .unwrap()
+                .is_ok()
+        );
+        for (address, count, exception) in [
+            (0x0000, 1, ExceptionCode::IllegalDataAddress),
+            (
+                CONNECTION_STATISTICS_REGISTER,
+                0,
+                ExceptionCode::IllegalDataValue,
+            ),
+            (
+                CONNECTION_STATISTICS_REGISTER,
+                6,
+                ExceptionCode::IllegalDataAddress,
+            ),
+            (
+                GATEWAY_STATISTICS_REGISTER + 255 * 5,
+                6,
+                ExceptionCode::IllegalDataAddress,
+            ),
+            (0xFFFF, 2, ExceptionCode::IllegalDataAddress),
+        ] {
+            assert_eq!(
+                context.read_input_registers(address, count).await.unwrap(),
+                Err(exception),
+                "read_input_registers({address:#06X}, {count})"
+            );
+        }
+    });
+}
+
+#[test]
+fn diagnostics() {
+    test(|socket_addr, _| async move {
+        let mut context = connect(socket_addr).await;
+        let query = vec![0x00, 0x00, 0xAB, 0xCD];
+        assert_eq!(
+            context
+                .call(Request::Custom(DIAGNOSTICS, query.clone().into()))
+                .await
+                .unwrap(),
+            Ok(Response::Custom(DIAGNOSTICS, query.into()))
+        );
+        // Clear counters, then the server message count is this request
+        let clear = vec![0x00, 0x0A, 0x00, 0x00];
+        assert_eq!(
+            context
+                .call(Request::Custom(DIAGNOSTICS, clear.clone().into()))
+                .await
+                .unwrap(),
+            Ok(Response::Custom(DIAGNOSTICS, clear.into()))
+        );
+        assert_eq!(
+            context
+                .call(Request::Custom(
+                    DIAGNOSTICS,
+                    vec![0x00, 0x0E, 0x00, 0x00].into()
+                ))
+                .await
+                .unwrap(),
+            Ok(Response::Custom(
+                DIAGNOSTICS,
+                vec![0x00, 0x0E, 0x00, 0x01].into()
+            ))
+        );
+        for data in [vec![0x00], vec![0x00, 0x01, 0x12, 0x34]] {
+            assert_eq!(
+                exception(&mut context, DIAGNOSTICS, data).await,
+                ExceptionCode::IllegalDataValue
+            );
+        }
+        assert_eq!(
+            exception(&mut context, DIAGNOSTICS, vec![0x00, 0x63, 0x00, 0x00]).await,
+            ExceptionCode::IllegalFunction
+        );
+        let Ok(Response::Custom(GET_COMM_EVENT_COUNTER, counter)) = context
+            .call(Request::Custom(GET_COMM_EVENT_COUNTER, Vec::new().into()))
+            .await
+            .unwrap()
+        else {
+            panic!("no comm event counter");
+        };
+        assert_eq!(counter.len(), 4);
+        let Ok(Response::Custom(GET_COMM_EVENT_LOG, log)) = context
+            .call(Request::Custom(GET_COMM_EVENT_LOG, Vec::new().into()))
+            .await
+            .unwrap()
+        else {
+            panic!("no comm event log");
+        };
+        assert_eq!(log[0] as usize, log.len() - 1);
+        // The counters of this connection and of a new one
+        let connection = |sub_function: u8| {
+            Request::Custom(DIAGNOSTICS, vec![0x00, sub_function, 0x00, 0x01].into())
+        };
+        let Ok(Ok(Response::Custom(DIAGNOSTICS, messages))) = context.call(connection(0x0E)).await
+        else {
+            panic!("no connection message count");
+        };
+        assert!(messages[3] > 1);
+        let mut other = connect(socket_addr).await;
+        assert_eq!(
+            other.call(connection(0x0E)).await.unwrap(),
+            Ok(Response::Custom(
+                DIAGNOSTICS,
+                vec![0x00, 0x0E, 0x00, 0x01].into()
+            ))
+        );
+        assert_eq!(
+            context.call(connection(0x0A)).await.unwrap(),
+            Ok(Response::Custom(
+                DIAGNOSTICS,
+                vec![0x00, 0x0A, 0x00, 0x01].into()
+            ))
+        );
+        assert_eq!(
+            context.call(connection(0x0E)).await.unwrap(),
+            Ok(Response::Custom(
+                DIAGNOSTICS,
+                vec![0x00, 0x0E, 0x00, 0x01].into()
+            ))
+        );
+    });
+}
+
+#[test]
+fn trace() {
+    test(|socket_addr, _| async move {
+        let mut context = connect(socket_addr).await;
+        context.read_coils(1, 1).await.unwrap().unwrap();
+        assert_eq!(
+            context.read_coils(2, 1).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // Download the whole trace, three entries at a time
+        let mut entries = Vec::new();
+        let mut pointer = 0u16;
+        loop {
+            let Ok(Response::Custom(READ_FIFO_QUEUE, data)) = context
+                .call(Request::Custom(
+                    READ_FIFO_QUEUE,
+                    pointer.to_be_bytes().to_vec().into(),
+                ))
+                .await
+                .unwrap()
+            else {
+                panic!("no FIFO queue");
+            };
+            let registers: Vec<_> = data
+                .chunks(2)
+                .map(|word| u16::from_be_bytes([word[0], word[1]]))
+                .collect();
+            assert_eq!(registers[0] as usize, data.len() - 2);
+            assert_eq!(registers[1] as usize, registers.len() - 2);
+            entries.extend(registers[2..].chunks(10).map(<[u16]>::to_vec));
+            if registers[1] < 30 {
+                break;
+            }
+            pointer = entries.last().unwrap()[0].wrapping_add(1);
+        }
+        let [.., coils, failed] = entries
+            .iter()
+            .filter(|entry| entry[5] == 0x0101)
+            .collect::<Vec<_>>()[..]
+        else {
+            panic!("no read coils in the trace");
+        };
+        // Localhost, unit 1, read coils
+        assert_eq!(coils[3..9], [0x7F00, 0x0001, 0x0101, 1, 1, 0]);
+        assert_eq!(failed[3..9], [0x7F00, 0x0001, 0x0101, 2, 1, 2]);
+        assert_eq!(failed[0], coils[0].wrapping_add(1));
+        assert_eq!(
+            exception(&mut context, READ_FIFO_QUEUE, vec![0]).await,
+            ExceptionCode::IllegalDataValue
+        );
+    });
+}
+
+#[test]
+fn unsupported_functions() {
+    test(|socket_addr, _| async move {
+        let mut context = connect(socket_addr).await;
+        assert_eq!(
+            context.read_discrete_inputs(0, 1).await.unwrap(),
+            Err(ExceptionCode::IllegalFunction)
+        );
+        assert_eq!(
+            context.call(Request::ReportServerId).await.unwrap(),
+            Err(ExceptionCode::IllegalFunction)
+        );
+        assert_eq!(
+            context
+                .call(Request::Custom(0x41, vec![0x01].into()))
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalFunction)
+        );
+    });
+}
+
+#[test]
+fn write_file_record() {
+    test(|socket_addr, _| async move {
+        let mut context = connect(socket_addr).await;
+        let pem = b"-----BEGIN CERTIFICATE-----\n";
+        let mut data = vec![7 + pem.len() as u8, 6, 0, 1, 0, 0, 0, pem.len() as u8 / 2];
+        data.extend_from_slice(pem);
+        assert_eq!(
+            context
+                .call(Request::Custom(WRITE_FILE_RECORD, data.clone().into()))
+                .await
+                .unwrap(),
+            Ok(Response::Custom(WRITE_FILE_RECORD, data.into()))
+        );
+        assert_eq!(
+            context
+                .write_single_register(TLS_COMMIT_REGISTER, 1)
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        // Nothing staged
+        assert_eq!(
+            context
+                .write_single_register(TLS_COMMIT_REGISTER, 2)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            context
+                .write_single_register(TLS_COMMIT_REGISTER, 4)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        for (data, code) in [
+            (vec![], ExceptionCode::IllegalDataValue),
+            (
+                vec![9, 6, 0, 1, 0, 0, 0, 1, 0],
+                ExceptionCode::IllegalDataValue,
+            ),
+            (
+                vec![9, 6, 0, 4, 0, 0, 0, 1, 0, 0],
+                ExceptionCode::IllegalDataAddress,
+            ),
+            (
+                vec![9, 6, 0, 1, 0x08, 0, 0, 1, 0, 0],
+                ExceptionCode::IllegalDataAddress,
+            ),
+        ] {
+            assert_eq!(exception(&mut context, WRITE_FILE_RECORD, data).await, code);
+        }
+    });
+}
+
+/// Resetting the energy totals needs a client certificate, a plaintext
+/// client with the configure role is not enough
+#[test]
+fn energy_reset() {
+    test(|socket_addr, _| async move {
+        let mut context = connect(socket_addr).await;
+        assert_eq!(
+            context
+                .write_single_register(ENERGY_RESET_REGISTER, 0b11)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+    });
+}
+
+#[test]
+fn roles() {
+    test(|socket_addr, relays| async move {
+        let mut configure = connect(socket_addr).await;
+        let audit_log = async |context: &mut Context| {
+            context
+                .read_input_registers(AUDIT_LOG_REGISTER, 1)
+                .await
+                .unwrap()
+                .unwrap()[0]
+        };
+        let denied = audit_log(&mut configure).await;
+        // Unit 5 is read-only for everybody
+        configure
+            .write_multiple_registers(UNIT_ROLES_REGISTER, &[5, 0])
+            .await
+            .unwrap()
+            .unwrap();
+        configure
+            .write_single_register(UNIT_ROLES_LENGTH_REGISTER, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut unit = connect_unit(socket_addr, 5).await;
+        assert_eq!(
+            unit.write_single_coil(0, true).await.unwrap(),
+            Err(ExceptionCode::IllegalFunction)
+        );
+        // Localhost is read-only
+        configure
+            .write_multiple_registers(ALLOWLIST_REGISTER, &[0x7F00, 0x0001, 32, 0])
+            .await
+            .unwrap()
+            .unwrap();
+        configure
+            .write_single_register(ALLOWLIST_LENGTH_REGISTER, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut read_only = connect(socket_addr).await;
+        assert_eq!(
+            read_only.read_coils(0, 2).await.unwrap(),
+            Ok(vec![false, false])
+        );
+        assert_eq!(
+            read_only.write_single_coil(0, true).await.unwrap(),
+            Err(ExceptionCode::IllegalFunction)
+        );
+        assert_eq!(
+            read_only
+                .write_single_register(RELAYS_REGISTER, 0b11)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalFunction)
+        );
+        // Operators switch relays, but do not configure
+        configure
+            .write_single_register(ALLOWLIST_REGISTER + 3, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut operate = connect(socket_addr).await;
+        assert_eq!(operate.write_single_coil(0, true).await.unwrap(), Ok(()));
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(
+            operate
+                .write_single_register(CONNECTION_SETTINGS_REGISTER, 4)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(audit_log(&mut configure).await, denied + 4);
+        // Most recent first: localhost, unit 1, write single register, the
+        // exception
+        assert_eq!(
+            configure
+                .read_input_registers(AUDIT_LOG_REGISTER + 3, 5)
+                .await
+                .unwrap(),
+            Ok(vec![
+                0x7F00,
+                0x0001,
+                0x0106,
+                CONNECTION_SETTINGS_REGISTER,
+                0x02
+            ])
+        );
+    });
+}
+
+#[test]
+fn locks() {
+    test(|socket_addr, relays| async move {
+        let mut owner = connect(socket_addr).await;
+        let mut other = connect(socket_addr).await;
+        assert_eq!(
+            owner
+                .write_multiple_registers(LOCKS_REGISTER, &[0x00A1, 10])
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            other
+                .read_holding_registers(LOCKS_REGISTER, 4)
+                .await
+                .unwrap(),
+            Ok(vec![0x00A1, 10, 0x7F00, 0x0001])
+        );
+        for result in [
+            other.write_single_coil(0, true).await.unwrap(),
+            other
+                .write_single_register(RELAYS_REGISTER, 0b11)
+                .await
+                .unwrap(),
+            other
+                .masked_write_register(RELAYS_REGISTER, 0b10, 0b01)
+                .await
+                .unwrap(),
+            other
+                .write_single_register(LOCKS_REGISTER, 0x00B2)
+                .await
+                .unwrap(),
+            other
+                .write_single_register(LOCKS_REGISTER + 1, 60)
+                .await
+                .unwrap(),
+        ] {
+            assert_eq!(result, Err(ExceptionCode::ServerDeviceBusy));
+        }
+        assert_eq!(relays.get(), [false, false]);
+        // The other relay is free
+        assert_eq!(other.write_single_coil(1, true).await.unwrap(), Ok(()));
+        assert_eq!(
+            other
+                .masked_write_register(RELAYS_REGISTER, 0b01, 0b00)
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(owner.write_single_coil(0, true).await.unwrap(), Ok(()));
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(
+            owner
+                .write_single_register(LOCKS_REGISTER + 1, 0)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            owner
+                .write_single_register(LOCKS_REGISTER + 2, 0)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // A reconnecting owner takes the lock over with its token
+        let mut reconnected = connect(socket_addr).await;
+        assert_eq!(
+            reconnected
+                .write_single_register(LOCKS_REGISTER, 0x00A1)
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            owner.write_single_coil(0, false).await.unwrap(),
+            Err(ExceptionCode::ServerDeviceBusy)
+        );
+        assert_eq!(
+            reconnected
+                .write_single_register(LOCKS_REGISTER, 0)
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            other
+                .read_holding_registers(LOCKS_REGISTER, 4)
+                .await
+                .unwrap(),
+            Ok(vec![0; 4])
+        );
+        assert_eq!(other.write_single_coil(0, false).await.unwrap(), Ok(()));
+    });
+}
+
+#[test]
+fn select_before_operate() {
+    test(|socket_addr, relays| async move {
+        let mut context = connect(socket_addr).await;
+        assert_eq!(
+            context
+                .write_multiple_registers(SBO_SETTINGS_REGISTER, &[1, 1])
+                .await
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            context
+                .write_single_register(SBO_SETTINGS_REGISTER + 1, 61)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        // Select, then operate
+        assert_eq!(
+            context.write_single_coil(0, true).await.unwrap(),
+            Err(ExceptionCode::Acknowledge)
+        );
+        // An acknowledge is not counted as busy
+        assert_eq!(
+            context
+                .call(Request::Custom(
+                    DIAGNOSTICS,
+                    vec![0x00, 0x11, 0x00, 0x01].into()
+                ))
+                .await
+                .unwrap(),
+            Ok(Response::Custom(
+                DIAGNOSTICS,
+                vec![0x00, 0x11, 0x00, 0x00].into()
+            ))
+        );
+        assert_eq!(relays.get(), [false, false]);
+        assert_eq!(context.write_single_coil(0, true).await.unwrap(), Ok(()));
+        assert_eq!(relays.get(), [true, false]);
+        // Relay 1 is operated directly
+        assert_eq!(context.write_single_coil(1, true).await.unwrap(), Ok(()));
+        assert_eq!(relays.get(), [true, true]);
+        // A different command cancels the selection
+        assert_eq!(
+            context
+                .write_single_register(RELAYS_REGISTER, 0b00)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::Acknowledge)
+        );
+        assert_eq!(
+            context
+                .write_single_register(RELAYS_REGISTER, 0b10)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            context
+                .write_single_register(RELAYS_REGISTER, 0b10)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::Acknowledge)
+        );
+        // So does the same command from another client
+        let mut other = connect(socket_addr).await;
+        assert_eq!(
+            other
+                .write_single_register(RELAYS_REGISTER, 0b10)
+                .await
+                .unwrap(),
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(relays.get(), [true, true]);
+        // The selection times out
+        assert_eq!(
+            context.write_single_coil(0, false).await.unwrap(),
+            Err(ExceptionCode::Acknowledge)
+        );
+        sleep(Duration::from_millis(1100)).await;
+        assert_eq!(
+            context.write_single_coil(0, false).await.unwrap(),
+            Err(ExceptionCode::Acknowledge)
+        );
+        assert_eq!(context.write_single_coil(0, false).await.unwrap(), Ok(()));
+        assert_eq!(relays.get(), [false, true]);
+    });
+}
+
+#[test]
+fn gateway() {
+    test(|socket_addr, _| async move {
+        let mut rtu = connect_unit(socket_addr, RTU_UNIT).await;
+        assert_eq!(
+            rtu.read_holding_registers(0, 2).await.unwrap(),
+            Ok(vec![0x1234, 0x1234])
+        );
+        assert_eq!(
+            rtu.read_input_registers(0, 1).await.unwrap(),
+            Err(ExceptionCode::IllegalFunction)
+        );
+        let mut silent = connect_unit(socket_addr, SILENT_UNIT).await;
+        assert_eq!(
+            silent.read_holding_registers(0, 1).await.unwrap(),
+            Err(ExceptionCode::GatewayTargetDevice)
+        );
+        // Unit 255 is the device itself
+        let mut local = connect_unit(socket_addr, 0xFF).await;
+        assert_eq!(
+            local.read_coils(0, 2).await.unwrap(),
+            Ok(vec![false, false])
+        );
+    });
+}
+
+#[test]
+fn concurrent_clients() {
+    test(|socket_addr, relays| async move {
+        // The clients write all relays on and off, then one relay each, so
+        // the relays end up in one of the last bitmasks written
+        let clients: Vec<_> = (0..4)
+            .map(|index| {
+                spawn(async move {
+                    let mut context = connect(socket_addr).await;
+                    let last = 1 << (index % COUNT);
+                    for write in 0..50 {
+                        let value = match write {
+                            49 => last,
+                            _ if write % 2 == 0 => (1 << COUNT) - 1,
+                            _ => 0,
+                        };
+                        context
+                            .write_single_register(RELAYS_REGISTER, value)
+                            .await
+                            .unwrap()
+                            .unwrap();
+                        let state = context
+                            .read_holding_registers(RELAYS_REGISTER, 1)
+                            .await
+                            .unwrap()
+                            .unwrap();
+                        assert_eq!(state[0] >> COUNT, 0);
+                    }
+                    last
+                })
+            })
+            .collect();
+        let mut last = Vec::new();
+        for client in clients {
+            last.push(client.await.unwrap());
+        }
+        let mut context = connect(socket_addr).await;
+        let state = context
+            .read_holding_registers(RELAYS_REGISTER, 1)
+            .await
+            .unwrap()
+            .unwrap()[0];
+        assert!(last.contains(&state), "{state:#04b} not in {last:?}");
+        let relays = relays.get();
+        assert_eq!(
+            (0..COUNT)
+                .map(|relay| state & (1 << relay) != 0)
+                .collect::<Vec<_>>(),
+            relays
+        );
+    });
+}
+
+#[test]
+fn connection_limit() {
+    test(|socket_addr, _| async move {
+        let mut oldest = connect(socket_addr).await;
+        assert!(oldest.read_coils(0, 1).await.unwrap().is_ok());
+        let mut contexts = Vec::new();
+        for _ in 0..4 {
+            let mut context = connect(socket_addr).await;
+            assert!(context.read_coils(0, 1).await.unwrap().is_ok());
+            contexts.push(context);
+        }
+        // The fifth connection evicted the one idle the longest
+        assert!(oldest.read_coils(0, 1).await.is_err());
+        for context in &mut contexts {
+            assert!(context.read_coils(0, 1).await.unwrap().is_ok());
+        }
+    });
+}
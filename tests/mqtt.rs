@@ -0,0 +1,544 @@
+//! MQTT topics against a mock relay backend
+//!
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use anyhow::Result;
+use digital_relay_controller::{
+    modbus::{self, gateway::Request as GatewayRequest},
+    mqtt,
+    relay::{self, Backend, COUNT, Request as RelayRequest},
+    timed,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::{
+    net::TcpListener,
+    runtime::Builder,
+    spawn,
+    sync::mpsc::{self, Sender},
+    time::{Duration, sleep},
+};
+use tokio_modbus::{
+    client::{Context, tcp},
+    prelude::*,
+};
+
+/// The register map is global, so the tests take turns
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Mock relay outputs
+#[derive(Clone, Default)]
+struct Relays(Arc<Mutex<[bool; COUNT as _]>>);
+
+impl Relays {
+    fn get(&self) -> [bool; COUNT as _] {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl Backend for Relays {
+    fn set(&mut self, index: usize, value: bool) -> Result<()> {
+        self.0.lock().unwrap()[index] = value;
+        Ok(())
+    }
+}
+
+/// Restores the register map when a test ends, passed or failed
+struct Reset;
+
+impl Drop for Reset {
+    fn drop(&mut self) {
+        modbus::reset();
+    }
+}
+
+/// Publishes commands like a broker would pass them on
+struct Broker {
+    relay_sender: Sender<RelayRequest>,
+    client: SocketAddr,
+}
+
+impl Broker {
+    async fn command(&self, topic: &str, payload: &str) -> Result<Option<String>, ExceptionCode> {
+        mqtt::command(&self.relay_sender, self.client, topic, payload.as_bytes()).await
+    }
+
+    /// Serve Modbus/TCP on localhost next to the topics, returns a client
+    /// connected to it
+    async fn serve(&self) -> Context {
+        let (led_sender, mut led_receiver) = mpsc::channel(9);
+        spawn(async move { while led_receiver.recv().await.is_some() {} });
+        let (gateway_sender, mut gateway_receiver) = mpsc::channel::<GatewayRequest>(9);
+        spawn(async move { while gateway_receiver.recv().await.is_some() {} });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        spawn(modbus::serve(
+            listener,
+            self.relay_sender.clone(),
+            led_sender,
+            gateway_sender,
+        ));
+        tcp::connect(socket_addr).await.unwrap()
+    }
+
+    /// The payload of a status topic
+    async fn status(&self, topic: &str) -> String {
+        mqtt::status(&self.relay_sender)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|(name, _)| name == topic)
+            .unwrap_or_else(|| panic!("no status topic {topic}"))
+            .1
+    }
+}
+
+fn test<F: Future<Output = ()>>(test: impl FnOnce(Broker, Relays) -> F) {
+    let _guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let _reset = Reset;
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+        let relays = Relays::default();
+        let relay_sender = relay::start(relays.clone()).unwrap();
+        let broker = Broker {
+            relay_sender,
+            client: "127.0.0.1:1883".parse().unwrap(),
+        };
+        test(broker, relays).await;
+    });
+}
+
+#[test]
+fn relays() {
+    test(|broker, relays| async move {
+        assert_eq!(broker.command("relays", "1").await, Ok(None));
+        assert!(relays.get()[0]);
+        assert_eq!(broker.status("relays").await, "1");
+        assert_eq!(
+            broker.command("relays", &(1 << COUNT).to_string()).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            broker.command("relays", "on").await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(broker.status("relays").await, "1");
+    });
+}
+
+#[test]
+fn topics() {
+    test(|broker, _| async move {
+        assert_eq!(
+            broker.command("unknown", "").await,
+            Err(ExceptionCode::IllegalFunction)
+        );
+        assert_eq!(
+            mqtt::command(&broker.relay_sender, broker.client, "relays", b"\xFF").await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+    });
+}
+
+#[test]
+fn trace() {
+    test(|broker, _| async move {
+        let mut context = broker.serve().await;
+        context.read_coils(1, 1).await.unwrap().unwrap();
+        let dump = broker.command("trace", "").await.unwrap().unwrap();
+        // Sequence number and time, then localhost, unit 255, read coils of
+        // one coil at 1 without an exception
+        let line = dump.lines().last().unwrap();
+        let fields: Vec<_> = line.split(' ').collect();
+        assert_eq!(fields[2..8], ["127.0.0.1", "255", "1", "1", "1", "0"]);
+    });
+}
+
+#[test]
+fn schedule() {
+    test(|broker, _| async move {
+        let windows = "mon-fri 07:30-18:00\nsat,sun 22:00-06:00";
+        assert_eq!(broker.command("schedule/1", windows).await, Ok(None));
+        assert_eq!(
+            broker.status("schedule/1").await,
+            "mon-fri 07:30-18:00\nsat-sun 22:00-06:00"
+        );
+        assert_eq!(broker.status("schedule/0").await, "");
+        // Separated by a semicolon, a range around the end of the week
+        assert_eq!(
+            broker
+                .command("schedule/0", "sat-mon 08:00-08:00; tue 12:00-13:00")
+                .await,
+            Ok(None)
+        );
+        assert_eq!(
+            broker.status("schedule/0").await,
+            "mon,sat-sun 08:00-08:00\ntue 12:00-13:00"
+        );
+        for windows in ["mon 24:00-01:00", "monday 07:00-08:00", "mon 07:00"] {
+            assert_eq!(
+                broker.command("schedule/0", windows).await,
+                Err(ExceptionCode::IllegalDataValue)
+            );
+        }
+        assert_eq!(
+            broker.command(&format!("schedule/{COUNT}"), windows).await,
+            Err(ExceptionCode::IllegalFunction)
+        );
+
+        let entry = "cron 0 pulse 30000 */15 6-17 * * 1-5";
+        assert_eq!(broker.command("schedule/entry/3", entry).await, Ok(None));
+        assert_eq!(broker.status("schedule/entry/3").await, entry);
+        for entry in ["solar 1 on sunset+15", "once 0 off 1767225600"] {
+            assert_eq!(broker.command("schedule/entry/4", entry).await, Ok(None));
+            assert_eq!(broker.status("schedule/entry/4").await, entry);
+        }
+        assert_eq!(broker.command("schedule/entry/4", "").await, Ok(None));
+        assert_eq!(broker.status("schedule/entry/4").await, "");
+        // A pulse needs its duration, a cron expression has to parse
+        for entry in [
+            "cron 0 pulse */5 * * * *",
+            "cron 0 on * * *",
+            "solar 2 on sunset",
+        ] {
+            assert_eq!(
+                broker.command("schedule/entry/4", entry).await,
+                Err(ExceptionCode::IllegalDataValue)
+            );
+        }
+
+        let timezone = "60 60 3 5 6 120 10 5 6 180";
+        assert_eq!(
+            broker.command("schedule/timezone", timezone).await,
+            Ok(None)
+        );
+        assert_eq!(broker.status("schedule/timezone").await, timezone);
+        assert_eq!(
+            broker.command("schedule/timezone", "60, 60").await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            broker.command("schedule/location", "55.75, -37.62").await,
+            Ok(None)
+        );
+        assert_eq!(broker.status("schedule/location").await, "55.75 -37.62");
+    });
+}
+
+#[test]
+fn calendar() {
+    test(|broker, _| async move {
+        assert_eq!(
+            broker.command("calendar/0", "weekday sun 3").await,
+            Ok(None)
+        );
+        assert_eq!(broker.status("calendar/0").await, "weekday sun 3");
+        assert_eq!(broker.command("calendar/1", "suppress 1").await, Ok(None));
+        assert_eq!(broker.status("calendar/1").await, "suppress 1");
+        for calendar in ["weekday 3", "suppress 4", "holiday 1"] {
+            assert_eq!(
+                broker.command("calendar/2", calendar).await,
+                Err(ExceptionCode::IllegalDataValue)
+            );
+        }
+
+        let dates = "2026-05-01, 2026-12-24..2027-01-03\n12-25 05-09..05-10";
+        assert_eq!(broker.command("calendar/0/dates", dates).await, Ok(None));
+        assert_eq!(
+            broker.status("calendar/0/dates").await,
+            "2026-05-01 2026-12-24..2027-01-03 12-25 05-09..05-10"
+        );
+        assert_eq!(
+            broker
+                .command("calendar/1/dates", "2026-08-03..2026-08-14")
+                .await,
+            Ok(None)
+        );
+        // A new list replaces the calendar's dates only
+        assert_eq!(broker.command("calendar/0/dates", "01-01").await, Ok(None));
+        assert_eq!(broker.status("calendar/0/dates").await, "01-01");
+        assert_eq!(
+            broker.status("calendar/1/dates").await,
+            "2026-08-03..2026-08-14"
+        );
+        for dates in [
+            "2026-02-30x",
+            "2026-13-01",
+            "2026-12-24..2028-01-03",
+            "2026-05-02..2026-05-01",
+            "2026-05-01..05-02",
+        ] {
+            assert_eq!(
+                broker.command("calendar/0/dates", dates).await,
+                Err(ExceptionCode::IllegalDataValue),
+                "{dates}"
+            );
+        }
+        // There is only room for the ranges the other calendars leave
+        let year: Vec<_> = (1..=64)
+            .map(|day| format!("2026-01-{:02}", day % 31 + 1))
+            .collect();
+        assert_eq!(
+            broker.command("calendar/0/dates", &year.join(" ")).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            broker
+                .command("calendar/0/dates", &year[..63].join(" "))
+                .await,
+            Ok(None)
+        );
+        assert_eq!(
+            broker.status("calendar/1/dates").await,
+            "2026-08-03..2026-08-14"
+        );
+    });
+}
+
+#[test]
+fn timed() {
+    test(|broker, relays| async move {
+        timed::start(broker.relay_sender.clone());
+        assert_eq!(broker.status("timed/0").await, "on 0");
+        assert_eq!(broker.command("timed/0", "on 100").await, Ok(None));
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(broker.status("timed/0").await, "on 100");
+        // Ending it switches back right away
+        assert_eq!(broker.command("timed/0", "0").await, Ok(None));
+        assert_eq!(relays.get(), [false, false]);
+        assert_eq!(broker.status("timed/0").await, "on 0");
+        // The state is kept for the next command, which ends once its time
+        // is up
+        assert_eq!(broker.command("relays", "2").await, Ok(None));
+        assert_eq!(broker.command("timed/1", "off 1").await, Ok(None));
+        assert_eq!(relays.get(), [false, false]);
+        assert_eq!(broker.command("timed/1", "1").await, Ok(None));
+        assert_eq!(broker.status("timed/1").await, "off 1");
+        sleep(Duration::from_millis(1300)).await;
+        assert_eq!(relays.get(), [false, true]);
+        assert_eq!(broker.status("timed/1").await, "off 0");
+        // A rejected command keeps the state
+        assert_eq!(broker.command("relays", "0").await, Ok(None));
+        assert_eq!(broker.command("timed/0", "off 0").await, Ok(None));
+        assert_eq!(broker.command("permissive/0", "relay[1]").await, Ok(None));
+        assert_eq!(
+            broker.command("timed/0", "on 5").await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(broker.status("timed/0").await, "off 0");
+        for payload in ["on", "on 1 2", "up 1", "-1", "2 1"] {
+            assert_eq!(
+                broker.command("timed/0", payload).await,
+                Err(ExceptionCode::IllegalDataValue),
+                "{payload}"
+            );
+        }
+    });
+}
+
+/// Commands stored in NVS resume after a reboot, the ones that ended while
+/// the device was down end then
+#[test]
+fn timed_restore() {
+    test(|broker, relays| async move {
+        assert_eq!(broker.command("timed/0", "on 100").await, Ok(None));
+        assert_eq!(broker.command("relays", "3").await, Ok(None));
+        assert_eq!(broker.command("timed/1", "off 1").await, Ok(None));
+        assert_eq!(relays.get(), [true, false]);
+        timed::reset();
+        relays.0.lock().unwrap().fill(false);
+        sleep(Duration::from_millis(1500)).await;
+        timed::start(broker.relay_sender.clone());
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(relays.get(), [true, true]);
+        let status = broker.status("timed/0").await;
+        let remaining: u16 = status.strip_prefix("on ").unwrap().parse().unwrap();
+        assert!((97..=99).contains(&remaining), "{status}");
+        // The state for the next command is not stored
+        assert_eq!(broker.status("timed/1").await, "on 0");
+    });
+}
+
+#[test]
+fn scene() {
+    test(|broker, relays| async move {
+        assert_eq!(broker.status("scene").await, "");
+        // Scene 0 is "night", scene 1 has no name
+        let mut context = broker.serve().await;
+        let scenes = [
+            0b11, 0b10, 0, 0x6E69, 0x6768, 0x7400, 0, 0, 0, 0, 0, 0b01, 0b01, 0,
+        ];
+        context
+            .write_multiple_registers(0x0441, &scenes)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(broker.command("scene", "night").await, Ok(None));
+        assert_eq!(relays.get(), [false, true]);
+        assert_eq!(broker.status("scene").await, "night");
+        assert_eq!(broker.command("scene", "1").await, Ok(None));
+        assert_eq!(relays.get(), [true, true]);
+        assert_eq!(broker.status("scene").await, "1");
+        for scene in ["day", "2", "8", ""] {
+            assert_eq!(
+                broker.command("scene", scene).await,
+                Err(ExceptionCode::IllegalDataValue),
+                "{scene}"
+            );
+        }
+    });
+}
+
+#[test]
+fn sequence() {
+    test(|broker, relays| async move {
+        assert_eq!(broker.status("sequence/0").await, "idle 0 none");
+        // Relay 0 on, then a wait of 10 s
+        let mut context = broker.serve().await;
+        context
+            .write_multiple_registers(0x0800, &[1, 0, 1, 0, 0, 0, 2, 0, 0, 0, 100, 0])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(broker.command("sequence/0", "start").await, Ok(None));
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(broker.status("sequence/0").await, "running 1 none");
+        assert_eq!(
+            broker.command("sequence/0", "start").await,
+            Err(ExceptionCode::ServerDeviceBusy)
+        );
+        assert_eq!(broker.command("sequence/0", "abort").await, Ok(None));
+        assert_eq!(broker.status("sequence/0").await, "aborted 1 command");
+        assert_eq!(
+            broker.command("sequence/0", "stop").await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            broker.command("sequence/4", "start").await,
+            Err(ExceptionCode::IllegalFunction)
+        );
+    });
+}
+
+#[test]
+fn rules() {
+    test(|broker, _| async move {
+        assert_eq!(broker.status("rules/status").await, "none 0 0");
+        let rules =
+            "IF relay[0] THEN relay[1] := ON\n# Comment\nIF NOT relay[0] THEN relay[1] := OFF";
+        assert_eq!(broker.command("rules", rules).await, Ok(None));
+        assert_eq!(broker.status("rules").await, rules);
+        let status = broker.status("rules/status").await;
+        assert!(status.starts_with("active 0 "), "{status}");
+        // Rejected with the line of the error
+        assert_eq!(
+            broker.command("rules", "IF relay[0] THEN\nIF THEN").await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        let status = broker.status("rules/status").await;
+        assert!(status.starts_with("error 1 "), "{status}");
+        assert_eq!(
+            broker.command("rules", &"#".repeat(1025)).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        // Empty text stops the rules
+        assert_eq!(broker.command("rules", "").await, Ok(None));
+        assert_eq!(broker.status("rules/status").await, "none 0 0");
+        assert_eq!(broker.status("rules").await, "");
+    });
+}
+
+#[test]
+fn permissive() {
+    test(|broker, relays| async move {
+        assert_eq!(broker.status("permissive/1").await, "");
+        assert_eq!(
+            broker.status("permissive/1/status").await,
+            "permitted permitted 0"
+        );
+        assert_eq!(broker.command("permissive/1", "relay[0]").await, Ok(None));
+        assert_eq!(broker.status("permissive/1").await, "relay[0]");
+        assert_eq!(
+            broker.command("relays", "2").await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(relays.get(), [false, false]);
+        assert_eq!(broker.status("permissive/1/status").await, "false false 1");
+        assert_eq!(broker.command("relays", "3").await, Ok(None));
+        assert_eq!(relays.get(), [true, true]);
+        assert_eq!(
+            broker.status("permissive/1/status").await,
+            "permitted false 1"
+        );
+        assert_eq!(
+            broker.command("permissive/0", "relay[").await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            broker.command("permissive/0", &"1".repeat(65)).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(broker.command("permissive/1", "").await, Ok(None));
+        assert_eq!(broker.status("permissive/1").await, "");
+    });
+}
+
+#[test]
+fn power() {
+    test(|broker, relays| async move {
+        assert_eq!(broker.status("power").await, "0 refuse");
+        assert_eq!(broker.command("power", "150 refuse").await, Ok(None));
+        assert_eq!(broker.command("power/0", "100 1").await, Ok(None));
+        assert_eq!(broker.command("power/1", "100, 2").await, Ok(None));
+        assert_eq!(broker.status("power/1").await, "100 2");
+        assert_eq!(broker.command("relays", "1").await, Ok(None));
+        // Refused as busy
+        assert_eq!(
+            broker.command("relays", "3").await,
+            Err(ExceptionCode::ServerDeviceBusy)
+        );
+        assert_eq!(relays.get(), [true, false]);
+        assert_eq!(broker.status("power/status").await, "100 150 0 1");
+        assert_eq!(broker.command("power", "150 shed").await, Ok(None));
+        assert_eq!(broker.status("power").await, "150 shed");
+        assert_eq!(broker.command("relays", "3").await, Ok(None));
+        assert_eq!(relays.get(), [false, true]);
+        assert_eq!(broker.status("power/status").await, "100 150 1 1");
+        for (topic, payload) in [("power", "150"), ("power", "150 drop"), ("power/0", "100")] {
+            assert_eq!(
+                broker.command(topic, payload).await,
+                Err(ExceptionCode::IllegalDataValue),
+                "{topic} {payload}"
+            );
+        }
+    });
+}
+
+#[test]
+fn energy() {
+    test(|broker, _| async move {
+        for period in ["day", "week", "month"] {
+            assert_eq!(broker.status(&format!("energy/1/{period}")).await, "0 0");
+        }
+        // Anybody may publish, so the totals are not reset over MQTT
+        assert_eq!(
+            broker.command("energy/reset", "3").await,
+            Err(ExceptionCode::IllegalFunction)
+        );
+    });
+}
+
+#[test]
+fn analog() {
+    test(|broker, _| async move {
+        for channel in 0..4 {
+            assert_eq!(
+                broker.status(&format!("analog/{channel}")).await,
+                "0 0 normal disabled"
+            );
+        }
+    });
+}
@@ -0,0 +1,80 @@
+//! Power budget plans of refused, shed and restored relays
+//!
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use digital_relay_controller::power::{Load, Policy, Settings};
+
+/// 100 W on each relay within 150 W, relay 1 is the more important one
+fn settings(policy: Policy) -> Settings {
+    Settings {
+        budget: 150,
+        policy,
+        relays: [
+            Load {
+                watts: 100,
+                priority: 1,
+            },
+            Load {
+                watts: 100,
+                priority: 2,
+            },
+        ],
+    }
+}
+
+#[test]
+fn refuse() {
+    let settings = settings(Policy::Refuse);
+    assert_eq!(settings.plan(0, 0b01, 0b11), (0b01, 0, 0b10));
+    assert_eq!(settings.plan(0, 0b10, 0b11), (0b10, 0, 0b01));
+    // Switched on together, the more important one gets the room
+    assert_eq!(settings.plan(0, 0, 0b11), (0b10, 0, 0b01));
+    // Relays that are on stay on, and there is no budget without one
+    assert_eq!(settings.plan(0, 0b11, 0b11), (0b11, 0, 0));
+    let unlimited = Settings {
+        budget: 0,
+        ..settings
+    };
+    assert_eq!(unlimited.plan(0, 0b01, 0b11), (0b11, 0, 0));
+}
+
+#[test]
+fn shed() {
+    let settings = settings(Policy::Shed);
+    // A more important relay sheds a less important one
+    assert_eq!(settings.plan(0, 0b01, 0b11), (0b10, 0b01, 0));
+    // A less important one is refused
+    assert_eq!(settings.plan(0, 0b10, 0b11), (0b10, 0, 0b01));
+    // So is one of the same priority
+    let mut equal = settings;
+    equal.relays[1].priority = 1;
+    assert_eq!(equal.plan(0, 0b01, 0b11), (0b01, 0, 0b10));
+    // Relays shed before stay shed
+    assert_eq!(settings.plan(0b01, 0b10, 0b10), (0b10, 0b01, 0));
+}
+
+#[test]
+fn restore() {
+    let permit = |state| state;
+    let settings = settings(Policy::Shed);
+    // The most important first, as far as the budget goes
+    assert_eq!(settings.restore(0b11, 0, permit), 0b10);
+    let mut swapped = settings;
+    swapped.relays[0].priority = 3;
+    assert_eq!(swapped.restore(0b11, 0, permit), 0b01);
+    assert_eq!(settings.restore(0b01, 0b10, permit), 0);
+    let larger = Settings {
+        budget: 200,
+        ..settings
+    };
+    assert_eq!(larger.restore(0b11, 0, permit), 0b11);
+}
+
+/// A shed relay whose permissive does not hold is not restored, and leaves
+/// the room to the next one
+#[test]
+fn restore_permissive() {
+    let settings = settings(Policy::Shed);
+    assert_eq!(settings.restore(0b11, 0, |state| state & !0b10), 0b01);
+    assert_eq!(settings.restore(0b10, 0, |state| state & !0b10), 0);
+}
@@ -0,0 +1,238 @@
+//! Rule compiler and bytecode machine against simulated values
+//!
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use digital_relay_controller::{
+    relay::Mask,
+    rule::{
+        compile::{Line, compile, condition},
+        program::{Context, Op, Program, Source},
+    },
+};
+
+/// Simulated device, `None` values are stale
+#[derive(Default)]
+struct Values {
+    inputs: [Option<u16>; 8],
+    relays: u16,
+    timers: [u16; 2],
+    minute: Option<u16>,
+}
+
+impl Context for Values {
+    fn value(&self, source: Source, index: u8) -> Option<i32> {
+        let index = index as usize;
+        match source {
+            Source::Input => self.inputs[index].map(|value| value as _),
+            Source::Temp => self.inputs[index].map(|value| value as i16 as _),
+            Source::Relay => Some((self.relays >> index & 1) as _),
+            Source::Timer => Some(self.timers[index] as _),
+            Source::Time => self.minute.map(|minute| minute as _),
+        }
+    }
+}
+
+/// The relay bitmask after running the rules
+fn run(source: &str, values: &Values) -> u16 {
+    compile(source).unwrap().run(values).apply(values.relays)
+}
+
+fn code(ops: &[Op]) -> Vec<u8> {
+    let mut code = Vec::new();
+    for op in ops {
+        op.encode(&mut code);
+    }
+    code
+}
+
+#[test]
+fn conditions() {
+    let rule = "IF temp[0] > 60 AND input[1] THEN relay[1] := OFF";
+    let mut values = Values {
+        inputs: [Some(61), Some(1), None, None, None, None, None, None],
+        relays: 0b11,
+        ..Values::default()
+    };
+    assert_eq!(run(rule, &values), 0b01);
+    values.inputs[0] = Some(60);
+    assert_eq!(run(rule, &values), 0b11);
+    values.inputs[0] = Some(-5i16 as u16);
+    values.relays = 0b10;
+    assert_eq!(run("if temp[0] < -4 then relay[0] := on", &values), 0b11);
+    assert_eq!(
+        run("IF input[0] > 60000 THEN relay[1] := OFF", &values),
+        0b00
+    );
+    // NOT binds tighter than AND, AND tighter than OR
+    values.relays = 0;
+    assert_eq!(
+        run("IF NOT 0 AND 0 OR 1 THEN relay[0] := ON", &values),
+        0b01
+    );
+    assert_eq!(run("IF NOT (0 OR 1) THEN relay[0] := ON", &values), 0b00);
+    assert_eq!(run("IF 2 + 3 - -1 = 6 THEN relay[0] := ON", &values), 0b01);
+}
+
+#[test]
+fn actions() {
+    let values = Values {
+        relays: 0b01,
+        timers: [0, 25],
+        ..Values::default()
+    };
+    // The last action on a relay wins, values are on when not zero
+    assert_eq!(
+        run(
+            "IF timer[1] > 0 THEN relay[0] := OFF, relay[1] := relay[0]\nIF ON THEN relay[0] := 2 - 1",
+            &values
+        ),
+        0b11
+    );
+    // Comments and blank lines
+    assert_eq!(
+        run(
+            "# Toggle\n\nIF TRUE THEN relay[0] := NOT relay[0] # both\n",
+            &values
+        ),
+        0b00
+    );
+    assert_eq!(compile("").unwrap().run(&values), Mask::READ);
+}
+
+#[test]
+fn time_of_day() {
+    let rule = "IF time >= 07:30 AND time < 18:00 THEN relay[0] := ON";
+    let mut values = Values {
+        minute: Some(7 * 60 + 29),
+        ..Values::default()
+    };
+    assert_eq!(run(rule, &values), 0b00);
+    values.minute = Some(7 * 60 + 30);
+    assert_eq!(run(rule, &values), 0b01);
+    values.minute = Some(18 * 60);
+    assert_eq!(run(rule, &values), 0b00);
+    // Not before the clock is set, even when the condition would hold
+    values.minute = None;
+    assert_eq!(run("IF NOT (time > 0) THEN relay[0] := ON", &values), 0b00);
+}
+
+#[test]
+fn unknown_values() {
+    let mut values = Values {
+        relays: 0b01,
+        ..Values::default()
+    };
+    // A stale input skips the rule instead of reading as 0
+    assert_eq!(run("IF input[0] = 0 THEN relay[0] := OFF", &values), 0b01);
+    assert_eq!(run("IF ON THEN relay[0] := input[0]", &values), 0b01);
+    assert_eq!(run("IF OFF OR input[2] THEN relay[1] := ON", &values), 0b01);
+    // The next rule is not affected
+    assert_eq!(
+        run(
+            "IF input[0] THEN relay[0] := OFF\nIF ON THEN relay[1] := ON",
+            &values
+        ),
+        0b11
+    );
+    values.inputs[0] = Some(0);
+    assert_eq!(run("IF input[0] = 0 THEN relay[0] := OFF", &values), 0b00);
+}
+
+#[test]
+fn compile_errors() {
+    let line = |source: &str| {
+        let error = compile(source).unwrap_err();
+        error.downcast_ref::<Line>().map(|line| line.0)
+    };
+    assert_eq!(line("IF input[0] relay[0] := ON"), Some(1));
+    assert_eq!(
+        line("IF ON THEN relay[0] := ON\n\nIF ON THEN relay[2] := ON"),
+        Some(3)
+    );
+    assert_eq!(line("IF input[8] THEN relay[0] := ON"), Some(1));
+    assert_eq!(line("IF ON THEN relay[0] = ON"), Some(1));
+    assert_eq!(line("IF ON THEN relay[0] := ON OFF"), Some(1));
+    assert_eq!(line("IF (ON THEN relay[0] := ON"), Some(1));
+    assert_eq!(line("IF 70000 THEN relay[0] := ON"), Some(1));
+    assert_eq!(line("IF time > 24:00 THEN relay[0] := ON"), Some(1));
+    assert_eq!(line("IF ON THEN relay[0] := ON;"), Some(1));
+    assert_eq!(line("relay[0] := ON"), Some(1));
+    let nested = format!(
+        "IF {}1{} THEN relay[0] := ON",
+        "(".repeat(17),
+        ")".repeat(17)
+    );
+    assert_eq!(line(&nested), Some(1));
+    let nested = format!(
+        "IF {}1{} THEN relay[0] := ON",
+        "(".repeat(16),
+        ")".repeat(16)
+    );
+    assert!(compile(&nested).is_ok());
+    // A rule too large for the stack is rejected by the verifier
+    let deep = format!(
+        "IF {}0{} THEN relay[0] := ON",
+        (1..=16).map(|n| format!("{n} + (")).collect::<String>(),
+        ")".repeat(16)
+    );
+    assert!(compile(&deep).is_err());
+}
+
+#[test]
+fn permissive_conditions() {
+    let permissive = condition("relay[0] AND input[1]").unwrap();
+    let mut values = Values {
+        inputs: [None, Some(1), None, None, None, None, None, None],
+        relays: 0b01,
+        ..Values::default()
+    };
+    assert_eq!(permissive.test(&values), Some(true));
+    values.relays = 0b10;
+    assert_eq!(permissive.test(&values), Some(false));
+    values.inputs[1] = None;
+    assert_eq!(permissive.test(&values), None);
+    // A condition without actions, actions without a condition
+    assert!(condition("IF ON THEN relay[0] := ON").is_err());
+    assert!(condition("relay[0] relay[1]").is_err());
+    assert!(Program::condition(code(&[Op::Push(1), Op::Store(0), Op::Push(1)])).is_err());
+    assert!(Program::condition(code(&[Op::Push(1), Op::Push(1)])).is_err());
+    assert!(compile("relay[0]").is_err());
+}
+
+#[test]
+fn verifier() {
+    let valid = code(&[
+        Op::Load(Source::Input, 1),
+        Op::Then(7),
+        Op::Push(0),
+        Op::Store(1),
+    ]);
+    assert!(Program::new(valid.clone()).is_ok());
+    // Jumps beyond the end or into an instruction
+    let mut beyond = valid.clone();
+    beyond[5] = 8;
+    assert!(Program::new(beyond).is_err());
+    let mut inside = valid.clone();
+    inside[5] = 6;
+    assert!(Program::new(inside).is_err());
+    // Cut off instruction
+    assert!(Program::new(valid[..valid.len() - 1].to_vec()).is_err());
+    // Unknown instruction, source and relay
+    assert!(Program::new(vec![0xFF]).is_err());
+    assert!(Program::new(vec![0x02, 9, 0, 0x04, 0]).is_err());
+    assert!(Program::new(code(&[Op::Load(Source::Temp, 8), Op::Store(0)])).is_err());
+    assert!(Program::new(code(&[Op::Push(1), Op::Store(2)])).is_err());
+    // Stack underflow, values left and a jump that skips a push
+    assert!(Program::new(code(&[Op::Store(0)])).is_err());
+    assert!(Program::new(code(&[Op::Push(1)])).is_err());
+    assert!(Program::new(code(&[Op::Push(1), Op::Then(5), Op::Push(1)])).is_err());
+    // Stack overflow
+    let mut deep = vec![Op::Push(1); 17];
+    deep.extend(vec![Op::And; 16]);
+    deep.push(Op::Store(0));
+    assert!(Program::new(code(&deep)).is_err());
+    // Too large, 8 bytes per rule
+    let rules = |count| code(&[Op::Push(1), Op::Then(0)].repeat(count));
+    assert!(Program::new(rules(128)).is_ok());
+    assert!(Program::new(rules(129)).is_err());
+}
@@ -0,0 +1,479 @@
+//! Relay schedules against a simulated clock
+//!
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use digital_relay_controller::schedule::{
+    Action::{self, *},
+    Entry, Scheduler, Settings, Window,
+    calendar::{Calendar, Mode, Range},
+    cron::Cron,
+    sun::{Event, Location, Solar},
+    time::{Rule, Timezone, days_from_civil},
+};
+use std::time::Duration;
+
+const MONDAY: u8 = 1 << 0;
+const FRIDAY: u8 = 1 << 4;
+const SUNDAY: u8 = 6;
+const EVERY_DAY: u8 = 0x7F;
+
+/// Central European Time: UTC+1, UTC+2 from the last Sunday of March 02:00
+/// to the last Sunday of October 03:00
+const CET: Timezone = Timezone {
+    offset: 60,
+    dst: 60,
+    start: Rule {
+        month: 3,
+        week: 5,
+        weekday: 6,
+        minute: 2 * 60,
+    },
+    end: Rule {
+        month: 10,
+        week: 5,
+        weekday: 6,
+        minute: 3 * 60,
+    },
+};
+
+/// UTC seconds since the epoch
+fn utc(year: i32, month: u8, day: u8, hour: i64, minute: i64) -> i64 {
+    days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60
+}
+
+fn settings(timezone: Timezone, window: Window) -> Settings {
+    let mut settings = Settings::DEFAULT;
+    settings.timezone = timezone;
+    settings.windows[0][0] = window;
+    settings
+}
+
+/// Simulated clock, ticking the scheduler like the device does
+struct Clock {
+    scheduler: Scheduler,
+    now: i64,
+    uptime: Duration,
+}
+
+impl Clock {
+    fn boot(now: i64) -> Self {
+        Self {
+            scheduler: Scheduler::new(),
+            now,
+            uptime: Duration::ZERO,
+        }
+    }
+
+    /// Advance both the wall clock and the uptime
+    fn advance(&mut self, settings: &Settings, seconds: i64) -> Vec<(u16, Action)> {
+        self.now += seconds;
+        self.uptime += Duration::from_secs(seconds as _);
+        self.scheduler.tick(settings, self.now, self.uptime)
+    }
+
+    /// Set the wall clock, only a second of uptime passes
+    fn jump(&mut self, settings: &Settings, now: i64) -> Vec<(u16, Action)> {
+        self.now = now;
+        self.uptime += Duration::from_secs(1);
+        self.scheduler.tick(settings, self.now, self.uptime)
+    }
+}
+
+#[test]
+fn civil_calendar() {
+    assert_eq!(days_from_civil(1970, 1, 1), 0);
+    assert_eq!(days_from_civil(2000, 3, 1), 11017);
+    assert_eq!(utc(2026, 10, 18, 0, 0), 1792281600);
+    let local = Timezone::UTC.local(utc(2026, 10, 18, 13, 45) + 30);
+    assert_eq!(
+        (local.year, local.month, local.day, local.weekday),
+        (2026, 10, 18, 6)
+    );
+    assert_eq!((local.minute, local.second), (13 * 60 + 45, 30));
+}
+
+#[test]
+fn daylight_saving_time() {
+    // 2026-03-29 01:00 UTC: 02:00 CET becomes 03:00 CEST
+    let start = utc(2026, 3, 29, 1, 0);
+    assert_eq!(CET.local(start - 60).minute, 2 * 60 - 1);
+    assert_eq!(CET.local(start).minute, 3 * 60);
+    // 2026-10-25 01:00 UTC: 03:00 CEST becomes 02:00 CET
+    let end = utc(2026, 10, 25, 1, 0);
+    assert_eq!(CET.local(end - 60).minute, 3 * 60 - 1);
+    assert_eq!(CET.local(end).minute, 2 * 60);
+    // Southern hemisphere: Sydney, UTC+10, UTC+11 from the first Sunday of
+    // October 02:00 to the first Sunday of April 03:00
+    let sydney = Timezone {
+        offset: 600,
+        dst: 60,
+        start: Rule {
+            month: 10,
+            week: 1,
+            weekday: 6,
+            minute: 2 * 60,
+        },
+        end: Rule {
+            month: 4,
+            week: 1,
+            weekday: 6,
+            minute: 3 * 60,
+        },
+    };
+    assert_eq!(sydney.offset(utc(2026, 1, 15, 0, 0)), 11 * 3600);
+    assert_eq!(sydney.offset(utc(2026, 7, 15, 0, 0)), 10 * 3600);
+}
+
+#[test]
+fn weekly_window() {
+    let settings = settings(
+        Timezone::UTC,
+        Window {
+            days: MONDAY,
+            on: 8 * 60,
+            off: 17 * 60,
+        },
+    );
+    // 2026-10-19 is a Monday, booted before the window the relay is off
+    let mut clock = Clock::boot(utc(2026, 10, 19, 7, 58));
+    assert_eq!(clock.advance(&settings, 60), [(0, Off)]);
+    assert_eq!(clock.advance(&settings, 30), []);
+    assert_eq!(clock.advance(&settings, 30), [(0, On)]);
+    assert_eq!(clock.advance(&settings, 60), []);
+    assert_eq!(clock.advance(&settings, 9 * 3600 - 60), [(0, Off)]);
+    // Tuesday has no window
+    assert_eq!(clock.advance(&settings, 24 * 3600), []);
+}
+
+#[test]
+fn overnight_window() {
+    let settings = settings(
+        Timezone::UTC,
+        Window {
+            days: FRIDAY,
+            on: 22 * 60,
+            off: 6 * 60,
+        },
+    );
+    // 2026-10-23 is a Friday
+    let mut clock = Clock::boot(utc(2026, 10, 23, 21, 59));
+    assert_eq!(clock.advance(&settings, 1), [(0, Off)]);
+    assert_eq!(clock.advance(&settings, 60), [(0, On)]);
+    // Saturday morning
+    assert_eq!(clock.advance(&settings, 8 * 3600), [(0, Off)]);
+}
+
+#[test]
+fn local_time_window() {
+    let settings = settings(
+        CET,
+        Window {
+            days: EVERY_DAY,
+            on: 7 * 60,
+            off: 8 * 60,
+        },
+    );
+    // 07:00 CET is 06:00 UTC before the switch to summer time
+    let mut clock = Clock::boot(utc(2026, 3, 28, 5, 58));
+    assert_eq!(clock.advance(&settings, 1), [(0, Off)]);
+    assert_eq!(clock.advance(&settings, 59), []);
+    assert_eq!(clock.advance(&settings, 60), [(0, On)]);
+    // and 05:00 UTC after it
+    assert_eq!(clock.jump(&settings, utc(2026, 3, 30, 4, 59)), [(0, Off)]);
+    assert_eq!(clock.advance(&settings, 60), [(0, On)]);
+}
+
+#[test]
+fn catch_up() {
+    let settings = settings(
+        Timezone::UTC,
+        Window {
+            days: MONDAY,
+            on: 8 * 60,
+            off: 17 * 60,
+        },
+    );
+    // A reboot in the middle of the window switches the relay on
+    let mut clock = Clock::boot(utc(2026, 10, 19, 12, 0));
+    assert_eq!(clock.advance(&settings, 1), [(0, On)]);
+    assert_eq!(clock.advance(&settings, 1), []);
+    // A clock jump sets the state again, the relay may have been switched
+    // in between
+    assert_eq!(clock.jump(&settings, utc(2026, 10, 19, 13, 0)), [(0, On)]);
+    assert_eq!(clock.advance(&settings, 1), []);
+    // Jumping past the end of the window switches the relay off
+    assert_eq!(clock.jump(&settings, utc(2026, 10, 19, 18, 0)), [(0, Off)]);
+    // Relays without a schedule are left alone
+    let mut clock = Clock::boot(utc(2026, 10, 19, 12, 0));
+    assert_eq!(clock.advance(&Settings::DEFAULT, 1), []);
+}
+
+#[test]
+fn cron_expression() {
+    // Every 15 minutes between 06:00 and 18:00 on weekdays
+    let cron: Cron = "*/15 6-17 * * 1-5".parse().unwrap();
+    // Friday 2026-10-23 17:50 CEST, the next one is Monday 06:00 CET
+    let friday = utc(2026, 10, 23, 15, 45);
+    assert_eq!(cron.next(&CET, friday), Some(utc(2026, 10, 26, 5, 0)));
+    assert_eq!(cron.next(&CET, friday - 1), Some(friday));
+    assert_eq!(
+        cron.next(&CET, utc(2026, 10, 26, 5, 0)),
+        Some(utc(2026, 10, 26, 5, 15))
+    );
+    // Day of month or Sunday, in UTC
+    let cron: Cron = "30 12 1,15 * 0".parse().unwrap();
+    let next = |after| cron.next(&Timezone::UTC, after);
+    assert_eq!(
+        next(utc(2026, 10, 19, 0, 0)),
+        Some(utc(2026, 10, 25, 12, 30))
+    );
+    assert_eq!(
+        next(utc(2026, 10, 25, 13, 0)),
+        Some(utc(2026, 11, 1, 12, 30))
+    );
+    assert_eq!(next(utc(2026, 11, 2, 0, 0)), Some(utc(2026, 11, 8, 12, 30)));
+    // 7 is Sunday as well, February 30 never comes
+    assert_eq!(
+        "0 0 * * 7".parse::<Cron>().unwrap(),
+        "0 0 * * 0".parse().unwrap()
+    );
+    assert_eq!("0 0 30 2 *".parse::<Cron>().unwrap().next(&CET, 0), None);
+    for invalid in [
+        "* * * *",
+        "60 * * * *",
+        "* * 0 * *",
+        "*/0 * * * *",
+        "5-1 * * * *",
+    ] {
+        assert!(invalid.parse::<Cron>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn cron_entry() {
+    let pulse = Pulse(Duration::from_secs(30));
+    let mut settings = Settings::DEFAULT;
+    settings.entries[0] = Entry::cron(1, pulse, "*/15 6-17 * * 1-5").unwrap();
+    // Monday 2026-10-19, fired once in its minute
+    let mut clock = Clock::boot(utc(2026, 10, 19, 5, 59));
+    assert_eq!(clock.advance(&settings, 30), []);
+    assert_eq!(clock.advance(&settings, 30), [(1, pulse)]);
+    assert_eq!(clock.advance(&settings, 30), []);
+    assert_eq!(clock.advance(&settings, 30), []);
+    assert_eq!(clock.advance(&settings, 14 * 60), [(1, pulse)]);
+    // Missed by a clock jump
+    assert_eq!(clock.jump(&settings, utc(2026, 10, 19, 7, 0)), []);
+    assert_eq!(clock.advance(&settings, 15 * 60), [(1, pulse)]);
+    // Not on the weekend
+    assert_eq!(clock.jump(&settings, utc(2026, 10, 24, 7, 0) - 1), []);
+    assert_eq!(clock.advance(&settings, 1), []);
+}
+
+#[test]
+fn one_shot_entry() {
+    let time = utc(2026, 10, 19, 12, 0);
+    let mut settings = Settings::DEFAULT;
+    settings.entries[3] = Entry::once(0, Toggle, time as _);
+    assert_eq!(settings.next(&settings.entries[3], time - 1), Some(time));
+    assert_eq!(settings.next(&settings.entries[3], time), None);
+    let mut clock = Clock::boot(time - 2);
+    assert_eq!(clock.advance(&settings, 1), []);
+    assert_eq!(clock.advance(&settings, 1), [(0, Toggle)]);
+    assert_eq!(clock.advance(&settings, 1), []);
+    // Not after a reboot past it
+    let mut clock = Clock::boot(time);
+    assert_eq!(clock.advance(&settings, 1), []);
+}
+
+/// The next fire times are kept until an entry fires, the settings change or
+/// the clock jumps
+#[test]
+fn next_fire_time() {
+    let time = utc(2026, 10, 19, 12, 0);
+    let mut settings = Settings::DEFAULT;
+    settings.entries[0] = Entry::once(0, On, time as _);
+    let mut clock = Clock::boot(time - 10);
+    assert_eq!(clock.advance(&settings, 0), []);
+    assert_eq!(clock.advance(&settings, 1), []);
+    // Moved closer while waiting for it
+    settings.entries[0] = Entry::once(0, On, (time - 5) as _);
+    assert_eq!(clock.advance(&settings, 3), []);
+    assert_eq!(clock.advance(&settings, 1), [(0, On)]);
+    assert_eq!(clock.advance(&settings, 10), []);
+    // Back before it, once the jump has been caught up with
+    assert_eq!(clock.jump(&settings, time - 20), []);
+    assert_eq!(clock.advance(&settings, 1), []);
+    assert_eq!(clock.advance(&settings, 15), [(0, On)]);
+}
+
+/// Within 2 minutes of the published time, tables are rounded to the minute
+#[track_caller]
+fn assert_near(event: Event, location: &Location, day: i64, expected: i64) {
+    let time = event.time(location, day).unwrap();
+    assert!(
+        (time - expected).abs() <= 120,
+        "{event:?} at {time}, {} s off",
+        time - expected
+    );
+}
+
+#[test]
+fn solar_events() {
+    use Event::*;
+    // timeanddate.com, in UTC
+    let london = Location {
+        latitude: 5151,
+        longitude: -13,
+    };
+    let day = days_from_civil(2024, 6, 20);
+    assert_near(Sunrise, &london, day, utc(2024, 6, 20, 3, 43));
+    assert_near(Sunset, &london, day, utc(2024, 6, 20, 20, 21));
+    let day = days_from_civil(2024, 12, 21);
+    assert_near(NauticalDawn, &london, day, utc(2024, 12, 21, 6, 41));
+    assert_near(CivilDawn, &london, day, utc(2024, 12, 21, 7, 25));
+    assert_near(Sunrise, &london, day, utc(2024, 12, 21, 8, 4));
+    assert_near(Sunset, &london, day, utc(2024, 12, 21, 15, 53));
+    assert_near(CivilDusk, &london, day, utc(2024, 12, 21, 16, 32));
+    assert_near(NauticalDusk, &london, day, utc(2024, 12, 21, 17, 16));
+    // West of Greenwich sunset is the next day in UTC
+    let new_york = Location {
+        latitude: 4071,
+        longitude: -7401,
+    };
+    let day = days_from_civil(2024, 6, 20);
+    assert_near(Sunrise, &new_york, day, utc(2024, 6, 20, 9, 25));
+    assert_near(Sunset, &new_york, day, utc(2024, 6, 21, 0, 31));
+    // and east of it sunrise is the previous day
+    let sydney = Location {
+        latitude: -3387,
+        longitude: 15121,
+    };
+    let day = days_from_civil(2024, 12, 21);
+    assert_near(Sunrise, &sydney, day, utc(2024, 12, 20, 18, 41));
+    assert_near(Sunset, &sydney, day, utc(2024, 12, 21, 9, 5));
+    // Polar night in Tromsø still has civil twilight, midnight sun has no
+    // sunset
+    let tromso = Location {
+        latitude: 6965,
+        longitude: 1896,
+    };
+    let day = days_from_civil(2024, 12, 21);
+    assert_eq!(Sunrise.time(&tromso, day), None);
+    assert!(CivilDawn.time(&tromso, day).is_some());
+    assert_eq!(Sunset.time(&tromso, days_from_civil(2024, 6, 21)), None);
+}
+
+#[test]
+fn solar_entry() {
+    let london = Location {
+        latitude: 5151,
+        longitude: -13,
+    };
+    assert_eq!(
+        "sunset+15".parse::<Solar>().unwrap(),
+        Solar {
+            event: Event::Sunset,
+            offset: 15
+        }
+    );
+    assert_eq!(
+        "civil-dusk -5".parse::<Solar>().unwrap(),
+        Solar {
+            event: Event::CivilDusk,
+            offset: -5
+        }
+    );
+    for invalid in ["noon", "sunset+", "sunset+721", "sunset 15"] {
+        assert!(invalid.parse::<Solar>().is_err(), "{invalid}");
+    }
+    // On at sunset + 15 min, off at sunrise - 10 min
+    let mut settings = Settings::DEFAULT;
+    settings.location = london;
+    settings.entries[0] = Entry::solar(0, On, "sunset+15").unwrap();
+    settings.entries[1] = Entry::solar(0, Off, "sunrise-10").unwrap();
+    let day = days_from_civil(2024, 12, 21);
+    let on = Event::Sunset.time(&london, day).unwrap() + 15 * 60;
+    let off = Event::Sunrise.time(&london, day + 1).unwrap() - 10 * 60;
+    let noon = utc(2024, 12, 21, 12, 0);
+    assert_eq!(settings.next(&settings.entries[0], noon), Some(on));
+    assert_eq!(settings.next(&settings.entries[1], noon), Some(off));
+    let mut clock = Clock::boot(on - 1);
+    assert_eq!(clock.advance(&settings, 0), []);
+    assert_eq!(clock.advance(&settings, 1), [(0, On)]);
+    assert_eq!(clock.advance(&settings, off - on - 1), []);
+    assert_eq!(clock.advance(&settings, 1), [(0, Off)]);
+}
+
+#[test]
+fn date_ranges() {
+    let local = |year, month, day| Timezone::UTC.local(utc(year, month, day, 12, 0));
+    // Christmas every year
+    let christmas = Range::date(1, 0, 12, 25);
+    assert!(christmas.contains(&local(2026, 12, 25)));
+    assert!(christmas.contains(&local(2031, 12, 25)));
+    assert!(!christmas.contains(&local(2026, 12, 26)));
+    // A shutdown over the turn of 2026
+    let shutdown = Range {
+        calendar: 1,
+        year: 2026,
+        start: (12, 24),
+        end: (1, 3),
+    };
+    assert!(!shutdown.contains(&local(2026, 12, 23)));
+    assert!(shutdown.contains(&local(2026, 12, 31)));
+    assert!(shutdown.contains(&local(2027, 1, 3)));
+    assert!(!shutdown.contains(&local(2027, 1, 4)));
+    assert!(!shutdown.contains(&local(2026, 1, 2)));
+    assert!(!shutdown.contains(&local(2027, 12, 28)));
+}
+
+#[test]
+fn calendars() {
+    let mut settings = settings(
+        Timezone::UTC,
+        Window {
+            days: MONDAY,
+            on: 8 * 60,
+            off: 17 * 60,
+        },
+    );
+    settings.entries[0] = Entry::cron(1, On, "0 7 * * 1").unwrap();
+    // Holidays are like Sunday for relay 0, nothing runs on shutdown days
+    settings.calendars[0] = Calendar {
+        mode: Mode::Weekday,
+        weekday: SUNDAY,
+        relays: 0b01,
+    };
+    settings.calendars[1] = Calendar {
+        mode: Mode::Suppress,
+        weekday: 0,
+        relays: 0b11,
+    };
+    // Monday 2026-10-26 is a holiday, 2026-11-02 is both, the first
+    // calendar wins
+    settings.ranges[0] = Range::date(1, 2026, 10, 26);
+    settings.ranges[1] = Range::date(1, 2026, 11, 2);
+    settings.ranges[2] = Range {
+        calendar: 2,
+        year: 2026,
+        start: (11, 2),
+        end: (11, 9),
+    };
+    let mut clock = Clock::boot(utc(2026, 10, 26, 11, 59));
+    assert_eq!(clock.advance(&settings, 1), [(0, Off)]);
+    assert_eq!(clock.jump(&settings, utc(2026, 11, 2, 12, 0)), [(0, Off)]);
+    // The cron entry of relay 1 skips the shutdown week
+    let next = settings.next(&settings.entries[0], utc(2026, 10, 26, 12, 0));
+    assert_eq!(next, Some(utc(2026, 11, 16, 7, 0)));
+    assert_eq!(
+        clock.jump(&settings, utc(2026, 11, 9, 7, 0) - 1),
+        [(0, Off)]
+    );
+    assert_eq!(clock.advance(&settings, 1), []);
+    // Holidays are only for relay 0
+    let mut clock = Clock::boot(utc(2026, 10, 26, 7, 0) - 1);
+    assert_eq!(clock.advance(&settings, 0), [(0, Off)]);
+    assert_eq!(clock.advance(&settings, 1), [(1, On)]);
+    // and the regular Monday after
+    assert_eq!(clock.jump(&settings, utc(2026, 11, 16, 12, 0)), [(0, On)]);
+}
@@ -0,0 +1,95 @@
+//! Timer relay functions against a simulated uptime
+//!
+//! `cargo test --target x86_64-unknown-linux-gnu`
+
+use digital_relay_controller::timer::{Mode, Settings, Timer};
+use std::time::Duration;
+
+/// Timer ticked every 100 ms like the device does
+struct Clock {
+    timer: Timer,
+    settings: Settings,
+    now: Duration,
+}
+
+impl Clock {
+    fn new(mode: Mode, time1: u16, time2: u16) -> Self {
+        Self {
+            timer: Timer::NEW,
+            settings: Settings {
+                mode,
+                trigger: 0,
+                time1,
+                time2,
+            },
+            now: Duration::ZERO,
+        }
+    }
+
+    /// Hold the signal for the tenths of a second, returns the relay changes
+    /// with the tenth they happen in
+    fn run(&mut self, signal: bool, tenths: u64) -> Vec<(u64, bool)> {
+        let start = self.now;
+        let mut changes = Vec::new();
+        for _ in 0..tenths {
+            if let Some(state) = self.timer.tick(&self.settings, signal, self.now) {
+                changes.push(((self.now - start).as_millis() as u64 / 100, state));
+            }
+            self.now += Duration::from_millis(100);
+        }
+        changes
+    }
+}
+
+#[test]
+fn on_delay() {
+    let mut clock = Clock::new(Mode::OnDelay, 20, 0);
+    assert_eq!(clock.run(true, 10), []);
+    assert_eq!(clock.timer.remaining(clock.now), Duration::from_secs(1));
+    // The signal dropped before the delay ended
+    assert_eq!(clock.run(false, 10), []);
+    assert_eq!(clock.run(true, 30), [(20, true)]);
+    assert_eq!(clock.run(false, 10), [(0, false)]);
+}
+
+#[test]
+fn off_delay() {
+    let mut clock = Clock::new(Mode::OffDelay, 15, 0);
+    assert_eq!(clock.run(true, 5), [(0, true)]);
+    assert_eq!(clock.run(false, 10), []);
+    // Back on within the delay, it starts over
+    assert_eq!(clock.run(true, 5), []);
+    assert_eq!(clock.run(false, 20), [(15, false)]);
+}
+
+#[test]
+fn interval() {
+    let mut clock = Clock::new(Mode::Interval, 10, 0);
+    assert_eq!(clock.run(true, 5), [(0, true)]);
+    // Not retriggered while it runs, and regardless of the signal
+    assert_eq!(clock.run(false, 2), []);
+    assert_eq!(clock.run(true, 10), [(3, false)]);
+    assert_eq!(clock.run(false, 1), []);
+    assert_eq!(clock.run(true, 1), [(0, true)]);
+}
+
+#[test]
+fn flasher() {
+    let mut clock = Clock::new(Mode::Flasher, 3, 7);
+    assert_eq!(
+        clock.run(true, 25),
+        [
+            (0, true),
+            (3, false),
+            (10, true),
+            (13, false),
+            (20, true),
+            (23, false)
+        ]
+    );
+    assert_eq!(clock.timer.remaining(clock.now), Duration::from_millis(500));
+    assert_eq!(clock.run(false, 10), []);
+    assert_eq!(clock.timer.remaining(clock.now), Duration::ZERO);
+    assert_eq!(clock.run(true, 2), [(0, true)]);
+    assert_eq!(clock.run(false, 1), [(0, false)]);
+}